@@ -57,7 +57,7 @@ const SYS_EXIT_EXCLUSIVE_GRAPHICS: u64 = 17;
 const SYS_FS_MKDIR: u64 = 18;
 const SYS_FS_REMOVE: u64 = 19;
 const SYS_FS_RENAME: u64 = 20;
-const SYS_GET_SCANCODE: u64 = 7;
+const SYS_INPUT_POLL: u64 = 52;
 
 
 fn print_char(c: u8) {
@@ -1404,8 +1404,6 @@ pub unsafe extern "C" fn memmove(d: *mut u8, s: *const u8, n: usize) -> *mut u8
 #[unsafe(no_mangle)]
 pub extern "C" fn DG_Init() {}
 
-static mut HAVE_E0_PREFIX: bool = false;
-
 fn scancode_to_doom_key(scancode: u8, extended: bool) -> Option<u8> {
     Some(match (extended, scancode) {
         (_, 0x01) => 27,
@@ -1523,35 +1521,53 @@ pub extern "C" fn DG_GetTicksMs() -> u32 {
     res as u32
 }
 
-#[unsafe(no_mangle)]
-pub unsafe extern "C" fn DG_GetKey(p: *mut i32, k: *mut u8) -> i32 {
+// The kernel's input event, see rustos_user::InputEvent
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct InputEvent {
+    time_ns: u64,
+    kind: u16,
+    code: u16,
+    modifiers: u16,
+    pressed: u8,
+    _reserved: u8,
+    dx: i32,
+    dy: i32,
+    unicode: u32,
+    _reserved2: u32,
+}
+
+const INPUT_KEY: u16 = 1;
+// Keycodes are set 1 make codes, with this bit for the keys sent after an E0
+const KEYCODE_EXTENDED: u16 = 0x80;
+
+fn input_poll() -> Option<InputEvent> {
+    let mut event = InputEvent::default();
+    let mut count: u64;
     unsafe {
-        loop {
-            let mut v: u64 = 0;
-            core::arch::asm!("int 0x80", in("rax") SYS_GET_SCANCODE, lateout("rax") v);
-            let raw = v as u8;
-
-            if raw == 0 {
-                return 0;
-            }
-
-            if raw == 0xE0 {
-                HAVE_E0_PREFIX = true;
-                continue;
-            }
-
-            let released = (raw & 0x80) != 0;
-            let scancode = raw & 0x7F;
-            let extended = HAVE_E0_PREFIX;
-            HAVE_E0_PREFIX = false;
+        core::arch::asm!("int 0x80", in("rax") SYS_INPUT_POLL, in("rdi") &mut event as *mut InputEvent as u64, in("rsi") 1u64, lateout("rax") count);
+    }
+    (count == 1).then_some(event)
+}
 
-            if let Some(doom_key) = scancode_to_doom_key(scancode, extended) {
-                *p = if released { 0 } else { 1 };
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn DG_GetKey(p: *mut i32, k: *mut u8) -> i32 {
+    // Doom has no mouse here, everything but keys is dropped
+    while let Some(event) = input_poll() {
+        if event.kind != INPUT_KEY {
+            continue;
+        }
+        let scancode = (event.code & !KEYCODE_EXTENDED) as u8;
+        let extended = event.code & KEYCODE_EXTENDED != 0;
+        if let Some(doom_key) = scancode_to_doom_key(scancode, extended) {
+            unsafe {
+                *p = event.pressed as i32;
                 *k = doom_key;
-                return 1;
             }
+            return 1;
         }
     }
+    0
 }
 
 #[unsafe(no_mangle)]
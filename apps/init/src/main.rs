@@ -0,0 +1,42 @@
+#![no_std]
+#![no_main]
+
+// PID 1. The kernel starts us at boot, we start the shell and restart it if it ever exits.
+
+use rustos_user::{
+    WAIT_KILLED, WAIT_STOPPED, continue_group, print_str, set_foreground, sleep_ms, spawn, wait,
+};
+
+const SHELL_PATH: &[u8] = b"/bin/sh\0";
+
+#[panic_handler]
+fn panic(_info: &core::panic::PanicInfo) -> ! {
+    loop {}
+}
+
+#[unsafe(no_mangle)]
+#[unsafe(link_section = ".text.start")]
+pub extern "C" fn _start() -> ! {
+    loop {
+        match spawn(SHELL_PATH, None) {
+            Some(task_id) => loop {
+                match wait(task_id) {
+                    // Ctrl+Z at the prompt stops the shell itself, just resume it
+                    WAIT_STOPPED => {
+                        set_foreground(task_id);
+                        continue_group(task_id);
+                    }
+                    WAIT_KILLED => {
+                        print_str("init: shell was killed, restarting\n");
+                        break;
+                    }
+                    _ => break,
+                }
+            },
+            None => {
+                print_str("init: could not start /bin/sh, retrying in 5s\n");
+                sleep_ms(5000);
+            }
+        }
+    }
+}
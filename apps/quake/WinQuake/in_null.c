@@ -22,13 +22,9 @@ Foundation, Inc., 59 Temple Place - Suite 330, Boston, MA  02111-1307, USA.
 #include "quakedef.h"
 
 extern int quake_poll_mouse_deltas(void);
-extern int quake_poll_mouse_buttons(void);
 
 cvar_t  m_filter = {"m_filter","1"};
 
-static int      mouse_buttons = 3;
-static int      mouse_oldbuttonstate;
-static int      mouse_buttonstate;
 static float    mouse_x, mouse_y;
 static float    old_mouse_x, old_mouse_y;
 
@@ -43,20 +39,7 @@ void IN_Shutdown (void)
 
 void IN_Commands (void)
 {
-	int i;
-
-	mouse_buttonstate = quake_poll_mouse_buttons() & 0x07;
-
-	for (i = 0; i < mouse_buttons; i++)
-	{
-		if ( (mouse_buttonstate & (1<<i)) && !(mouse_oldbuttonstate & (1<<i)) )
-			Key_Event (K_MOUSE1 + i, true);
-
-		if ( !(mouse_buttonstate & (1<<i)) && (mouse_oldbuttonstate & (1<<i)) )
-			Key_Event (K_MOUSE1 + i, false);
-	}
-
-	mouse_oldbuttonstate = mouse_buttonstate;
+	// Mouse buttons come with the keys, in Sys_SendKeyEvents
 }
 
 void IN_Move (usercmd_t *cmd)
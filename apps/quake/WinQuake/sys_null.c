@@ -22,8 +22,10 @@ Foundation, Inc., 59 Temple Place - Suite 330, Boston, MA  02111-1307, USA.
 #include "quakedef.h"
 #include "errno.h"
 
-extern int quake_poll_key(void);
-extern int quake_poll_scancode(void);
+#define QUAKE_INPUT_KEY		1
+#define QUAKE_INPUT_BUTTON	2
+
+extern int quake_poll_input(int *code, int *down);
 extern void quake_yield(void);
 extern unsigned long long quake_uptime_ms(void);
 
@@ -293,48 +295,24 @@ void Sys_Sleep (void)
 
 void Sys_SendKeyEvents (void)
 {
-        int i;
-        qboolean extended = false;
-
-        for (i = 0; i < 128; ++i)
+        int kind, code, down;
+
+        /*
+         * Keys and mouse buttons arrive in the order they happened. Keycodes are set 1 make
+         * codes with 0x80 set for the E0 keys. The mouse movement read on the way is picked
+         * up by IN_Move.
+         */
+        while ((kind = quake_poll_input(&code, &down)) != 0)
         {
-                int sc = quake_poll_scancode();
-                int key;
-                qboolean down;
-
-                if (sc <= 0)
-                        break;
-
-                if (sc == 0xE0)
-                {
-                        extended = true;
-                        continue;
-                }
+                int key = 0;
 
-                down = ((sc & 0x80) == 0);
-                key = QuakeKeyFromScancode(sc & 0x7F, extended);
-                extended = false;
+                if (kind == QUAKE_INPUT_KEY)
+                        key = QuakeKeyFromScancode(code & 0x7F, (code & 0x80) != 0);
+                else if (kind == QUAKE_INPUT_BUTTON && code < 3)
+                        key = K_MOUSE1 + code;
 
                 if (key)
-                        Key_Event(key, down);
-        }
-
-        /* Fallback for simple cooked ASCII streams when raw scancodes are not delivered. */
-        for (i = 0; i < 16; ++i)
-        {
-                int c = quake_poll_key();
-                if (c <= 0)
-                        break;
-                /*
-                 * Raw scancode path above is authoritative. Restrict fallback to ASCII only,
-                 * otherwise synthetic high-byte keycodes can fight menu navigation.
-                 */
-                if (c > 127)
-                        continue;
-                if (c >= 'A' && c <= 'Z')
-                        c = c - 'A' + 'a';
-                Key_Event(c & 0xFF, true);
-                Key_Event(c & 0xFF, false);
+                        Key_Event(key, down != 0);
         }
 }
 
@@ -6,10 +6,11 @@ mod libc;
 
 use core::ffi::c_void;
 use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicI32, Ordering};
 use rustos_user::{
-    SYS_DRAW_BUFFER, SYS_ENTER_EXCLUSIVE_GRAPHICS, SYS_EXIT_EXCLUSIVE_GRAPHICS, SYS_GET_KEY,
-    SYS_GET_SCANCODE, SYS_GET_UPTIME, SYS_MOUSE_GET_BUTTONS, SYS_MOUSE_GET_DELTAS, SYS_YIELD,
-    exit as user_exit, syscall0, syscall2,
+    INPUT_BUTTON, INPUT_KEY, INPUT_MOTION, InputEvent, SYS_DRAW_BUFFER,
+    SYS_ENTER_EXCLUSIVE_GRAPHICS, SYS_EXIT_EXCLUSIVE_GRAPHICS, SYS_GET_UPTIME, SYS_YIELD,
+    exit as user_exit, input_poll, syscall0, syscall2,
 };
 
 pub use crate::libc::math::{
@@ -58,21 +59,53 @@ pub extern "C" fn quake_draw_buffer(p: *const u32, w: u32, h: u32) {
     let dims = (w as u64) | ((h as u64) << 32);
     let _ = syscall2(SYS_DRAW_BUFFER, p as u64, dims);
 }
+
+// Quake takes keys and mouse buttons in Sys_SendKeyEvents and the mouse movement in IN_Move.
+// Both come out of the one input queue, so a click lands between the keys it came between;
+// the movement read on the way is kept here until IN_Move asks for it.
+static MOUSE_DX: AtomicI32 = AtomicI32::new(0);
+static MOUSE_DY: AtomicI32 = AtomicI32::new(0);
+
+const QUAKE_INPUT_NONE: i32 = 0;
+const QUAKE_INPUT_KEY: i32 = 1;
+const QUAKE_INPUT_BUTTON: i32 = 2;
+
+// The next key or mouse button change. For a key `code` is its keycode (the set 1 make
+// code, 0x80 set for E0 keys), for a button its number from 0 (left, right, middle).
 #[unsafe(no_mangle)]
-pub extern "C" fn quake_poll_key() -> i32 {
-    syscall0(SYS_GET_KEY) as i32
-}
-#[unsafe(no_mangle)]
-pub extern "C" fn quake_poll_scancode() -> i32 {
-    syscall0(SYS_GET_SCANCODE) as i32
+pub unsafe extern "C" fn quake_poll_input(code: *mut i32, down: *mut i32) -> i32 {
+    let mut event = [InputEvent::default()];
+    while input_poll(&mut event) == 1 {
+        let event = event[0];
+        let kind = match event.kind {
+            INPUT_KEY => QUAKE_INPUT_KEY,
+            INPUT_BUTTON => QUAKE_INPUT_BUTTON,
+            INPUT_MOTION => {
+                MOUSE_DX.fetch_add(event.dx, Ordering::Relaxed);
+                MOUSE_DY.fetch_add(event.dy, Ordering::Relaxed);
+                continue;
+            }
+            _ => continue,
+        };
+        unsafe {
+            *code = if kind == QUAKE_INPUT_BUTTON {
+                event.code.trailing_zeros() as i32
+            } else {
+                event.code as i32
+            };
+            *down = event.pressed as i32;
+        }
+        return kind;
+    }
+    QUAKE_INPUT_NONE
 }
+// The movement since the last call, dx in the low 16 bits and dy (positive up) in the high
 #[unsafe(no_mangle)]
 pub extern "C" fn quake_poll_mouse_deltas() -> i32 {
-    syscall0(SYS_MOUSE_GET_DELTAS) as i32
-}
-#[unsafe(no_mangle)]
-pub extern "C" fn quake_poll_mouse_buttons() -> i32 {
-    syscall0(SYS_MOUSE_GET_BUTTONS) as i32
+    let clamp = |v: i32| v.clamp(i16::MIN as i32, i16::MAX as i32) as u16 as u32;
+    let dx = clamp(MOUSE_DX.swap(0, Ordering::Relaxed));
+    let dy = clamp(MOUSE_DY.swap(0, Ordering::Relaxed));
+    (dx | (dy << 16)) as i32
 }
 #[unsafe(no_mangle)]
 pub extern "C" fn quake_yield() {
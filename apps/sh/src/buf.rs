@@ -0,0 +1,86 @@
+// Fixed-capacity ASCII string that always keeps a trailing NUL, so it can be handed
+// straight to syscalls that take C strings. We have no heap in user space.
+pub struct StrBuf<const N: usize> {
+    bytes: [u8; N],
+    len: usize,
+}
+
+impl<const N: usize> StrBuf<N> {
+    pub const fn new() -> Self {
+        Self {
+            bytes: [0; N],
+            len: 0,
+        }
+    }
+
+    pub fn from_str(s: &str) -> Self {
+        let mut buf = Self::new();
+        buf.push_str(s);
+        buf
+    }
+
+    pub fn as_str(&self) -> &str {
+        core::str::from_utf8(&self.bytes[..self.len]).unwrap_or("")
+    }
+
+    // The contents including the NUL terminator
+    pub fn as_cstr(&self) -> &[u8] {
+        &self.bytes[..=self.len]
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn clear(&mut self) {
+        self.truncate(0);
+    }
+
+    pub fn truncate(&mut self, len: usize) {
+        if len < self.len {
+            self.len = len;
+            self.bytes[len] = 0;
+        }
+    }
+
+    pub fn push(&mut self, byte: u8) -> bool {
+        self.insert(self.len, byte)
+    }
+
+    pub fn push_str(&mut self, s: &str) -> bool {
+        self.insert_str(self.len, s)
+    }
+
+    pub fn insert(&mut self, index: usize, byte: u8) -> bool {
+        self.insert_bytes(index, &[byte])
+    }
+
+    pub fn insert_str(&mut self, index: usize, s: &str) -> bool {
+        self.insert_bytes(index, s.as_bytes())
+    }
+
+    fn insert_bytes(&mut self, index: usize, bytes: &[u8]) -> bool {
+        let add = bytes.len();
+        if index > self.len || self.len + add >= N {
+            return false;
+        }
+        self.bytes.copy_within(index..self.len, index + add);
+        self.bytes[index..index + add].copy_from_slice(bytes);
+        self.len += add;
+        self.bytes[self.len] = 0;
+        true
+    }
+
+    pub fn remove_range(&mut self, start: usize, end: usize) {
+        if start >= end || end > self.len {
+            return;
+        }
+        self.bytes.copy_within(end..self.len, start);
+        self.len -= end - start;
+        self.bytes[self.len] = 0;
+    }
+}
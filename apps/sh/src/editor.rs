@@ -0,0 +1,427 @@
+use rustos_user::{DirEntries, fs_read, fs_read_dir, fs_write, print_char, print_str};
+
+use crate::buf::StrBuf;
+use crate::{BUILTINS, PathBuf, print_num, print_prompt, resolve_path};
+
+pub const LINE_MAX: usize = 256;
+pub type Line = StrBuf<LINE_MAX>;
+
+const HISTORY_FILE: &[u8] = b"/.history\0";
+const HISTORY_CAPACITY: usize = 64;
+const MAX_CANDIDATES: usize = 64;
+
+/***************
+ * LINE EDITOR *
+ ***************/
+// Any edit that isn't a plain append redraws the whole line and moves the
+// terminal cursor back into place with ESC[<n>D.
+pub struct LineEditor {
+    pub buffer: Line,
+    pub cursor: usize,
+}
+
+impl LineEditor {
+    pub const fn new() -> Self {
+        Self {
+            buffer: StrBuf::new(),
+            cursor: 0,
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        self.buffer.as_str()
+    }
+
+    pub fn clear(&mut self) {
+        self.buffer.clear();
+        self.cursor = 0;
+    }
+
+    pub fn redraw(&self, cwd: &str) {
+        print_char(b'\r');
+        print_prompt(cwd);
+        print_str(self.buffer.as_str());
+        print_str("\x1B[K");
+        self.cursor_back(self.buffer.len() - self.cursor);
+    }
+
+    fn cursor_back(&self, n: usize) {
+        if n > 0 {
+            print_str("\x1B[");
+            print_num(n as u64);
+            print_char(b'D');
+        }
+    }
+
+    pub fn insert(&mut self, byte: u8, cwd: &str) {
+        if !self.buffer.insert(self.cursor, byte) {
+            return;
+        }
+        self.cursor += 1;
+        if self.cursor == self.buffer.len() {
+            print_char(byte);
+        } else {
+            self.redraw(cwd);
+        }
+    }
+
+    pub fn insert_str(&mut self, text: &str, cwd: &str) {
+        if self.buffer.insert_str(self.cursor, text) {
+            self.cursor += text.len();
+            self.redraw(cwd);
+        }
+    }
+
+    pub fn replace(&mut self, text: &str, cwd: &str) {
+        self.buffer.clear();
+        self.buffer.push_str(text);
+        self.cursor = self.buffer.len();
+        self.redraw(cwd);
+    }
+
+    pub fn backspace(&mut self, cwd: &str) {
+        if self.cursor == 0 {
+            return;
+        }
+        self.cursor -= 1;
+        self.buffer.remove_range(self.cursor, self.cursor + 1);
+        if self.cursor == self.buffer.len() {
+            // Erase character from screen: backspace, space, backspace
+            print_str("\x08 \x08");
+        } else {
+            self.redraw(cwd);
+        }
+    }
+
+    pub fn delete(&mut self, cwd: &str) {
+        if self.cursor < self.buffer.len() {
+            self.buffer.remove_range(self.cursor, self.cursor + 1);
+            self.redraw(cwd);
+        }
+    }
+
+    pub fn move_left(&mut self) {
+        if self.cursor > 0 {
+            self.cursor -= 1;
+            print_str("\x1B[D");
+        }
+    }
+
+    pub fn move_right(&mut self) {
+        if self.cursor < self.buffer.len() {
+            self.cursor += 1;
+            print_str("\x1B[C");
+        }
+    }
+
+    pub fn move_home(&mut self) {
+        self.cursor_back(self.cursor);
+        self.cursor = 0;
+    }
+
+    pub fn move_end(&mut self, cwd: &str) {
+        if self.cursor < self.buffer.len() {
+            self.cursor = self.buffer.len();
+            self.redraw(cwd);
+        }
+    }
+
+    pub fn kill_to_end(&mut self, cwd: &str) {
+        self.buffer.truncate(self.cursor);
+        self.redraw(cwd);
+    }
+
+    pub fn kill_to_start(&mut self, cwd: &str) {
+        self.buffer.remove_range(0, self.cursor);
+        self.cursor = 0;
+        self.redraw(cwd);
+    }
+
+    pub fn kill_word(&mut self, cwd: &str) {
+        let bytes = self.buffer.as_str().as_bytes();
+        let mut start = self.cursor;
+        while start > 0 && bytes[start - 1] == b' ' {
+            start -= 1;
+        }
+        while start > 0 && bytes[start - 1] != b' ' {
+            start -= 1;
+        }
+        self.buffer.remove_range(start, self.cursor);
+        self.cursor = start;
+        self.redraw(cwd);
+    }
+
+    // Start of the word the cursor is currently in
+    fn word_start(&self) -> usize {
+        self.buffer.as_str().as_bytes()[..self.cursor]
+            .iter()
+            .rposition(|&b| b == b' ')
+            .map(|i| i + 1)
+            .unwrap_or(0)
+    }
+}
+
+/****************
+ * HISTORY RING *
+ ****************/
+// The last HISTORY_CAPACITY commands, oldest first. Rewritten to HISTORY_FILE
+// after every command so it survives a reboot.
+pub struct History {
+    entries: [Line; HISTORY_CAPACITY],
+    start: usize,
+    count: usize,
+    cursor: Option<usize>, // Index of the entry being shown while browsing with Up/Down
+    draft: Line,           // What was typed before browsing started
+}
+
+static mut HISTORY_IO: [u8; HISTORY_CAPACITY * LINE_MAX] = [0; HISTORY_CAPACITY * LINE_MAX];
+
+impl History {
+    pub const fn new() -> Self {
+        Self {
+            entries: [const { StrBuf::new() }; HISTORY_CAPACITY],
+            start: 0,
+            count: 0,
+            cursor: None,
+            draft: StrBuf::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.count
+    }
+
+    pub fn get(&self, index: usize) -> Option<&str> {
+        if index >= self.count {
+            return None;
+        }
+        Some(self.entries[(self.start + index) % HISTORY_CAPACITY].as_str())
+    }
+
+    pub fn load(&mut self) {
+        let io = unsafe { &mut *core::ptr::addr_of_mut!(HISTORY_IO) };
+        let Some(len) = fs_read(HISTORY_FILE, io) else {
+            return;
+        };
+        let Ok(text) = core::str::from_utf8(&io[..len]) else {
+            return;
+        };
+        for entry in text.lines().map(str::trim).filter(|l| !l.is_empty()) {
+            self.push_entry(entry);
+        }
+    }
+
+    fn push_entry(&mut self, entry: &str) {
+        if self.count > 0 && self.get(self.count - 1) == Some(entry) {
+            return;
+        }
+        let slot = if self.count == HISTORY_CAPACITY {
+            let oldest = self.start;
+            self.start = (self.start + 1) % HISTORY_CAPACITY;
+            oldest
+        } else {
+            self.count += 1;
+            (self.start + self.count - 1) % HISTORY_CAPACITY
+        };
+        self.entries[slot] = StrBuf::from_str(entry);
+    }
+
+    pub fn push(&mut self, entry: &str) {
+        self.push_entry(entry);
+        self.save();
+    }
+
+    fn save(&self) {
+        let io = unsafe { &mut *core::ptr::addr_of_mut!(HISTORY_IO) };
+        let mut len = 0;
+        for i in 0..self.count {
+            let entry = self.get(i).unwrap_or("").as_bytes();
+            io[len..len + entry.len()].copy_from_slice(entry);
+            io[len + entry.len()] = b'\n';
+            len += entry.len() + 1;
+        }
+        if fs_write(HISTORY_FILE, &io[..len]).is_none() {
+            print_str("sh: could not save history\n");
+        }
+    }
+
+    pub fn reset_cursor(&mut self) {
+        self.cursor = None;
+        self.draft.clear();
+    }
+
+    pub fn previous(&mut self, current_line: &str) -> Option<&str> {
+        let index = match self.cursor {
+            None => {
+                self.draft = StrBuf::from_str(current_line);
+                self.count.checked_sub(1)?
+            }
+            Some(0) => return None,
+            Some(i) => i - 1,
+        };
+        self.cursor = Some(index);
+        self.get(index)
+    }
+
+    pub fn next(&mut self) -> Option<&str> {
+        let index = self.cursor? + 1;
+        if index < self.count {
+            self.cursor = Some(index);
+            self.get(index)
+        } else {
+            // Walked past the newest entry, give back what was being typed
+            self.cursor = None;
+            Some(self.draft.as_str())
+        }
+    }
+}
+
+/******************
+ * TAB COMPLETION *
+ ******************/
+// The first word completes against built-ins and programs in PATH, any other
+// word (or one containing a '/') completes against the filesystem.
+struct Candidate {
+    name: StrBuf<64>,
+    is_dir: bool,
+}
+
+struct Candidates {
+    items: [Candidate; MAX_CANDIDATES],
+    count: usize,
+}
+
+static mut CANDIDATES: Candidates = Candidates {
+    items: [const {
+        Candidate {
+            name: StrBuf::new(),
+            is_dir: false,
+        }
+    }; MAX_CANDIDATES],
+    count: 0,
+};
+
+static mut DIR_BUF: [u8; 8192] = [0; 8192];
+
+impl Candidates {
+    fn add(&mut self, name: &str, is_dir: bool, prefix: &str) {
+        if name == "." || name == ".." || name.len() < prefix.len() || name.len() >= 64 {
+            return;
+        }
+        if !name.is_char_boundary(prefix.len())
+            || !name[..prefix.len()].eq_ignore_ascii_case(prefix)
+        {
+            return;
+        }
+        if self.count == MAX_CANDIDATES
+            || self.items[..self.count]
+                .iter()
+                .any(|c| c.name.as_str().eq_ignore_ascii_case(name))
+        {
+            return;
+        }
+        self.items[self.count] = Candidate {
+            name: StrBuf::from_str(name),
+            is_dir,
+        };
+        self.count += 1;
+    }
+
+    fn common_prefix_len(&self) -> usize {
+        let first = self.items[0].name.as_str().as_bytes();
+        let mut len = first.len();
+        for candidate in &self.items[1..self.count] {
+            let other = candidate.name.as_str().as_bytes();
+            len = len.min(other.len());
+            for i in 0..len {
+                if !first[i].eq_ignore_ascii_case(&other[i]) {
+                    len = i;
+                    break;
+                }
+            }
+        }
+        len
+    }
+}
+
+pub fn complete(line: &mut LineEditor, cwd: &str, path_entries: &[PathBuf]) {
+    let candidates = unsafe { &mut *core::ptr::addr_of_mut!(CANDIDATES) };
+    candidates.count = 0;
+
+    let word_start = line.word_start();
+    let word: StrBuf<LINE_MAX> = StrBuf::from_str(&line.as_str()[word_start..line.cursor]);
+    let word = word.as_str();
+    let is_command = line.as_str()[..word_start].trim().is_empty();
+
+    let (dir_part, prefix) = match word.rfind('/') {
+        Some(i) => (&word[..=i], &word[i + 1..]),
+        None => ("", word),
+    };
+
+    if is_command && dir_part.is_empty() {
+        for builtin in BUILTINS.iter() {
+            candidates.add(builtin, false, prefix);
+        }
+        for dir in path_entries {
+            let resolved = resolve_path(cwd, dir.as_str());
+            for_each_entry(resolved.as_str(), |name, is_dir| {
+                if !is_dir {
+                    candidates.add(strip_bin_suffix(name), false, prefix);
+                }
+            });
+        }
+    } else {
+        let dir = resolve_path(cwd, dir_part);
+        for_each_entry(dir.as_str(), |name, is_dir| {
+            candidates.add(name, is_dir, prefix);
+        });
+    }
+
+    match candidates.count {
+        0 => {}
+        1 => {
+            let candidate = &candidates.items[0];
+            let mut completion: StrBuf<72> =
+                StrBuf::from_str(&candidate.name.as_str()[prefix.len()..]);
+            completion.push(if candidate.is_dir { b'/' } else { b' ' });
+            line.insert_str(completion.as_str(), cwd);
+        }
+        _ => {
+            let common = candidates.common_prefix_len();
+            if common > prefix.len() {
+                line.insert_str(
+                    &candidates.items[0].name.as_str()[prefix.len()..common],
+                    cwd,
+                );
+            } else {
+                print_char(b'\n');
+                for candidate in &candidates.items[..candidates.count] {
+                    print_str(candidate.name.as_str());
+                    print_str(if candidate.is_dir { "/  " } else { "  " });
+                }
+                print_char(b'\n');
+                line.redraw(cwd);
+            }
+        }
+    }
+}
+
+fn strip_bin_suffix(name: &str) -> &str {
+    if name.len() > 4 && name[name.len() - 4..].eq_ignore_ascii_case(".bin") {
+        &name[..name.len() - 4]
+    } else {
+        name
+    }
+}
+
+// Calls `f(name, is_dir)` for every entry in a directory
+fn for_each_entry(path: &str, mut f: impl FnMut(&str, bool)) {
+    let c_path: PathBuf = StrBuf::from_str(path);
+    let buf = unsafe { &mut *core::ptr::addr_of_mut!(DIR_BUF) };
+    let Some(len) = fs_read_dir(c_path.as_cstr(), buf) else {
+        return;
+    };
+    for entry in DirEntries::new(&buf[..len]) {
+        f(entry.name, entry.is_dir);
+    }
+}
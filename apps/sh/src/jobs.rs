@@ -0,0 +1,155 @@
+use rustos_user::{
+    TaskState, WAIT_STOPPED, continue_group, print_char, print_str, set_foreground, task_state,
+    wait,
+};
+
+use crate::buf::StrBuf;
+use crate::print_num;
+
+const MAX_JOBS: usize = 16;
+
+/*************
+ * JOB TABLE *
+ *************/
+// Every program we launch leads its own process group, so a job is just the
+// task ID of that leader. Job numbers (%n) are the slot index plus one.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum JobState {
+    Running,
+    Stopped,
+}
+
+pub struct Job {
+    pub task_id: u64,
+    pub state: JobState,
+    pub command: StrBuf<64>,
+}
+
+pub struct Jobs {
+    slots: [Option<Job>; MAX_JOBS],
+}
+
+impl Jobs {
+    pub const fn new() -> Self {
+        Self {
+            slots: [const { None }; MAX_JOBS],
+        }
+    }
+
+    pub fn add(&mut self, task_id: u64, state: JobState, command: &str) -> Option<usize> {
+        let index = self.slots.iter().position(|slot| slot.is_none())?;
+        let mut name = StrBuf::new();
+        for &b in command.as_bytes().iter().take(63) {
+            name.push(b);
+        }
+        self.slots[index] = Some(Job {
+            task_id,
+            state,
+            command: name,
+        });
+        Some(index + 1)
+    }
+
+    // "%n", "n" or nothing for the most recent job
+    pub fn resolve(&self, spec: Option<&str>) -> Option<usize> {
+        match spec {
+            None => self
+                .slots
+                .iter()
+                .rposition(|slot| slot.is_some())
+                .map(|i| i + 1),
+            Some(spec) => {
+                let number: usize = spec.strip_prefix('%').unwrap_or(spec).parse().ok()?;
+                match self.slots.get(number.checked_sub(1)?) {
+                    Some(Some(_)) => Some(number),
+                    _ => None,
+                }
+            }
+        }
+    }
+
+    // Drops jobs whose task is gone and picks up state changes, optionally
+    // reporting every job (for the `jobs` built-in).
+    pub fn refresh(&mut self, list_all: bool) {
+        for (i, slot) in self.slots.iter_mut().enumerate() {
+            let Some(job) = slot else {
+                continue;
+            };
+            match task_state(job.task_id) {
+                None => {
+                    print_job(i + 1, "Done", job.command.as_str());
+                    *slot = None;
+                }
+                Some(state) => {
+                    job.state = match state {
+                        TaskState::Stopped => JobState::Stopped,
+                        TaskState::Running => JobState::Running,
+                    };
+                    if list_all {
+                        print_job(i + 1, job.state_name(), job.command.as_str());
+                    }
+                }
+            }
+        }
+    }
+
+    // Resumes a job in the foreground and waits for it like a normal command.
+    pub fn foreground(&mut self, number: usize) {
+        let Some(job) = self.slots[number - 1].take() else {
+            return;
+        };
+        print_str(job.command.as_str());
+        print_char(b'\n');
+        set_foreground(job.task_id);
+        continue_group(job.task_id);
+        self.wait_foreground(job.task_id, job.command.as_str());
+    }
+
+    pub fn background(&mut self, number: usize) {
+        let Some(job) = self.slots[number - 1].as_mut() else {
+            return;
+        };
+        if job.state == JobState::Running {
+            print_str("bg: job already running\n");
+            return;
+        }
+        continue_group(job.task_id);
+        job.state = JobState::Running;
+        print_job(number, "Running", job.command.as_str());
+    }
+
+    // Waits for a foreground program, if it gets stopped it becomes a job.
+    pub fn wait_foreground(&mut self, task_id: u64, command: &str) {
+        if wait(task_id) != WAIT_STOPPED {
+            return;
+        }
+        print_char(b'\n');
+        match self.add(task_id, JobState::Stopped, command) {
+            Some(number) => print_job(number, "Stopped", command),
+            None => {
+                // No slot to remember it in, so don't leave it stuck forever
+                print_str("sh: too many jobs, resuming in background\n");
+                continue_group(task_id);
+            }
+        }
+    }
+}
+
+impl Job {
+    fn state_name(&self) -> &'static str {
+        match self.state {
+            JobState::Running => "Running",
+            JobState::Stopped => "Stopped",
+        }
+    }
+}
+
+fn print_job(number: usize, state: &str, command: &str) {
+    print_char(b'[');
+    print_num(number as u64);
+    print_str("]  ");
+    print_str(state);
+    print_str("    ");
+    print_str(command);
+    print_char(b'\n');
+}
@@ -0,0 +1,563 @@
+#![no_std]
+#![no_main]
+
+// The RustOS shell. Started by init, talks to the kernel only through syscalls.
+
+mod buf;
+mod editor;
+mod jobs;
+
+use buf::StrBuf;
+use editor::{History, LineEditor, complete};
+use jobs::{JobState, Jobs};
+use rustos_user::{
+    DateTime, DirEntries, PciInfo, clear_screen, fs_mkdir, fs_open, fs_read_dir, fs_read_handle,
+    fs_remove, get_key, gettimeofday, pci_list, print_char, print_str, reboot, set_cursor,
+    set_keymap, shutdown, spawn, spawn_background, yield_now,
+};
+
+pub const BUILTINS: [&str; 17] = [
+    "help", "clear", "ls", "mkdir", "cd", "pwd", "rm", "path", "history", "jobs", "fg", "bg",
+    "lspci", "date", "keymap", "shutdown", "reboot",
+];
+
+const PATH_MAX: usize = 128;
+const MAX_PATH_ENTRIES: usize = 8;
+
+pub type PathBuf = StrBuf<PATH_MAX>;
+
+// Key codes produced by the kernel's scancode_to_byte
+const KEY_CTRL_A: u8 = 0x01;
+const KEY_CTRL_E: u8 = 0x05;
+const KEY_CTRL_K: u8 = 0x0B;
+const KEY_CTRL_U: u8 = 0x15;
+const KEY_CTRL_W: u8 = 0x17;
+const KEY_UP: u8 = 0x80;
+const KEY_DOWN: u8 = 0x81;
+const KEY_LEFT: u8 = 0x82;
+const KEY_RIGHT: u8 = 0x83;
+const KEY_HOME: u8 = 0x84;
+const KEY_END: u8 = 0x85;
+const KEY_DELETE: u8 = 0x86;
+
+struct Shell {
+    line: LineEditor,
+    history: History,
+    jobs: Jobs,
+    current_dir: PathBuf,
+    path_entries: [PathBuf; MAX_PATH_ENTRIES],
+    path_count: usize,
+}
+
+// No heap in user space, so all shell state lives here
+static mut SHELL: Shell = Shell {
+    line: LineEditor::new(),
+    history: History::new(),
+    jobs: Jobs::new(),
+    current_dir: StrBuf::new(),
+    path_entries: [const { StrBuf::new() }; MAX_PATH_ENTRIES],
+    path_count: 0,
+};
+
+const KEYMAP_DIR: &[u8] = b"/keymaps\0";
+
+static mut LS_BUF: [u8; 8192] = [0; 8192];
+static mut PCI_BUF: [PciInfo; 64] = [const { PciInfo::new() }; 64];
+
+#[panic_handler]
+fn panic(_info: &core::panic::PanicInfo) -> ! {
+    loop {}
+}
+
+#[unsafe(no_mangle)]
+#[unsafe(link_section = ".text.start")]
+pub extern "C" fn _start() -> ! {
+    let shell = unsafe { &mut *core::ptr::addr_of_mut!(SHELL) };
+    shell.current_dir = StrBuf::from_str("/");
+    for dir in ["/apps", "/bin", "/"] {
+        shell.path_entries[shell.path_count] = StrBuf::from_str(dir);
+        shell.path_count += 1;
+    }
+    shell.history.load();
+
+    print_str("\nWelcome to RustOS Shell!\n");
+    print_prompt(shell.current_dir.as_str());
+
+    // Reads of /dev/tty sleep until a key comes, polling is only left for a kernel without it
+    let tty = fs_open(b"/dev/tty\0");
+    let mut keys = [0u8; 64];
+    loop {
+        match tty.and_then(|tty| fs_read_handle(tty, &mut keys)) {
+            Some(n) => keys[..n].iter().for_each(|&key| shell.handle_key(key)),
+            None => {
+                while let Some(key) = get_key() {
+                    shell.handle_key(key);
+                }
+                yield_now();
+            }
+        }
+    }
+}
+
+pub fn print_prompt(current_dir: &str) {
+    print_str(current_dir);
+    print_str("> ");
+}
+
+// Zero padded to `width` hex digits
+fn print_hex(n: u64, width: usize) {
+    for i in (0..width).rev() {
+        print_char(b"0123456789abcdef"[((n >> (i * 4)) & 0xF) as usize]);
+    }
+}
+
+pub fn print_num(mut n: u64) {
+    let mut digits = [0u8; 20];
+    let mut i = digits.len();
+    loop {
+        i -= 1;
+        digits[i] = b'0' + (n % 10) as u8;
+        n /= 10;
+        if n == 0 {
+            break;
+        }
+    }
+    for &d in &digits[i..] {
+        print_char(d);
+    }
+}
+
+impl Shell {
+    fn handle_key(&mut self, key: u8) {
+        let cwd = self.current_dir.as_str();
+        match key {
+            b'\n' => {
+                self.line.move_end(cwd);
+                print_char(b'\n');
+                let cmd: editor::Line = StrBuf::from_str(self.line.as_str().trim());
+                self.line.clear();
+                self.history.reset_cursor();
+                if !cmd.is_empty() {
+                    self.history.push(cmd.as_str());
+                    self.execute_command(cmd.as_str());
+                }
+                self.jobs.refresh(false);
+                print_prompt(self.current_dir.as_str());
+            }
+            b'\x08' => self.line.backspace(cwd),
+            b'\t' => complete(&mut self.line, cwd, &self.path_entries[..self.path_count]),
+            KEY_LEFT => self.line.move_left(),
+            KEY_RIGHT => self.line.move_right(),
+            KEY_HOME | KEY_CTRL_A => self.line.move_home(),
+            KEY_END | KEY_CTRL_E => self.line.move_end(cwd),
+            KEY_DELETE => self.line.delete(cwd),
+            KEY_CTRL_K => self.line.kill_to_end(cwd),
+            KEY_CTRL_U => self.line.kill_to_start(cwd),
+            KEY_CTRL_W => self.line.kill_word(cwd),
+            KEY_UP => {
+                if let Some(entry) = self.history.previous(self.line.as_str()) {
+                    self.line.replace(entry, cwd);
+                }
+            }
+            KEY_DOWN => {
+                if let Some(entry) = self.history.next() {
+                    self.line.replace(entry, cwd);
+                }
+            }
+            0x20..=0x7E => self.line.insert(key, cwd),
+            _ => {}
+        }
+    }
+
+    fn execute_command(&mut self, cmd_line: &str) {
+        // A trailing '&' runs the program as a background job
+        let (cmd_line, background) = match cmd_line.strip_suffix('&') {
+            Some(rest) => (rest.trim_end(), true),
+            None => (cmd_line, false),
+        };
+        let mut parts = cmd_line.split_whitespace();
+        let cmd = parts.next().unwrap_or("");
+
+        match cmd {
+            "help" => {
+                print_str("Available commands:\n");
+                print_str("  help      - Show this message\n");
+                print_str("  clear     - Clear the screen\n");
+                print_str("  ls        - List files in current directory\n");
+                print_str("  mkdir <name> - Create a directory in the current directory\n");
+                print_str("  cd <path> - Change current directory\n");
+                print_str("  pwd       - Print current directory\n");
+                print_str("  rm <file> - Remove a file\n");
+                print_str("  path      - Show executable search path\n");
+                print_str("  path add <dir> - Add a search directory\n");
+                print_str("  path rm <dir> - Remove a search directory\n");
+                print_str("  history   - Show command history\n");
+                print_str("  jobs      - List background and stopped jobs\n");
+                print_str("  fg [%n]   - Bring a job to the foreground\n");
+                print_str("  bg [%n]   - Resume a stopped job in the background\n");
+                print_str("  lspci     - List PCI devices\n");
+                print_str("  date      - Show the date and time (UTC)\n");
+                print_str("  keymap [name] - List keyboard layouts or switch to one\n");
+                print_str("  shutdown  - Save everything to disk and power off\n");
+                print_str("  reboot    - Save everything to disk and restart\n");
+                print_str("  <program> - Run a .bin program\n");
+                print_str("  <program> & - Run a program in the background (Ctrl+Z stops one)\n");
+                print_str(
+                    "Editing: arrows, Home/End, Ctrl+A/E/K/U/W, Up/Down history, Tab completes\n",
+                );
+            }
+            "clear" => {
+                clear_screen();
+                set_cursor(0, 0);
+            }
+            "ls" => self.list_dir(),
+            "mkdir" => match parts.next() {
+                Some(dir_name) => {
+                    let full_path = resolve_path(self.current_dir.as_str(), dir_name);
+                    if fs_mkdir(full_path.as_cstr()) {
+                        print_str("Directory '");
+                        print_str(full_path.as_str());
+                        print_str("' created.\n");
+                    } else {
+                        print_str("Error creating directory '");
+                        print_str(full_path.as_str());
+                        print_str("'\n");
+                    }
+                }
+                None => print_str("Usage: mkdir <directory_name>\n"),
+            },
+            "cd" => {
+                let target = parts.next().unwrap_or("/");
+                let new_dir = resolve_path(self.current_dir.as_str(), target);
+                if fs_read_dir(new_dir.as_cstr(), &mut []).is_some() {
+                    self.current_dir = new_dir;
+                } else {
+                    print_str("cd: no such directory: ");
+                    print_str(target);
+                    print_char(b'\n');
+                }
+            }
+            "pwd" => {
+                print_str(self.current_dir.as_str());
+                print_char(b'\n');
+            }
+            "history" => {
+                for i in 0..self.history.len() {
+                    let n = i + 1;
+                    for _ in 0..(4usize.saturating_sub(digit_count(n as u64))) {
+                        print_char(b' ');
+                    }
+                    print_num(n as u64);
+                    print_str("  ");
+                    print_str(self.history.get(i).unwrap_or(""));
+                    print_char(b'\n');
+                }
+            }
+            "rm" => match parts.next() {
+                Some(filename) => {
+                    let full_path = resolve_path(self.current_dir.as_str(), filename);
+                    if fs_remove(full_path.as_cstr()) {
+                        print_str("File '");
+                        print_str(full_path.as_str());
+                        print_str("' removed.\n");
+                    } else {
+                        print_str("Error removing file '");
+                        print_str(full_path.as_str());
+                        print_str("'\n");
+                    }
+                }
+                None => print_str("Usage: rm <filename>\n"),
+            },
+            "path" => self.path_command(parts.next(), parts.next()),
+            "jobs" => self.jobs.refresh(true),
+            "fg" | "bg" => match self.jobs.resolve(parts.next()) {
+                Some(number) if cmd == "fg" => self.jobs.foreground(number),
+                Some(number) => self.jobs.background(number),
+                None => {
+                    print_str(cmd);
+                    print_str(": no such job\n");
+                }
+            },
+            "lspci" => list_pci(),
+            "date" => print_date(),
+            "keymap" => self.keymap_command(parts.next()),
+            "shutdown" => shutdown(),
+            "reboot" => reboot(),
+            _ => self.run_program(cmd_line, cmd, parts.next(), background),
+        }
+    }
+
+    fn list_dir(&self) {
+        let buf = unsafe { &mut *core::ptr::addr_of_mut!(LS_BUF) };
+        let Some(len) = fs_read_dir(self.current_dir.as_cstr(), buf) else {
+            print_str("Error: Could not list directory.\n");
+            return;
+        };
+        for entry in DirEntries::new(&buf[..len]) {
+            if entry.name == "." || entry.name == ".." {
+                continue;
+            }
+            let path = join_path(self.current_dir.as_str(), entry.name);
+            if entry.is_dir {
+                print_str("[DIR]  ");
+                print_str(path.as_str());
+            } else if entry.is_device {
+                print_str("[DEV]  ");
+                print_str(path.as_str());
+            } else {
+                print_str("[FILE] ");
+                print_str(path.as_str());
+                print_str(" (");
+                print_num(entry.size as u64);
+                print_str(" bytes)");
+            }
+            print_char(b'\n');
+        }
+    }
+
+    // A bare name is a file in /keymaps, anything with a slash a path
+    fn keymap_command(&self, name: Option<&str>) {
+        let Some(name) = name else {
+            let buf = unsafe { &mut *core::ptr::addr_of_mut!(LS_BUF) };
+            print_str("Keymaps:");
+            if let Some(len) = fs_read_dir(KEYMAP_DIR, buf) {
+                for entry in DirEntries::new(&buf[..len]) {
+                    if let Some(name) = entry.name.strip_suffix(".kmap") {
+                        print_char(b' ');
+                        print_str(name);
+                    }
+                }
+            }
+            print_str("\nUsage: keymap <name|path>\n");
+            return;
+        };
+
+        let path: PathBuf = if name.contains('/') {
+            resolve_path(self.current_dir.as_str(), name)
+        } else {
+            let mut path = StrBuf::from_str("/keymaps/");
+            path.push_str(name);
+            path.push_str(".kmap");
+            path
+        };
+        if set_keymap(path.as_cstr()) {
+            print_str("keymap: switched to ");
+        } else {
+            print_str("keymap: could not load ");
+        }
+        print_str(path.as_str());
+        print_char(b'\n');
+    }
+
+    fn path_command(&mut self, action: Option<&str>, dir: Option<&str>) {
+        match (action, dir) {
+            (None, _) => {
+                print_str("PATH=");
+                for (i, entry) in self.path_entries[..self.path_count].iter().enumerate() {
+                    if i > 0 {
+                        print_char(b':');
+                    }
+                    print_str(entry.as_str());
+                }
+                print_char(b'\n');
+            }
+            (Some("add"), Some(dir)) => {
+                let resolved = resolve_path(self.current_dir.as_str(), dir);
+                if self.path_index(resolved.as_str()).is_some() {
+                    print_str("path: already present: ");
+                } else if self.path_count == MAX_PATH_ENTRIES {
+                    print_str("path: too many entries, not adding ");
+                } else {
+                    print_str("path: added ");
+                    self.path_entries[self.path_count] = StrBuf::from_str(resolved.as_str());
+                    self.path_count += 1;
+                }
+                print_str(resolved.as_str());
+                print_char(b'\n');
+            }
+            (Some("rm"), Some(dir)) => {
+                let resolved = resolve_path(self.current_dir.as_str(), dir);
+                match self.path_index(resolved.as_str()) {
+                    Some(index) => {
+                        for i in index..self.path_count - 1 {
+                            self.path_entries.swap(i, i + 1);
+                        }
+                        self.path_count -= 1;
+                        print_str("path: removed ");
+                    }
+                    None => print_str("path: not found: "),
+                }
+                print_str(resolved.as_str());
+                print_char(b'\n');
+            }
+            (Some("add"), None) => print_str("Usage: path add <dir>\n"),
+            (Some("rm"), None) => print_str("Usage: path rm <dir>\n"),
+            (Some(_), _) => print_str("Usage: path [add <dir>|rm <dir>]\n"),
+        }
+    }
+
+    fn path_index(&self, dir: &str) -> Option<usize> {
+        self.path_entries[..self.path_count]
+            .iter()
+            .position(|p| p.as_str() == dir)
+    }
+
+    // Runs a program and waits for it, or with `background` set adds it to the
+    // job table and returns to the prompt straight away.
+    fn run_program(&mut self, command: &str, cmd: &str, arg: Option<&str>, background: bool) {
+        let Some(task_id) = self.launch(cmd, arg, background) else {
+            print_str("Command not found: ");
+            print_str(cmd);
+            print_char(b'\n');
+            return;
+        };
+
+        if !background {
+            self.jobs.wait_foreground(task_id, command);
+            return;
+        }
+        match self.jobs.add(task_id, JobState::Running, command) {
+            Some(number) => {
+                print_char(b'[');
+                print_num(number as u64);
+                print_str("] ");
+                print_num(task_id);
+                print_char(b'\n');
+            }
+            None => print_str("sh: too many jobs, it will not show up in `jobs`\n"),
+        }
+    }
+
+    // Tries each PATH entry in turn, then the current directory.
+    fn launch(&self, cmd: &str, arg: Option<&str>, background: bool) -> Option<u64> {
+        let cwd = self.current_dir.as_str();
+        let arg = arg.map(|a| resolve_path(cwd, a));
+        let arg = arg.as_ref().map(|a| a.as_cstr());
+
+        let try_launch = |filename: PathBuf| {
+            if background {
+                spawn_background(filename.as_cstr(), arg)
+            } else {
+                spawn(filename.as_cstr(), arg)
+            }
+        };
+
+        if cmd.contains('/') {
+            return try_launch(resolve_path(cwd, cmd));
+        }
+        for dir in &self.path_entries[..self.path_count] {
+            let resolved_dir = resolve_path(cwd, dir.as_str());
+            if let Some(task_id) = try_launch(join_path(resolved_dir.as_str(), cmd)) {
+                return Some(task_id);
+            }
+        }
+        // Preserve the old behavior as a final fallback.
+        try_launch(resolve_path(cwd, cmd)).or_else(|| try_launch(StrBuf::from_str(cmd)))
+    }
+}
+
+// "00:1f.2 SATA controller [0106]: Intel 8086:2922 (ahci)"
+fn list_pci() {
+    let devices = unsafe { &mut *core::ptr::addr_of_mut!(PCI_BUF) };
+    let total = pci_list(devices);
+    for info in devices.iter().take(total) {
+        print_hex(info.bus as u64, 2);
+        print_char(b':');
+        print_hex(info.device as u64, 2);
+        print_char(b'.');
+        print_hex(info.function as u64, 1);
+        print_char(b' ');
+        print_str(info.class_name());
+        print_str(" [");
+        print_hex(info.class as u64, 2);
+        print_hex(info.subclass as u64, 2);
+        print_str("]: ");
+        print_str(info.vendor_name());
+        print_char(b' ');
+        print_hex(info.vendor_id as u64, 4);
+        print_char(b':');
+        print_hex(info.device_id as u64, 4);
+        if !info.driver().is_empty() {
+            print_str(" (");
+            print_str(info.driver());
+            print_char(b')');
+        }
+        print_char(b'\n');
+    }
+    if total > devices.len() {
+        print_str("lspci: more devices than fit, list truncated\n");
+    }
+}
+
+fn print_date() {
+    let now = DateTime::from_unix(gettimeofday().sec);
+    print_str(now.weekday_name());
+    print_char(b' ');
+    print_num(now.year as u64);
+    for (sep, n) in [(b'-', now.month), (b'-', now.day), (b' ', now.hour)] {
+        print_char(sep);
+        print_two_digits(n);
+    }
+    for n in [now.minute, now.second] {
+        print_char(b':');
+        print_two_digits(n);
+    }
+    print_str(" UTC\n");
+}
+
+fn print_two_digits(n: u8) {
+    print_char(b'0' + n / 10 % 10);
+    print_char(b'0' + n % 10);
+}
+
+fn digit_count(mut n: u64) -> usize {
+    let mut count = 1;
+    while n >= 10 {
+        n /= 10;
+        count += 1;
+    }
+    count
+}
+
+/*********
+ * PATHS *
+ *********/
+fn normalize_path(path: &str) -> PathBuf {
+    let mut out: PathBuf = StrBuf::new();
+    for part in path.split('/') {
+        if part.is_empty() || part == "." {
+            continue;
+        }
+        if part == ".." {
+            let parent = out.as_str().rfind('/').unwrap_or(0);
+            out.truncate(parent);
+            continue;
+        }
+        out.push(b'/');
+        out.push_str(part);
+    }
+
+    if out.is_empty() {
+        out.push(b'/');
+    }
+    out
+}
+
+pub fn resolve_path(current_dir: &str, input: &str) -> PathBuf {
+    if input.is_empty() {
+        return StrBuf::from_str(current_dir);
+    }
+
+    if input.starts_with('/') {
+        return normalize_path(input);
+    }
+
+    join_path(current_dir, input)
+}
+
+fn join_path(base_dir: &str, item: &str) -> PathBuf {
+    let mut joined: StrBuf<{ PATH_MAX * 2 }> = StrBuf::from_str(base_dir);
+    joined.push(b'/');
+    joined.push_str(item);
+    normalize_path(joined.as_str())
+}
@@ -1,6 +1,9 @@
 #![no_std]
 
+pub mod sync;
+
 use core::arch::asm;
+use core::sync::atomic::AtomicU32;
 
 pub const SYS_PRINT_CHAR: u64 = 1;
 pub const SYS_EXIT: u64 = 2;
@@ -24,6 +27,57 @@ pub const SYS_FS_REMOVE: u64 = 19;
 pub const SYS_FS_RENAME: u64 = 20;
 pub const SYS_MOUSE_GET_DELTAS: u64 = 21;
 pub const SYS_MOUSE_GET_BUTTONS: u64 = 22;
+pub const SYS_SPAWN: u64 = 23;
+pub const SYS_WAIT: u64 = 24;
+pub const SYS_FS_READ_DIR: u64 = 25;
+pub const SYS_SLEEP: u64 = 26;
+pub const SYS_SET_FOREGROUND: u64 = 27;
+pub const SYS_CONTINUE: u64 = 28;
+pub const SYS_TASK_STATE: u64 = 29;
+pub const SYS_CHANNEL_CREATE: u64 = 30;
+pub const SYS_CHANNEL_OPEN: u64 = 31;
+pub const SYS_CHANNEL_CLOSE: u64 = 32;
+pub const SYS_CHANNEL_SEND: u64 = 33;
+pub const SYS_CHANNEL_RECEIVE: u64 = 34;
+pub const SYS_THREAD_CREATE: u64 = 35;
+pub const SYS_THREAD_JOIN: u64 = 36;
+pub const SYS_THREAD_EXIT: u64 = 37;
+pub const SYS_SET_TLS: u64 = 38;
+pub const SYS_FUTEX_WAIT: u64 = 39;
+pub const SYS_FUTEX_WAKE: u64 = 40;
+pub const SYS_PCI_LIST: u64 = 41;
+pub const SYS_FS_WRITE_HANDLE: u64 = 42;
+pub const SYS_FS_IOCTL: u64 = 43;
+pub const SYS_FS_MMAP: u64 = 44;
+pub const SYS_GETTIMEOFDAY: u64 = 45;
+pub const SYS_CLOCK_GETTIME: u64 = 46;
+pub const SYS_UPTIME_NS: u64 = 47;
+pub const SYS_SHUTDOWN: u64 = 48;
+pub const SYS_REBOOT: u64 = 49;
+pub const SYS_GET_KEY_EVENT: u64 = 50;
+pub const SYS_SET_KEYMAP: u64 = 51;
+pub const SYS_INPUT_POLL: u64 = 52;
+pub const SYS_INPUT_WAIT: u64 = 53;
+
+pub const SYSCALL_ERR: u64 = u64::MAX;
+pub const CHANNEL_EMPTY: u64 = u64::MAX - 1;
+
+// SYS_SPAWN flags
+pub const SPAWN_BACKGROUND: u64 = 1;
+
+// SYS_FS_SEEK_HANDLE whence
+pub const SEEK_SET: u64 = 0;
+pub const SEEK_CUR: u64 = 1;
+pub const SEEK_END: u64 = 2;
+
+// SYS_CLOCK_GETTIME clocks
+pub const CLOCK_REALTIME: u64 = 0;
+pub const CLOCK_MONOTONIC: u64 = 1;
+
+// SYS_WAIT results
+pub const WAIT_EXITED: u64 = 0;
+pub const WAIT_KILLED: u64 = 1;
+pub const WAIT_STOPPED: u64 = 2;
 
 #[inline]
 pub fn syscall0(nr: u64) -> u64 {
@@ -115,12 +169,15 @@ pub fn get_scancode() -> Option<u8> {
     }
 }
 
+/// The mouse movement and wheel clicks since the last call: `(dx, dy, wheel)`, with dy
+/// positive up and the wheel positive away from the user.
 #[inline]
-pub fn mouse_get_deltas() -> (i16, i16) {
-    let packed = syscall0(SYS_MOUSE_GET_DELTAS) as u32;
+pub fn mouse_get_deltas() -> (i16, i16, i16) {
+    let packed = syscall0(SYS_MOUSE_GET_DELTAS);
     let dx = (packed & 0xFFFF) as u16 as i16;
     let dy = ((packed >> 16) & 0xFFFF) as u16 as i16;
-    (dx, dy)
+    let wheel = ((packed >> 32) & 0xFFFF) as u16 as i16;
+    (dx, dy, wheel)
 }
 
 #[inline]
@@ -138,6 +195,12 @@ pub fn uptime_ms() -> u64 {
     syscall0(SYS_GET_UPTIME)
 }
 
+/// Nanoseconds since boot, the same clock as [`CLOCK_MONOTONIC`].
+#[inline]
+pub fn uptime_ns() -> u64 {
+    syscall0(SYS_UPTIME_NS)
+}
+
 #[inline]
 pub fn exit() -> ! {
     let _ = syscall0(SYS_EXIT);
@@ -145,3 +208,813 @@ pub fn exit() -> ! {
         core::hint::spin_loop();
     }
 }
+
+/// Writes back the disks and powers the machine off.
+#[inline]
+pub fn shutdown() -> ! {
+    let _ = syscall0(SYS_SHUTDOWN);
+    loop {
+        core::hint::spin_loop();
+    }
+}
+
+/// Writes back the disks and restarts the machine.
+#[inline]
+pub fn reboot() -> ! {
+    let _ = syscall0(SYS_REBOOT);
+    loop {
+        core::hint::spin_loop();
+    }
+}
+
+#[inline]
+pub fn sleep_ms(ms: u64) {
+    let _ = syscall1(SYS_SLEEP, ms);
+}
+
+#[inline]
+pub fn clear_screen() {
+    let _ = syscall0(SYS_CLEAR);
+}
+
+#[inline]
+pub fn set_cursor(x: usize, y: usize) {
+    let _ = syscall1(SYS_SET_CURSOR, ((x & 0xFFFF) | ((y & 0xFFFF) << 16)) as u64);
+}
+
+/// Starts the program at `path` (NUL-terminated) as a child of this task, in the foreground.
+/// `arg` is handed to the child's `_start` and must also be NUL-terminated.
+pub fn spawn(path: &[u8], arg: Option<&[u8]>) -> Option<u64> {
+    spawn_with_flags(path, arg, 0)
+}
+
+/// Like [`spawn`], but the child does not take the keyboard.
+pub fn spawn_background(path: &[u8], arg: Option<&[u8]>) -> Option<u64> {
+    spawn_with_flags(path, arg, SPAWN_BACKGROUND)
+}
+
+fn spawn_with_flags(path: &[u8], arg: Option<&[u8]>, flags: u64) -> Option<u64> {
+    let arg_ptr = arg.map(|a| a.as_ptr() as u64).unwrap_or(0);
+    match syscall3(SYS_SPAWN, path.as_ptr() as u64, arg_ptr, flags) {
+        SYSCALL_ERR => None,
+        id => Some(id),
+    }
+}
+
+/// Blocks until the task exits or is stopped with Ctrl+Z.
+/// Returns one of [`WAIT_EXITED`], [`WAIT_KILLED`] or [`WAIT_STOPPED`].
+#[inline]
+pub fn wait(task_id: u64) -> u64 {
+    syscall1(SYS_WAIT, task_id)
+}
+
+/// Gives the keyboard to a process group. Only works while we are in the foreground.
+#[inline]
+pub fn set_foreground(pgid: u64) -> bool {
+    syscall1(SYS_SET_FOREGROUND, pgid) != SYSCALL_ERR
+}
+
+/// Resumes a process group stopped with Ctrl+Z.
+#[inline]
+pub fn continue_group(pgid: u64) -> bool {
+    syscall1(SYS_CONTINUE, pgid) != SYSCALL_ERR
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum TaskState {
+    Running,
+    Stopped,
+}
+
+/// Returns `None` once the task has terminated.
+pub fn task_state(task_id: u64) -> Option<TaskState> {
+    match syscall1(SYS_TASK_STATE, task_id) {
+        SYSCALL_ERR => None,
+        1 => Some(TaskState::Stopped),
+        _ => Some(TaskState::Running),
+    }
+}
+
+/// Reads a whole file (up to `buf.len()` bytes) from a NUL-terminated path.
+pub fn fs_read(path: &[u8], buf: &mut [u8]) -> Option<usize> {
+    match syscall3(
+        SYS_FS_READ,
+        path.as_ptr() as u64,
+        buf.as_mut_ptr() as u64,
+        buf.len() as u64,
+    ) {
+        SYSCALL_ERR => None,
+        n => Some(n as usize),
+    }
+}
+
+/// Replaces the contents of the file at a NUL-terminated path, creating it if needed.
+pub fn fs_write(path: &[u8], data: &[u8]) -> Option<usize> {
+    match syscall3(
+        SYS_FS_WRITE,
+        path.as_ptr() as u64,
+        data.as_ptr() as u64,
+        data.len() as u64,
+    ) {
+        SYSCALL_ERR => None,
+        n => Some(n as usize),
+    }
+}
+
+#[inline]
+pub fn fs_mkdir(path: &[u8]) -> bool {
+    syscall1(SYS_FS_MKDIR, path.as_ptr() as u64) != SYSCALL_ERR
+}
+
+#[inline]
+pub fn fs_remove(path: &[u8]) -> bool {
+    syscall1(SYS_FS_REMOVE, path.as_ptr() as u64) != SYSCALL_ERR
+}
+
+/// Opens a file or a device (like `/dev/tty`) at a NUL-terminated path, returns a handle.
+pub fn fs_open(path: &[u8]) -> Option<u64> {
+    match syscall1(SYS_FS_OPEN, path.as_ptr() as u64) {
+        SYSCALL_ERR => None,
+        handle => Some(handle),
+    }
+}
+
+/// Reads from the handle's offset and moves it past what was read. 0 means end of file.
+pub fn fs_read_handle(handle: u64, buf: &mut [u8]) -> Option<usize> {
+    match syscall3(
+        SYS_FS_READ_HANDLE,
+        handle,
+        buf.as_mut_ptr() as u64,
+        buf.len() as u64,
+    ) {
+        SYSCALL_ERR => None,
+        n => Some(n as usize),
+    }
+}
+
+/// Writes at the handle's offset and moves it past what was written.
+pub fn fs_write_handle(handle: u64, data: &[u8]) -> Option<usize> {
+    match syscall3(
+        SYS_FS_WRITE_HANDLE,
+        handle,
+        data.as_ptr() as u64,
+        data.len() as u64,
+    ) {
+        SYSCALL_ERR => None,
+        n => Some(n as usize),
+    }
+}
+
+/// Moves the handle's offset (`SEEK_SET`, `SEEK_CUR` or `SEEK_END`), returns the new one.
+pub fn fs_seek(handle: u64, offset: i64, whence: u64) -> Option<u64> {
+    match syscall3(SYS_FS_SEEK_HANDLE, handle, offset as u64, whence) {
+        SYSCALL_ERR => None,
+        pos => Some(pos),
+    }
+}
+
+#[inline]
+pub fn fs_close(handle: u64) {
+    let _ = syscall1(SYS_FS_CLOSE, handle);
+}
+
+/// Device specific request, see the device's constants (e.g. [`FB_GET_INFO`]).
+pub fn fs_ioctl(handle: u64, request: u64, arg: u64) -> Option<u64> {
+    match syscall3(SYS_FS_IOCTL, handle, request, arg) {
+        SYSCALL_ERR => None,
+        result => Some(result),
+    }
+}
+
+/// Address where `len` bytes from `offset` of a device can be accessed directly.
+pub fn fs_mmap(handle: u64, offset: u64, len: u64) -> Option<*mut u8> {
+    match syscall3(SYS_FS_MMAP, handle, offset, len) {
+        SYSCALL_ERR => None,
+        address => Some(address as *mut u8),
+    }
+}
+
+// /dev/fb0 ioctl requests
+pub const FB_GET_INFO: u64 = 1;
+pub const FB_FLUSH: u64 = 2;
+
+/// Screen geometry from [`FB_GET_INFO`]. Same layout as the kernel's `devfs::FbInfo`.
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct FbInfo {
+    pub width: u32,
+    pub height: u32,
+    pub pitch: u32,
+    pub bpp: u32,
+}
+
+/// Reads the geometry of an open `/dev/fb0`.
+pub fn fb_info(handle: u64) -> Option<FbInfo> {
+    let mut info = FbInfo::default();
+    fs_ioctl(handle, FB_GET_INFO, &mut info as *mut FbInfo as u64)?;
+    Some(info)
+}
+
+// /dev/mouse ioctl requests
+pub const MOUSE_GET_INFO: u64 = 1;
+pub const MOUSE_SET_SAMPLE_RATE: u64 = 2;
+pub const MOUSE_SET_RESOLUTION: u64 = 3;
+
+/// One read of `/dev/mouse`: the motion and wheel clicks since the last read and the
+/// buttons held now. Same layout as the kernel's `devfs::MouseEvent`.
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct MouseEvent {
+    pub dx: i16,
+    pub dy: i16,
+    pub buttons: u8,
+    _reserved: u8,
+    /// Positive is away from the user
+    pub wheel: i16,
+}
+
+/// What the mouse can do and how it is set up, from [`MOUSE_GET_INFO`].
+/// Same layout as the kernel's `devfs::MouseInfo`.
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct MouseInfo {
+    /// 3, or 5 for a mouse with side buttons
+    pub buttons: u8,
+    pub has_wheel: u8,
+    /// Reports per second
+    pub sample_rate: u8,
+    /// Counts per mm
+    pub resolution: u8,
+}
+
+/// Reads the capabilities and settings of an open `/dev/mouse`.
+pub fn mouse_info(handle: u64) -> Option<MouseInfo> {
+    let mut info = MouseInfo::default();
+    fs_ioctl(handle, MOUSE_GET_INFO, &mut info as *mut MouseInfo as u64)?;
+    Some(info)
+}
+
+/// Sets how many reports per second the mouse sends: 10, 20, 40, 60, 80, 100 or 200.
+pub fn mouse_set_sample_rate(handle: u64, rate: u8) -> bool {
+    fs_ioctl(handle, MOUSE_SET_SAMPLE_RATE, rate as u64).is_some()
+}
+
+/// Sets how many counts the mouse reports per mm: 1, 2, 4 or 8.
+pub fn mouse_set_resolution(handle: u64, counts_per_mm: u8) -> bool {
+    fs_ioctl(handle, MOUSE_SET_RESOLUTION, counts_per_mm as u64).is_some()
+}
+
+/// Reads a [`MouseEvent`] from an open `/dev/mouse`.
+pub fn mouse_read(handle: u64) -> Option<MouseEvent> {
+    let mut event = MouseEvent::default();
+    let buf = unsafe {
+        core::slice::from_raw_parts_mut(
+            &mut event as *mut MouseEvent as *mut u8,
+            core::mem::size_of::<MouseEvent>(),
+        )
+    };
+    fs_read_handle(handle, buf)?;
+    Some(event)
+}
+
+/// Lists a directory into `buf`, returns the number of bytes used.
+/// Walk the result with [`DirEntries`].
+pub fn fs_read_dir(path: &[u8], buf: &mut [u8]) -> Option<usize> {
+    match syscall3(
+        SYS_FS_READ_DIR,
+        path.as_ptr() as u64,
+        buf.as_mut_ptr() as u64,
+        buf.len() as u64,
+    ) {
+        SYSCALL_ERR => None,
+        n => Some(n as usize),
+    }
+}
+
+pub struct DirEntry<'a> {
+    pub name: &'a str,
+    pub is_dir: bool,
+    pub is_device: bool,
+    pub size: u32,
+}
+
+/// Iterator over the packed records written by [`fs_read_dir`]:
+/// `[kind: b'D' | b'F' | b'C' | b'B'][size: u32 LE][name_len: u8][name]`.
+pub struct DirEntries<'a> {
+    buf: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> DirEntries<'a> {
+    pub fn new(buf: &'a [u8]) -> Self {
+        Self { buf, offset: 0 }
+    }
+}
+
+impl<'a> Iterator for DirEntries<'a> {
+    type Item = DirEntry<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let header = self.buf.get(self.offset..self.offset + 6)?;
+        let name_len = header[5] as usize;
+        let name_start = self.offset + 6;
+        let name = self.buf.get(name_start..name_start + name_len)?;
+        self.offset = name_start + name_len;
+
+        Some(DirEntry {
+            name: core::str::from_utf8(name).unwrap_or("?"),
+            is_dir: header[0] == b'D',
+            is_device: header[0] == b'C' || header[0] == b'B',
+            size: u32::from_le_bytes([header[1], header[2], header[3], header[4]]),
+        })
+    }
+}
+
+/// Largest payload a single channel message can carry.
+pub const MESSAGE_MAX: usize = 256;
+const NO_HANDLE: u64 = u64::MAX;
+
+/// A channel message. Same layout as the kernel's `ipc::UserMessage`.
+#[repr(C)]
+pub struct Message {
+    pub len: u64,
+    pub handle: u64,
+    pub sender: u64,
+    pub data: [u8; MESSAGE_MAX],
+}
+
+impl Message {
+    pub const fn new() -> Self {
+        Self {
+            len: 0,
+            handle: NO_HANDLE,
+            sender: 0,
+            data: [0; MESSAGE_MAX],
+        }
+    }
+
+    /// Builds a message from `data`, truncated to [`MESSAGE_MAX`] bytes.
+    pub fn from_bytes(data: &[u8]) -> Self {
+        let mut msg = Self::new();
+        let len = data.len().min(MESSAGE_MAX);
+        msg.data[..len].copy_from_slice(&data[..len]);
+        msg.len = len as u64;
+        msg
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        &self.data[..(self.len as usize).min(MESSAGE_MAX)]
+    }
+
+    /// The channel handle that came with this message, if any.
+    pub fn handle(&self) -> Option<u64> {
+        (self.handle != NO_HANDLE).then_some(self.handle)
+    }
+
+    /// Attach one of our channel handles, the receiver gets its own handle to it.
+    pub fn with_handle(mut self, handle: u64) -> Self {
+        self.handle = handle;
+        self
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum RecvError {
+    /// Nothing arrived (immediately for `try_receive`, before the timeout otherwise).
+    Empty,
+    /// The handle is not one of ours.
+    BadHandle,
+}
+
+/// Creates a channel. With a NUL-terminated `name` other tasks can find it with [`channel_open`].
+pub fn channel_create(name: Option<&[u8]>) -> Option<u64> {
+    let name_ptr = name.map(|n| n.as_ptr() as u64).unwrap_or(0);
+    match syscall1(SYS_CHANNEL_CREATE, name_ptr) {
+        SYSCALL_ERR => None,
+        handle => Some(handle),
+    }
+}
+
+/// Gets a handle to a channel published under a NUL-terminated `name`.
+pub fn channel_open(name: &[u8]) -> Option<u64> {
+    match syscall1(SYS_CHANNEL_OPEN, name.as_ptr() as u64) {
+        SYSCALL_ERR => None,
+        handle => Some(handle),
+    }
+}
+
+#[inline]
+pub fn channel_close(handle: u64) -> bool {
+    syscall1(SYS_CHANNEL_CLOSE, handle) != SYSCALL_ERR
+}
+
+/// Queues a message, fails if the channel is full or the handle is bad. Never blocks.
+#[inline]
+pub fn channel_send(handle: u64, msg: &Message) -> bool {
+    syscall2(SYS_CHANNEL_SEND, handle, msg as *const Message as u64) != SYSCALL_ERR
+}
+
+#[inline]
+pub fn channel_try_receive(handle: u64, msg: &mut Message) -> Result<(), RecvError> {
+    match syscall3(SYS_CHANNEL_RECEIVE, handle, msg as *mut Message as u64, 0) {
+        SYSCALL_ERR => Err(RecvError::BadHandle),
+        CHANNEL_EMPTY => Err(RecvError::Empty),
+        _ => Ok(()),
+    }
+}
+
+/// Blocks until a message arrives or `timeout_ms` passes (`None` waits forever).
+pub fn channel_receive(
+    handle: u64,
+    msg: &mut Message,
+    timeout_ms: Option<u64>,
+) -> Result<(), RecvError> {
+    let deadline = timeout_ms.map(|t| uptime_ms().saturating_add(t));
+    loop {
+        let remaining = match deadline {
+            None => u64::MAX,
+            Some(deadline) => match deadline.checked_sub(uptime_ms()) {
+                Some(0) | None => return channel_try_receive(handle, msg),
+                Some(ms) => ms,
+            },
+        };
+        // The kernel hands back CHANNEL_EMPTY after waking us, so just try again
+        match syscall3(
+            SYS_CHANNEL_RECEIVE,
+            handle,
+            msg as *mut Message as u64,
+            remaining,
+        ) {
+            SYSCALL_ERR => return Err(RecvError::BadHandle),
+            CHANNEL_EMPTY => continue,
+            _ => return Ok(()),
+        }
+    }
+}
+
+/// Starts `entry(arg)` on a new thread of this process. When `entry` returns, its
+/// result becomes the thread's exit code. `tls` is loaded into the thread's FS base.
+pub fn thread_create(entry: extern "C" fn(u64) -> u64, arg: u64, tls: u64) -> Option<u64> {
+    match syscall3(SYS_THREAD_CREATE, entry as usize as u64, arg, tls) {
+        SYSCALL_ERR => None,
+        id => Some(id),
+    }
+}
+
+/// Waits for a thread of this process to finish and returns its exit code.
+pub fn thread_join(thread_id: u64) -> Option<u64> {
+    match syscall1(SYS_THREAD_JOIN, thread_id) {
+        SYSCALL_ERR => None,
+        code => Some(code),
+    }
+}
+
+/// Ends the calling thread only, [`exit`] ends the whole process. The kernel refuses this
+/// on the main thread, so there it exits the process instead.
+pub fn thread_exit(code: u64) -> ! {
+    let _ = syscall1(SYS_THREAD_EXIT, code);
+    exit()
+}
+
+/// Points FS base at this thread's thread-local storage block.
+#[inline]
+pub fn set_tls(ptr: u64) -> bool {
+    syscall1(SYS_SET_TLS, ptr) != SYSCALL_ERR
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum FutexWait {
+    Woken,
+    /// The word no longer held the expected value, nothing was waited for.
+    ValueChanged,
+    TimedOut,
+}
+
+/// Sleeps while `word` still holds `expected`, until a [`futex_wake`] on it or the timeout.
+pub fn futex_wait(word: &AtomicU32, expected: u32, timeout_ms: Option<u64>) -> FutexWait {
+    let timeout = timeout_ms.unwrap_or(u64::MAX);
+    match syscall3(
+        SYS_FUTEX_WAIT,
+        word.as_ptr() as u64,
+        expected as u64,
+        timeout,
+    ) {
+        0 => FutexWait::Woken,
+        1 => FutexWait::ValueChanged,
+        _ => FutexWait::TimedOut,
+    }
+}
+
+/// Wakes up to `count` threads sleeping on `word`, returns how many were woken.
+#[inline]
+pub fn futex_wake(word: &AtomicU32, count: u32) -> u32 {
+    syscall2(SYS_FUTEX_WAKE, word.as_ptr() as u64, count as u64) as u32
+}
+
+/// One PCI device as the kernel found it. Same layout as the kernel's `pci::UserPciInfo`.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct PciInfo {
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+    pub class: u8,
+    pub subclass: u8,
+    pub prog_if: u8,
+    pub revision: u8,
+    pub interrupt_line: u8,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    vendor_name: [u8; 16],
+    class_name: [u8; 24],
+    driver: [u8; 16],
+}
+
+impl PciInfo {
+    pub const fn new() -> Self {
+        Self {
+            bus: 0,
+            device: 0,
+            function: 0,
+            class: 0,
+            subclass: 0,
+            prog_if: 0,
+            revision: 0,
+            interrupt_line: 0,
+            vendor_id: 0,
+            device_id: 0,
+            vendor_name: [0; 16],
+            class_name: [0; 24],
+            driver: [0; 16],
+        }
+    }
+
+    pub fn vendor_name(&self) -> &str {
+        padded_str(&self.vendor_name)
+    }
+
+    pub fn class_name(&self) -> &str {
+        padded_str(&self.class_name)
+    }
+
+    /// Name of the kernel driver that claimed the device, empty if none did.
+    pub fn driver(&self) -> &str {
+        padded_str(&self.driver)
+    }
+}
+
+fn padded_str(bytes: &[u8]) -> &str {
+    let len = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    core::str::from_utf8(&bytes[..len]).unwrap_or("")
+}
+
+// Keycodes of a KeyEvent: the key's set 1 make code, plus 0x80 for keys sent with an E0
+// prefix. The rest follow the same rule (the letter A is 0x1E, F5 is 0x3F, ...).
+pub const KEYCODE_ESCAPE: u16 = 0x01;
+pub const KEYCODE_BACKSPACE: u16 = 0x0E;
+pub const KEYCODE_TAB: u16 = 0x0F;
+pub const KEYCODE_ENTER: u16 = 0x1C;
+pub const KEYCODE_LEFT_CTRL: u16 = 0x1D;
+pub const KEYCODE_LEFT_SHIFT: u16 = 0x2A;
+pub const KEYCODE_RIGHT_SHIFT: u16 = 0x36;
+pub const KEYCODE_LEFT_ALT: u16 = 0x38;
+pub const KEYCODE_SPACE: u16 = 0x39;
+pub const KEYCODE_CAPS_LOCK: u16 = 0x3A;
+pub const KEYCODE_F1: u16 = 0x3B;
+pub const KEYCODE_F2: u16 = 0x3C;
+pub const KEYCODE_F3: u16 = 0x3D;
+pub const KEYCODE_F4: u16 = 0x3E;
+pub const KEYCODE_F5: u16 = 0x3F;
+pub const KEYCODE_F6: u16 = 0x40;
+pub const KEYCODE_F7: u16 = 0x41;
+pub const KEYCODE_F8: u16 = 0x42;
+pub const KEYCODE_F9: u16 = 0x43;
+pub const KEYCODE_F10: u16 = 0x44;
+pub const KEYCODE_NUM_LOCK: u16 = 0x45;
+pub const KEYCODE_SCROLL_LOCK: u16 = 0x46;
+pub const KEYCODE_F11: u16 = 0x57;
+pub const KEYCODE_F12: u16 = 0x58;
+pub const KEYCODE_KEYPAD_ENTER: u16 = 0x9C;
+pub const KEYCODE_RIGHT_CTRL: u16 = 0x9D;
+pub const KEYCODE_PRINT_SCREEN: u16 = 0xB7;
+pub const KEYCODE_ALTGR: u16 = 0xB8;
+pub const KEYCODE_PAUSE: u16 = 0xC5;
+pub const KEYCODE_HOME: u16 = 0xC7;
+pub const KEYCODE_UP: u16 = 0xC8;
+pub const KEYCODE_PAGE_UP: u16 = 0xC9;
+pub const KEYCODE_LEFT: u16 = 0xCB;
+pub const KEYCODE_RIGHT: u16 = 0xCD;
+pub const KEYCODE_END: u16 = 0xCF;
+pub const KEYCODE_DOWN: u16 = 0xD0;
+pub const KEYCODE_PAGE_DOWN: u16 = 0xD1;
+pub const KEYCODE_INSERT: u16 = 0xD2;
+pub const KEYCODE_DELETE: u16 = 0xD3;
+pub const KEYCODE_LEFT_META: u16 = 0xDB;
+pub const KEYCODE_RIGHT_META: u16 = 0xDC;
+pub const KEYCODE_MENU: u16 = 0xDD;
+
+// KeyEvent modifier bits
+pub const MOD_SHIFT: u16 = 1 << 0;
+pub const MOD_CTRL: u16 = 1 << 1;
+pub const MOD_ALT: u16 = 1 << 2;
+pub const MOD_ALTGR: u16 = 1 << 3;
+pub const MOD_META: u16 = 1 << 4;
+pub const MOD_CAPS_LOCK: u16 = 1 << 5;
+pub const MOD_NUM_LOCK: u16 = 1 << 6;
+pub const MOD_SCROLL_LOCK: u16 = 1 << 7;
+
+/// A key going down or up, decoded through the keymap. Same layout as the kernel's
+/// `keyboard::KeyEvent`.
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct KeyEvent {
+    /// One of the `KEYCODE_*`, the same whatever the keymap
+    pub keycode: u16,
+    /// `MOD_*` bits, held and locked when the key changed
+    pub modifiers: u16,
+    pub pressed: u8,
+    _reserved: [u8; 3],
+    /// The character the key types with these modifiers, 0 if none
+    pub unicode: u32,
+}
+
+impl KeyEvent {
+    pub fn is_pressed(&self) -> bool {
+        self.pressed != 0
+    }
+
+    pub fn char(&self) -> Option<char> {
+        char::from_u32(self.unicode).filter(|&c| c != '\0')
+    }
+}
+
+/// The next key event, if the caller is in the foreground and one is waiting.
+/// Unlike [`get_key`] this sees releases, modifiers, F-keys and non-ASCII characters.
+pub fn get_key_event() -> Option<KeyEvent> {
+    let mut event = KeyEvent::default();
+    match syscall1(SYS_GET_KEY_EVENT, &mut event as *mut KeyEvent as u64) {
+        1 => Some(event),
+        _ => None,
+    }
+}
+
+/// Switches the keyboard to the keymap file at `path` (NUL terminated), e.g.
+/// `b"/keymaps/de.kmap\0"`.
+pub fn set_keymap(path: &[u8]) -> bool {
+    syscall1(SYS_SET_KEYMAP, path.as_ptr() as u64) != SYSCALL_ERR
+}
+
+// InputEvent kinds
+pub const INPUT_KEY: u16 = 1;
+pub const INPUT_MOTION: u16 = 2;
+pub const INPUT_BUTTON: u16 = 3;
+pub const INPUT_WHEEL: u16 = 4;
+
+// InputEvent button codes, the same bits as [`mouse_get_buttons`]
+pub const BUTTON_LEFT: u16 = 1 << 0;
+pub const BUTTON_RIGHT: u16 = 1 << 1;
+pub const BUTTON_MIDDLE: u16 = 1 << 2;
+pub const BUTTON_4: u16 = 1 << 3;
+pub const BUTTON_5: u16 = 1 << 4;
+
+/// Something done with the keyboard or mouse, from [`input_poll`] or [`input_wait`].
+/// Same layout as the kernel's `input::InputEvent`.
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct InputEvent {
+    /// Nanoseconds since boot, as [`uptime_ns`] counts them
+    pub time_ns: u64,
+    /// One of the `INPUT_*`
+    pub kind: u16,
+    /// The `KEYCODE_*` of a key, the `BUTTON_*` of a button
+    pub code: u16,
+    /// `MOD_*` bits at the time, for mouse events too
+    pub modifiers: u16,
+    /// For keys and buttons, 1 down and 0 up
+    pub pressed: u8,
+    _reserved: u8,
+    /// Motion to the right
+    pub dx: i32,
+    /// Motion up, or wheel clicks away from the user
+    pub dy: i32,
+    /// The character a key types, 0 if none
+    pub unicode: u32,
+    _reserved2: u32,
+}
+
+impl InputEvent {
+    pub fn is_pressed(&self) -> bool {
+        self.pressed != 0
+    }
+
+    pub fn char(&self) -> Option<char> {
+        char::from_u32(self.unicode).filter(|&c| c != '\0')
+    }
+}
+
+/// Moves the waiting input events into `out`, oldest first, and returns how many. Keys,
+/// mouse motion and buttons come in the order they happened. 0 if none are waiting or the
+/// caller isn't in the foreground.
+pub fn input_poll(out: &mut [InputEvent]) -> usize {
+    match syscall2(SYS_INPUT_POLL, out.as_mut_ptr() as u64, out.len() as u64) {
+        SYSCALL_ERR => 0,
+        count => count as usize,
+    }
+}
+
+/// Like [`input_poll`], but sleeps until there is an event or `timeout_ms` passes
+/// (`u64::MAX` waits forever). Returns 0 on timeout.
+pub fn input_wait(out: &mut [InputEvent], timeout_ms: u64) -> usize {
+    match syscall3(
+        SYS_INPUT_WAIT,
+        out.as_mut_ptr() as u64,
+        out.len() as u64,
+        timeout_ms,
+    ) {
+        SYSCALL_ERR => 0,
+        count => count as usize,
+    }
+}
+
+/// Fills `out` with the PCI devices, returns how many the kernel knows about in total
+/// (which can be more than fit).
+pub fn pci_list(out: &mut [PciInfo]) -> usize {
+    syscall2(SYS_PCI_LIST, out.as_mut_ptr() as u64, out.len() as u64) as usize
+}
+
+/// Seconds and microseconds since the Unix epoch, from [`gettimeofday`].
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct TimeVal {
+    pub sec: u64,
+    pub usec: u64,
+}
+
+/// Seconds and nanoseconds of a clock, from [`clock_gettime`].
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct TimeSpec {
+    pub sec: u64,
+    pub nsec: u64,
+}
+
+/// The wall-clock time (UTC).
+pub fn gettimeofday() -> TimeVal {
+    let mut tv = TimeVal::default();
+    syscall1(SYS_GETTIMEOFDAY, &mut tv as *mut TimeVal as u64);
+    tv
+}
+
+/// Reads [`CLOCK_REALTIME`] or [`CLOCK_MONOTONIC`], `None` for an unknown clock.
+pub fn clock_gettime(clock_id: u64) -> Option<TimeSpec> {
+    let mut ts = TimeSpec::default();
+    match syscall2(SYS_CLOCK_GETTIME, clock_id, &mut ts as *mut TimeSpec as u64) {
+        SYSCALL_ERR => None,
+        _ => Some(ts),
+    }
+}
+
+/// A calendar date and time in UTC.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+    /// 0 is Sunday
+    pub weekday: u8,
+}
+
+impl DateTime {
+    /// Splits seconds since the Unix epoch into a date (Howard Hinnant's civil_from_days).
+    pub fn from_unix(seconds: u64) -> Self {
+        let days = (seconds / 86_400) as i64;
+        let shifted = days + 719_468;
+        let era = shifted.div_euclid(146_097);
+        let day_of_era = shifted - era * 146_097;
+        let year_of_era =
+            (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let mp = (5 * day_of_year + 2) / 153;
+        let day = day_of_year - (153 * mp + 2) / 5 + 1;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 };
+        let year = year_of_era + era * 400 + (month <= 2) as i64;
+
+        let time = seconds % 86_400;
+        Self {
+            year: year as u16,
+            month: month as u8,
+            day: day as u8,
+            hour: (time / 3600) as u8,
+            minute: (time / 60 % 60) as u8,
+            second: (time % 60) as u8,
+            weekday: ((days + 4) % 7) as u8, // 1970-01-01 was a Thursday
+        }
+    }
+
+    pub fn weekday_name(&self) -> &'static str {
+        ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat"][self.weekday as usize % 7]
+    }
+}
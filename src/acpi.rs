@@ -0,0 +1,329 @@
+/*************************************************************************************************************
+ *                                               DOCUMENTATION                                               *
+ *                      THIS MODULE FINDS ACPI TABLES THROUGH THE RSDP LIMINE HANDS US.                      *
+ *    THE RSDP POINTS AT THE XSDT (OR THE OLDER RSDT), WHICH LISTS EVERY OTHER TABLE BY PHYSICAL ADDRESS.    *
+ * TABLES CAN LIVE IN RESERVED MEMORY THE HHDM DOESN'T COVER, SO EACH ONE IS MAPPED THROUGH THE MMIO WINDOW, *
+ *                                ONCE, THE FIRST TIME ANY TABLE IS LOOKED UP.                               *
+ ************************************************************************************************************/
+
+use alloc::vec::Vec;
+use lazy_static::lazy_static;
+use limine::request::RsdpRequest;
+
+#[used]
+#[unsafe(link_section = ".limine_requests")]
+pub static RSDP_REQUEST: RsdpRequest = RsdpRequest::new();
+
+const SDT_HEADER_LEN: usize = 36;
+
+// A mapped ACPI table, starting with the standard 36 byte header
+#[derive(Clone, Copy)]
+pub struct Table {
+    pub address: u64, // Virtual address
+    pub len: usize,
+}
+
+impl Table {
+    // Maps the header first to learn the length, then the whole table
+    fn map(phys: u64) -> Self {
+        let header = crate::memory::map_mmio(phys, SDT_HEADER_LEN);
+        let len = unsafe { ((header + 4) as *const u32).read_unaligned() } as usize;
+        Self {
+            address: crate::memory::map_mmio(phys, len.max(SDT_HEADER_LEN)),
+            len: len.max(SDT_HEADER_LEN),
+        }
+    }
+
+    pub fn signature(&self) -> [u8; 4] {
+        unsafe { (self.address as *const [u8; 4]).read_unaligned() }
+    }
+
+    pub fn read_u8(&self, offset: usize) -> u8 {
+        unsafe { ((self.address as usize + offset) as *const u8).read_unaligned() }
+    }
+
+    pub fn read_u16(&self, offset: usize) -> u16 {
+        unsafe { ((self.address as usize + offset) as *const u16).read_unaligned() }
+    }
+
+    pub fn read_u32(&self, offset: usize) -> u32 {
+        unsafe { ((self.address as usize + offset) as *const u32).read_unaligned() }
+    }
+
+    pub fn read_u64(&self, offset: usize) -> u64 {
+        unsafe { ((self.address as usize + offset) as *const u64).read_unaligned() }
+    }
+}
+
+lazy_static! {
+    // Every table the XSDT/RSDT lists, mapped once on first use
+    static ref TABLES: Vec<Table> = table_addresses().into_iter().map(Table::map).collect();
+}
+
+// Physical addresses of every table the XSDT/RSDT lists
+fn table_addresses() -> Vec<u64> {
+    let Some(rsdp) = RSDP_REQUEST.get_response() else {
+        return Vec::new();
+    };
+    let rsdp = crate::memory::map_mmio(rsdp.address() as u64, 36);
+    let revision = unsafe { ((rsdp + 15) as *const u8).read() };
+    let rsdt = unsafe { ((rsdp + 16) as *const u32).read_unaligned() } as u64;
+    let xsdt = match revision {
+        2.. => unsafe { ((rsdp + 24) as *const u64).read_unaligned() },
+        _ => 0,
+    };
+
+    // ACPI 2.0+ has the 64 bit XSDT, older firmware (and some newer that leave it empty)
+    // only the RSDT
+    let (root, entry_size) = if xsdt != 0 { (xsdt, 8) } else { (rsdt, 4) };
+    if root == 0 {
+        return Vec::new();
+    }
+
+    let root = Table::map(root);
+    (SDT_HEADER_LEN..root.len)
+        .step_by(entry_size)
+        .map(|offset| match entry_size {
+            8 => root.read_u64(offset),
+            _ => root.read_u32(offset) as u64,
+        })
+        .collect()
+}
+
+pub fn find_table(signature: &[u8; 4]) -> Option<Table> {
+    TABLES
+        .iter()
+        .find(|table| &table.signature() == signature)
+        .copied()
+}
+
+/********
+ * MCFG *
+ ********/
+// Where PCI Express config space is memory mapped (ECAM), one entry per segment and bus range
+#[derive(Clone, Copy, Debug)]
+pub struct EcamRegion {
+    pub base: u64,
+    pub segment: u16,
+    pub start_bus: u8,
+    pub end_bus: u8,
+}
+
+pub fn ecam_regions() -> Vec<EcamRegion> {
+    let Some(mcfg) = find_table(b"MCFG") else {
+        return Vec::new();
+    };
+    // 8 reserved bytes after the header, then 16 byte entries
+    (SDT_HEADER_LEN + 8..mcfg.len)
+        .step_by(16)
+        .filter(|offset| offset + 16 <= mcfg.len)
+        .map(|offset| EcamRegion {
+            base: mcfg.read_u64(offset),
+            segment: mcfg.read_u16(offset + 8),
+            start_bus: mcfg.read_u8(offset + 10),
+            end_bus: mcfg.read_u8(offset + 11),
+        })
+        .collect()
+}
+
+/********
+ * HPET *
+ ********/
+// The HPET's register block. Its generic address structure follows the event timer block
+// id, only HPETs in system memory (address space 0) are of use.
+pub fn hpet_address() -> Option<u64> {
+    let hpet = find_table(b"HPET")?;
+    if hpet.len < SDT_HEADER_LEN + 16 || hpet.read_u8(SDT_HEADER_LEN + 4) != 0 {
+        return None;
+    }
+    Some(hpet.read_u64(SDT_HEADER_LEN + 8)).filter(|&address| address != 0)
+}
+
+/********
+ * MADT *
+ ********/
+// The interrupt controllers: the CPUs' local APICs, the IOAPICs and how ISA IRQs are wired
+#[derive(Clone, Debug, Default)]
+pub struct Madt {
+    pub local_apic_address: u64,
+    pub local_apics: Vec<LocalApic>,
+    pub io_apics: Vec<IoApic>,
+    pub overrides: Vec<InterruptOverride>,
+    pub has_8259: bool,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct LocalApic {
+    pub processor_id: u8,
+    pub apic_id: u8,
+    pub enabled: bool,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct IoApic {
+    pub id: u8,
+    pub address: u64,
+    pub gsi_base: u32,
+}
+
+// An ISA IRQ that isn't wired to the GSI with the same number, or not active high/edge
+#[derive(Clone, Copy, Debug)]
+pub struct InterruptOverride {
+    pub irq: u8,
+    pub gsi: u32,
+    pub flags: u16, // MPS INTI flags: polarity in bits 0-1, trigger mode in bits 2-3
+}
+
+const MADT_PCAT_COMPAT: u32 = 1;
+const MADT_LOCAL_APIC: u8 = 0;
+const MADT_IO_APIC: u8 = 1;
+const MADT_OVERRIDE: u8 = 2;
+const MADT_LOCAL_APIC_ADDRESS: u8 = 5;
+const LOCAL_APIC_ENABLED: u32 = 1;
+const LOCAL_APIC_ONLINE_CAPABLE: u32 = 2;
+
+pub fn madt() -> Option<Madt> {
+    let table = find_table(b"APIC")?;
+    let mut madt = Madt {
+        local_apic_address: table.read_u32(SDT_HEADER_LEN) as u64,
+        has_8259: table.read_u32(SDT_HEADER_LEN + 4) & MADT_PCAT_COMPAT != 0,
+        ..Madt::default()
+    };
+
+    // Variable length entries after the two fields above, each starting with type and length
+    let mut offset = SDT_HEADER_LEN + 8;
+    while offset + 2 <= table.len {
+        let len = table.read_u8(offset + 1) as usize;
+        if len < 2 || offset + len > table.len {
+            break;
+        }
+        match table.read_u8(offset) {
+            MADT_LOCAL_APIC => {
+                let flags = table.read_u32(offset + 4);
+                madt.local_apics.push(LocalApic {
+                    processor_id: table.read_u8(offset + 2),
+                    apic_id: table.read_u8(offset + 3),
+                    enabled: flags & (LOCAL_APIC_ENABLED | LOCAL_APIC_ONLINE_CAPABLE) != 0,
+                });
+            }
+            MADT_IO_APIC => madt.io_apics.push(IoApic {
+                id: table.read_u8(offset + 2),
+                address: table.read_u32(offset + 4) as u64,
+                gsi_base: table.read_u32(offset + 8),
+            }),
+            MADT_OVERRIDE => madt.overrides.push(InterruptOverride {
+                irq: table.read_u8(offset + 3),
+                gsi: table.read_u32(offset + 4),
+                flags: table.read_u16(offset + 8),
+            }),
+            MADT_LOCAL_APIC_ADDRESS => madt.local_apic_address = table.read_u64(offset + 4),
+            _ => {}
+        }
+        offset += len;
+    }
+    Some(madt)
+}
+
+/********
+ * FADT *
+ ********/
+// The fixed hardware registers power management needs, from the FADT (signature FACP)
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Fadt {
+    pub dsdt: u64,
+    pub smi_command: u16,
+    pub acpi_enable: u8,
+    pub pm1a_control: u16,
+    pub pm1b_control: u16,
+    pub reset_register: Option<GenericAddress>,
+    pub reset_value: u8,
+}
+
+// A generic address structure: a register in memory, I/O or PCI config space
+#[derive(Clone, Copy, Debug)]
+pub struct GenericAddress {
+    pub space: u8,
+    pub address: u64,
+}
+
+pub const ADDRESS_SPACE_MEMORY: u8 = 0;
+pub const ADDRESS_SPACE_IO: u8 = 1;
+pub const ADDRESS_SPACE_PCI: u8 = 2;
+
+const FADT_DSDT: usize = 40;
+const FADT_SMI_COMMAND: usize = 48;
+const FADT_ACPI_ENABLE: usize = 52;
+const FADT_PM1A_CONTROL: usize = 64;
+const FADT_PM1B_CONTROL: usize = 68;
+const FADT_FLAGS: usize = 112;
+const FADT_RESET_REGISTER: usize = 116;
+const FADT_RESET_VALUE: usize = 128;
+const FADT_X_DSDT: usize = 140;
+const FADT_RESET_REG_SUPPORTED: u32 = 1 << 10;
+
+pub fn fadt() -> Option<Fadt> {
+    let table = find_table(b"FACP")?;
+    if table.len < FADT_FLAGS {
+        return None;
+    }
+    let mut fadt = Fadt {
+        dsdt: table.read_u32(FADT_DSDT) as u64,
+        smi_command: table.read_u32(FADT_SMI_COMMAND) as u16,
+        acpi_enable: table.read_u8(FADT_ACPI_ENABLE),
+        pm1a_control: table.read_u32(FADT_PM1A_CONTROL) as u16,
+        pm1b_control: table.read_u32(FADT_PM1B_CONTROL) as u16,
+        ..Fadt::default()
+    };
+
+    // ACPI 2.0 added the reset register and 64 bit addresses, older tables stop before them
+    if table.len > FADT_RESET_VALUE && table.read_u32(FADT_FLAGS) & FADT_RESET_REG_SUPPORTED != 0 {
+        fadt.reset_register = Some(GenericAddress {
+            space: table.read_u8(FADT_RESET_REGISTER),
+            address: table.read_u64(FADT_RESET_REGISTER + 4),
+        })
+        .filter(|reg| reg.address != 0);
+        fadt.reset_value = table.read_u8(FADT_RESET_VALUE);
+    }
+    if table.len >= FADT_X_DSDT + 8 && table.read_u64(FADT_X_DSDT) != 0 {
+        fadt.dsdt = table.read_u64(FADT_X_DSDT);
+    }
+    Some(fadt)
+}
+
+// SLP_TYPa and SLP_TYPb for the S5 (soft off) state. They only live in AML, as the `_S5_`
+// package in the DSDT. Rather than run an AML interpreter we look for the encoding firmware
+// uses in practice: NameOp `_S5_` PackageOp PkgLength NumElements, then two integers.
+pub fn s5_sleep_types(fadt: &Fadt) -> Option<(u8, u8)> {
+    const NAME_OP: u8 = 0x08;
+    const ROOT_PREFIX: u8 = b'\\';
+    const PACKAGE_OP: u8 = 0x12;
+    const BYTE_PREFIX: u8 = 0x0A;
+
+    if fadt.dsdt == 0 {
+        return None;
+    }
+    let dsdt = Table::map(fadt.dsdt);
+    let aml = (SDT_HEADER_LEN..dsdt.len.saturating_sub(4)).find(|&i| {
+        dsdt.read_u32(i) == u32::from_le_bytes(*b"_S5_")
+            && (dsdt.read_u8(i - 1) == NAME_OP
+                || (dsdt.read_u8(i - 1) == ROOT_PREFIX && dsdt.read_u8(i - 2) == NAME_OP))
+            && i + 4 < dsdt.len
+            && dsdt.read_u8(i + 4) == PACKAGE_OP
+    })?;
+
+    // The top two bits of PkgLength's lead byte count the bytes that follow it
+    let mut offset = aml + 5;
+    offset += ((dsdt.read_u8(offset) >> 6) as usize) + 2;
+    let mut integer = || {
+        if offset < dsdt.len && dsdt.read_u8(offset) == BYTE_PREFIX {
+            offset += 1;
+        }
+        // ZeroOp and OneOp are 0x00 and 0x01, the value they stand for
+        let value = (offset < dsdt.len).then(|| dsdt.read_u8(offset));
+        offset += 1;
+        value
+    };
+    let slp_typ_a = integer()?;
+    let slp_typ_b = integer()?;
+    Some((slp_typ_a, slp_typ_b))
+}
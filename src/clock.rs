@@ -0,0 +1,193 @@
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+// Wall-clock time. The RTC is read once at boot, after that the time is the boot time plus
+// the uptime, so reading the clock never touches the CMOS. The RTC is taken to be UTC
+// (what QEMU and Linux keep it in), there are no time zones.
+
+// Unix time in milliseconds at uptime 0
+static BOOT_UNIX_MS: AtomicU64 = AtomicU64::new(0);
+
+// Clock ids for clock_gettime
+pub const CLOCK_REALTIME: u64 = 0;
+pub const CLOCK_MONOTONIC: u64 = 1;
+
+const SECONDS_PER_DAY: u64 = 86_400;
+// Starting from Thursday because 1970-01-01 was one
+const WEEKDAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+pub fn init() {
+    let rtc = crate::io::rtc::read();
+    let now = DateTime {
+        year: rtc.year,
+        month: rtc.month,
+        day: rtc.day,
+        hour: rtc.hour,
+        minute: rtc.minute,
+        second: rtc.second,
+    };
+    let boot_ms = (now.to_unix() * 1000).saturating_sub(crate::timer::get_uptime_ms());
+    BOOT_UNIX_MS.store(boot_ms, Ordering::Relaxed);
+    crate::println!("Clock: {}", now);
+}
+
+pub fn now_unix_ms() -> u64 {
+    now_unix_ns() / 1_000_000
+}
+
+pub fn now_unix_ns() -> u64 {
+    BOOT_UNIX_MS.load(Ordering::Relaxed) * 1_000_000 + crate::timer::uptime_ns()
+}
+
+pub fn now() -> DateTime {
+    DateTime::from_unix(now_unix_ms() / 1000)
+}
+
+impl DateTime {
+    // Howard Hinnant's days_from_civil: days since 1970-01-01 of a proleptic Gregorian date
+    pub fn to_unix(&self) -> u64 {
+        let (month, day) = (self.month as i64, self.day as i64);
+        let year = self.year as i64 - (month <= 2) as i64;
+        let era = year.div_euclid(400);
+        let year_of_era = year - era * 400;
+        let day_of_year = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + day - 1;
+        let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+        let days = era * 146_097 + day_of_era - 719_468;
+
+        let seconds = self.hour as u64 * 3600 + self.minute as u64 * 60 + self.second as u64;
+        days.max(0) as u64 * SECONDS_PER_DAY + seconds
+    }
+
+    // And its inverse, civil_from_days
+    pub fn from_unix(seconds: u64) -> Self {
+        let days = (seconds / SECONDS_PER_DAY) as i64 + 719_468;
+        let era = days.div_euclid(146_097);
+        let day_of_era = days - era * 146_097;
+        let year_of_era =
+            (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let mp = (5 * day_of_year + 2) / 153;
+        let day = day_of_year - (153 * mp + 2) / 5 + 1;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 };
+        let year = year_of_era + era * 400 + (month <= 2) as i64;
+
+        let time = seconds % SECONDS_PER_DAY;
+        Self {
+            year: year as u16,
+            month: month as u8,
+            day: day as u8,
+            hour: (time / 3600) as u8,
+            minute: (time / 60 % 60) as u8,
+            second: (time % 60) as u8,
+        }
+    }
+
+    pub fn weekday(&self) -> &'static str {
+        WEEKDAYS[(self.to_unix() / SECONDS_PER_DAY % 7) as usize]
+    }
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} {:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+            self.weekday(),
+            self.year,
+            self.month,
+            self.day,
+            self.hour,
+            self.minute,
+            self.second
+        )
+    }
+}
+
+/*************
+ * FAT CLOCK *
+ *************/
+// Timestamps for files created or changed through simple_fatfs
+#[derive(Clone, Copy, Debug, Default)]
+pub struct WallClock;
+
+impl simple_fatfs::Clock for WallClock {
+    fn now(&self) -> time::PrimitiveDateTime {
+        let now = now();
+        let date = time::Month::try_from(now.month)
+            .ok()
+            .and_then(|month| time::Date::from_calendar_date(now.year as i32, month, now.day).ok());
+        let time = time::Time::from_hms(now.hour, now.minute, now.second).ok();
+        match (date, time) {
+            // FAT can't store anything before 1980
+            (Some(date), Some(time)) if now.year >= 1980 => {
+                time::PrimitiveDateTime::new(date, time)
+            }
+            _ => simple_fatfs::EPOCH,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::string::ToString;
+
+    fn date(year: u16, month: u8, day: u8, hour: u8, minute: u8, second: u8) -> DateTime {
+        DateTime {
+            year,
+            month,
+            day,
+            hour,
+            minute,
+            second,
+        }
+    }
+
+    #[test]
+    fn known_dates_convert_both_ways() {
+        let known = [
+            (date(1970, 1, 1, 0, 0, 0), 0),
+            (date(2000, 2, 29, 12, 34, 56), 951_827_696),
+            (date(2024, 12, 31, 23, 59, 59), 1_735_689_599),
+            (date(2038, 1, 19, 3, 14, 8), 2_147_483_648),
+            (date(2100, 3, 1, 0, 0, 0), 4_107_542_400),
+        ];
+        for (date, unix) in known {
+            assert_eq!(date.to_unix(), unix, "{}", date);
+            assert_eq!(DateTime::from_unix(unix), date);
+        }
+    }
+
+    #[test]
+    fn every_day_round_trips() {
+        // Through 2000 (a leap year) and 2100 (not one)
+        let mut last = DateTime::from_unix(0);
+        for day in 1..(150 * 366) {
+            let unix = day * SECONDS_PER_DAY + 43_200;
+            let date = DateTime::from_unix(unix);
+            assert_eq!(date.to_unix(), unix);
+            assert_eq!(date.hour, 12);
+            assert!((date.year, date.month, date.day) > (last.year, last.month, last.day));
+            last = date;
+        }
+    }
+
+    #[test]
+    fn display_has_the_weekday() {
+        assert_eq!(
+            date(2000, 1, 1, 9, 5, 0).to_string(),
+            "Sat 2000-01-01 09:05:00 UTC"
+        );
+        assert_eq!(DateTime::from_unix(0).weekday(), "Thu");
+    }
+}
@@ -0,0 +1,424 @@
+use crate::fs::block_device::BlockDevice;
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::vec;
+use alloc::vec::Vec;
+use embedded_io::{ErrorKind, ErrorType};
+use simple_fatfs::block_io::{BlockBase, BlockRead, BlockSize, BlockWrite};
+use spin::{Mutex, MutexGuard};
+
+// Sector cache between the filesystems and the disk driver. simple_fatfs only keeps the
+// sector it's working on, so walking directories and FAT chains reads the same sectors
+// over and over. Here every sector read or written stays in memory until it's the least
+// recently used one and the space is needed. Writes only mark the cached copy dirty,
+// dirty sectors go to the disk on flush/sync, when evicted, or from the writeback task.
+
+const SECTOR_SIZE: usize = 512;
+const CACHE_SECTORS: usize = 2048; // 1 MiB
+const READ_AHEAD_SECTORS: u64 = 32;
+const MAX_WRITE_RUN: usize = 128; // Sectors per write command when flushing
+pub const WRITEBACK_INTERVAL_MS: u64 = 5000;
+
+struct Entry {
+    lba: u64,
+    data: Box<[u8; SECTOR_SIZE]>,
+    dirty: bool,
+    last_used: u64,
+}
+
+#[derive(Clone, Copy, Default, Debug)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub read_ahead: u64,  // Sectors fetched before anyone asked for them
+    pub write_backs: u64, // Dirty sectors written to the disk
+    pub evictions: u64,
+}
+
+pub struct BlockCache<D = BlockDevice> {
+    disk: D,
+    entries: Vec<Entry>,
+    index: BTreeMap<u64, usize>, // LBA -> slot in entries, ordered so flushes go out sorted
+    clock: u64,
+    next_sequential: u64, // The sector right after the last read, for spotting streams
+    stats: CacheStats,
+}
+
+static CACHE: Mutex<Option<BlockCache>> = Mutex::new(None);
+
+impl<D: BlockWrite<Error = ErrorKind>> BlockCache<D> {
+    fn new(disk: D) -> Self {
+        Self {
+            disk,
+            entries: Vec::with_capacity(CACHE_SECTORS),
+            index: BTreeMap::new(),
+            clock: 0,
+            next_sequential: u64::MAX,
+            stats: CacheStats::default(),
+        }
+    }
+
+    fn read(&mut self, block: u64, buf: &mut [u8]) -> Result<(), ErrorKind> {
+        let sectors = buf.len().div_ceil(SECTOR_SIZE) as u64;
+        let sequential = block == self.next_sequential;
+        self.next_sequential = block + sectors;
+
+        let mut i = 0;
+        while i < sectors {
+            let lba = block + i;
+            let out = &mut buf[i as usize * SECTOR_SIZE..];
+            if let Some(slot) = self.touch(lba) {
+                let len = out.len().min(SECTOR_SIZE);
+                out[..len].copy_from_slice(&self.entries[slot].data[..len]);
+                self.stats.hits += 1;
+                i += 1;
+                continue;
+            }
+
+            // Fetch the whole run of missing sectors with one command, and keep going
+            // past the end of the request when the reads look sequential
+            let mut run = 1;
+            while i + run < sectors && !self.index.contains_key(&(lba + run)) {
+                run += 1;
+            }
+            let mut fetch = run;
+            if sequential && i + run == sectors {
+                let end = self.disk.block_count() as u64;
+                while fetch < run + READ_AHEAD_SECTORS
+                    && lba + fetch < end
+                    && !self.index.contains_key(&(lba + fetch))
+                {
+                    fetch += 1;
+                }
+            }
+
+            let mut data = vec![0u8; fetch as usize * SECTOR_SIZE];
+            self.disk.read(lba32(lba)?, &mut data)?;
+            for (k, sector) in data.chunks(SECTOR_SIZE).enumerate() {
+                self.insert(lba + k as u64, sector, false)?;
+            }
+            let len = out.len().min(run as usize * SECTOR_SIZE);
+            out[..len].copy_from_slice(&data[..len]);
+
+            self.stats.misses += run;
+            self.stats.read_ahead += fetch - run;
+            i += run;
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, block: u64, buf: &[u8]) -> Result<(), ErrorKind> {
+        for (i, chunk) in buf.chunks(SECTOR_SIZE).enumerate() {
+            let lba = block + i as u64;
+            if chunk.len() < SECTOR_SIZE {
+                // Only part of the sector changes, the rest has to come from the disk
+                let mut sector = [0u8; SECTOR_SIZE];
+                self.read(lba, &mut sector)?;
+                sector[..chunk.len()].copy_from_slice(chunk);
+                self.insert(lba, &sector, true)?;
+            } else {
+                self.insert(lba, chunk, true)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Writes every dirty sector back, contiguous ones in a single command
+    fn flush(&mut self) -> Result<(), ErrorKind> {
+        let dirty: Vec<(u64, usize)> = self
+            .index
+            .iter()
+            .filter(|&(_, &slot)| self.entries[slot].dirty)
+            .map(|(&lba, &slot)| (lba, slot))
+            .collect();
+
+        let mut start = 0;
+        while start < dirty.len() {
+            let mut end = start + 1;
+            while end < dirty.len()
+                && end - start < MAX_WRITE_RUN
+                && dirty[end].0 == dirty[end - 1].0 + 1
+            {
+                end += 1;
+            }
+
+            let mut data = Vec::with_capacity((end - start) * SECTOR_SIZE);
+            for &(_, slot) in &dirty[start..end] {
+                data.extend_from_slice(&self.entries[slot].data[..]);
+            }
+            self.disk.write(lba32(dirty[start].0)?, &data)?;
+            for &(_, slot) in &dirty[start..end] {
+                self.entries[slot].dirty = false;
+            }
+            self.stats.write_backs += (end - start) as u64;
+            start = end;
+        }
+        self.disk.flush()
+    }
+
+    // Slot of a cached sector, marked as just used
+    fn touch(&mut self, lba: u64) -> Option<usize> {
+        let slot = *self.index.get(&lba)?;
+        self.clock += 1;
+        self.entries[slot].last_used = self.clock;
+        Some(slot)
+    }
+
+    // Puts a sector in the cache, replacing the cached copy if there is one
+    fn insert(&mut self, lba: u64, data: &[u8], dirty: bool) -> Result<(), ErrorKind> {
+        let slot = match self.touch(lba) {
+            Some(slot) => slot,
+            None => {
+                let slot = self.free_slot()?;
+                self.index.insert(lba, slot);
+                self.clock += 1;
+                let entry = &mut self.entries[slot];
+                entry.lba = lba;
+                entry.dirty = false;
+                entry.last_used = self.clock;
+                slot
+            }
+        };
+        let entry = &mut self.entries[slot];
+        entry.data.copy_from_slice(&data[..SECTOR_SIZE]);
+        entry.dirty |= dirty;
+        Ok(())
+    }
+
+    // A new slot while there's room, otherwise the least recently used one (written back
+    // first if it's dirty)
+    fn free_slot(&mut self) -> Result<usize, ErrorKind> {
+        if self.entries.len() < CACHE_SECTORS {
+            self.entries.push(Entry {
+                lba: 0,
+                data: Box::new([0; SECTOR_SIZE]),
+                dirty: false,
+                last_used: self.clock,
+            });
+            return Ok(self.entries.len() - 1);
+        }
+
+        let slot = (0..self.entries.len())
+            .min_by_key(|&slot| self.entries[slot].last_used)
+            .unwrap_or(0);
+        let victim = &self.entries[slot];
+        if victim.dirty {
+            self.disk.write(lba32(victim.lba)?, &victim.data[..])?;
+            self.stats.write_backs += 1;
+        }
+        self.index.remove(&victim.lba);
+        self.stats.evictions += 1;
+        Ok(slot)
+    }
+}
+
+// The block traits address sectors with a u32
+fn lba32(lba: u64) -> Result<u32, ErrorKind> {
+    u32::try_from(lba).map_err(|_| ErrorKind::InvalidInput)
+}
+
+// Same deal as FatFs::lock: whoever holds the cache may be asleep on a disk IRQ
+fn lock_cache() -> MutexGuard<'static, Option<BlockCache>> {
+    loop {
+        if let Some(guard) = CACHE.try_lock() {
+            return guard;
+        }
+        crate::multitasker::yield_now();
+    }
+}
+
+pub fn init(disk: BlockDevice) {
+    *lock_cache() = Some(BlockCache::new(disk));
+}
+
+// Writes every dirty sector to the disk
+pub fn sync() -> Result<(), ErrorKind> {
+    match lock_cache().as_mut() {
+        Some(cache) => cache.flush(),
+        None => Ok(()),
+    }
+}
+
+pub fn stats() -> CacheStats {
+    lock_cache()
+        .as_ref()
+        .map(|cache| cache.stats)
+        .unwrap_or_default()
+}
+
+// Kernel thread that pushes dirty sectors out every WRITEBACK_INTERVAL_MS, so a crash or
+// power cut loses at most that much
+pub extern "C" fn writeback_task(_: u64) -> u64 {
+    loop {
+        crate::timer::sleep_ms(WRITEBACK_INTERVAL_MS);
+        if let Err(e) = sync() {
+            crate::serial_println!("Block cache: writeback failed: {:?}", e);
+        }
+    }
+}
+
+/***************
+ * CACHED DISK *
+ ***************/
+// Handle to the cached boot disk, what partitions and filesystems sit on
+pub struct CachedDisk;
+
+impl ErrorType for CachedDisk {
+    type Error = ErrorKind;
+}
+
+impl BlockBase for CachedDisk {
+    fn block_size(&self) -> BlockSize {
+        SECTOR_SIZE as BlockSize
+    }
+
+    fn block_count(&self) -> u32 {
+        lock_cache()
+            .as_ref()
+            .map(|cache| cache.disk.block_count())
+            .unwrap_or(0)
+    }
+}
+
+impl BlockRead for CachedDisk {
+    fn read(&mut self, block: u32, buf: &mut [u8]) -> Result<(), Self::Error> {
+        match lock_cache().as_mut() {
+            Some(cache) => cache.read(block as u64, buf),
+            None => Err(ErrorKind::NotConnected),
+        }
+    }
+}
+
+impl BlockWrite for CachedDisk {
+    fn write(&mut self, block: u32, buf: &[u8]) -> Result<(), Self::Error> {
+        match lock_cache().as_mut() {
+            Some(cache) => cache.write(block as u64, buf),
+            None => Err(ErrorKind::NotConnected),
+        }
+    }
+
+    fn flush(&mut self) -> Result<(), Self::Error> {
+        match lock_cache().as_mut() {
+            Some(cache) => cache.flush(),
+            None => Err(ErrorKind::NotConnected),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECTORS: usize = 4 * CACHE_SECTORS;
+
+    struct MemoryDisk(Vec<u8>);
+
+    impl ErrorType for MemoryDisk {
+        type Error = ErrorKind;
+    }
+
+    impl BlockBase for MemoryDisk {
+        fn block_size(&self) -> BlockSize {
+            SECTOR_SIZE as BlockSize
+        }
+
+        fn block_count(&self) -> u32 {
+            (self.0.len() / SECTOR_SIZE) as u32
+        }
+    }
+
+    impl BlockRead for MemoryDisk {
+        fn read(&mut self, block: u32, buf: &mut [u8]) -> Result<(), Self::Error> {
+            let start = block as usize * SECTOR_SIZE;
+            buf.copy_from_slice(&self.0[start..start + buf.len()]);
+            Ok(())
+        }
+    }
+
+    impl BlockWrite for MemoryDisk {
+        fn write(&mut self, block: u32, buf: &[u8]) -> Result<(), Self::Error> {
+            let start = block as usize * SECTOR_SIZE;
+            self.0[start..start + buf.len()].copy_from_slice(buf);
+            Ok(())
+        }
+
+        fn flush(&mut self) -> Result<(), Self::Error> {
+            Ok(())
+        }
+    }
+
+    fn cache() -> BlockCache<MemoryDisk> {
+        BlockCache::new(MemoryDisk(vec![0; SECTORS * SECTOR_SIZE]))
+    }
+
+    fn read(cache: &mut BlockCache<MemoryDisk>, lba: u64) -> [u8; SECTOR_SIZE] {
+        let mut sector = [0; SECTOR_SIZE];
+        cache.read(lba, &mut sector).unwrap();
+        sector
+    }
+
+    // Fills every slot with the even sectors from `first` on, too far apart to read ahead
+    fn fill(cache: &mut BlockCache<MemoryDisk>, first: u64) {
+        for i in 0..CACHE_SECTORS as u64 {
+            read(cache, first + 2 * i);
+        }
+        assert_eq!(cache.entries.len(), CACHE_SECTORS);
+    }
+
+    #[test]
+    fn evicts_the_least_recently_used() {
+        let mut cache = cache();
+        fill(&mut cache, 0);
+        read(&mut cache, 0);
+
+        let far = 2 * CACHE_SECTORS as u64 + 1;
+        read(&mut cache, far);
+        read(&mut cache, far + 2);
+        read(&mut cache, far + 4);
+        assert_eq!(cache.stats.evictions, 3);
+        for lba in [0, 8, far, far + 2, far + 4] {
+            assert!(cache.index.contains_key(&lba), "sector {} was evicted", lba);
+        }
+        for lba in [2, 4, 6] {
+            assert!(
+                !cache.index.contains_key(&lba),
+                "sector {} is still cached",
+                lba
+            );
+        }
+    }
+
+    #[test]
+    fn read_ahead_stays_cached() {
+        let mut cache = cache();
+        fill(&mut cache, 0);
+
+        let far = 2 * CACHE_SECTORS as u64 + 1;
+        read(&mut cache, far);
+        read(&mut cache, far + 1);
+        assert_eq!(cache.stats.read_ahead, READ_AHEAD_SECTORS);
+        for lba in far..far + 2 + READ_AHEAD_SECTORS {
+            assert!(cache.index.contains_key(&lba), "sector {} was evicted", lba);
+        }
+
+        let misses = cache.stats.misses;
+        read(&mut cache, far + 2);
+        assert_eq!(cache.stats.misses, misses);
+    }
+
+    #[test]
+    fn dirty_victim_is_written_back() {
+        let mut cache = cache();
+        cache.write(1, &[0xAB; SECTOR_SIZE]).unwrap();
+        assert_eq!(cache.disk.0[SECTOR_SIZE], 0);
+
+        fill(&mut cache, 2);
+        assert!(!cache.index.contains_key(&1));
+        assert_eq!(cache.stats.write_backs, 1);
+        assert_eq!(
+            cache.disk.0[SECTOR_SIZE..2 * SECTOR_SIZE],
+            [0xAB; SECTOR_SIZE]
+        );
+        assert_eq!(read(&mut cache, 1), [0xAB; SECTOR_SIZE]);
+    }
+}
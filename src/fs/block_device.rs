@@ -0,0 +1,182 @@
+use crate::fs::fat_driver::AtaIoWrapper;
+use crate::io::ahci::AhciDisk;
+use crate::io::ata_driver::AtaPio;
+use crate::io::pci::{self, PciDevice, PciDriver};
+use crate::io::virtio_blk::{VIRTIO_VENDOR, VirtioBlk};
+use embedded_io::{ErrorKind, ErrorType};
+use simple_fatfs::block_io::{BlockBase, BlockRead, BlockSize, BlockWrite};
+use spin::Mutex;
+
+// Every disk driver the filesystem can sit on. They all speak simple_fatfs's block
+// traits, this just forwards to whichever one found the disk.
+pub enum BlockDevice {
+    Ata(AtaIoWrapper),
+    Ahci(AhciDisk),
+    Virtio(VirtioBlk),
+}
+
+// The first disk a driver claims, picked up by detect()
+static DETECTED: Mutex<Option<BlockDevice>> = Mutex::new(None);
+
+// Registered in order: virtio-blk first (fastest under QEMU), then AHCI (q35 and real
+// hardware), then the IDE controller, which only adds bus-master DMA to legacy ATA
+static DISK_DRIVERS: [PciDriver; 3] = [
+    PciDriver {
+        name: "virtio-blk",
+        class: 0x01,
+        subclass: 0x00,
+        vendor_id: Some(VIRTIO_VENDOR),
+        probe: probe_virtio,
+    },
+    PciDriver {
+        name: "ahci",
+        class: 0x01,
+        subclass: 0x06,
+        vendor_id: None,
+        probe: probe_ahci,
+    },
+    PciDriver {
+        name: "ata",
+        class: 0x01,
+        subclass: 0x01,
+        vendor_id: None,
+        probe: probe_ide,
+    },
+];
+
+fn probe_virtio(device: &PciDevice) -> bool {
+    match VirtioBlk::init(device) {
+        Ok(disk) => {
+            crate::println!("Disk: virtio-blk, {} sectors", disk.sector_count());
+            if DETECTED.lock().is_none() {
+                disk.setup_interrupts(device);
+            }
+            attach(BlockDevice::Virtio(disk));
+            true
+        }
+        Err(e) => {
+            crate::println!("Disk: {}", e);
+            false
+        }
+    }
+}
+
+fn probe_ahci(device: &PciDevice) -> bool {
+    match AhciDisk::init(device) {
+        Ok(disk) => {
+            crate::println!(
+                "Disk: AHCI port {}, {} sectors",
+                disk.port(),
+                disk.sector_count()
+            );
+            // Only the disk that gets attached takes the controller's interrupts
+            if DETECTED.lock().is_none() {
+                disk.setup_interrupts(device);
+            }
+            attach(BlockDevice::Ahci(disk));
+            true
+        }
+        Err(e) => {
+            crate::println!("Disk: {}", e);
+            false
+        }
+    }
+}
+
+fn probe_ide(device: &PciDevice) -> bool {
+    if let Err(e) = crate::io::ata_driver::attach_bus_master(device) {
+        crate::println!("ATA: {}, falling back to PIO", e);
+    }
+    // The legacy ports work either way, so the controller is ours
+    true
+}
+
+// Keeps the first disk found, later ones are dropped for now
+fn attach(disk: BlockDevice) {
+    let mut detected = DETECTED.lock();
+    if detected.is_none() {
+        *detected = Some(disk);
+    }
+}
+
+// Called once interrupts are on, so the disk drivers stop polling
+pub fn enable_interrupts() {
+    crate::io::ata_driver::enable_interrupts();
+    crate::io::ahci::enable_interrupts();
+    crate::io::virtio_blk::enable_interrupts();
+}
+
+// Makes the disk drivers poll again. Shutdown calls this once the other CPUs are halted,
+// their interrupts are routed to the BSP.
+pub fn disable_interrupts() {
+    crate::io::ata_driver::disable_interrupts();
+    crate::io::ahci::disable_interrupts();
+    crate::io::virtio_blk::disable_interrupts();
+}
+
+impl BlockDevice {
+    // Lets every disk driver probe the PCI devices, falling back to legacy ATA on port
+    // 0x1F0 when none of them found a disk
+    pub fn detect() -> Self {
+        for driver in DISK_DRIVERS.iter() {
+            pci::register_driver(driver);
+        }
+        if let Some(disk) = DETECTED.lock().take() {
+            return disk;
+        }
+        crate::println!("Disk: no PCI disk found, trying legacy ATA");
+        let disk = AtaIoWrapper::new(AtaPio::init());
+        crate::println!("Disk: ATA, {} sectors", disk.sector_count());
+        Self::Ata(disk)
+    }
+}
+
+impl ErrorType for BlockDevice {
+    type Error = ErrorKind;
+}
+
+impl BlockBase for BlockDevice {
+    fn block_size(&self) -> BlockSize {
+        match self {
+            Self::Ata(disk) => disk.block_size(),
+            Self::Ahci(disk) => disk.block_size(),
+            Self::Virtio(disk) => disk.block_size(),
+        }
+    }
+
+    fn block_count(&self) -> u32 {
+        match self {
+            Self::Ata(disk) => disk.block_count(),
+            Self::Ahci(disk) => disk.block_count(),
+            Self::Virtio(disk) => disk.block_count(),
+        }
+    }
+}
+
+impl BlockRead for BlockDevice {
+    fn read(&mut self, block: u32, buf: &mut [u8]) -> Result<(), Self::Error> {
+        match self {
+            Self::Ata(disk) => disk.read(block, buf),
+            Self::Ahci(disk) => disk.read(block, buf),
+            Self::Virtio(disk) => disk.read(block, buf),
+        }
+    }
+}
+
+impl BlockWrite for BlockDevice {
+    fn write(&mut self, block: u32, buf: &[u8]) -> Result<(), Self::Error> {
+        match self {
+            Self::Ata(disk) => disk.write(block, buf),
+            Self::Ahci(disk) => disk.write(block, buf),
+            Self::Virtio(disk) => disk.write(block, buf),
+        }
+    }
+
+    fn flush(&mut self) -> Result<(), Self::Error> {
+        match self {
+            Self::Ata(disk) => disk.flush(),
+            Self::Ahci(disk) => disk.flush(),
+            Self::Virtio(disk) => disk.flush(),
+        }
+    }
+}
@@ -0,0 +1,608 @@
+use crate::fs::block_cache::CachedDisk;
+use crate::fs::partition::{Partition, PartitionDevice};
+use crate::fs::vfs::{DirEntry, Filesystem, NodeKind, NodeRef, Stat, Vnode};
+use crate::screen::renderer::{FramebufferWriter, WRITER};
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::any::Any;
+use core::sync::atomic::{AtomicU8, AtomicU64, Ordering};
+use simple_fatfs::block_io::{BlockBase, BlockRead, BlockWrite};
+use spin::{Mutex, MutexGuard};
+
+// Devices as files under /dev, so programs can use open/read/write on them instead of a
+// syscall per device. The set of devices is fixed when the filesystem is created.
+
+const SECTOR_SIZE: usize = 512;
+const MAX_BLOCK_RUN: usize = 128; // Sectors per disk command on a block device
+
+// ioctl requests of /dev/fb0
+pub const FB_GET_INFO: u64 = 1; // arg: *mut FbInfo
+pub const FB_FLUSH: u64 = 2; // Pushes the whole mapped buffer to the screen
+
+// Same layout as FbInfo in rustos_user
+#[repr(C)]
+pub struct FbInfo {
+    pub width: u32,
+    pub height: u32,
+    pub pitch: u32, // Bytes per row
+    pub bpp: u32,
+}
+
+// ioctl requests of /dev/mouse
+pub const MOUSE_GET_INFO: u64 = 1; // arg: *mut MouseInfo
+pub const MOUSE_SET_SAMPLE_RATE: u64 = 2; // arg: reports per second, 10 to 200
+pub const MOUSE_SET_RESOLUTION: u64 = 3; // arg: counts per mm, 1, 2, 4 or 8
+
+// What a read of /dev/mouse returns, same layout as MouseEvent in rustos_user
+#[repr(C)]
+pub struct MouseEvent {
+    pub dx: i16,
+    pub dy: i16,
+    pub buttons: u8,
+    _reserved: u8,
+    pub wheel: i16, // Clicks, positive is away from the user
+}
+
+// Same layout as MouseInfo in rustos_user
+#[repr(C)]
+pub struct MouseInfo {
+    pub buttons: u8,
+    pub has_wheel: u8,
+    pub sample_rate: u8,
+    pub resolution: u8,
+}
+
+pub struct DevFs {
+    root: Arc<DevDir>,
+}
+
+impl DevFs {
+    pub fn new(partitions: &[Partition]) -> Arc<Self> {
+        let mut entries: Vec<(String, NodeRef)> = Vec::new();
+        entries.push((String::from("null"), Arc::new(Null)));
+        entries.push((String::from("zero"), Arc::new(Zero)));
+        entries.push((String::from("random"), Arc::new(Random)));
+        entries.push((String::from("tty"), Arc::new(Tty)));
+        entries.push((String::from("ttyS0"), Arc::new(SerialTty)));
+        entries.push((String::from("fb0"), Arc::new(Framebuffer)));
+        entries.push((String::from("mouse"), Arc::new(Mouse)));
+
+        let disk = CachedDisk;
+        let sectors = disk.block_count() as u64;
+        if sectors > 0 {
+            entries.push((String::from("sda"), BlockNode::new(disk, sectors)));
+            for p in partitions {
+                let device = PartitionDevice::new(CachedDisk, p);
+                entries.push((
+                    format!("sda{}", p.number),
+                    BlockNode::new(device, p.sector_count),
+                ));
+            }
+        }
+
+        Arc::new(Self {
+            root: Arc::new(DevDir { entries }),
+        })
+    }
+}
+
+impl Filesystem for DevFs {
+    fn name(&self) -> &'static str {
+        "devfs"
+    }
+
+    fn root(self: Arc<Self>) -> NodeRef {
+        self.root.clone()
+    }
+}
+
+struct DevDir {
+    entries: Vec<(String, NodeRef)>,
+}
+
+impl Vnode for DevDir {
+    fn stat(&self) -> Result<Stat, &'static str> {
+        Ok(Stat {
+            kind: NodeKind::Directory,
+            size: 0,
+        })
+    }
+
+    fn lookup(&self, name: &str) -> Result<NodeRef, &'static str> {
+        self.entries
+            .iter()
+            .find(|(entry, _)| entry == name)
+            .map(|(_, node)| node.clone())
+            .ok_or("no such device")
+    }
+
+    fn readdir(&self) -> Result<Vec<DirEntry>, &'static str> {
+        self.entries
+            .iter()
+            .map(|(name, node)| {
+                let stat = node.stat()?;
+                Ok(DirEntry {
+                    name: name.clone(),
+                    kind: stat.kind,
+                    size: stat.size,
+                })
+            })
+            .collect()
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+fn char_device() -> Result<Stat, &'static str> {
+    Ok(Stat {
+        kind: NodeKind::CharDevice,
+        size: 0,
+    })
+}
+
+/*************
+ * NULL/ZERO *
+ *************/
+// Reads nothing, swallows everything
+struct Null;
+
+impl Vnode for Null {
+    fn stat(&self) -> Result<Stat, &'static str> {
+        char_device()
+    }
+
+    fn read(&self, _offset: u64, _buf: &mut [u8]) -> Result<usize, &'static str> {
+        Ok(0)
+    }
+
+    fn write(&self, _offset: u64, buf: &[u8]) -> Result<usize, &'static str> {
+        Ok(buf.len())
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+// Endless zeros, swallows everything
+struct Zero;
+
+impl Vnode for Zero {
+    fn stat(&self) -> Result<Stat, &'static str> {
+        char_device()
+    }
+
+    fn read(&self, _offset: u64, buf: &mut [u8]) -> Result<usize, &'static str> {
+        buf.fill(0);
+        Ok(buf.len())
+    }
+
+    fn write(&self, _offset: u64, buf: &[u8]) -> Result<usize, &'static str> {
+        Ok(buf.len())
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+/**********
+ * RANDOM *
+ **********/
+// RDRAND when the CPU has it, otherwise xorshift64* seeded from the TSC. Good enough for
+// games and temp names, not for keys.
+struct Random;
+
+static HAS_RDRAND: AtomicU8 = AtomicU8::new(0); // 0 = not checked yet, 1 = yes, 2 = no
+static XORSHIFT_STATE: AtomicU64 = AtomicU64::new(0);
+
+fn has_rdrand() -> bool {
+    match HAS_RDRAND.load(Ordering::Relaxed) {
+        0 => {
+            let supported = unsafe { core::arch::x86_64::__cpuid(1) }.ecx & (1 << 30) != 0;
+            HAS_RDRAND.store(if supported { 1 } else { 2 }, Ordering::Relaxed);
+            supported
+        }
+        state => state == 1,
+    }
+}
+
+fn rdrand() -> Option<u64> {
+    // RDRAND may come back empty-handed when the hardware is drained, retrying is the fix
+    for _ in 0..10 {
+        let value: u64;
+        let ok: u8;
+        unsafe {
+            core::arch::asm!("rdrand {}", "setc {}", out(reg) value, out(reg_byte) ok, options(nomem, nostack));
+        }
+        if ok != 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+fn xorshift() -> u64 {
+    let mut x = XORSHIFT_STATE.load(Ordering::Relaxed);
+    if x == 0 {
+        x = unsafe { core::arch::x86_64::_rdtsc() } | 1;
+    }
+    x ^= x >> 12;
+    x ^= x << 25;
+    x ^= x >> 27;
+    XORSHIFT_STATE.store(x, Ordering::Relaxed);
+    x.wrapping_mul(0x2545_F491_4F6C_DD1D)
+}
+
+fn random_u64() -> u64 {
+    if has_rdrand() {
+        if let Some(value) = rdrand() {
+            return value;
+        }
+    }
+    xorshift()
+}
+
+impl Vnode for Random {
+    fn stat(&self) -> Result<Stat, &'static str> {
+        char_device()
+    }
+
+    fn read(&self, _offset: u64, buf: &mut [u8]) -> Result<usize, &'static str> {
+        for chunk in buf.chunks_mut(8) {
+            chunk.copy_from_slice(&random_u64().to_le_bytes()[..chunk.len()]);
+        }
+        Ok(buf.len())
+    }
+
+    // Writes are accepted and ignored, there is no pool to stir
+    fn write(&self, _offset: u64, buf: &[u8]) -> Result<usize, &'static str> {
+        Ok(buf.len())
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+/*******
+ * TTY *
+ *******/
+// The console: reads are keyboard and serial input, writes go to the screen and serial like
+// SYS_PRINT_CHAR
+struct Tty;
+
+impl Vnode for Tty {
+    fn stat(&self) -> Result<Stat, &'static str> {
+        char_device()
+    }
+
+    // Sleeps until the first byte, then takes whatever else is already typed
+    fn read(&self, _offset: u64, buf: &mut [u8]) -> Result<usize, &'static str> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        buf[0] = crate::io::keyboard::wait_key_byte(crate::io::keyboard::read_foreground_byte);
+        let mut n = 1;
+        while n < buf.len() {
+            match crate::io::keyboard::read_foreground_byte() {
+                Some(b) => buf[n] = b,
+                None => break,
+            }
+            n += 1;
+        }
+        Ok(n)
+    }
+
+    fn write(&self, _offset: u64, buf: &[u8]) -> Result<usize, &'static str> {
+        for &c in buf {
+            crate::io::log_buffer::SERIAL_QUEUE.push_char(c);
+            crate::io::log_buffer::DISPLAY_QUEUE.push_char(c);
+        }
+        Ok(buf.len())
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+// COM1 alone: reads are what the serial line discipline decoded, writes only go to serial
+struct SerialTty;
+
+impl Vnode for SerialTty {
+    fn stat(&self) -> Result<Stat, &'static str> {
+        char_device()
+    }
+
+    // Sleeps until the first byte, then takes whatever else is already typed
+    fn read(&self, _offset: u64, buf: &mut [u8]) -> Result<usize, &'static str> {
+        let read_key = || {
+            crate::io::keyboard::current_task_has_input()
+                .then(crate::io::tty::read_key)
+                .flatten()
+        };
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        buf[0] = crate::io::keyboard::wait_key_byte(read_key);
+        let mut n = 1;
+        while n < buf.len() {
+            match read_key() {
+                Some(b) => buf[n] = b,
+                None => break,
+            }
+            n += 1;
+        }
+        Ok(n)
+    }
+
+    fn write(&self, _offset: u64, buf: &[u8]) -> Result<usize, &'static str> {
+        for &c in buf {
+            crate::io::log_buffer::SERIAL_QUEUE.push_char(c);
+        }
+        Ok(buf.len())
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+/***************
+ * FRAMEBUFFER *
+ ***************/
+// The screen's shadow buffer, 32 bits per pixel. Writes through read/write are shown on the
+// next frame, writes through a mapping need an FB_FLUSH.
+struct Framebuffer;
+
+// The compositor holds the writer while drawing and may get preempted doing so, so
+// spinning here with interrupts off could wait forever
+fn with_fb<R>(f: impl FnOnce(&mut FramebufferWriter) -> R) -> Result<R, &'static str> {
+    loop {
+        if let Some(mut guard) = WRITER.try_lock() {
+            return guard.as_mut().map(f).ok_or("no framebuffer");
+        }
+        crate::multitasker::yield_now();
+    }
+}
+
+impl Vnode for Framebuffer {
+    fn stat(&self) -> Result<Stat, &'static str> {
+        let size = with_fb(|fb| fb.buffer.len() as u64)?;
+        Ok(Stat {
+            kind: NodeKind::CharDevice,
+            size,
+        })
+    }
+
+    fn read(&self, offset: u64, buf: &mut [u8]) -> Result<usize, &'static str> {
+        with_fb(|fb| {
+            let start = (offset as usize).min(fb.buffer.len());
+            let n = buf.len().min(fb.buffer.len() - start);
+            buf[..n].copy_from_slice(&fb.buffer[start..start + n]);
+            n
+        })
+    }
+
+    fn write(&self, offset: u64, buf: &[u8]) -> Result<usize, &'static str> {
+        with_fb(|fb| {
+            let start = (offset as usize).min(fb.buffer.len());
+            let n = buf.len().min(fb.buffer.len() - start);
+            fb.buffer[start..start + n].copy_from_slice(&buf[..n]);
+            if n > 0 {
+                let first_row = start as u64 / fb.pitch;
+                let last_row = (start + n - 1) as u64 / fb.pitch;
+                fb.mark_dirty(first_row, last_row - first_row + 1);
+            }
+            n
+        })
+    }
+
+    fn ioctl(&self, request: u64, arg: u64) -> Result<u64, &'static str> {
+        match request {
+            FB_GET_INFO => {
+                if arg == 0 {
+                    return Err("null FbInfo pointer");
+                }
+                let info = with_fb(|fb| FbInfo {
+                    width: fb.width as u32,
+                    height: fb.height as u32,
+                    pitch: fb.pitch as u32,
+                    bpp: 32,
+                })?;
+                unsafe { (arg as *mut FbInfo).write(info) };
+                Ok(0)
+            }
+            FB_FLUSH => with_fb(|fb| fb.mark_dirty(0, fb.height)).map(|_| 0),
+            _ => Err("unknown framebuffer request"),
+        }
+    }
+
+    // Programs share the kernel's address space, so the buffer's own address will do
+    fn mmap(&self, offset: u64, len: u64) -> Result<u64, &'static str> {
+        with_fb(|fb| {
+            let end = offset.checked_add(len)?;
+            (end <= fb.buffer.len() as u64).then(|| fb.buffer.as_ptr() as u64 + offset)
+        })?
+        .ok_or("past the end of the framebuffer")
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+/*********
+ * MOUSE *
+ *********/
+// Each read takes the motion and wheel clicks since the previous one, plus the buttons held
+// right now
+struct Mouse;
+
+impl Vnode for Mouse {
+    fn stat(&self) -> Result<Stat, &'static str> {
+        char_device()
+    }
+
+    fn read(&self, _offset: u64, buf: &mut [u8]) -> Result<usize, &'static str> {
+        let len = core::mem::size_of::<MouseEvent>();
+        if buf.len() < len {
+            return Err("buffer smaller than a mouse event");
+        }
+        let packed = crate::io::mouse::take_deltas_packed();
+        let event = MouseEvent {
+            dx: packed as u16 as i16,
+            dy: (packed >> 16) as u16 as i16,
+            buttons: crate::io::mouse::get_buttons_mask(),
+            _reserved: 0,
+            wheel: (packed >> 32) as u16 as i16,
+        };
+        unsafe { (buf.as_mut_ptr() as *mut MouseEvent).write_unaligned(event) };
+        Ok(len)
+    }
+
+    fn ioctl(&self, request: u64, arg: u64) -> Result<u64, &'static str> {
+        let mouse = crate::io::mouse::info();
+        let setting = u8::try_from(arg).map_err(|_| "mouse setting out of range");
+        match request {
+            MOUSE_GET_INFO => {
+                if arg == 0 {
+                    return Err("null MouseInfo pointer");
+                }
+                let info = MouseInfo {
+                    buttons: mouse.buttons,
+                    has_wheel: mouse.has_wheel as u8,
+                    sample_rate: mouse.sample_rate,
+                    resolution: mouse.resolution,
+                };
+                unsafe { (arg as *mut MouseInfo).write_unaligned(info) };
+                Ok(0)
+            }
+            MOUSE_SET_SAMPLE_RATE => {
+                crate::io::mouse::configure(setting?, mouse.resolution).map(|_| 0)
+            }
+            MOUSE_SET_RESOLUTION => {
+                crate::io::mouse::configure(mouse.sample_rate, setting?).map(|_| 0)
+            }
+            _ => Err("unknown mouse request"),
+        }
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+/*****************
+ * BLOCK DEVICES *
+ *****************/
+// A disk or partition read and written at byte offsets. Partial sectors are read, patched
+// and written back. Everything goes through the sector cache, so this stays coherent with
+// the filesystems on the same disk.
+struct BlockNode<D> {
+    device: Mutex<D>,
+    size: u64,
+}
+
+impl<D: BlockRead + BlockWrite + Send + 'static> BlockNode<D> {
+    fn new(device: D, sectors: u64) -> NodeRef {
+        Arc::new(Self {
+            device: Mutex::new(device),
+            size: sectors * SECTOR_SIZE as u64,
+        })
+    }
+
+    // Disk access can sleep on an IRQ, so wait for the device like FatFs::lock does
+    fn lock(&self) -> MutexGuard<'_, D> {
+        loop {
+            if let Some(guard) = self.device.try_lock() {
+                return guard;
+            }
+            crate::multitasker::yield_now();
+        }
+    }
+}
+
+fn block32(lba: u64) -> Result<u32, &'static str> {
+    u32::try_from(lba).map_err(|_| "past the end of the device")
+}
+
+impl<D: BlockRead + BlockWrite + Send + 'static> Vnode for BlockNode<D> {
+    fn stat(&self) -> Result<Stat, &'static str> {
+        Ok(Stat {
+            kind: NodeKind::BlockDevice,
+            size: self.size,
+        })
+    }
+
+    fn read(&self, offset: u64, buf: &mut [u8]) -> Result<usize, &'static str> {
+        let len = buf.len().min(self.size.saturating_sub(offset) as usize);
+        let mut device = self.lock();
+        let mut done = 0;
+        while done < len {
+            let pos = offset + done as u64;
+            let lba = block32(pos / SECTOR_SIZE as u64)?;
+            let within = (pos % SECTOR_SIZE as u64) as usize;
+            let whole = (len - done) / SECTOR_SIZE;
+            if within == 0 && whole > 0 {
+                let bytes = whole.min(MAX_BLOCK_RUN) * SECTOR_SIZE;
+                device
+                    .read(lba, &mut buf[done..done + bytes])
+                    .map_err(|_| "disk read failed")?;
+                done += bytes;
+            } else {
+                let mut sector = [0u8; SECTOR_SIZE];
+                device
+                    .read(lba, &mut sector)
+                    .map_err(|_| "disk read failed")?;
+                let n = (SECTOR_SIZE - within).min(len - done);
+                buf[done..done + n].copy_from_slice(&sector[within..within + n]);
+                done += n;
+            }
+        }
+        Ok(len)
+    }
+
+    fn write(&self, offset: u64, buf: &[u8]) -> Result<usize, &'static str> {
+        let len = buf.len().min(self.size.saturating_sub(offset) as usize);
+        if len == 0 && !buf.is_empty() {
+            return Err("past the end of the device");
+        }
+        let mut device = self.lock();
+        let mut done = 0;
+        while done < len {
+            let pos = offset + done as u64;
+            let lba = block32(pos / SECTOR_SIZE as u64)?;
+            let within = (pos % SECTOR_SIZE as u64) as usize;
+            let whole = (len - done) / SECTOR_SIZE;
+            if within == 0 && whole > 0 {
+                let bytes = whole.min(MAX_BLOCK_RUN) * SECTOR_SIZE;
+                device
+                    .write(lba, &buf[done..done + bytes])
+                    .map_err(|_| "disk write failed")?;
+                done += bytes;
+            } else {
+                let mut sector = [0u8; SECTOR_SIZE];
+                device
+                    .read(lba, &mut sector)
+                    .map_err(|_| "disk read failed")?;
+                let n = (SECTOR_SIZE - within).min(len - done);
+                sector[within..within + n].copy_from_slice(&buf[done..done + n]);
+                device
+                    .write(lba, &sector)
+                    .map_err(|_| "disk write failed")?;
+                done += n;
+            }
+        }
+        Ok(len)
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
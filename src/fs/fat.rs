@@ -0,0 +1,283 @@
+use crate::clock::WallClock;
+use crate::fs::partition::PartitionDevice;
+use crate::fs::vfs::{DirEntry, Filesystem, NodeKind, NodeRef, Stat, Vnode};
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::any::Any;
+use embedded_io::{Read, Seek, SeekFrom, Write};
+use simple_fatfs::{FileProps, FileSystem, ROFile};
+use spin::{Mutex, MutexGuard};
+
+// simple_fatfs behind the VFS. The library works on whole paths, so a node is just the
+// path of its entry on the volume. Names are matched case-insensitively, like FAT does.
+
+// The FAT volume, sitting on a partition of the boot disk
+pub type FatVolume = FileSystem<PartitionDevice, WallClock>;
+
+pub struct FatFs {
+    volume: Mutex<FatVolume>,
+}
+
+impl FatFs {
+    pub fn new(volume: FatVolume) -> Arc<Self> {
+        Arc::new(Self {
+            volume: Mutex::new(volume),
+        })
+    }
+
+    // Takes the volume lock, yielding while someone else holds it. The holder may be
+    // asleep waiting for a disk IRQ, so spinning (with interrupts off in a syscall) would hang.
+    fn lock(&self) -> MutexGuard<'_, FatVolume> {
+        loop {
+            if let Some(guard) = self.volume.try_lock() {
+                return guard;
+            }
+            crate::multitasker::yield_now();
+        }
+    }
+}
+
+impl Filesystem for FatFs {
+    fn name(&self) -> &'static str {
+        "fat"
+    }
+
+    fn root(self: Arc<Self>) -> NodeRef {
+        Arc::new(FatNode {
+            fs: self,
+            path: String::from("/"),
+            kind: NodeKind::Directory,
+            cursor: Mutex::new(None),
+        })
+    }
+
+    // Writes the sector simple_fatfs keeps buffered and the FS info, then the cached disk
+    fn sync(&self) -> Result<(), &'static str> {
+        self.lock().unmount().map_err(|_| "FAT sync failed")?;
+        crate::fs::block_cache::sync().map_err(|_| "disk cache sync failed")
+    }
+}
+
+pub struct FatNode {
+    fs: Arc<FatFs>,
+    path: String, // Path on the volume, as simple_fatfs spells it
+    kind: NodeKind,
+    // Where the last read stopped. Reopening the file would walk its whole cluster chain,
+    // reads from here on only follow it forward.
+    cursor: Mutex<Option<FileProps>>,
+}
+
+impl FatNode {
+    fn child(&self, path: String, kind: NodeKind) -> NodeRef {
+        Arc::new(FatNode {
+            fs: self.fs.clone(),
+            path,
+            kind,
+            cursor: Mutex::new(None),
+        })
+    }
+
+    fn child_path(&self, name: &str) -> String {
+        format!("{}/{}", self.path.trim_end_matches(['/', '\\']), name)
+    }
+
+    // The entries of this directory as (name, path on the volume, kind, size)
+    fn entries(
+        &self,
+        volume: &FatVolume,
+    ) -> Result<Vec<(String, String, NodeKind, u64)>, &'static str> {
+        let iter = volume
+            .read_dir(self.path.as_str())
+            .map_err(|_| "not a directory")?;
+        let mut entries: Vec<(String, String, NodeKind, u64)> = Vec::new();
+        for entry in iter.flatten() {
+            let path = format!("{}", entry.path());
+            let name = path.rsplit(['/', '\\']).next().unwrap_or("");
+            // The directory iterator can report the same entry more than once
+            if name.is_empty() || name == "." || name == ".." || entries.iter().any(|e| e.0 == name)
+            {
+                continue;
+            }
+            let kind = if entry.is_dir() {
+                NodeKind::Directory
+            } else {
+                NodeKind::File
+            };
+            entries.push((
+                String::from(name),
+                path.clone(),
+                kind,
+                entry.file_size() as u64,
+            ));
+        }
+        Ok(entries)
+    }
+}
+
+// Case-insensitive, and failing that also ignoring dots ("DOOM1WAD" finds "DOOM1.WAD")
+fn names_match(entry: &str, wanted: &str) -> bool {
+    entry.eq_ignore_ascii_case(wanted) || dotless(entry).eq(dotless(wanted))
+}
+
+fn dotless(name: &str) -> impl Iterator<Item = u8> + '_ {
+    name.bytes()
+        .filter(|&b| b != b'.')
+        .map(|b| b.to_ascii_uppercase())
+}
+
+impl Vnode for FatNode {
+    fn stat(&self) -> Result<Stat, &'static str> {
+        let size = match self.kind {
+            NodeKind::Directory => 0,
+            _ => {
+                let volume = self.fs.lock();
+                let file = volume
+                    .get_ro_file(self.path.as_str())
+                    .map_err(|_| "file not found")?;
+                file.file_size() as u64
+            }
+        };
+        Ok(Stat {
+            kind: self.kind,
+            size,
+        })
+    }
+
+    fn lookup(&self, name: &str) -> Result<NodeRef, &'static str> {
+        let volume = self.fs.lock();
+        let entries = self.entries(&volume)?;
+        let found = entries
+            .iter()
+            .find(|e| e.0.eq_ignore_ascii_case(name))
+            .or_else(|| entries.iter().find(|e| names_match(&e.0, name)))
+            .ok_or("no such file or directory")?;
+        Ok(self.child(found.1.clone(), found.2))
+    }
+
+    fn read(&self, offset: u64, buf: &mut [u8]) -> Result<usize, &'static str> {
+        let volume = self.fs.lock();
+        let mut cursor = self.cursor.lock();
+        let mut file = match cursor.take() {
+            Some(props) => ROFile::from_props(props, &volume),
+            None => volume
+                .get_ro_file(self.path.as_str())
+                .map_err(|_| "file not found")?,
+        };
+        file.seek(SeekFrom::Start(offset))
+            .map_err(|_| "seek failed")?;
+        let n = file.read(buf).map_err(|_| "read failed")?;
+        *cursor = Some(file.props.clone());
+        Ok(n)
+    }
+
+    fn write(&self, offset: u64, buf: &[u8]) -> Result<usize, &'static str> {
+        let volume = self.fs.lock();
+        *self.cursor.lock() = None; // The cluster chain may change under it
+        let n = {
+            let mut file = volume
+                .get_rw_file(self.path.as_str())
+                .map_err(|_| "file not found")?;
+            file.seek(SeekFrom::Start(offset))
+                .map_err(|_| "seek failed")?;
+            file.write(buf).map_err(|_| "write failed")?
+        };
+        let _ = volume.unmount();
+        Ok(n)
+    }
+
+    fn truncate(&self, size: u64) -> Result<(), &'static str> {
+        let volume = self.fs.lock();
+        *self.cursor.lock() = None;
+        {
+            let mut file = volume
+                .get_rw_file(self.path.as_str())
+                .map_err(|_| "file not found")?;
+            file.seek(SeekFrom::Start(size))
+                .map_err(|_| "seek failed")?;
+            file.truncate().map_err(|_| "truncate failed")?;
+        }
+        let _ = volume.unmount();
+        Ok(())
+    }
+
+    fn readdir(&self) -> Result<Vec<DirEntry>, &'static str> {
+        let volume = self.fs.lock();
+        Ok(self
+            .entries(&volume)?
+            .into_iter()
+            .map(|(name, _, kind, size)| DirEntry { name, kind, size })
+            .collect())
+    }
+
+    fn create(&self, name: &str, kind: NodeKind) -> Result<NodeRef, &'static str> {
+        let path = self.child_path(name);
+        {
+            let volume = self.fs.lock();
+            match kind {
+                NodeKind::Directory => volume
+                    .create_dir(path.as_str())
+                    .map_err(|_| "can't create directory")?,
+                NodeKind::File => {
+                    // simple_fatfs has trouble growing a file that owns no cluster yet, so a
+                    // new file gets one: write past the first cluster, then cut back to 0
+                    let mut file = volume
+                        .create_file(path.as_str())
+                        .map_err(|_| "can't create file")?;
+                    file.write(&[0u8; 4097]).map_err(|_| "can't create file")?;
+                    file.seek(SeekFrom::Start(0))
+                        .map_err(|_| "can't create file")?;
+                    file.truncate().map_err(|_| "can't create file")?;
+                }
+                _ => return Err("FAT can't hold device nodes"),
+            }
+            let _ = volume.unmount();
+        }
+        self.lookup(name)
+    }
+
+    fn unlink(&self, name: &str) -> Result<(), &'static str> {
+        let volume = self.fs.lock();
+        let entries = self.entries(&volume)?;
+        let (_, path, kind, _) = entries
+            .iter()
+            .find(|e| e.0.eq_ignore_ascii_case(name))
+            .ok_or("no such file or directory")?;
+        let result = if *kind == NodeKind::Directory {
+            volume.remove_empty_dir(path.as_str())
+        } else {
+            volume.remove_file(path.as_str())
+        };
+        result.map_err(|_| "can't remove")?;
+        let _ = volume.unmount();
+        Ok(())
+    }
+
+    fn rename(
+        &self,
+        old_name: &str,
+        new_dir: &NodeRef,
+        new_name: &str,
+    ) -> Result<(), &'static str> {
+        let new_dir = new_dir
+            .as_any()
+            .downcast_ref::<FatNode>()
+            .ok_or("can't rename across filesystems")?;
+        let volume = self.fs.lock();
+        let entries = self.entries(&volume)?;
+        let (_, from, _, _) = entries
+            .iter()
+            .find(|e| e.0.eq_ignore_ascii_case(old_name))
+            .ok_or("no such file or directory")?;
+        volume
+            .rename(from.as_str(), new_dir.child_path(new_name).as_str())
+            .map_err(|_| "rename failed")?;
+        let _ = volume.unmount();
+        Ok(())
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
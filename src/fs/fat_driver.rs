@@ -5,11 +5,26 @@ use simple_fatfs::block_io::{BlockBase, BlockRead, BlockSize, BlockWrite};
 
 pub struct AtaIoWrapper {
     pub driver: AtaPio,
+    sector_count: u64,
 }
 
 impl AtaIoWrapper {
-    pub fn new(driver: AtaPio) -> Self {
-        Self { driver }
+    pub fn new(mut driver: AtaPio) -> Self {
+        let sector_count = match driver.identify() {
+            Ok(count) => count,
+            Err(e) => {
+                crate::println!("ATA: {}", e);
+                0
+            }
+        };
+        Self {
+            driver,
+            sector_count,
+        }
+    }
+
+    pub fn sector_count(&self) -> u64 {
+        self.sector_count
     }
 }
 
@@ -23,8 +38,7 @@ impl BlockBase for AtaIoWrapper {
     }
 
     fn block_count(&self) -> u32 {
-        // Disk size is 1024 MiB = 2,097,152 sectors (512 bytes each)
-        0x200000
+        self.sector_count.min(u32::MAX as u64) as u32
     }
 }
 
@@ -42,29 +56,17 @@ impl BlockRead for AtaIoWrapper {
             );
         }
 
-        let count = (buf.len() / 512) as usize;
-        if count == 0 {
+        if buf.len() < 512 {
             crate::serial_println!("AtaIoWrapper: Read error: buf too small ({})", buf.len());
             return Err(ErrorKind::Other);
         }
 
-        let mut remaining = count;
-        let mut cur_block = block;
-        let mut offset = 0;
-
-        while remaining > 0 {
-            let chunk = core::cmp::min(remaining, 255) as u8;
-            self.driver.read_sectors(
-                cur_block,
-                chunk,
-                &mut buf[offset..offset + (chunk as usize) * 512],
-            );
-            cur_block += chunk as u32;
-            offset += (chunk as usize) * 512;
-            remaining -= chunk as usize;
-        }
-
-        Ok(())
+        // Only whole sectors are transferred. The driver splits big requests itself,
+        // LBA48 has no 255 sector limit.
+        let whole = buf.len() / 512 * 512;
+        self.driver
+            .read_sectors(block as u64, &mut buf[..whole])
+            .map_err(|_| ErrorKind::Other)
     }
 }
 
@@ -82,30 +84,21 @@ impl BlockWrite for AtaIoWrapper {
             );
         }
 
-        let count = (buf.len() / 512) as usize;
-        if count == 0 {
+        if buf.len() < 512 {
             crate::serial_println!("AtaIoWrapper: Write error: buf too small ({})", buf.len());
             return Err(ErrorKind::Other);
         }
 
-        // Handle possible overflow of u8 if simple_fatfs passes > 255 sectors
-        let mut remaining = count;
-        let mut cur_block = block;
-        let mut offset = 0;
-
-        while remaining > 0 {
-            let chunk = core::cmp::min(remaining, 255) as u8;
-            self.driver
-                .write_sectors(cur_block, chunk, &buf[offset..offset + (chunk as usize) * 512]);
-            cur_block += chunk as u32;
-            offset += (chunk as usize) * 512;
-            remaining -= chunk as usize;
-        }
-
-        Ok(())
+        let whole = buf.len() / 512 * 512;
+        self.driver
+            .write_sectors(block as u64, &buf[..whole])
+            .map_err(|_| ErrorKind::Other)
     }
 
     fn flush(&mut self) -> Result<(), Self::Error> {
-        Ok(())
+        self.driver.flush_cache().map_err(|e| {
+            crate::serial_println!("AtaIoWrapper: flush failed: {}", e);
+            ErrorKind::Other
+        })
     }
 }
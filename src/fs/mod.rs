@@ -1,64 +1,87 @@
+pub mod block_cache;
+pub mod block_device;
+pub mod devfs;
+mod fat;
 mod fat_driver;
+mod partition;
+mod procfs;
+mod tmpfs;
+pub mod vfs;
 
-use crate::fs::fat_driver::AtaIoWrapper;
-use crate::io::ata_driver::AtaPio;
+use crate::fs::block_cache::CachedDisk;
+use crate::fs::block_device::BlockDevice;
+use crate::fs::devfs::DevFs;
+use crate::fs::fat::FatFs;
+use crate::fs::partition::{Partition, PartitionDevice};
+use crate::fs::procfs::ProcFs;
+use crate::fs::tmpfs::TmpFs;
+use crate::fs::vfs::OpenFile;
 use lazy_static::lazy_static;
 use spin::Mutex;
-use x86_64::instructions::interrupts;
-// Import DefaultClock as indicated by the compiler
-use simple_fatfs::{DefaultClock, FSOptions, FileSystem, ROFile, FileProps};
+use simple_fatfs::{FSOptions, FileSystem};
 use alloc::vec::Vec;
 
-lazy_static! {
-    // Change () to DefaultClock
-    pub static ref FILESYSTEM: Mutex<Option<FileSystem<AtaIoWrapper, DefaultClock>>> = Mutex::new(None);
-    pub static ref OPEN_FILES: Mutex<Vec<Option<FileProps>>> = Mutex::new(Vec::new());
-}
+// How much file data /tmp may hold
+pub const TMPFS_SIZE_CAP: u64 = 16 * 1024 * 1024;
 
-pub fn with_filesystem<R>(
-    f: impl FnOnce(&mut Option<FileSystem<AtaIoWrapper, DefaultClock>>) -> R,
-) -> R {
-    interrupts::without_interrupts(|| {
-        let mut fs_lock = FILESYSTEM.lock();
-        f(&mut fs_lock)
-    })
+lazy_static! {
+    pub static ref OPEN_FILES: Mutex<Vec<Option<OpenFile>>> = Mutex::new(Vec::new());
 }
 
 pub fn init_fs() {
-    let mut ata = AtaPio::init();
+    let partitions = mount_boot_disk();
 
-    let mut buf = [0u8; 512];
-
-    // Read the very first sector of the drive
-    ata.read_sectors(0, 1, &mut buf);
+    if let Err(e) = vfs::mount("/dev", DevFs::new(&partitions)) {
+        crate::println!("Mount Error: {}", e);
+    }
+    if let Err(e) = vfs::mount("/proc", ProcFs::new()) {
+        crate::println!("Mount Error: {}", e);
+    }
 
-    // Print the first 16 bytes.
-    // FAT32 usually starts with EB 58 90 or EB 3C 90.
-    // MBR (partitioned disk) starts with various code but ends in 55 AA.
-    crate::println!(
-        "DISK HEX: {:02X} {:02X} {:02X} {:02X} | {:02X} {:02X} {:02X} {:02X}",
-        buf[0],
-        buf[1],
-        buf[2],
-        buf[3],
-        buf[4],
-        buf[5],
-        buf[6],
-        buf[7]
-    );
-    crate::println!("DISK SIGNATURE: {:02X} {:02X}", buf[510], buf[511]);
-    let wrapper = AtaIoWrapper::new(ata);
+    // Scratch space that never touches the disk
+    if let Err(e) = vfs::mount("/tmp", TmpFs::new(TMPFS_SIZE_CAP)) {
+        crate::println!("Mount Error: {}", e);
+    }
+}
 
-    // FSOptions::new() defaults to DefaultClock
-    let options = FSOptions::new();
+// Mounts the first FAT partition of the boot disk at /, returns the partitions found
+fn mount_boot_disk() -> Vec<Partition> {
+    block_cache::init(BlockDevice::detect());
+    let mut disk = CachedDisk;
 
-    match FileSystem::new(wrapper, options) {
-        Ok(fs) => {
-            with_filesystem(|slot| *slot = Some(fs));
-            crate::println!("Filesystem: FAT32 mounted successfully.");
+    let partitions = match partition::scan(&mut disk) {
+        Ok(partitions) => partitions,
+        Err(e) => {
+            crate::println!("Partition Error: {}", e);
+            return Vec::new();
         }
+    };
+    for p in &partitions {
+        crate::println!(
+            "Partition {}: {}, LBA {}, {} sectors",
+            p.number,
+            p.kind,
+            p.start_lba,
+            p.sector_count
+        );
+    }
+    let Some(volume) = partitions.iter().find(|p| p.is_fat()) else {
+        crate::println!("Mount Error: no FAT partition on the disk");
+        return partitions;
+    };
+    let volume = PartitionDevice::new(disk, volume);
+
+    // New entries get their creation time from the wall clock
+    let options = FSOptions::new_with_clock(crate::clock::WallClock);
+
+    match FileSystem::new(volume, options) {
+        Ok(fs) => match vfs::mount("/", FatFs::new(fs)) {
+            Ok(()) => crate::println!("Filesystem: FAT32 mounted successfully."),
+            Err(e) => crate::println!("Mount Error: {}", e),
+        },
         Err(e) => {
             crate::println!("Mount Error: {:?}", e); // See if it's 'InvalidSignature' or 'IoError'
         }
     }
+    partitions
 }
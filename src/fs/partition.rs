@@ -0,0 +1,477 @@
+use crate::fs::block_cache::CachedDisk;
+use alloc::vec::Vec;
+use embedded_io::{ErrorKind, ErrorType};
+use simple_fatfs::block_io::{BlockBase, BlockRead, BlockSize, BlockWrite};
+
+// Finds the partitions on a disk. A GPT disk starts with a protective MBR (one entry of
+// type 0xEE), anything else with a 55 AA signature is read as an MBR, following the
+// chain of extended boot records for logical partitions. A disk formatted without any
+// partition table (mkfs.fat straight onto the image) counts as one partition covering
+// everything.
+
+const SECTOR_SIZE: usize = 512;
+const MBR_TABLE_OFFSET: usize = 0x1BE;
+const MBR_TYPE_GPT_PROTECTIVE: u8 = 0xEE;
+const MAX_LOGICAL_PARTITIONS: usize = 64; // Guards against a looping EBR chain
+const MAX_GPT_ENTRIES: u32 = 128;
+
+// FAT partition type GUIDs as they are stored on disk (the first three fields little endian)
+const GPT_BASIC_DATA: [u8; 16] = [
+    0xA2, 0xA0, 0xD0, 0xEB, 0xE5, 0xB9, 0x33, 0x44, 0x87, 0xC0, 0x68, 0xB6, 0xB7, 0x26, 0x99, 0xC7,
+];
+const GPT_EFI_SYSTEM: [u8; 16] = [
+    0x28, 0x73, 0x2A, 0xC1, 0x1F, 0xF8, 0xD2, 0x11, 0xBA, 0x4B, 0x00, 0xA0, 0xC9, 0x3E, 0xC9, 0x3B,
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PartitionKind {
+    Whole,         // No partition table
+    Mbr(u8),       // Partition type byte
+    Gpt([u8; 16]), // Partition type GUID
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Partition {
+    pub number: usize, // 1-based, as in sda1
+    pub start_lba: u64,
+    pub sector_count: u64,
+    pub kind: PartitionKind,
+}
+
+impl core::fmt::Display for PartitionKind {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            PartitionKind::Whole => write!(f, "whole disk"),
+            PartitionKind::Mbr(kind) => write!(f, "MBR type {:#04x}", kind),
+            PartitionKind::Gpt(guid) if *guid == GPT_BASIC_DATA => write!(f, "GPT basic data"),
+            PartitionKind::Gpt(guid) if *guid == GPT_EFI_SYSTEM => write!(f, "GPT EFI system"),
+            PartitionKind::Gpt(_) => write!(f, "GPT"),
+        }
+    }
+}
+
+impl Partition {
+    pub fn is_fat(&self) -> bool {
+        match self.kind {
+            PartitionKind::Whole => true,
+            PartitionKind::Mbr(kind) => {
+                matches!(kind, 0x01 | 0x04 | 0x06 | 0x0B | 0x0C | 0x0E | 0xEF)
+            }
+            PartitionKind::Gpt(guid) => guid == GPT_BASIC_DATA || guid == GPT_EFI_SYSTEM,
+        }
+    }
+}
+
+/**************
+ * PARTITIONS *
+ **************/
+pub fn scan(disk: &mut impl BlockRead) -> Result<Vec<Partition>, &'static str> {
+    let mut sector = [0u8; SECTOR_SIZE];
+    disk.read(0, &mut sector)
+        .map_err(|_| "can't read the partition table")?;
+
+    let whole = Partition {
+        number: 1,
+        start_lba: 0,
+        sector_count: disk.block_count() as u64,
+        kind: PartitionKind::Whole,
+    };
+    if sector[510] != 0x55 || sector[511] != 0xAA || is_fat_boot_sector(&sector) {
+        return Ok(alloc::vec![whole]);
+    }
+
+    let entries = mbr_entries(&sector);
+    if entries
+        .iter()
+        .any(|&(kind, _, _)| kind == MBR_TYPE_GPT_PROTECTIVE)
+    {
+        return scan_gpt(disk);
+    }
+
+    let mut partitions = Vec::new();
+    for (i, &(kind, start, count)) in entries.iter().enumerate() {
+        if kind == 0 || count == 0 {
+            continue;
+        }
+        if is_extended(kind) {
+            scan_logical(disk, start, &mut partitions)?;
+            continue;
+        }
+        partitions.push(Partition {
+            number: i + 1,
+            start_lba: start,
+            sector_count: count,
+            kind: PartitionKind::Mbr(kind),
+        });
+    }
+    if partitions.is_empty() {
+        partitions.push(whole);
+    }
+    Ok(partitions)
+}
+
+// A FAT volume boot sector also ends in 55 AA, but has a BIOS parameter block in front
+fn is_fat_boot_sector(sector: &[u8; SECTOR_SIZE]) -> bool {
+    let jumps = sector[0] == 0xEB || sector[0] == 0xE9;
+    let bytes_per_sector = u16::from_le_bytes([sector[11], sector[12]]);
+    let fat_label = &sector[0x36..0x39] == b"FAT" || &sector[0x52..0x55] == b"FAT";
+    jumps && bytes_per_sector == SECTOR_SIZE as u16 && fat_label
+}
+
+// (type, first LBA, sector count) of the four primary entries
+fn mbr_entries(sector: &[u8; SECTOR_SIZE]) -> [(u8, u64, u64); 4] {
+    core::array::from_fn(|i| {
+        let entry = &sector[MBR_TABLE_OFFSET + i * 16..MBR_TABLE_OFFSET + (i + 1) * 16];
+        (
+            entry[4],
+            u32::from_le_bytes([entry[8], entry[9], entry[10], entry[11]]) as u64,
+            u32::from_le_bytes([entry[12], entry[13], entry[14], entry[15]]) as u64,
+        )
+    })
+}
+
+fn is_extended(kind: u8) -> bool {
+    matches!(kind, 0x05 | 0x0F | 0x85)
+}
+
+// Logical partitions are numbered from 5, each EBR holds one of them and a link to the
+// next EBR. Both are relative: the partition to its EBR, the link to the extended partition.
+fn scan_logical(
+    disk: &mut impl BlockRead,
+    extended_start: u64,
+    partitions: &mut Vec<Partition>,
+) -> Result<(), &'static str> {
+    let mut ebr = extended_start;
+    let mut sector = [0u8; SECTOR_SIZE];
+    for number in 5..5 + MAX_LOGICAL_PARTITIONS {
+        disk.read(lba32(ebr)?, &mut sector)
+            .map_err(|_| "can't read an extended boot record")?;
+        if sector[510] != 0x55 || sector[511] != 0xAA {
+            break;
+        }
+        let [(kind, start, count), (_, next, _), ..] = mbr_entries(&sector);
+        if kind != 0 && count != 0 {
+            partitions.push(Partition {
+                number,
+                start_lba: ebr + start,
+                sector_count: count,
+                kind: PartitionKind::Mbr(kind),
+            });
+        }
+        if next == 0 {
+            break;
+        }
+        ebr = extended_start + next;
+    }
+    Ok(())
+}
+
+fn scan_gpt(disk: &mut impl BlockRead) -> Result<Vec<Partition>, &'static str> {
+    let mut header = [0u8; SECTOR_SIZE];
+    disk.read(1, &mut header)
+        .map_err(|_| "can't read the GPT header")?;
+    if &header[0..8] != b"EFI PART" {
+        return Err("protective MBR but no GPT header");
+    }
+    let entries_lba = u64::from_le_bytes(header[72..80].try_into().unwrap());
+    let entry_count = u32::from_le_bytes(header[80..84].try_into().unwrap());
+    let entry_size = u32::from_le_bytes(header[84..88].try_into().unwrap()) as usize;
+    if !(128..=SECTOR_SIZE).contains(&entry_size) || SECTOR_SIZE % entry_size != 0 {
+        return Err("unsupported GPT entry size");
+    }
+
+    let per_sector = SECTOR_SIZE / entry_size;
+    let mut partitions = Vec::new();
+    let mut sector = [0u8; SECTOR_SIZE];
+    for index in 0..entry_count.min(MAX_GPT_ENTRIES) as usize {
+        if index % per_sector == 0 {
+            let lba = lba32(entries_lba + (index / per_sector) as u64)?;
+            disk.read(lba, &mut sector)
+                .map_err(|_| "can't read the GPT entries")?;
+        }
+        let entry = &sector[(index % per_sector) * entry_size..][..entry_size];
+        let guid: [u8; 16] = entry[0..16].try_into().unwrap();
+        if guid == [0; 16] {
+            continue;
+        }
+        let first = u64::from_le_bytes(entry[32..40].try_into().unwrap());
+        let last = u64::from_le_bytes(entry[40..48].try_into().unwrap());
+        if last < first {
+            continue;
+        }
+        partitions.push(Partition {
+            number: index + 1,
+            start_lba: first,
+            sector_count: last - first + 1,
+            kind: PartitionKind::Gpt(guid),
+        });
+    }
+    Ok(partitions)
+}
+
+// The block traits address sectors with a u32, so everything past 2 TiB is out of reach
+fn lba32(lba: u64) -> Result<u32, &'static str> {
+    u32::try_from(lba).map_err(|_| "partition lies beyond 2 TiB")
+}
+
+/********************
+ * PARTITION DEVICE *
+ ********************/
+// A window onto one partition: block 0 is the partition's first sector, and nothing
+// outside the partition can be read or written through it.
+pub struct PartitionDevice {
+    disk: CachedDisk,
+    start_lba: u64,
+    sector_count: u64,
+}
+
+impl PartitionDevice {
+    pub fn new(disk: CachedDisk, partition: &Partition) -> Self {
+        Self {
+            disk,
+            start_lba: partition.start_lba,
+            sector_count: partition.sector_count,
+        }
+    }
+
+    // Disk LBA of `block`, if the whole `len` byte request stays inside the partition
+    fn translate(&self, block: u32, len: usize) -> Result<u32, ErrorKind> {
+        let sectors = len.div_ceil(SECTOR_SIZE) as u64;
+        if block as u64 + sectors > self.sector_count {
+            crate::serial_println!(
+                "Partition: block {} (+{}) is past the end ({} sectors)",
+                block,
+                sectors,
+                self.sector_count
+            );
+            return Err(ErrorKind::InvalidInput);
+        }
+        lba32(self.start_lba + block as u64).map_err(|_| ErrorKind::InvalidInput)
+    }
+}
+
+impl ErrorType for PartitionDevice {
+    type Error = ErrorKind;
+}
+
+impl BlockBase for PartitionDevice {
+    fn block_size(&self) -> BlockSize {
+        self.disk.block_size()
+    }
+
+    fn block_count(&self) -> u32 {
+        self.sector_count.min(u32::MAX as u64) as u32
+    }
+}
+
+impl BlockRead for PartitionDevice {
+    fn read(&mut self, block: u32, buf: &mut [u8]) -> Result<(), Self::Error> {
+        let lba = self.translate(block, buf.len())?;
+        self.disk.read(lba, buf)
+    }
+}
+
+impl BlockWrite for PartitionDevice {
+    fn write(&mut self, block: u32, buf: &[u8]) -> Result<(), Self::Error> {
+        let lba = self.translate(block, buf.len())?;
+        self.disk.write(lba, buf)
+    }
+
+    fn flush(&mut self) -> Result<(), Self::Error> {
+        self.disk.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECTORS: usize = 400;
+
+    struct MemoryDisk(Vec<u8>);
+
+    impl MemoryDisk {
+        fn new() -> Self {
+            Self(alloc::vec![0; SECTORS * SECTOR_SIZE])
+        }
+
+        fn sector(&mut self, lba: u64) -> &mut [u8] {
+            &mut self.0[lba as usize * SECTOR_SIZE..][..SECTOR_SIZE]
+        }
+
+        // Fills in one of the four entries of the MBR or EBR at `lba` and signs it
+        fn set_entry(&mut self, lba: u64, index: usize, kind: u8, start: u32, count: u32) {
+            let sector = self.sector(lba);
+            let entry = &mut sector[MBR_TABLE_OFFSET + index * 16..][..16];
+            entry[4] = kind;
+            entry[8..12].copy_from_slice(&start.to_le_bytes());
+            entry[12..16].copy_from_slice(&count.to_le_bytes());
+            sector[510] = 0x55;
+            sector[511] = 0xAA;
+        }
+
+        // A GPT header at LBA 1 pointing at `count` entries of `size` bytes from LBA 2
+        fn set_gpt_header(&mut self, count: u32, size: u32) {
+            self.set_entry(0, 0, MBR_TYPE_GPT_PROTECTIVE, 1, SECTORS as u32 - 1);
+            let header = self.sector(1);
+            header[0..8].copy_from_slice(b"EFI PART");
+            header[72..80].copy_from_slice(&2u64.to_le_bytes());
+            header[80..84].copy_from_slice(&count.to_le_bytes());
+            header[84..88].copy_from_slice(&size.to_le_bytes());
+        }
+
+        fn set_gpt_entry(&mut self, index: usize, guid: [u8; 16], first: u64, last: u64) {
+            let entries = &mut self.0[2 * SECTOR_SIZE..];
+            let entry = &mut entries[index * 128..][..128];
+            entry[0..16].copy_from_slice(&guid);
+            entry[32..40].copy_from_slice(&first.to_le_bytes());
+            entry[40..48].copy_from_slice(&last.to_le_bytes());
+        }
+    }
+
+    impl ErrorType for MemoryDisk {
+        type Error = ErrorKind;
+    }
+
+    impl BlockBase for MemoryDisk {
+        fn block_size(&self) -> BlockSize {
+            SECTOR_SIZE as BlockSize
+        }
+
+        fn block_count(&self) -> u32 {
+            (self.0.len() / SECTOR_SIZE) as u32
+        }
+    }
+
+    impl BlockRead for MemoryDisk {
+        fn read(&mut self, block: u32, buf: &mut [u8]) -> Result<(), Self::Error> {
+            let start = block as usize * SECTOR_SIZE;
+            let source = self
+                .0
+                .get(start..start + buf.len())
+                .ok_or(ErrorKind::InvalidInput)?;
+            buf.copy_from_slice(source);
+            Ok(())
+        }
+    }
+
+    fn spans(partitions: &[Partition]) -> Vec<(usize, u64, u64)> {
+        partitions
+            .iter()
+            .map(|p| (p.number, p.start_lba, p.sector_count))
+            .collect()
+    }
+
+    #[test]
+    fn unsigned_disk_is_one_partition() {
+        let partitions = scan(&mut MemoryDisk::new()).unwrap();
+        assert_eq!(spans(&partitions), [(1, 0, SECTORS as u64)]);
+        assert_eq!(partitions[0].kind, PartitionKind::Whole);
+    }
+
+    #[test]
+    fn fat_boot_sector_is_not_an_mbr() {
+        let mut disk = MemoryDisk::new();
+        // The BPB of a FAT32 volume, whose bytes would read as a bogus partition entry
+        disk.set_entry(0, 0, 0x0C, 2048, 100);
+        let sector = disk.sector(0);
+        sector[0] = 0xEB;
+        sector[11..13].copy_from_slice(&512u16.to_le_bytes());
+        sector[0x52..0x55].copy_from_slice(b"FAT");
+
+        let partitions = scan(&mut disk).unwrap();
+        assert_eq!(partitions[0].kind, PartitionKind::Whole);
+    }
+
+    #[test]
+    fn primary_partitions_keep_their_slot_number() {
+        let mut disk = MemoryDisk::new();
+        disk.set_entry(0, 0, 0x0C, 10, 100);
+        disk.set_entry(0, 2, 0x83, 200, 50);
+
+        let partitions = scan(&mut disk).unwrap();
+        assert_eq!(spans(&partitions), [(1, 10, 100), (3, 200, 50)]);
+        assert!(partitions[0].is_fat());
+        assert!(!partitions[1].is_fat());
+    }
+
+    #[test]
+    fn empty_mbr_is_one_partition() {
+        let mut disk = MemoryDisk::new();
+        disk.set_entry(0, 0, 0, 0, 0);
+
+        let partitions = scan(&mut disk).unwrap();
+        assert_eq!(partitions[0].kind, PartitionKind::Whole);
+    }
+
+    #[test]
+    fn logical_partitions_follow_the_ebr_chain() {
+        let mut disk = MemoryDisk::new();
+        disk.set_entry(0, 0, 0x0C, 10, 50);
+        disk.set_entry(0, 1, 0x0F, 100, 200);
+        // Partition relative to its EBR, link relative to the extended partition
+        disk.set_entry(100, 0, 0x0B, 1, 20);
+        disk.set_entry(100, 1, 0x05, 50, 30);
+        disk.set_entry(150, 0, 0x06, 2, 10);
+
+        let partitions = scan(&mut disk).unwrap();
+        assert_eq!(
+            spans(&partitions),
+            [(1, 10, 50), (5, 101, 20), (6, 152, 10)]
+        );
+        assert_eq!(partitions[2].kind, PartitionKind::Mbr(0x06));
+    }
+
+    #[test]
+    fn looping_ebr_chain_stops() {
+        let mut disk = MemoryDisk::new();
+        disk.set_entry(0, 0, 0x0F, 100, 200);
+        // The second EBR links to the third and the third back to the second
+        disk.set_entry(100, 0, 0x0B, 1, 1);
+        disk.set_entry(100, 1, 0x05, 50, 1);
+        disk.set_entry(150, 0, 0x0B, 1, 1);
+        disk.set_entry(150, 1, 0x05, 80, 1);
+        disk.set_entry(180, 0, 0x0B, 1, 1);
+        disk.set_entry(180, 1, 0x05, 50, 1);
+
+        let partitions = scan(&mut disk).unwrap();
+        assert_eq!(partitions.len(), MAX_LOGICAL_PARTITIONS);
+    }
+
+    #[test]
+    fn gpt_entries_skip_unused_slots() {
+        let mut disk = MemoryDisk::new();
+        disk.set_gpt_header(4, 128);
+        disk.set_gpt_entry(0, GPT_BASIC_DATA, 34, 133);
+        disk.set_gpt_entry(2, GPT_EFI_SYSTEM, 200, 299);
+        disk.set_gpt_entry(3, [1; 16], 350, 340); // Ends before it starts
+
+        let partitions = scan(&mut disk).unwrap();
+        assert_eq!(spans(&partitions), [(1, 34, 100), (3, 200, 100)]);
+        assert!(partitions.iter().all(Partition::is_fat));
+    }
+
+    #[test]
+    fn gpt_entries_span_sectors() {
+        let mut disk = MemoryDisk::new();
+        disk.set_gpt_header(8, 128);
+        disk.set_gpt_entry(5, GPT_BASIC_DATA, 40, 49); // Second entry sector
+
+        let partitions = scan(&mut disk).unwrap();
+        assert_eq!(spans(&partitions), [(6, 40, 10)]);
+    }
+
+    #[test]
+    fn gpt_needs_its_header() {
+        let mut disk = MemoryDisk::new();
+        disk.set_entry(0, 0, MBR_TYPE_GPT_PROTECTIVE, 1, 100);
+        assert!(scan(&mut disk).is_err());
+    }
+
+    #[test]
+    fn gpt_rejects_odd_entry_sizes() {
+        let mut disk = MemoryDisk::new();
+        disk.set_gpt_header(4, 96);
+        assert!(scan(&mut disk).is_err());
+    }
+}
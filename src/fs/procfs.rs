@@ -0,0 +1,341 @@
+use crate::fs::vfs::{self, DirEntry, Filesystem, NodeKind, NodeRef, Stat, Vnode};
+use crate::multitasker::task::TaskStatus;
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::any::Any;
+use core::fmt::Write;
+
+// Kernel state as text files under /proc. Nothing is stored, every read renders the file
+// again from the live state, so a file's size is whatever it renders to right now.
+//
+//   /proc/<id>/status  one task        /proc/meminfo     frames and heap
+//   /proc/<id>/maps    its memory      /proc/interrupts  counts per vector
+//   /proc/self         the reader      /proc/uptime      seconds since boot
+//                                      /proc/mounts      the mount table
+
+pub struct ProcFs;
+
+impl ProcFs {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self)
+    }
+}
+
+impl Filesystem for ProcFs {
+    fn name(&self) -> &'static str {
+        "proc"
+    }
+
+    fn root(self: Arc<Self>) -> NodeRef {
+        Arc::new(ProcDir { task: None })
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Source {
+    Meminfo,
+    Interrupts,
+    Uptime,
+    Mounts,
+    Status(u64),
+    Maps(u64),
+}
+
+const ROOT_FILES: [(&str, Source); 4] = [
+    ("meminfo", Source::Meminfo),
+    ("interrupts", Source::Interrupts),
+    ("uptime", Source::Uptime),
+    ("mounts", Source::Mounts),
+];
+
+/*********
+ * TASKS *
+ *********/
+// What /proc shows of a task, copied out so the scheduler isn't held while formatting
+struct TaskInfo {
+    id: u64,
+    name: String,
+    process_id: u64,
+    parent_id: u64,
+    pgid: u64,
+    status: TaskStatus,
+    running: bool,
+    wake_at: u64,
+    waiting_on: Option<u64>,
+    wait_channel: Option<u64>,
+    wait_futex: Option<u64>,
+    fs_base: u64,
+    stack: (u64, usize),
+    image: Option<(u64, usize)>,
+    arg: Option<(u64, usize)>,
+}
+
+fn tasks() -> Vec<TaskInfo> {
+    let mut tasks = Vec::new();
+    crate::multitasker::scheduler::SCHEDULER.for_each_task(|task, running| {
+        tasks.push(TaskInfo {
+            id: task.id,
+            name: task.name.clone(),
+            process_id: task.process_id,
+            parent_id: task.parent_id,
+            pgid: task.pgid,
+            status: task.status,
+            running,
+            wake_at: task.wake_at,
+            waiting_on: task.waiting_on,
+            wait_channel: task.wait_channel,
+            wait_futex: task.wait_futex,
+            fs_base: task.fs_base,
+            stack: (task.stack_base, task.stack_size),
+            image: task
+                .owned_program_image
+                .as_ref()
+                .map(|image| (image.as_ptr() as u64, image.len())),
+            arg: task
+                .owned_arg_bytes
+                .as_ref()
+                .map(|arg| (arg.as_ptr() as u64, arg.len())),
+        })
+    });
+    tasks.sort_by_key(|task| task.id);
+    tasks
+}
+
+fn task(id: u64) -> Result<TaskInfo, &'static str> {
+    tasks()
+        .into_iter()
+        .find(|task| task.id == id)
+        .ok_or("no such task")
+}
+
+fn current_task_id() -> Option<u64> {
+    crate::multitasker::scheduler::SCHEDULER.with_current(|task| task.id)
+}
+
+/*************
+ * RENDERING *
+ *************/
+impl Source {
+    fn render(self) -> Result<String, &'static str> {
+        let mut out = String::new();
+        match self {
+            Source::Meminfo => meminfo(&mut out),
+            Source::Interrupts => interrupts(&mut out),
+            Source::Uptime => {
+                let ms = crate::timer::get_uptime_ms();
+                writeln!(out, "{}.{:02}", ms / 1000, ms % 1000 / 10)
+            }
+            Source::Mounts => vfs::mounts()
+                .iter()
+                .try_for_each(|(path, fs)| writeln!(out, "{} {}", fs, path)),
+            Source::Status(id) => status(&mut out, &task(id)?),
+            Source::Maps(id) => maps(&mut out, &task(id)?),
+        }
+        .map_err(|_| "formatting failed")?;
+        Ok(out)
+    }
+}
+
+fn meminfo(out: &mut String) -> core::fmt::Result {
+    let (total_frames, used_frames) = crate::memory::frame_stats();
+    let heap_size = crate::memory::get_heap_size();
+    let heap_used = crate::memory::get_heap_usage();
+    let cache = crate::fs::block_cache::stats();
+
+    writeln!(out, "MemTotal:     {:>10} kB", total_frames * 4)?;
+    writeln!(out, "MemUsed:      {:>10} kB", used_frames * 4)?;
+    writeln!(
+        out,
+        "MemFree:      {:>10} kB",
+        (total_frames - used_frames) * 4
+    )?;
+    writeln!(out, "HeapTotal:    {:>10} kB", heap_size / 1024)?;
+    writeln!(out, "HeapUsed:     {:>10} kB", heap_used / 1024)?;
+    writeln!(
+        out,
+        "HeapFree:     {:>10} kB",
+        (heap_size - heap_used) / 1024
+    )?;
+    writeln!(out, "CacheHits:    {:>10}", cache.hits)?;
+    writeln!(out, "CacheMisses:  {:>10}", cache.misses)
+}
+
+fn interrupts(out: &mut String) -> core::fmt::Result {
+    writeln!(out, "VEC      COUNT  NAME")?;
+    for (vector, count) in crate::interrupts::interrupt_counts() {
+        let name = crate::interrupts::vector_name(vector);
+        writeln!(out, "{:>3} {:>10}  {}", vector, count, name)?;
+    }
+    Ok(())
+}
+
+fn status(out: &mut String, task: &TaskInfo) -> core::fmt::Result {
+    let state = match task.status {
+        _ if task.running => "running",
+        TaskStatus::Ready => "ready",
+        TaskStatus::Running => "running",
+        TaskStatus::Waiting => "waiting",
+        TaskStatus::Stopped => "stopped",
+        TaskStatus::Killed => "killed",
+        TaskStatus::Exited => "exited",
+    };
+    let name = if task.name.is_empty() {
+        "?"
+    } else {
+        task.name.as_str()
+    };
+
+    writeln!(out, "Name:    {}", name)?;
+    writeln!(out, "State:   {}", state)?;
+    writeln!(out, "Id:      {}", task.id)?;
+    writeln!(out, "Process: {}", task.process_id)?;
+    writeln!(out, "Parent:  {}", task.parent_id)?;
+    writeln!(out, "Group:   {}", task.pgid)?;
+    writeln!(out, "Threads: {}", thread_count(task.process_id))?;
+    if let Some(id) = task.waiting_on {
+        writeln!(out, "WaitTask:    {}", id)?;
+    }
+    if let Some(channel) = task.wait_channel {
+        writeln!(out, "WaitChannel: {}", channel)?;
+    }
+    if let Some(futex) = task.wait_futex {
+        writeln!(out, "WaitFutex:   {:#x}", futex)?;
+    }
+    if task.wake_at > crate::timer::get_uptime_ms() {
+        writeln!(out, "WakeAt:  {} ms", task.wake_at)?;
+    }
+    writeln!(out, "Stack:   {} kB", task.stack.1 / 1024)?;
+    if let Some((_, len)) = task.image {
+        writeln!(out, "Image:   {} kB", len.div_ceil(1024))?;
+    }
+    Ok(())
+}
+
+fn thread_count(process_id: u64) -> usize {
+    tasks()
+        .iter()
+        .filter(|task| task.process_id == process_id)
+        .count()
+}
+
+// One line per region: start-end, permissions, what it is
+fn maps(out: &mut String, task: &TaskInfo) -> core::fmt::Result {
+    let mut region = |start: u64, len: usize, perms: &str, what: &str| {
+        writeln!(
+            out,
+            "{:016x}-{:016x} {} {}",
+            start,
+            start + len as u64,
+            perms,
+            what
+        )
+    };
+    if let Some((start, len)) = task.image {
+        region(start, len, "rwx", task.name.as_str())?;
+    }
+    if let Some((start, len)) = task.arg {
+        region(start, len, "r--", "[arg]")?;
+    }
+    if task.stack.1 > 0 {
+        region(task.stack.0, task.stack.1, "rw-", "[stack]")?;
+    }
+    if task.fs_base != 0 {
+        region(task.fs_base, 0, "rw-", "[tls]")?;
+    }
+    Ok(())
+}
+
+/*********
+ * NODES *
+ *********/
+// The root (task None) or a task's directory
+struct ProcDir {
+    task: Option<u64>,
+}
+
+impl ProcDir {
+    fn files(&self) -> Vec<(&'static str, Source)> {
+        match self.task {
+            None => ROOT_FILES.to_vec(),
+            Some(id) => alloc::vec![("status", Source::Status(id)), ("maps", Source::Maps(id))],
+        }
+    }
+}
+
+impl Vnode for ProcDir {
+    fn stat(&self) -> Result<Stat, &'static str> {
+        Ok(Stat {
+            kind: NodeKind::Directory,
+            size: 0,
+        })
+    }
+
+    fn lookup(&self, name: &str) -> Result<NodeRef, &'static str> {
+        if let Some(&(_, source)) = self.files().iter().find(|(file, _)| *file == name) {
+            return Ok(Arc::new(ProcFile { source }));
+        }
+        if self.task.is_some() {
+            return Err("no such file");
+        }
+        let id = match name {
+            "self" => current_task_id().ok_or("no current task")?,
+            _ => name.parse().map_err(|_| "no such file")?,
+        };
+        task(id)?;
+        Ok(Arc::new(ProcDir { task: Some(id) }))
+    }
+
+    fn readdir(&self) -> Result<Vec<DirEntry>, &'static str> {
+        let mut entries: Vec<DirEntry> = self
+            .files()
+            .into_iter()
+            .map(|(name, source)| DirEntry {
+                name: String::from(name),
+                kind: NodeKind::File,
+                size: source.render().map(|text| text.len() as u64).unwrap_or(0),
+            })
+            .collect();
+        if self.task.is_none() {
+            let dirs = tasks().into_iter().map(|task| format!("{}", task.id));
+            for name in core::iter::once(String::from("self")).chain(dirs) {
+                entries.push(DirEntry {
+                    name,
+                    kind: NodeKind::Directory,
+                    size: 0,
+                });
+            }
+        }
+        Ok(entries)
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+struct ProcFile {
+    source: Source,
+}
+
+impl Vnode for ProcFile {
+    fn stat(&self) -> Result<Stat, &'static str> {
+        Ok(Stat {
+            kind: NodeKind::File,
+            size: self.source.render()?.len() as u64,
+        })
+    }
+
+    fn read(&self, offset: u64, buf: &mut [u8]) -> Result<usize, &'static str> {
+        let text = self.source.render()?;
+        let start = (offset as usize).min(text.len());
+        let n = buf.len().min(text.len() - start);
+        buf[..n].copy_from_slice(&text.as_bytes()[start..start + n]);
+        Ok(n)
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
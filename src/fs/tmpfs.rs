@@ -0,0 +1,291 @@
+use crate::fs::vfs::{DirEntry, Filesystem, NodeKind, NodeRef, Stat, Vnode};
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::any::Any;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+
+// A filesystem that only lives in memory, for scratch files that shouldn't touch the disk.
+// Everything is gone after a reboot. File contents count against a size cap, directories
+// and names are free.
+
+// How many bytes of file data the filesystem may hold, shared by all its nodes
+struct Usage {
+    used: AtomicU64,
+    cap: u64,
+}
+
+impl Usage {
+    fn reserve(&self, bytes: u64) -> Result<(), &'static str> {
+        self.used
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |used| {
+                used.checked_add(bytes).filter(|&total| total <= self.cap)
+            })
+            .map(|_| ())
+            .map_err(|_| "no space left on tmpfs")
+    }
+
+    fn release(&self, bytes: u64) {
+        self.used.fetch_sub(bytes, Ordering::SeqCst);
+    }
+}
+
+pub struct TmpFs {
+    usage: Arc<Usage>,
+    root: Arc<TmpNode>,
+}
+
+impl TmpFs {
+    pub fn new(cap: u64) -> Arc<Self> {
+        let usage = Arc::new(Usage {
+            used: AtomicU64::new(0),
+            cap,
+        });
+        Arc::new(Self {
+            root: TmpNode::new(&usage, NodeKind::Directory),
+            usage,
+        })
+    }
+}
+
+impl Filesystem for TmpFs {
+    fn name(&self) -> &'static str {
+        "tmpfs"
+    }
+
+    fn root(self: Arc<Self>) -> NodeRef {
+        self.root.clone()
+    }
+}
+
+enum Content {
+    File(Vec<u8>),
+    Directory(BTreeMap<String, Arc<TmpNode>>),
+}
+
+pub struct TmpNode {
+    usage: Arc<Usage>,
+    content: Mutex<Content>,
+}
+
+impl TmpNode {
+    fn new(usage: &Arc<Usage>, kind: NodeKind) -> Arc<Self> {
+        let content = match kind {
+            NodeKind::Directory => Content::Directory(BTreeMap::new()),
+            _ => Content::File(Vec::new()),
+        };
+        Arc::new(Self {
+            usage: usage.clone(),
+            content: Mutex::new(content),
+        })
+    }
+
+    fn kind(&self) -> NodeKind {
+        match *self.content.lock() {
+            Content::File(_) => NodeKind::File,
+            Content::Directory(_) => NodeKind::Directory,
+        }
+    }
+
+    fn child(&self, name: &str) -> Result<Arc<TmpNode>, &'static str> {
+        match &*self.content.lock() {
+            Content::Directory(children) => children
+                .get(name)
+                .cloned()
+                .ok_or("no such file or directory"),
+            Content::File(_) => Err("not a directory"),
+        }
+    }
+
+    fn is_empty_dir(&self) -> bool {
+        matches!(&*self.content.lock(), Content::Directory(children) if children.is_empty())
+    }
+
+    // Whether `node` is this node or somewhere below it, a directory can't move there
+    fn contains(&self, node: &TmpNode) -> bool {
+        if core::ptr::eq(self, node) {
+            return true;
+        }
+        match &*self.content.lock() {
+            Content::Directory(children) => children.values().any(|child| child.contains(node)),
+            Content::File(_) => false,
+        }
+    }
+
+    // Grows or shrinks the file to `size`, keeping the size cap
+    fn resize(data: &mut Vec<u8>, usage: &Usage, size: usize) -> Result<(), &'static str> {
+        if size > data.len() {
+            usage.reserve((size - data.len()) as u64)?;
+        } else {
+            usage.release((data.len() - size) as u64);
+        }
+        data.resize(size, 0);
+        Ok(())
+    }
+}
+
+// An unlinked file keeps its data until the last handle to it is closed
+impl Drop for TmpNode {
+    fn drop(&mut self) {
+        if let Content::File(data) = self.content.get_mut() {
+            self.usage.release(data.len() as u64);
+        }
+    }
+}
+
+impl Vnode for TmpNode {
+    fn stat(&self) -> Result<Stat, &'static str> {
+        Ok(match &*self.content.lock() {
+            Content::File(data) => Stat {
+                kind: NodeKind::File,
+                size: data.len() as u64,
+            },
+            Content::Directory(_) => Stat {
+                kind: NodeKind::Directory,
+                size: 0,
+            },
+        })
+    }
+
+    fn lookup(&self, name: &str) -> Result<NodeRef, &'static str> {
+        Ok(self.child(name)?)
+    }
+
+    fn read(&self, offset: u64, buf: &mut [u8]) -> Result<usize, &'static str> {
+        let content = self.content.lock();
+        let Content::File(data) = &*content else {
+            return Err("not a file");
+        };
+        let start = (offset as usize).min(data.len());
+        let n = buf.len().min(data.len() - start);
+        buf[..n].copy_from_slice(&data[start..start + n]);
+        Ok(n)
+    }
+
+    fn write(&self, offset: u64, buf: &[u8]) -> Result<usize, &'static str> {
+        let mut content = self.content.lock();
+        let Content::File(data) = &mut *content else {
+            return Err("not a file");
+        };
+        let start = offset as usize;
+        let end = start.checked_add(buf.len()).ok_or("file too large")?;
+        if end > data.len() {
+            Self::resize(data, &self.usage, end)?;
+        }
+        data[start..end].copy_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn truncate(&self, size: u64) -> Result<(), &'static str> {
+        let mut content = self.content.lock();
+        let Content::File(data) = &mut *content else {
+            return Err("not a file");
+        };
+        Self::resize(data, &self.usage, size as usize)?;
+        if data.capacity() > data.len() * 2 {
+            data.shrink_to_fit();
+        }
+        Ok(())
+    }
+
+    fn readdir(&self) -> Result<Vec<DirEntry>, &'static str> {
+        let content = self.content.lock();
+        let Content::Directory(children) = &*content else {
+            return Err("not a directory");
+        };
+        Ok(children
+            .iter()
+            .map(|(name, child)| {
+                let stat = child.stat().unwrap_or(Stat {
+                    kind: NodeKind::File,
+                    size: 0,
+                });
+                DirEntry {
+                    name: name.clone(),
+                    kind: stat.kind,
+                    size: stat.size,
+                }
+            })
+            .collect())
+    }
+
+    fn create(&self, name: &str, kind: NodeKind) -> Result<NodeRef, &'static str> {
+        let mut content = self.content.lock();
+        let Content::Directory(children) = &mut *content else {
+            return Err("not a directory");
+        };
+        if children.contains_key(name) {
+            return Err("already exists");
+        }
+        if !matches!(kind, NodeKind::File | NodeKind::Directory) {
+            return Err("tmpfs can't hold device nodes");
+        }
+        let node = TmpNode::new(&self.usage, kind);
+        children.insert(String::from(name), node.clone());
+        Ok(node)
+    }
+
+    fn unlink(&self, name: &str) -> Result<(), &'static str> {
+        let mut content = self.content.lock();
+        let Content::Directory(children) = &mut *content else {
+            return Err("not a directory");
+        };
+        let child = children.get(name).ok_or("no such file or directory")?;
+        if child.kind() == NodeKind::Directory && !child.is_empty_dir() {
+            return Err("directory not empty");
+        }
+        children.remove(name);
+        Ok(())
+    }
+
+    fn rename(
+        &self,
+        old_name: &str,
+        new_dir: &NodeRef,
+        new_name: &str,
+    ) -> Result<(), &'static str> {
+        let new_dir = new_dir
+            .as_any()
+            .downcast_ref::<TmpNode>()
+            .ok_or("can't rename across filesystems")?;
+
+        let node = self.child(old_name)?;
+        if node.contains(new_dir) {
+            return Err("can't move a directory inside itself");
+        }
+        if let Ok(existing) = new_dir.child(new_name) {
+            if Arc::ptr_eq(&existing, &node) {
+                return Ok(());
+            }
+            if existing.kind() != node.kind() {
+                return Err("a different kind of entry is in the way");
+            }
+            if !existing.is_empty_dir() && existing.kind() == NodeKind::Directory {
+                return Err("directory not empty");
+            }
+        }
+
+        // Whatever had the new name is dropped here, which frees its data
+        match &mut *new_dir.content.lock() {
+            Content::Directory(children) => {
+                children.insert(String::from(new_name), node.clone());
+            }
+            Content::File(_) => return Err("not a directory"),
+        }
+        if let Content::Directory(children) = &mut *self.content.lock() {
+            if children
+                .get(old_name)
+                .is_some_and(|n| Arc::ptr_eq(n, &node))
+            {
+                children.remove(old_name);
+            }
+        }
+        Ok(())
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
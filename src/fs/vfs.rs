@@ -0,0 +1,362 @@
+/**********************************************************************************************
+ *                                       DOCUMENTATION                                        *
+ *   THE VIRTUAL FILESYSTEM. EVERY FILESYSTEM EXPOSES ITS FILES AND DIRECTORIES AS VNODES,    *
+ *              AND THE MOUNT TABLE MAPS PATH PREFIXES TO FILESYSTEM INSTANCES.               *
+ * A PATH IS RESOLVED BY FINDING THE LONGEST MOUNT PREFIX, THEN WALKING THE REST WITH LOOKUP. *
+ *               SYSCALLS AND THE PROGRAM LOADER ONLY EVER TALK TO THIS LAYER.                *
+ *********************************************************************************************/
+
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::any::Any;
+use spin::Mutex;
+
+pub type NodeRef = Arc<dyn Vnode>;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NodeKind {
+    File,
+    Directory,
+    CharDevice,  // A stream, offsets mean nothing to it
+    BlockDevice, // Addressed by byte offset like a file, but backed by a disk
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Stat {
+    pub kind: NodeKind,
+    pub size: u64,
+}
+
+#[derive(Clone, Debug)]
+pub struct DirEntry {
+    pub name: String,
+    pub kind: NodeKind,
+    pub size: u64,
+}
+
+/**********
+ * VNODES *
+ **********/
+// A file or directory of some filesystem. Directory operations take a single name
+// component, the VFS does the path walking. Anything a node doesn't support keeps the
+// default, which just fails.
+pub trait Vnode: Send + Sync {
+    fn stat(&self) -> Result<Stat, &'static str>;
+
+    fn lookup(&self, _name: &str) -> Result<NodeRef, &'static str> {
+        Err("not a directory")
+    }
+
+    // Reads at `offset`, returns how much was read (0 at the end of the file)
+    fn read(&self, _offset: u64, _buf: &mut [u8]) -> Result<usize, &'static str> {
+        Err("not a file")
+    }
+
+    // Writes at `offset`, growing the file if needed
+    fn write(&self, _offset: u64, _buf: &[u8]) -> Result<usize, &'static str> {
+        Err("not writable")
+    }
+
+    fn truncate(&self, _size: u64) -> Result<(), &'static str> {
+        Err("not writable")
+    }
+
+    fn readdir(&self) -> Result<Vec<DirEntry>, &'static str> {
+        Err("not a directory")
+    }
+
+    fn create(&self, _name: &str, _kind: NodeKind) -> Result<NodeRef, &'static str> {
+        Err("read-only filesystem")
+    }
+
+    fn unlink(&self, _name: &str) -> Result<(), &'static str> {
+        Err("read-only filesystem")
+    }
+
+    // Moves `old_name` out of this directory into `new_dir` as `new_name`. Both
+    // directories are always on the same mount.
+    fn rename(
+        &self,
+        _old_name: &str,
+        _new_dir: &NodeRef,
+        _new_name: &str,
+    ) -> Result<(), &'static str> {
+        Err("read-only filesystem")
+    }
+
+    // Device specific requests, `arg` is usually a pointer to a request struct
+    fn ioctl(&self, _request: u64, _arg: u64) -> Result<u64, &'static str> {
+        Err("not a device")
+    }
+
+    // Address where `len` bytes from `offset` can be accessed directly
+    fn mmap(&self, _offset: u64, _len: u64) -> Result<u64, &'static str> {
+        Err("can't be mapped")
+    }
+
+    // Lets a filesystem get its own node type back from a NodeRef (see rename)
+    fn as_any(&self) -> &dyn Any;
+}
+
+// One instance of a filesystem, what gets mounted
+pub trait Filesystem: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    fn root(self: Arc<Self>) -> NodeRef;
+
+    // Pushes anything cached out to the backing store
+    fn sync(&self) -> Result<(), &'static str> {
+        Ok(())
+    }
+}
+
+/***************
+ * MOUNT TABLE *
+ ***************/
+struct Mount {
+    path: String, // Normalised, "/" or without a trailing slash
+    fs: Arc<dyn Filesystem>,
+}
+
+static MOUNTS: Mutex<Vec<Mount>> = Mutex::new(Vec::new());
+
+pub fn mount(path: &str, fs: Arc<dyn Filesystem>) -> Result<(), &'static str> {
+    let path = normalize(path);
+    let mut mounts = MOUNTS.lock();
+    if mounts.iter().any(|m| m.path == path) {
+        return Err("something is already mounted there");
+    }
+    crate::println!("VFS: mounted {} at {}", fs.name(), path);
+    mounts.push(Mount { path, fs });
+    Ok(())
+}
+
+// Syncs and removes the mount at `path`, returning the filesystem
+pub fn unmount(path: &str) -> Result<Arc<dyn Filesystem>, &'static str> {
+    let path = normalize(path);
+    let fs = {
+        let mut mounts = MOUNTS.lock();
+        let index = mounts
+            .iter()
+            .position(|m| m.path == path)
+            .ok_or("nothing mounted there")?;
+        mounts.remove(index).fs
+    };
+    fs.sync()?;
+    Ok(fs)
+}
+
+// (mount point, filesystem name) of every mount
+pub fn mounts() -> Vec<(String, &'static str)> {
+    MOUNTS
+        .lock()
+        .iter()
+        .map(|m| (m.path.clone(), m.fs.name()))
+        .collect()
+}
+
+pub fn sync_all() -> Result<(), &'static str> {
+    let filesystems: Vec<Arc<dyn Filesystem>> =
+        MOUNTS.lock().iter().map(|m| m.fs.clone()).collect();
+    filesystems.iter().try_for_each(|fs| fs.sync())
+}
+
+// The mount with the longest prefix of `path` and the rest of the path below it
+fn find_mount(path: &str) -> Result<(Arc<dyn Filesystem>, String), &'static str> {
+    let mounts = MOUNTS.lock();
+    let mount = mounts
+        .iter()
+        .filter(|m| is_below(path, &m.path))
+        .max_by_key(|m| m.path.len())
+        .ok_or("nothing mounted at /")?;
+    Ok((mount.fs.clone(), String::from(&path[mount.path.len()..])))
+}
+
+fn is_below(path: &str, prefix: &str) -> bool {
+    prefix == "/"
+        || path == prefix
+        || (path.starts_with(prefix) && path.as_bytes().get(prefix.len()) == Some(&b'/'))
+}
+
+/*******************
+ * PATH RESOLUTION *
+ *******************/
+// Absolute, '/' separated, with ".", ".." and repeated slashes resolved
+pub fn normalize(path: &str) -> String {
+    let mut parts: Vec<&str> = Vec::new();
+    for part in path.split(['/', '\\']) {
+        match part {
+            "" | "." => {}
+            ".." => {
+                parts.pop();
+            }
+            part => parts.push(part),
+        }
+    }
+    let mut out = String::new();
+    for part in parts {
+        out.push('/');
+        out.push_str(part);
+    }
+    if out.is_empty() {
+        out.push('/');
+    }
+    out
+}
+
+pub fn resolve(path: &str) -> Result<NodeRef, &'static str> {
+    let (fs, rest) = find_mount(&normalize(path))?;
+    let mut node = fs.root();
+    for name in rest.split('/').filter(|name| !name.is_empty()) {
+        node = node.lookup(name)?;
+    }
+    Ok(node)
+}
+
+// The directory holding `path` and the last component of it
+fn resolve_parent(path: &str) -> Result<(NodeRef, String), &'static str> {
+    let path = normalize(path);
+    let (parent, name) = path.rsplit_once('/').ok_or("bad path")?;
+    if name.is_empty() {
+        return Err("the root has no parent");
+    }
+    let parent = if parent.is_empty() { "/" } else { parent };
+    Ok((resolve(parent)?, String::from(name)))
+}
+
+/*************
+ * SHORTCUTS *
+ *************/
+// Whole-path operations on top of the vnodes, what the syscalls use
+
+pub fn read_file(path: &str) -> Result<Vec<u8>, &'static str> {
+    let node = resolve(path)?;
+    let size = node.stat()?.size as usize;
+    let mut data = alloc::vec![0u8; size];
+    let mut total = 0;
+    while total < size {
+        match node.read(total as u64, &mut data[total..])? {
+            0 => break,
+            n => total += n,
+        }
+    }
+    data.truncate(total);
+    Ok(data)
+}
+
+// Replaces the contents of `path`, creating the file if it doesn't exist
+pub fn write_file(path: &str, data: &[u8]) -> Result<usize, &'static str> {
+    let node = match resolve(path) {
+        Ok(node) => node,
+        Err(_) => {
+            let (parent, name) = resolve_parent(path)?;
+            parent.create(&name, NodeKind::File)?
+        }
+    };
+    let written = node.write(0, data)?;
+    // Devices have no length to cut back to
+    if node.stat()?.kind == NodeKind::File {
+        node.truncate(written as u64)?;
+    }
+    Ok(written)
+}
+
+// Succeeds if the directory already exists
+pub fn create_dir(path: &str) -> Result<(), &'static str> {
+    if let Ok(node) = resolve(path) {
+        return match node.stat()?.kind {
+            NodeKind::Directory => Ok(()),
+            _ => Err("a file is in the way"),
+        };
+    }
+    let (parent, name) = resolve_parent(path)?;
+    parent.create(&name, NodeKind::Directory).map(|_| ())
+}
+
+pub fn remove(path: &str) -> Result<(), &'static str> {
+    let path = normalize(path);
+    if MOUNTS.lock().iter().any(|m| m.path == path) {
+        return Err("can't remove a mount point");
+    }
+    let (parent, name) = resolve_parent(&path)?;
+    parent.unlink(&name)
+}
+
+pub fn rename(from: &str, to: &str) -> Result<(), &'static str> {
+    let (from, to) = (normalize(from), normalize(to));
+    let (from_fs, _) = find_mount(&from)?;
+    let (to_fs, _) = find_mount(&to)?;
+    if !Arc::ptr_eq(&from_fs, &to_fs) {
+        return Err("can't rename across mounts");
+    }
+    let (from_dir, old_name) = resolve_parent(&from)?;
+    let (to_dir, new_name) = resolve_parent(&to)?;
+    from_dir.rename(&old_name, &to_dir, &new_name)
+}
+
+// The directory's own entries plus any mount points directly inside it
+pub fn read_dir(path: &str) -> Result<Vec<DirEntry>, &'static str> {
+    let path = normalize(path);
+    let mut entries = resolve(&path)?.readdir()?;
+    for mount in MOUNTS.lock().iter() {
+        let Some((parent, name)) = mount.path.rsplit_once('/') else {
+            continue;
+        };
+        let parent = if parent.is_empty() { "/" } else { parent };
+        if name.is_empty() || parent != path || entries.iter().any(|e| e.name == name) {
+            continue;
+        }
+        entries.push(DirEntry {
+            name: String::from(name),
+            kind: NodeKind::Directory,
+            size: 0,
+        });
+    }
+    Ok(entries)
+}
+
+/**************
+ * OPEN FILES *
+ **************/
+// What a file handle refers to: the node and where the next read starts
+pub struct OpenFile {
+    pub node: NodeRef,
+    pub offset: u64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalize_makes_paths_absolute() {
+        assert_eq!(normalize(""), "/");
+        assert_eq!(normalize("/"), "/");
+        assert_eq!(normalize("bin/sh"), "/bin/sh");
+        assert_eq!(normalize("/bin/sh/"), "/bin/sh");
+    }
+
+    #[test]
+    fn normalize_resolves_dots_and_slashes() {
+        assert_eq!(normalize("//usr///lib//"), "/usr/lib");
+        assert_eq!(normalize("/usr/./lib/."), "/usr/lib");
+        assert_eq!(normalize("/usr/lib/../bin"), "/usr/bin");
+        assert_eq!(normalize("\\usr\\lib"), "/usr/lib");
+    }
+
+    #[test]
+    fn normalize_stops_at_the_root() {
+        assert_eq!(normalize("/.."), "/");
+        assert_eq!(normalize("/../../tmp"), "/tmp");
+        assert_eq!(normalize("/a/b/../../.."), "/");
+    }
+
+    #[test]
+    fn mounts_cover_whole_components() {
+        assert!(is_below("/mnt/disk/a", "/mnt/disk"));
+        assert!(is_below("/mnt/disk", "/mnt/disk"));
+        assert!(!is_below("/mnt/disk2", "/mnt/disk"));
+        assert!(is_below("/anything", "/"));
+    }
+}
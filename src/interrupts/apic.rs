@@ -0,0 +1,282 @@
+use crate::acpi::InterruptOverride;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use spin::Mutex;
+
+// The local APIC and IOAPIC, used instead of the 8259 PICs when the MADT lists them.
+// ISA IRQs keep the vectors the PICs were remapped to (IRQ n on vector 32 + n), so the
+// handlers don't care which controller delivered them. The scheduler tick comes from the
+// local APIC timer, calibrated against the HPET or PIT, on vector 32 where IRQ0 used to be.
+
+const LAPIC_ID: u64 = 0x20;
+const LAPIC_EOI: u64 = 0xB0;
+const LAPIC_SPURIOUS: u64 = 0xF0;
+const LAPIC_ICR_LOW: u64 = 0x300;
+const LAPIC_ICR_HIGH: u64 = 0x310;
+const LAPIC_LVT_TIMER: u64 = 0x320;
+const LAPIC_TIMER_INITIAL: u64 = 0x380;
+const LAPIC_TIMER_CURRENT: u64 = 0x390;
+const LAPIC_TIMER_DIVIDE: u64 = 0x3E0;
+
+const APIC_SOFTWARE_ENABLE: u32 = 0x100;
+const LVT_MASKED: u32 = 1 << 16;
+const TIMER_PERIODIC: u32 = 1 << 17;
+const TIMER_DIVIDE_BY_16: u32 = 0x3;
+
+const ICR_DELIVERY_PENDING: u32 = 1 << 12;
+const ICR_LEVEL_ASSERT: u32 = 1 << 14;
+const ICR_ALL_EXCLUDING_SELF: u32 = 0b11 << 18;
+
+const IOAPIC_REGSEL: u64 = 0x00;
+const IOAPIC_WINDOW: u64 = 0x10;
+const IOAPIC_VERSION: u32 = 0x01;
+const IOAPIC_REDIRECTION: u32 = 0x10;
+
+const REDIRECT_ACTIVE_LOW: u64 = 1 << 13;
+const REDIRECT_LEVEL: u64 = 1 << 15;
+const REDIRECT_MASKED: u64 = 1 << 16;
+
+// MPS INTI flags of an override, 0b11 means low/level, 0b00 is the ISA default (high/edge)
+const INTI_POLARITY_LOW: u16 = 0b11;
+const INTI_TRIGGER_LEVEL: u16 = 0b11 << 2;
+
+pub const SPURIOUS_VECTOR: u8 = 0xFF;
+pub const TIMER_VECTOR: u8 = 32;
+pub const RESCHEDULE_VECTOR: u8 = 48;
+pub const TLB_SHOOTDOWN_VECTOR: u8 = 49;
+pub const AHCI_VECTOR: u8 = 50; // MSI
+pub const VIRTIO_BLK_VECTOR: u8 = 51; // MSI-X
+pub const HALT_VECTOR: u8 = 52;
+const IRQ_BASE_VECTOR: u8 = 32;
+
+// The ISA IRQs with handlers: keyboard, COM1, mouse and both ATA channels (init_pic unmasks
+// the same)
+const ROUTED_IRQS: [u8; 5] = [1, 4, 12, 14, 15];
+
+// Virtual address of the local APIC registers, 0 while the PICs are in charge
+static LAPIC_BASE: AtomicU64 = AtomicU64::new(0);
+// The timer's initial count for the tick rate and its speed, measured once on the BSP and
+// reused by the APs
+static TIMER_INITIAL_COUNT: AtomicU32 = AtomicU32::new(0);
+static TIMER_TICKS_PER_MS: AtomicU64 = AtomicU64::new(0);
+static IO_APICS: Mutex<Vec<MappedIoApic>> = Mutex::new(Vec::new());
+static OVERRIDES: Mutex<Vec<InterruptOverride>> = Mutex::new(Vec::new());
+
+struct MappedIoApic {
+    base: u64,
+    gsi_base: u32,
+    pins: u32,
+}
+
+impl MappedIoApic {
+    fn read(&self, reg: u32) -> u32 {
+        unsafe {
+            ((self.base + IOAPIC_REGSEL) as *mut u32).write_volatile(reg);
+            ((self.base + IOAPIC_WINDOW) as *const u32).read_volatile()
+        }
+    }
+
+    fn write(&self, reg: u32, value: u32) {
+        unsafe {
+            ((self.base + IOAPIC_REGSEL) as *mut u32).write_volatile(reg);
+            ((self.base + IOAPIC_WINDOW) as *mut u32).write_volatile(value);
+        }
+    }
+
+    // High half first, so an unmasked entry never points at the wrong CPU
+    fn set_redirection(&self, pin: u32, entry: u64) {
+        self.write(IOAPIC_REDIRECTION + pin * 2 + 1, (entry >> 32) as u32);
+        self.write(IOAPIC_REDIRECTION + pin * 2, entry as u32);
+    }
+}
+
+fn lapic_read(reg: u64) -> u32 {
+    unsafe { ((LAPIC_BASE.load(Ordering::Relaxed) + reg) as *const u32).read_volatile() }
+}
+
+fn lapic_write(reg: u64, value: u32) {
+    unsafe { ((LAPIC_BASE.load(Ordering::Relaxed) + reg) as *mut u32).write_volatile(value) }
+}
+
+pub fn apic_enabled() -> bool {
+    LAPIC_BASE.load(Ordering::Relaxed) != 0
+}
+
+pub fn lapic_id() -> u8 {
+    (lapic_read(LAPIC_ID) >> 24) as u8
+}
+
+// Switches interrupt delivery to the APICs. Returns false (and leaves the PICs alone) if
+// the MADT has no local APIC or IOAPIC.
+pub fn init_apic() -> bool {
+    let Some(madt) = crate::acpi::madt() else {
+        return false;
+    };
+    if madt.local_apic_address == 0 || madt.io_apics.is_empty() {
+        return false;
+    }
+
+    let io_apics: Vec<MappedIoApic> = madt
+        .io_apics
+        .iter()
+        .map(|io_apic| {
+            let mut mapped = MappedIoApic {
+                base: crate::memory::map_mmio(io_apic.address, 0x20),
+                gsi_base: io_apic.gsi_base,
+                pins: 0,
+            };
+            mapped.pins = ((mapped.read(IOAPIC_VERSION) >> 16) & 0xFF) + 1;
+            for pin in 0..mapped.pins {
+                mapped.set_redirection(pin, REDIRECT_MASKED);
+            }
+            mapped
+        })
+        .collect();
+
+    super::idt::disable_pic();
+    LAPIC_BASE.store(
+        crate::memory::map_mmio(madt.local_apic_address, 0x1000),
+        Ordering::Relaxed,
+    );
+    lapic_write(
+        LAPIC_SPURIOUS,
+        APIC_SOFTWARE_ENABLE | SPURIOUS_VECTOR as u32,
+    );
+    lapic_write(LAPIC_LVT_TIMER, LVT_MASKED);
+
+    *IO_APICS.lock() = io_apics;
+    *OVERRIDES.lock() = madt.overrides;
+    for irq in ROUTED_IRQS {
+        route_irq(irq);
+    }
+
+    crate::println!(
+        "APIC: local APIC {} at {:#x}, {} IOAPIC(s), {} CPU(s)",
+        lapic_id(),
+        madt.local_apic_address,
+        madt.io_apics.len(),
+        madt.local_apics.iter().filter(|cpu| cpu.enabled).count()
+    );
+    true
+}
+
+// Sends ISA IRQ `irq` to this CPU on vector 32 + irq, following the MADT's overrides
+pub fn route_irq(irq: u8) {
+    let (gsi, flags) = OVERRIDES
+        .lock()
+        .iter()
+        .find(|o| o.irq == irq)
+        .map(|o| (o.gsi, o.flags))
+        .unwrap_or((irq as u32, 0));
+
+    let mut entry = (IRQ_BASE_VECTOR + irq) as u64 | (lapic_id() as u64) << 56;
+    if flags & INTI_POLARITY_LOW == INTI_POLARITY_LOW {
+        entry |= REDIRECT_ACTIVE_LOW;
+    }
+    if flags & INTI_TRIGGER_LEVEL == INTI_TRIGGER_LEVEL {
+        entry |= REDIRECT_LEVEL;
+    }
+
+    let io_apics = IO_APICS.lock();
+    match io_apics
+        .iter()
+        .find(|io_apic| (io_apic.gsi_base..io_apic.gsi_base + io_apic.pins).contains(&gsi))
+    {
+        Some(io_apic) => io_apic.set_redirection(gsi - io_apic.gsi_base, entry),
+        None => crate::println!("APIC: no IOAPIC handles GSI {} (IRQ {})", gsi, irq),
+    }
+}
+
+pub fn send_eoi() {
+    lapic_write(LAPIC_EOI, 0);
+}
+
+// Starts the periodic local APIC timer at `hz`. If it can't be measured, the PIT gets
+// IRQ0 routed back and the caller should fall back to it.
+pub fn start_apic_timer(hz: u64) -> bool {
+    if !apic_enabled() {
+        return false;
+    }
+    let ticks_per_ms = calibrate() / crate::timer::CALIBRATION_MS;
+    if ticks_per_ms == 0 {
+        crate::println!("APIC: timer calibration failed, using the PIT");
+        route_irq(0);
+        return false;
+    }
+
+    TIMER_TICKS_PER_MS.store(ticks_per_ms, Ordering::Relaxed);
+    TIMER_INITIAL_COUNT.store((ticks_per_ms * 1000 / hz).max(1) as u32, Ordering::Relaxed);
+    start_periodic_timer();
+    true
+}
+
+// Whether the APs can run their own timers at the BSP's rate
+pub fn apic_timer_calibrated() -> bool {
+    TIMER_INITIAL_COUNT.load(Ordering::Relaxed) != 0
+}
+
+fn start_periodic_timer() {
+    lapic_write(LAPIC_TIMER_DIVIDE, TIMER_DIVIDE_BY_16);
+    lapic_write(LAPIC_LVT_TIMER, TIMER_PERIODIC | TIMER_VECTOR as u32);
+    lapic_write(
+        LAPIC_TIMER_INITIAL,
+        TIMER_INITIAL_COUNT.load(Ordering::Relaxed),
+    );
+}
+
+// Fires the timer once, `ns` from now. Replaces whatever it was set to, periodic or not.
+pub fn arm_apic_timer(ns: u64) {
+    let ticks = ns as u128 * TIMER_TICKS_PER_MS.load(Ordering::Relaxed) as u128 / 1_000_000;
+    lapic_write(LAPIC_TIMER_DIVIDE, TIMER_DIVIDE_BY_16);
+    lapic_write(LAPIC_LVT_TIMER, TIMER_VECTOR as u32);
+    lapic_write(LAPIC_TIMER_INITIAL, ticks.clamp(1, u32::MAX as u128) as u32);
+}
+
+// An AP's own local APIC: the registers sit at the same address on every CPU, only
+// enabling it and starting its timer is left to do
+pub fn init_ap_apic() {
+    lapic_write(
+        LAPIC_SPURIOUS,
+        APIC_SOFTWARE_ENABLE | SPURIOUS_VECTOR as u32,
+    );
+    if crate::timer::is_tickless() {
+        arm_apic_timer(crate::timer::TIMESLICE_NS);
+    } else {
+        start_periodic_timer();
+    }
+}
+
+/********
+ * IPIS *
+ ********/
+fn send_icr(destination: u32, command: u32) {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        lapic_write(LAPIC_ICR_HIGH, destination << 24);
+        lapic_write(LAPIC_ICR_LOW, command | ICR_LEVEL_ASSERT);
+        while lapic_read(LAPIC_ICR_LOW) & ICR_DELIVERY_PENDING != 0 {
+            core::hint::spin_loop();
+        }
+    });
+}
+
+pub fn send_ipi(apic_id: u32, vector: u8) {
+    if apic_enabled() {
+        send_icr(apic_id, vector as u32);
+    }
+}
+
+// To every CPU but this one
+pub fn broadcast_ipi(vector: u8) {
+    if apic_enabled() {
+        send_icr(0, ICR_ALL_EXCLUDING_SELF | vector as u32);
+    }
+}
+
+// Local APIC timer ticks (divided by 16) in timer::CALIBRATION_MS
+fn calibrate() -> u64 {
+    lapic_write(LAPIC_TIMER_DIVIDE, TIMER_DIVIDE_BY_16);
+    lapic_write(LAPIC_TIMER_INITIAL, u32::MAX);
+    let elapsed = crate::timer::measure(|| (u32::MAX - lapic_read(LAPIC_TIMER_CURRENT)) as u64);
+    lapic_write(LAPIC_TIMER_INITIAL, 0);
+    elapsed
+}
@@ -5,11 +5,14 @@
  * EACH STUB IS RESPONSIBLE FOR SAVING THE CPU STATE, CALLING THE APPROPRIATE RUST HANDLER FUNCTION, AND THEN RESTORING THE CPU STATE BEFORE RETURNING CONTROL TO THE INTERRUPTED CODE. *
  ****************************************************************************************************************************************************************************************/
 
+use crate::smp::percpu::PerCpu;
 use core::arch::global_asm;
+use core::mem::offset_of;
 
 unsafe extern "C" {
-    pub(super) static isr_stub_table: [extern "C" fn(); 48];
+    pub(super) static isr_stub_table: [extern "C" fn(); 53];
     pub(super) fn isr_stub_128();
+    pub(super) fn isr_stub_spurious();
 }
 
 global_asm!(
@@ -34,7 +37,7 @@ global_asm!(
 
     /* 2. Generation Loop */
     .set i, 0
-    .rept 48
+    .rept 53
         .if i == 8 || (i >= 10 && i <= 14) || i == 17 // Interrupts with error codes
             isr_err_stub %i
         .else
@@ -48,8 +51,8 @@ global_asm!(
     isr_stub_128:
         push 0 // no error code
         push 128 // interrupt number
-        fxsave [rip + INTERRUPT_FPU_SNAPSHOT]
-        mov byte ptr [rip + INTERRUPT_FPU_SNAPSHOT_VALID], 1
+        fxsave gs:[{fpu_snapshot}]
+        mov byte ptr gs:[{fpu_snapshot_valid}], 1
         push r15; push r14; push r13; push r12
         push r11; push r10; push r9;  push r8
         push rbp; push rdi; push rsi; push rdx
@@ -59,23 +62,29 @@ global_asm!(
         call syscall_handler
 
         mov rsp, rax
+        mov qword ptr gs:[{switching_from}], -1 // Off the old task's stack now
         pop rax; pop rbx; pop rcx; pop rdx
         pop rsi; pop rdi; pop rbp; pop r8
         pop r9;  pop r10; pop r11; pop r12
         pop r13; pop r14; pop r15
 
-        cmp byte ptr [rip + INTERRUPT_FPU_SNAPSHOT_VALID], 0
+        cmp byte ptr gs:[{fpu_snapshot_valid}], 0
         je 1f
-        fxrstor [rip + INTERRUPT_FPU_SNAPSHOT]
-        mov byte ptr [rip + INTERRUPT_FPU_SNAPSHOT_VALID], 0
+        fxrstor gs:[{fpu_snapshot}]
+        mov byte ptr gs:[{fpu_snapshot_valid}], 0
     1:
         add rsp, 16
         iretq
 
+    // The local APIC's spurious vector. It doesn't take an EOI, so there's nothing to do
+    .global isr_stub_spurious
+    isr_stub_spurious:
+        iretq
+
     /* 3. The Common Handler */
     isr_common_stub:
-        fxsave [rip + INTERRUPT_FPU_SNAPSHOT]
-        mov byte ptr [rip + INTERRUPT_FPU_SNAPSHOT_VALID], 1
+        fxsave gs:[{fpu_snapshot}]
+        mov byte ptr gs:[{fpu_snapshot_valid}], 1
         push r15; push r14; push r13; push r12
         push r11; push r10; push r9;  push r8
         push rbp; push rdi; push rsi; push rdx
@@ -86,6 +95,7 @@ global_asm!(
 
         // On return, rax contains the new rsp
         mov rsp, rax
+        mov qword ptr gs:[{switching_from}], -1 // Off the old task's stack now
 
         // Restore registers
         pop rax; pop rbx; pop rcx; pop rdx
@@ -93,10 +103,10 @@ global_asm!(
         pop r9;  pop r10; pop r11; pop r12
         pop r13; pop r14; pop r15
 
-        cmp byte ptr [rip + INTERRUPT_FPU_SNAPSHOT_VALID], 0
+        cmp byte ptr gs:[{fpu_snapshot_valid}], 0
         je 2f
-        fxrstor [rip + INTERRUPT_FPU_SNAPSHOT]
-        mov byte ptr [rip + INTERRUPT_FPU_SNAPSHOT_VALID], 0
+        fxrstor gs:[{fpu_snapshot}]
+        mov byte ptr gs:[{fpu_snapshot_valid}], 0
     2:
 
         add rsp, 16
@@ -113,11 +123,14 @@ global_asm!(
 
     isr_stub_table:
         .set i, 0
-        .rept 48
+        .rept 53
             push_stub_addr %i
             .set i, i + 1
         .endr
 
     .noaltmacro
-    "#
+    "#,
+    fpu_snapshot = const offset_of!(PerCpu, fpu_snapshot),
+    fpu_snapshot_valid = const offset_of!(PerCpu, fpu_snapshot_valid),
+    switching_from = const offset_of!(PerCpu, switching_from),
 );
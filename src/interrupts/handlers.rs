@@ -44,6 +44,7 @@ pub struct InterruptStackFrame {
 pub extern "C" fn exception_handler(frame: &InterruptStackFrame) -> u64 {
     let num = frame.interrupt_number;
     let mut current_rsp = frame as *const _ as u64;
+    super::count_interrupt(num);
 
     if num < 32 {
         let error_string = "There was a CPU Exception!";
@@ -111,74 +112,73 @@ pub extern "C" fn exception_handler(frame: &InterruptStackFrame) -> u64 {
             cr2.wrapping_sub(frame.rdi)
         );
 
-        if let Some(mut guard) = crate::multitasker::scheduler::SCHEDULER.try_lock() {
-            if let Some(sched) = guard.as_mut() {
-                if let Some(task) = sched.current_task.as_mut() {
+        // Another CPU may be holding our run queue for a moment, but if this CPU crashed
+        // while holding it we'd wait forever, so only try for a while
+        let scheduler = &crate::multitasker::scheduler::SCHEDULER;
+        let queue = (0..1_000_000).find_map(|_| {
+            core::hint::spin_loop();
+            scheduler.try_lock_queue()
+        });
+        if let Some(mut queue) = queue {
+            if let Some(task) = queue.current.as_mut() {
+                crate::println!(
+                    "[EXC DEBUG] task id={} status={:?} stack_ptr={:#x}",
+                    task.id,
+                    task.status,
+                    task.stack_pointer
+                );
+
+                if let Some(img) = task.owned_program_image.as_ref() {
+                    let img_base = img.as_ptr() as u64;
+                    let img_end = img_base.saturating_add(img.len() as u64);
+                    let rip_off = frame.rip.wrapping_sub(img_base);
+                    let cr2_off = cr2.wrapping_sub(img_base);
+                    let rip_in = frame.rip >= img_base && frame.rip < img_end;
+                    let cr2_in = cr2 >= img_base && cr2 < img_end;
+
+                    crate::println!(
+                        "[EXC DEBUG] task image base={:#x} end={:#x} len={}",
+                        img_base,
+                        img_end,
+                        img.len()
+                    );
                     crate::println!(
-                        "[EXC DEBUG] task id={} status={:?} stack_ptr={:#x}",
-                        task.id,
-                        task.status,
-                        task.stack_pointer
+                        "[EXC DEBUG] rip_off={:#x} (in_image={}) cr2_off={:#x} (in_image={})",
+                        rip_off,
+                        rip_in,
+                        cr2_off,
+                        cr2_in
                     );
+                }
+
+                // A crash takes down every thread of the process
+                let process_id = task.process_id;
+                drop(queue);
+                scheduler
+                    .terminate_process(process_id, crate::multitasker::task::TaskStatus::Killed);
 
-                    if let Some(img) = task.owned_program_image.as_ref() {
-                        let img_base = img.as_ptr() as u64;
-                        let img_end = img_base.saturating_add(img.len() as u64);
-                        let rip_off = frame.rip.wrapping_sub(img_base);
-                        let cr2_off = cr2.wrapping_sub(img_base);
-                        let rip_in = frame.rip >= img_base && frame.rip < img_end;
-                        let cr2_in = cr2 >= img_base && cr2 < img_end;
-
-                        crate::println!(
-                            "[EXC DEBUG] task image base={:#x} end={:#x} len={}",
-                            img_base,
-                            img_end,
-                            img.len()
-                        );
-                        crate::println!(
-                            "[EXC DEBUG] rip_off={:#x} (in_image={}) cr2_off={:#x} (in_image={})",
-                            rip_off,
-                            rip_in,
-                            cr2_off,
-                            cr2_in
-                        );
-                    }
-
-                    if crate::io::keyboard::task_has_focus(task.id) {
-                        crate::io::keyboard::set_focus_and_clear(
-                            crate::io::keyboard::SHELL_TASK_ID,
-                        );
-                        crate::screen::exit_exclusive_mode();
-                        crate::screen::vfb::release_owner(task.id);
-                    }
-
-                    task.status = crate::multitasker::task::TaskStatus::Killed;
-
-                    unsafe {
-                        let lock_ptr =
-                            core::ptr::addr_of!(crate::screen::renderer::WRITER) as *mut u64;
-                        lock_ptr.write_volatile(0);
-                    }
-
-                    current_rsp = sched.schedule(current_rsp);
-                    return current_rsp;
+                unsafe {
+                    let lock_ptr = core::ptr::addr_of!(crate::screen::renderer::WRITER) as *mut u64;
+                    lock_ptr.write_volatile(0);
                 }
+
+                current_rsp = scheduler.schedule(current_rsp);
+                return current_rsp;
             }
         }
 
-        serial_println!("KERNEL PANIC: Exception outside task context or Scheduler locked.");
+        serial_println!("KERNEL PANIC: Exception outside task context or run queue locked.");
         hcf();
     }
 
     if num == 32 {
-        super::on_timer_tick();
-        crate::timer::tick();
-
-        if let Some(mut guard) = crate::multitasker::scheduler::SCHEDULER.try_lock() {
-            if let Some(sched) = guard.as_mut() {
-                current_rsp = sched.schedule(current_rsp);
-            }
+        // Every CPU has its own timer, only the BSP's keeps time
+        if crate::smp::current().is_bsp() {
+            super::on_timer_tick();
+            crate::timer::tick();
         }
+
+        current_rsp = crate::multitasker::scheduler::SCHEDULER.schedule(current_rsp);
     } else if num == 33 {
         let status = ps2_status();
         let scancode: u8;
@@ -206,6 +206,33 @@ pub extern "C" fn exception_handler(frame: &InterruptStackFrame) -> u64 {
         }
     }
 
+    if num == 36 {
+        // PIC IRQ4 (COM1) is remapped to vector 36.
+        crate::io::serial::on_irq();
+    }
+
+    if num == 46 || num == 47 {
+        // PIC IRQ14/15 (primary/secondary ATA) are remapped to vectors 46/47.
+        crate::io::ata_driver::on_irq(num == 47);
+    }
+
+    if num == super::AHCI_VECTOR as u64 {
+        crate::io::ahci::on_irq();
+    }
+
+    if num == super::VIRTIO_BLK_VECTOR as u64 {
+        crate::io::virtio_blk::on_irq();
+    }
+
+    if num == super::RESCHEDULE_VECTOR as u64 {
+        // Another CPU queued work for us or killed what we are running
+        current_rsp = crate::multitasker::scheduler::SCHEDULER.schedule(current_rsp);
+    } else if num == super::TLB_SHOOTDOWN_VECTOR as u64 {
+        crate::smp::on_tlb_shootdown();
+    } else if num == super::HALT_VECTOR as u64 {
+        crate::smp::on_halt();
+    }
+
     if num >= 32 {
         super::idt::send_eoi(num);
     }
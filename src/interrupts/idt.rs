@@ -1,7 +1,9 @@
 use crate::{globals, println};
 use core::arch::asm;
 
-use super::asm_stubs::{isr_stub_128, isr_stub_table};
+use super::apic::SPURIOUS_VECTOR;
+use super::asm_stubs::{isr_stub_128, isr_stub_spurious, isr_stub_table};
+use crate::smp::percpu::DOUBLE_FAULT_IST;
 
 #[repr(C, packed)]
 #[derive(Copy, Clone)]
@@ -37,6 +39,11 @@ impl IdtEntry {
         self.ist = 0;
         self.reserved = 0;
     }
+
+    // Runs the handler on stack `index` (1-7) of the TSS interrupt stack table
+    fn set_stack_index(&mut self, index: u8) {
+        self.ist = index;
+    }
 }
 
 #[repr(C, packed)]
@@ -62,12 +69,20 @@ pub unsafe fn init_idt() {
     println!("Current CS is: {:#x}", cs);
 
     unsafe {
-        for i in 0..48 {
+        for i in 0..isr_stub_table.len() {
             IDT[i].set_handler(isr_stub_table[i] as u64, cs);
         }
         IDT[0x80].set_handler(isr_stub_128 as u64, cs);
+        IDT[SPURIOUS_VECTOR as usize].set_handler(isr_stub_spurious as u64, cs);
+        // A double fault gets a known good stack, whatever happened to the current one
+        IDT[8].set_stack_index(DOUBLE_FAULT_IST as u8 + 1);
     }
 
+    unsafe { load_idt() };
+}
+
+// Every CPU loads the same IDT
+pub unsafe fn load_idt() {
     let idt_ptr = IdtPointer {
         limit: (core::mem::size_of::<[IdtEntry; 256]>() - 1) as u16,
         base: core::ptr::addr_of!(IDT) as u64,
@@ -96,13 +111,23 @@ pub unsafe fn init_pic() {
     outb(PIC1_DATA, 0x01);
     outb(PIC2_DATA, 0x01);
 
-    // Unmask IRQ0 (timer), IRQ1 (keyboard) and IRQ2 (cascade) on master.
-    // Unmask IRQ12 (mouse) on slave.
-    outb(PIC1_DATA, 0xF8);
-    outb(PIC2_DATA, 0xEF);
+    // Unmask IRQ0 (timer), IRQ1 (keyboard), IRQ2 (cascade) and IRQ4 (COM1) on master.
+    // Unmask IRQ12 (mouse) and IRQ14/15 (ATA) on slave.
+    outb(PIC1_DATA, 0xE8);
+    outb(PIC2_DATA, 0x2F);
+}
+
+// Masks every line on both PICs, once the APICs take over
+pub(super) fn disable_pic() {
+    outb(0x21, 0xFF);
+    outb(0xA1, 0xFF);
 }
 
 pub(super) fn send_eoi(interrupt_number: u64) {
+    if super::apic::apic_enabled() {
+        super::apic::send_eoi();
+        return;
+    }
     if interrupt_number >= 40 {
         outb(0xA0, 0x20);
     }
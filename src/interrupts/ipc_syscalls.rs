@@ -0,0 +1,74 @@
+/**********************************************************************************************************************************
+ *                                                         DOCUMENTATION                                                          *
+ *             IPC SYSCALLS, THIN WRAPPERS THAT COPY MESSAGES BETWEEN USER MEMORY AND THE KERNEL CHANNELS IN IPC.RS.              *
+ * HANDLES ARE LOOKED UP IN THE CALLING PROCESS'S TABLE, SO A TASK CAN ONLY USE CHANNELS ITS PROCESS CREATED, OPENED OR WAS SENT. *
+ *                         THE BLOCKING PART OF RECEIVE LIVES IN SYSCALL.RS SINCE IT NEEDS TO RESCHEDULE.                         *
+ *********************************************************************************************************************************/
+
+use super::fs_syscalls::user_cstr_to_string;
+use crate::ipc::{self, NO_HANDLE, UserMessage};
+
+const SYSCALL_ERR: u64 = u64::MAX;
+pub(super) const CHANNEL_EMPTY: u64 = u64::MAX - 1;
+const MAX_CHANNEL_NAME: usize = 64;
+
+// Handle tables belong to the process, so all of its threads share them.
+fn current_process_id() -> u64 {
+    crate::multitasker::scheduler::SCHEDULER.get_current_process_id()
+}
+
+// Creates a channel, published under `name_ptr` unless it is null. Returns the handle.
+pub(super) unsafe fn sys_channel_create(name_ptr: u64) -> u64 {
+    let name = unsafe { user_cstr_to_string(name_ptr, MAX_CHANNEL_NAME) };
+    match ipc::create(current_process_id(), name.as_deref()) {
+        Ok(handle) => handle,
+        Err(e) => {
+            crate::serial_println!("channel_create failed: {}", e);
+            SYSCALL_ERR
+        }
+    }
+}
+
+pub(super) unsafe fn sys_channel_open(name_ptr: u64) -> u64 {
+    let Some(name) = (unsafe { user_cstr_to_string(name_ptr, MAX_CHANNEL_NAME) }) else {
+        return SYSCALL_ERR;
+    };
+    ipc::open(current_process_id(), name.as_str()).unwrap_or(SYSCALL_ERR)
+}
+
+pub(super) fn sys_channel_close(handle: u64) -> u64 {
+    match ipc::close(current_process_id(), handle) {
+        Ok(()) => 0,
+        Err(_) => SYSCALL_ERR,
+    }
+}
+
+// Sends the UserMessage at `msg_ptr`. On success returns the channel ID, which the
+// dispatcher uses to wake blocked receivers.
+pub(super) unsafe fn sys_channel_send(handle: u64, msg_ptr: u64) -> Result<u64, &'static str> {
+    if msg_ptr == 0 {
+        return Err("Null message");
+    }
+    let msg = unsafe { &*(msg_ptr as *const UserMessage) };
+    let data = msg
+        .data
+        .get(..msg.len as usize)
+        .ok_or("Message too large")?;
+    let pass_handle = (msg.handle != NO_HANDLE).then_some(msg.handle);
+
+    ipc::send(current_process_id(), handle, data, pass_handle)
+}
+
+// Receives into the UserMessage at `msg_ptr` without blocking.
+// Returns the message length, CHANNEL_EMPTY, or SYSCALL_ERR for a bad handle.
+pub(super) unsafe fn sys_channel_try_receive(handle: u64, msg_ptr: u64) -> u64 {
+    if msg_ptr == 0 {
+        return SYSCALL_ERR;
+    }
+    let out = unsafe { &mut *(msg_ptr as *mut UserMessage) };
+    match ipc::try_receive(current_process_id(), handle, out) {
+        Ok(true) => out.len,
+        Ok(false) => CHANNEL_EMPTY,
+        Err(_) => SYSCALL_ERR,
+    }
+}
@@ -1,16 +1,27 @@
+mod apic;
 mod asm_stubs;
 mod fs_syscalls;
 mod handlers;
 mod idt;
+mod ipc_syscalls;
 mod syscall;
 
+use alloc::vec::Vec;
 use core::sync::atomic::{AtomicU64, Ordering};
 
-pub use idt::{init_idt, init_pic};
+pub use apic::{
+    AHCI_VECTOR, HALT_VECTOR, RESCHEDULE_VECTOR, TLB_SHOOTDOWN_VECTOR, VIRTIO_BLK_VECTOR,
+    apic_enabled, apic_timer_calibrated, arm_apic_timer, broadcast_ipi, init_ap_apic, init_apic,
+    lapic_id, send_ipi, start_apic_timer,
+};
+pub use idt::{init_idt, init_pic, load_idt};
 
 static BUSY_TICKS: AtomicU64 = AtomicU64::new(0);
 static TOTAL_TICKS: AtomicU64 = AtomicU64::new(0);
 
+// How often each vector fired since boot, for /proc/interrupts
+static INTERRUPT_COUNTS: [AtomicU64; 256] = [const { AtomicU64::new(0) }; 256];
+
 pub(crate) fn on_timer_tick() {
     TOTAL_TICKS.fetch_add(1, Ordering::Relaxed);
 }
@@ -25,3 +36,47 @@ pub fn get_cpu_usage() -> u32 {
 
     ((busy as u64 * 100) / total as u64) as u32
 }
+
+pub(crate) fn count_interrupt(vector: u64) {
+    if let Some(count) = INTERRUPT_COUNTS.get(vector as usize) {
+        count.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+// (vector, count) of every vector that fired at least once
+pub fn interrupt_counts() -> Vec<(u8, u64)> {
+    INTERRUPT_COUNTS
+        .iter()
+        .enumerate()
+        .map(|(vector, count)| (vector as u8, count.load(Ordering::Relaxed)))
+        .filter(|&(_, count)| count > 0)
+        .collect()
+}
+
+pub fn vector_name(vector: u8) -> &'static str {
+    match vector {
+        0 => "divide error",
+        1 => "debug",
+        2 => "NMI",
+        3 => "breakpoint",
+        6 => "invalid opcode",
+        7 => "device not available",
+        8 => "double fault",
+        13 => "general protection",
+        14 => "page fault",
+        0..=31 => "exception",
+        32 => "timer",
+        33 => "keyboard",
+        36 => "com1",
+        44 => "mouse",
+        46 => "ata primary",
+        47 => "ata secondary",
+        48 => "reschedule IPI",
+        49 => "TLB shootdown IPI",
+        50 => "ahci",
+        51 => "virtio-blk",
+        52 => "halt IPI",
+        0x80 => "syscall",
+        _ => "irq",
+    }
+}
@@ -2,10 +2,19 @@ use crate::io::keyboard::SCANCODE_QUEUE;
 use crate::serial_println;
 
 use super::fs_syscalls::{
-    sys_fs_close, sys_fs_mkdir, sys_fs_open, sys_fs_read, sys_fs_read_handle, sys_fs_remove,
-    sys_fs_rename, sys_fs_seek_handle, sys_fs_write,
+    sys_fs_close, sys_fs_ioctl, sys_fs_mkdir, sys_fs_mmap, sys_fs_open, sys_fs_read,
+    sys_fs_read_dir, sys_fs_read_handle, sys_fs_remove, sys_fs_rename, sys_fs_seek_handle,
+    sys_fs_write, sys_fs_write_handle, user_cstr_to_string,
 };
 use super::handlers::InterruptStackFrame;
+use super::ipc_syscalls::{
+    CHANNEL_EMPTY, sys_channel_close, sys_channel_create, sys_channel_open, sys_channel_send,
+    sys_channel_try_receive,
+};
+use crate::multitasker::scheduler::{SCHEDULER, Wait};
+use crate::multitasker::task::{Task, TaskStatus};
+
+const SPAWN_BACKGROUND: u64 = 1;
 
 #[unsafe(no_mangle)]
 pub extern "C" fn syscall_handler(frame: &mut InterruptStackFrame) -> u64 {
@@ -13,6 +22,7 @@ pub extern "C" fn syscall_handler(frame: &mut InterruptStackFrame) -> u64 {
     let arg1 = frame.rdi;
     let arg2 = frame.rsi;
     let arg3 = frame.rdx;
+    super::count_interrupt(0x80);
 
     match syscall_nr {
         1 => {
@@ -21,20 +31,10 @@ pub extern "C" fn syscall_handler(frame: &mut InterruptStackFrame) -> u64 {
             crate::io::log_buffer::DISPLAY_QUEUE.push_char(c);
         }
         2 => {
-            let mut guard = crate::multitasker::scheduler::SCHEDULER.lock();
-            if let Some(sched) = guard.as_mut() {
-                if let Some(task) = sched.current_task.as_mut() {
-                    if crate::io::keyboard::task_has_focus(task.id) {
-                        crate::io::keyboard::set_focus_and_clear(
-                            crate::io::keyboard::SHELL_TASK_ID,
-                        );
-                        crate::screen::exit_exclusive_mode();
-                        crate::screen::vfb::release_owner(task.id);
-                    }
-                    task.status = crate::multitasker::task::TaskStatus::Exited;
-                }
-                return sched.schedule(frame as *const _ as u64);
-            }
+            // Exiting ends every thread of the process
+            let process_id = SCHEDULER.get_current_process_id();
+            SCHEDULER.terminate_process(process_id, TaskStatus::Exited);
+            return SCHEDULER.schedule(frame as *const _ as u64);
         }
         3 => {
             // Clear screen
@@ -62,17 +62,7 @@ pub extern "C" fn syscall_handler(frame: &mut InterruptStackFrame) -> u64 {
             frame.rax = unsafe { sys_fs_write(arg1, arg2, arg3) };
         }
         7 => {
-            let focused = crate::io::keyboard::focused_task();
-            let current_task = crate::multitasker::scheduler::SCHEDULER
-                .try_lock()
-                .and_then(|guard| {
-                    guard
-                        .as_ref()
-                        .and_then(|sched| sched.current_task.as_ref().map(|task| task.id))
-                });
-
-            // If we can't observe current_task due lock contention, don't drop input on the floor.
-            let allow_input = current_task.map(|id| id == focused).unwrap_or(true);
+            let allow_input = crate::io::keyboard::current_task_has_input();
 
             frame.rax = if allow_input {
                 SCANCODE_QUEUE.pop().map(|s| s as u64).unwrap_or(0)
@@ -81,33 +71,13 @@ pub extern "C" fn syscall_handler(frame: &mut InterruptStackFrame) -> u64 {
             };
         }
         8 => {
-            let mut guard = crate::multitasker::scheduler::SCHEDULER.lock();
-            if let Some(sched) = guard.as_mut() {
-                return sched.schedule(frame as *const _ as u64);
-            }
+            return SCHEDULER.schedule(frame as *const _ as u64);
         }
         9 => {
-            let focused = crate::io::keyboard::focused_task();
-            let current_task = crate::multitasker::scheduler::SCHEDULER
-                .try_lock()
-                .and_then(|guard| {
-                    guard
-                        .as_ref()
-                        .and_then(|sched| sched.current_task.as_ref().map(|task| task.id))
-                });
-
-            // Same reasoning as syscall 7: transient lock contention should not starve input.
-            let allow_input = current_task.map(|id| id == focused).unwrap_or(true);
-
-            frame.rax = if allow_input {
-                SCANCODE_QUEUE
-                    .pop()
-                    .and_then(|s| crate::io::keyboard::scancode_to_byte(s))
-                    .map(|b| b as u64)
-                    .unwrap_or(0)
-            } else {
-                0
-            };
+            // Keys typed on the serial console count as keyboard input
+            frame.rax = crate::io::keyboard::read_foreground_byte()
+                .map(|b| b as u64)
+                .unwrap_or(0);
         }
         10 => {
             let ptr = arg1 as *const u32;
@@ -150,11 +120,260 @@ pub extern "C" fn syscall_handler(frame: &mut InterruptStackFrame) -> u64 {
             frame.rax = unsafe { sys_fs_rename(arg1, arg2) };
         }
         21 => {
-            frame.rax = crate::io::mouse::take_deltas_packed() as u64;
+            // Mouse motion and wheel clicks since the last call, packed as i16s: dx, dy, wheel
+            frame.rax = crate::io::mouse::take_deltas_packed();
         }
         22 => {
+            // The mouse buttons held, bit 0 left, 1 right, 2 middle, 3 and 4 the side buttons
             frame.rax = crate::io::mouse::get_buttons_mask() as u64;
         }
+        23 => {
+            frame.rax = unsafe { sys_spawn(arg1, arg2, arg3) };
+        }
+        24 => {
+            // Block until task `arg1` terminates or is stopped.
+            // Returns 0 if it exited, 1 if it was killed and 2 if it was stopped by Ctrl+Z.
+            frame.rax = 0;
+            if let Wait::Blocked(next) = SCHEDULER.wait_for(frame as *const _ as u64, arg1, false) {
+                return next;
+            }
+        }
+        25 => {
+            frame.rax = unsafe { sys_fs_read_dir(arg1, arg2, arg3) };
+        }
+        26 => {
+            SCHEDULER.with_current(|task| task.wake_at = crate::timer::get_uptime_ms() + arg1);
+            return SCHEDULER.schedule(frame as *const _ as u64);
+        }
+        27 => {
+            // Hand the keyboard to process group `arg1`. Only the current foreground
+            // group may give it away, so background jobs can't grab it.
+            frame.rax = u64::MAX;
+            let caller_group = SCHEDULER.with_current(|t| t.pgid);
+            if caller_group == Some(crate::io::keyboard::foreground_group())
+                && SCHEDULER.group_exists(arg1)
+            {
+                crate::io::keyboard::set_foreground_and_clear(arg1);
+                frame.rax = 0;
+            }
+        }
+        28 => {
+            // Resume the stopped process group `arg1`.
+            frame.rax = if SCHEDULER.continue_group(arg1) {
+                0
+            } else {
+                u64::MAX
+            };
+        }
+        29 => {
+            // Returns 1 if task `arg1` is stopped, 0 if it is still alive, u64::MAX if it is gone.
+            frame.rax = match SCHEDULER.task_status(arg1) {
+                Some(TaskStatus::Stopped) => 1,
+                Some(_) => 0,
+                None => u64::MAX,
+            };
+        }
+        30 => {
+            frame.rax = unsafe { sys_channel_create(arg1) };
+        }
+        31 => {
+            frame.rax = unsafe { sys_channel_open(arg1) };
+        }
+        32 => {
+            frame.rax = sys_channel_close(arg1);
+        }
+        33 => {
+            frame.rax = match unsafe { sys_channel_send(arg1, arg2) } {
+                Ok(channel_id) => {
+                    SCHEDULER.wake_channel_waiters(channel_id);
+                    0
+                }
+                Err(_) => u64::MAX,
+            };
+        }
+        34 => {
+            // Receive on channel `arg1` into the message at `arg2`, waiting up to `arg3` ms
+            // (0 = don't wait, u64::MAX = forever). If we have to block, CHANNEL_EMPTY is
+            // returned once woken and rustos_user retries until its deadline passes.
+            frame.rax = unsafe { sys_channel_try_receive(arg1, arg2) };
+            if frame.rax == CHANNEL_EMPTY && arg3 != 0 {
+                // A send from another CPU may have come in since, it wakes nobody as we
+                // aren't waiting yet. Then just return and let the caller retry.
+                let blocked = SCHEDULER.block_current(frame as *const _ as u64, |task| {
+                    let process_id = task.process_id;
+                    let channel_id = crate::ipc::channel_id(process_id, arg1)
+                        .filter(|_| !crate::ipc::has_messages(process_id, arg1));
+                    let Some(channel_id) = channel_id else {
+                        return false;
+                    };
+                    task.status = TaskStatus::Waiting;
+                    task.wait_channel = Some(channel_id);
+                    task.wait_deadline = arg3
+                        .checked_add(crate::timer::get_uptime_ms())
+                        .filter(|_| arg3 != u64::MAX);
+                    true
+                });
+                if let Some(next) = blocked {
+                    return next;
+                }
+            }
+        }
+        35 => {
+            // Start a thread at `arg1` with `arg2` as its argument and `arg3` as its FS base.
+            frame.rax = u64::MAX;
+            let id = crate::multitasker::allocate_task_id();
+            let thread = SCHEDULER.with_current(|current| {
+                Task::new(id, arg1, arg2, None)
+                    .as_thread_of(current)
+                    .with_fs_base(arg3)
+                    .with_return_address(crate::multitasker::thread_return_address())
+            });
+            if let Some(thread) = thread {
+                SCHEDULER.add_task(thread);
+                frame.rax = id;
+            }
+        }
+        36 => {
+            // Join thread `arg1` of our own process, returns its exit code.
+            frame.rax = u64::MAX;
+            match SCHEDULER.wait_for(frame as *const _ as u64, arg1, true) {
+                Wait::Exited(code) => frame.rax = code,
+                Wait::Blocked(next) => return next,
+                Wait::Refused => {}
+            }
+        }
+        37 => {
+            // End just the calling thread with exit code `arg1`. The main thread can't, its
+            // exit is the process's and would release SYS_WAIT while other threads still run.
+            frame.rax = u64::MAX;
+            let ended = SCHEDULER.with_current(|task| {
+                if task.id == task.process_id {
+                    return false;
+                }
+                crate::multitasker::release_foreground(task);
+                task.exit_code = arg1;
+                task.status = TaskStatus::Exited;
+                true
+            });
+            if ended == Some(true) {
+                return SCHEDULER.schedule(frame as *const _ as u64);
+            }
+        }
+        38 => {
+            // Set the calling thread's FS base (thread-local storage pointer).
+            frame.rax = u64::MAX;
+            if let Ok(addr) = x86_64::VirtAddr::try_new(arg1) {
+                let set = SCHEDULER.with_current(|task| {
+                    task.fs_base = arg1;
+                    x86_64::registers::model_specific::FsBase::write(addr);
+                });
+                if set.is_some() {
+                    frame.rax = 0;
+                }
+            }
+        }
+        39 => {
+            // futex_wait(addr, expected, timeout_ms): sleep while the u32 at `addr` holds
+            // `expected`. Returns 0 when woken, 1 if the value had already changed and
+            // 2 on timeout (u64::MAX = no timeout).
+            // The value is checked with our run queue locked, so a futex_wake from another
+            // CPU either sees us waiting or we see the value it changed.
+            let word = arg1 as *const u32;
+            if arg1 == 0 || arg1 % 4 != 0 {
+                frame.rax = u64::MAX;
+            } else {
+                frame.rax = 2;
+                let blocked = SCHEDULER.block_current(frame as *const _ as u64, |task| {
+                    if unsafe { word.read_volatile() } != arg2 as u32 {
+                        frame.rax = 1;
+                        return false;
+                    }
+                    task.status = TaskStatus::Waiting;
+                    task.wait_futex = Some(arg1);
+                    task.wait_deadline = arg3
+                        .checked_add(crate::timer::get_uptime_ms())
+                        .filter(|_| arg3 != u64::MAX);
+                    true
+                });
+                if let Some(next) = blocked {
+                    return next;
+                }
+            }
+        }
+        40 => {
+            // futex_wake(addr, count): returns how many waiters were woken.
+            frame.rax = SCHEDULER.wake_futex(arg1, arg2);
+        }
+        41 => {
+            // pci_list(buf, count): fills up to `count` UserPciInfo records, returns how
+            // many devices there are in total.
+            frame.rax = unsafe { sys_pci_list(arg1, arg2) };
+        }
+        42 => {
+            frame.rax = unsafe { sys_fs_write_handle(arg1, arg2, arg3) };
+        }
+        43 => {
+            // ioctl(handle, request, arg)
+            frame.rax = unsafe { sys_fs_ioctl(arg1, arg2, arg3) };
+        }
+        44 => {
+            // mmap(handle, offset, len) -> address
+            frame.rax = unsafe { sys_fs_mmap(arg1, arg2, arg3) };
+        }
+        45 => {
+            // gettimeofday(tv): fills {seconds, microseconds} since the Unix epoch
+            frame.rax = unsafe { sys_gettimeofday(arg1) };
+        }
+        46 => {
+            // clock_gettime(clock_id, ts): fills {seconds, nanoseconds} of the given clock
+            frame.rax = unsafe { sys_clock_gettime(arg1, arg2) };
+        }
+        47 => {
+            // Nanoseconds since boot, the monotonic clock without the struct
+            frame.rax = crate::timer::uptime_ns();
+        }
+        48 => {
+            // Power off, after writing back everything cached. Doesn't return.
+            crate::power::shutdown();
+        }
+        49 => {
+            // Restart the machine, after writing back everything cached. Doesn't return.
+            crate::power::reboot();
+        }
+        50 => {
+            // The next key event for the foreground group into `arg1`, 1 if there was one
+            frame.rax = if crate::io::keyboard::current_task_has_input() {
+                unsafe { sys_get_key_event(arg1) }
+            } else {
+                0
+            };
+        }
+        51 => {
+            // Switch to the keymap in the file at `arg1`
+            frame.rax = unsafe { sys_set_keymap(arg1) };
+        }
+        52 => {
+            // input_poll(buf, max): moves up to `max` input events of the foreground group
+            // into `buf`, returns how many
+            frame.rax = if crate::io::keyboard::current_task_has_input() {
+                unsafe { sys_input_poll(arg1, arg2) }
+            } else {
+                0
+            };
+        }
+        53 => {
+            // input_wait(buf, max, timeout_ms): like input_poll, but first waits up to
+            // `arg3` ms for an event (u64::MAX = forever). Returns 0 on timeout.
+            let deadline = arg3
+                .checked_add(crate::timer::get_uptime_ms())
+                .filter(|_| arg3 != u64::MAX);
+            crate::io::input::wait(deadline);
+            frame.rax = if crate::io::keyboard::current_task_has_input() {
+                unsafe { sys_input_poll(arg1, arg2) }
+            } else {
+                0
+            };
+        }
         _ => {
             serial_println!("Unknown syscall: {}", syscall_nr);
         }
@@ -163,6 +382,110 @@ pub extern "C" fn syscall_handler(frame: &mut InterruptStackFrame) -> u64 {
     frame as *const InterruptStackFrame as u64
 }
 
+// Launch the program at `path_ptr` as a child of the calling task, returns the new task ID.
+// The child leads a new process group that takes the keyboard unless SPAWN_BACKGROUND is set.
+unsafe fn sys_spawn(path_ptr: u64, arg_ptr: u64, flags: u64) -> u64 {
+    let path = match unsafe { user_cstr_to_string(path_ptr, 512) } {
+        Some(p) => p,
+        None => return u64::MAX,
+    };
+    let arg = unsafe { user_cstr_to_string(arg_ptr, 512) };
+
+    let parent_id = SCHEDULER.get_current_task_id();
+
+    let foreground = flags & SPAWN_BACKGROUND == 0;
+    match crate::program_loader::launch_program(
+        path.as_str(),
+        arg.as_deref(),
+        parent_id,
+        foreground,
+    ) {
+        Ok(task_id) => task_id,
+        Err(e) => {
+            serial_println!("spawn {} failed: {}", path, e);
+            u64::MAX
+        }
+    }
+}
+
+unsafe fn sys_pci_list(buf_ptr: u64, count: u64) -> u64 {
+    let devices = crate::io::pci::devices();
+    if buf_ptr != 0 {
+        let out = buf_ptr as *mut crate::io::pci::UserPciInfo;
+        for (i, device) in devices.iter().take(count as usize).enumerate() {
+            unsafe {
+                out.add(i)
+                    .write_unaligned(crate::io::pci::UserPciInfo::new(device))
+            };
+        }
+    }
+    devices.len() as u64
+}
+
+unsafe fn sys_gettimeofday(tv_ptr: u64) -> u64 {
+    if tv_ptr == 0 {
+        return u64::MAX;
+    }
+    let us = crate::clock::now_unix_ns() / 1000;
+    unsafe { (tv_ptr as *mut [u64; 2]).write_unaligned([us / 1_000_000, us % 1_000_000]) };
+    0
+}
+
+unsafe fn sys_clock_gettime(clock_id: u64, ts_ptr: u64) -> u64 {
+    let ns = match clock_id {
+        crate::clock::CLOCK_REALTIME => crate::clock::now_unix_ns(),
+        crate::clock::CLOCK_MONOTONIC => crate::timer::uptime_ns(),
+        _ => return u64::MAX,
+    };
+    if ts_ptr == 0 {
+        return u64::MAX;
+    }
+    unsafe { (ts_ptr as *mut [u64; 2]).write_unaligned([ns / 1_000_000_000, ns % 1_000_000_000]) };
+    0
+}
+
+unsafe fn sys_get_key_event(event_ptr: u64) -> u64 {
+    if event_ptr == 0 {
+        return u64::MAX;
+    }
+    match crate::io::keyboard::read_event() {
+        Some(event) => {
+            unsafe { (event_ptr as *mut crate::io::keyboard::KeyEvent).write_unaligned(event) };
+            1
+        }
+        None => 0,
+    }
+}
+
+unsafe fn sys_input_poll(buf_ptr: u64, max: u64) -> u64 {
+    if buf_ptr == 0 {
+        return u64::MAX;
+    }
+    let buf = buf_ptr as *mut crate::io::input::InputEvent;
+    let mut count = 0;
+    while count < max {
+        let Some(event) = crate::io::input::pop() else {
+            break;
+        };
+        unsafe { buf.add(count as usize).write_unaligned(event) };
+        count += 1;
+    }
+    count
+}
+
+unsafe fn sys_set_keymap(path_ptr: u64) -> u64 {
+    let Some(path) = (unsafe { user_cstr_to_string(path_ptr, 512) }) else {
+        return u64::MAX;
+    };
+    match crate::io::keymap::load(&path) {
+        Ok(()) => 0,
+        Err(e) => {
+            serial_println!("keymap {} failed: {}", path, e);
+            u64::MAX
+        }
+    }
+}
+
 fn push_u64_digits(q: &crate::io::log_buffer::LogQueue, mut n: u64) {
     if n == 0 {
         q.push_char(b'0');
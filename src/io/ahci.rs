@@ -0,0 +1,394 @@
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use embedded_io::{ErrorKind, ErrorType};
+use simple_fatfs::block_io::{BlockBase, BlockRead, BlockSize, BlockWrite};
+
+use crate::interrupts::AHCI_VECTOR;
+use crate::io::pci::PciDevice;
+use crate::multitasker::wait_queue::WaitQueue;
+
+// AHCI exposes SATA disks through memory mapped registers (ABAR, BAR5 of the controller).
+// Each port has a command list of 32 slots, we only ever use slot 0, so there is no NCQ.
+// Data goes through a 64 KiB DMA bounce buffer. Once interrupts are on, the port signals
+// completion over MSI and the issuing task sleeps on COMMAND_WAIT; before that, or on a
+// controller without MSI, the slot is polled. Either way a command gets COMMAND_TIMEOUT_MS.
+
+// HBA registers
+const HBA_GHC: usize = 0x04;
+const HBA_IS: usize = 0x08;
+const HBA_PI: usize = 0x0C;
+const GHC_INTERRUPT_ENABLE: u32 = 1 << 1;
+const GHC_AHCI_ENABLE: u32 = 1 << 31;
+
+// Port registers, relative to 0x100 + port * 0x80
+const PORT_CLB: usize = 0x00;
+const PORT_CLBU: usize = 0x04;
+const PORT_FB: usize = 0x08;
+const PORT_FBU: usize = 0x0C;
+const PORT_IS: usize = 0x10;
+const PORT_IE: usize = 0x14;
+const PORT_CMD: usize = 0x18;
+const PORT_TFD: usize = 0x20;
+const PORT_SIG: usize = 0x24;
+const PORT_SSTS: usize = 0x28;
+const PORT_SERR: usize = 0x30;
+const PORT_CI: usize = 0x38;
+
+const CMD_START: u32 = 1 << 0;
+const CMD_FIS_RECEIVE: u32 = 1 << 4;
+const CMD_FIS_RUNNING: u32 = 1 << 14;
+const CMD_LIST_RUNNING: u32 = 1 << 15;
+const IS_DEVICE_TO_HOST: u32 = 1 << 0; // The drive's status after a non-data or DMA command
+const IS_TASK_FILE_ERROR: u32 = 1 << 30;
+const TFD_BUSY: u32 = 0x80;
+const TFD_DRQ: u32 = 0x08;
+const SIG_SATA_DISK: u32 = 0x0000_0101;
+
+const FIS_TYPE_H2D: u8 = 0x27;
+const ATA_IDENTIFY: u8 = 0xEC;
+const ATA_READ_DMA_EXT: u8 = 0x25;
+const ATA_WRITE_DMA_EXT: u8 = 0x35;
+const ATA_FLUSH_CACHE_EXT: u8 = 0xEA;
+
+// The command list (1 KiB), received FIS area (256 bytes) and our single command table
+// all share one frame
+const RECEIVED_FIS_OFFSET: u64 = 0x400;
+const COMMAND_TABLE_OFFSET: u64 = 0x500;
+
+const DMA_BUFFER_SIZE: usize = 64 * 1024;
+const DMA_MAX_SECTORS: usize = DMA_BUFFER_SIZE / 512;
+
+const COMMAND_TIMEOUT_MS: u64 = 5000;
+// Register reads while polling, several seconds on any machine. The uptime doesn't move
+// yet when the disk is probed, so polling counts instead.
+const POLL_LIMIT: u32 = 10_000_000;
+
+// The port of the disk the filesystem is on, for the interrupt handler
+static IRQ_PORT: AtomicU64 = AtomicU64::new(0); // Virtual address of its registers
+static IRQ_HBA: AtomicU64 = AtomicU64::new(0);
+static IRQ_PORT_BIT: AtomicU32 = AtomicU32::new(0);
+// Port interrupt status the handler acknowledged since the command was issued
+static PORT_EVENTS: AtomicU32 = AtomicU32::new(0);
+static COMMAND_WAIT: WaitQueue = WaitQueue::new();
+static MSI_ENABLED: AtomicBool = AtomicBool::new(false);
+static IRQ_MODE: AtomicBool = AtomicBool::new(false);
+
+pub struct AhciDisk {
+    hba: u64, // Virtual address of the mapped ABAR
+    port: usize,
+    memory_phys: u64,
+    buffer_phys: u64,
+    sector_count: u64,
+}
+
+impl AhciDisk {
+    // Gets the first port of an AHCI controller with a SATA disk attached running
+    pub fn init(device: &PciDevice) -> Result<Self, &'static str> {
+        let abar = device.bars[5]
+            .memory_address()
+            .ok_or("AHCI controller has no ABAR")?;
+        device.address.enable_bus_master();
+        let hba = crate::memory::map_mmio(abar, 0x1100);
+
+        let mut disk = Self {
+            hba,
+            port: 0,
+            memory_phys: 0,
+            buffer_phys: 0,
+            sector_count: 0,
+        };
+        disk.write_hba(HBA_GHC, disk.read_hba(HBA_GHC) | GHC_AHCI_ENABLE);
+
+        let implemented = disk.read_hba(HBA_PI);
+        disk.port = (0..32)
+            .filter(|port| (implemented & (1 << port)) != 0)
+            .find(|&port| disk.has_disk(port))
+            .ok_or("no SATA disk on any AHCI port")?;
+
+        disk.memory_phys = crate::memory::allocate_contiguous(1, 0x1000, 1 << 32)
+            .ok_or("no memory for the AHCI command list")?;
+        disk.buffer_phys =
+            crate::memory::allocate_contiguous(DMA_BUFFER_SIZE / 0x1000, 0x1000, 1 << 32)
+                .ok_or("no memory for the AHCI DMA buffer")?;
+        unsafe {
+            core::ptr::write_bytes(
+                crate::memory::phys_to_virt(disk.memory_phys) as *mut u8,
+                0,
+                0x1000,
+            );
+        }
+
+        disk.rebase_port()?;
+        disk.sector_count = disk.identify()?;
+        Ok(disk)
+    }
+
+    pub fn port(&self) -> usize {
+        self.port
+    }
+
+    pub fn sector_count(&self) -> u64 {
+        self.sector_count
+    }
+
+    // Routes the port's interrupts to AHCI_VECTOR over MSI. Only the disk the filesystem
+    // ends up on calls this, commands are still polled until enable_interrupts().
+    pub fn setup_interrupts(&self, device: &PciDevice) {
+        if !crate::interrupts::apic_enabled() {
+            return;
+        }
+        if let Err(e) = device.enable_msi(crate::interrupts::lapic_id(), AHCI_VECTOR) {
+            crate::println!("AHCI: {}, polling for completions", e);
+            return;
+        }
+        IRQ_HBA.store(self.hba, Ordering::Relaxed);
+        IRQ_PORT.store(
+            self.hba + 0x100 + self.port as u64 * 0x80,
+            Ordering::Relaxed,
+        );
+        IRQ_PORT_BIT.store(1 << self.port, Ordering::Relaxed);
+        self.write_port(PORT_IS, u32::MAX);
+        self.write_port(PORT_IE, IS_DEVICE_TO_HOST | IS_TASK_FILE_ERROR);
+        self.write_hba(HBA_GHC, self.read_hba(HBA_GHC) | GHC_INTERRUPT_ENABLE);
+        MSI_ENABLED.store(true, Ordering::Release);
+    }
+
+    // Device present with the link up, and it's a disk rather than ATAPI
+    fn has_disk(&self, port: usize) -> bool {
+        let status = self.read_hba(0x100 + port * 0x80 + PORT_SSTS);
+        let signature = self.read_hba(0x100 + port * 0x80 + PORT_SIG);
+        (status & 0x0F) == 3 && ((status >> 8) & 0x0F) == 1 && signature == SIG_SATA_DISK
+    }
+
+    // Points the port at our command list and FIS area, the command engine has to be
+    // stopped while those move
+    fn rebase_port(&mut self) -> Result<(), &'static str> {
+        let cmd = self.read_port(PORT_CMD);
+        self.write_port(PORT_CMD, cmd & !(CMD_START | CMD_FIS_RECEIVE));
+        if !poll(|| (self.read_port(PORT_CMD) & (CMD_FIS_RUNNING | CMD_LIST_RUNNING)) == 0) {
+            return Err("AHCI port won't stop");
+        }
+
+        self.write_port(PORT_CLB, self.memory_phys as u32);
+        self.write_port(PORT_CLBU, 0);
+        self.write_port(PORT_FB, (self.memory_phys + RECEIVED_FIS_OFFSET) as u32);
+        self.write_port(PORT_FBU, 0);
+        self.write_port(PORT_SERR, u32::MAX);
+        self.write_port(PORT_IS, u32::MAX);
+
+        let cmd = self.read_port(PORT_CMD);
+        self.write_port(PORT_CMD, cmd | CMD_FIS_RECEIVE);
+        self.write_port(PORT_CMD, cmd | CMD_FIS_RECEIVE | CMD_START);
+        Ok(())
+    }
+
+    // Returns the number of sectors the drive reports
+    fn identify(&mut self) -> Result<u64, &'static str> {
+        self.issue(ATA_IDENTIFY, 0, 0, 512, false)?;
+
+        let words = crate::memory::phys_to_virt(self.buffer_phys) as *const u16;
+        let word = |i: usize| unsafe { words.add(i).read_volatile() as u64 };
+        if (word(83) & (1 << 10)) != 0 {
+            Ok(word(100) | (word(101) << 16) | (word(102) << 32) | (word(103) << 48))
+        } else {
+            Ok(word(60) | (word(61) << 16))
+        }
+    }
+
+    // Runs one command in slot 0 with a single PRD entry over the bounce buffer, or none
+    // when `bytes` is 0, and waits for the port to clear the slot
+    fn issue(
+        &mut self,
+        command: u8,
+        lba: u64,
+        count: u16,
+        bytes: usize,
+        writing: bool,
+    ) -> Result<(), &'static str> {
+        if !poll(|| (self.read_port(PORT_TFD) & (TFD_BUSY | TFD_DRQ)) == 0) {
+            return Err("AHCI port stuck busy");
+        }
+
+        let memory = crate::memory::phys_to_virt(self.memory_phys);
+        let table_phys = self.memory_phys + COMMAND_TABLE_OFFSET;
+        unsafe {
+            // Command header: FIS length in dwords, write flag, PRD entry count
+            let prds = (bytes != 0) as u32;
+            let header = memory as *mut u32;
+            header.write_volatile(5 | ((writing as u32) << 6) | (prds << 16));
+            header.add(1).write_volatile(0);
+            header.add(2).write_volatile(table_phys as u32);
+            header.add(3).write_volatile(0);
+
+            let table = (memory + COMMAND_TABLE_OFFSET) as *mut u8;
+            core::ptr::write_bytes(table, 0, 0x80 + 16);
+            let fis = [
+                FIS_TYPE_H2D,
+                0x80, // This is a command, not a control update
+                command,
+                0,
+                lba as u8,
+                (lba >> 8) as u8,
+                (lba >> 16) as u8,
+                0x40, // LBA addressing
+                (lba >> 24) as u8,
+                (lba >> 32) as u8,
+                (lba >> 40) as u8,
+                0,
+                count as u8,
+                (count >> 8) as u8,
+            ];
+            core::ptr::copy_nonoverlapping(fis.as_ptr(), table, fis.len());
+
+            if bytes != 0 {
+                let prd = table.add(0x80) as *mut u32;
+                prd.write_volatile(self.buffer_phys as u32);
+                prd.add(1).write_volatile(0);
+                prd.add(3).write_volatile((bytes as u32 - 1) | (1 << 31));
+            }
+        }
+
+        PORT_EVENTS.store(0, Ordering::Relaxed);
+        self.write_port(PORT_IS, u32::MAX);
+        self.write_port(PORT_CI, 1);
+        if !self.wait_for_completion() {
+            return Err("AHCI command timed out");
+        }
+        if (self.port_events() & IS_TASK_FILE_ERROR) != 0 {
+            return Err("AHCI task file error");
+        }
+        Ok(())
+    }
+
+    // Waits for the slot to clear or the drive to report an error
+    fn wait_for_completion(&self) -> bool {
+        let finished =
+            || (self.read_port(PORT_CI) & 1) == 0 || (self.port_events() & IS_TASK_FILE_ERROR) != 0;
+        if IRQ_MODE.load(Ordering::Acquire) {
+            let deadline = crate::timer::get_uptime_ms() + COMMAND_TIMEOUT_MS;
+            COMMAND_WAIT.wait_until_deadline(finished, Some(deadline))
+        } else {
+            poll(finished)
+        }
+    }
+
+    // The port's interrupt status, including what the interrupt handler already cleared
+    fn port_events(&self) -> u32 {
+        self.read_port(PORT_IS) | PORT_EVENTS.load(Ordering::Acquire)
+    }
+
+    fn read_hba(&self, offset: usize) -> u32 {
+        unsafe { ((self.hba as usize + offset) as *const u32).read_volatile() }
+    }
+
+    fn write_hba(&self, offset: usize, value: u32) {
+        unsafe { ((self.hba as usize + offset) as *mut u32).write_volatile(value) }
+    }
+
+    fn read_port(&self, offset: usize) -> u32 {
+        self.read_hba(0x100 + self.port * 0x80 + offset)
+    }
+
+    fn write_port(&self, offset: usize, value: u32) {
+        self.write_hba(0x100 + self.port * 0x80 + offset, value)
+    }
+
+    fn buffer(&self) -> *mut u8 {
+        crate::memory::phys_to_virt(self.buffer_phys) as *mut u8
+    }
+}
+
+// Spins until `done()`, giving up after POLL_LIMIT tries
+fn poll(mut done: impl FnMut() -> bool) -> bool {
+    for _ in 0..POLL_LIMIT {
+        if done() {
+            return true;
+        }
+        core::hint::spin_loop();
+    }
+    false
+}
+
+// Called once interrupts are on, from then on a command sleeps until its MSI arrives
+pub fn enable_interrupts() {
+    IRQ_MODE.store(MSI_ENABLED.load(Ordering::Acquire), Ordering::Release);
+}
+
+// Back to polling, for when the CPU the MSI goes to may be gone
+pub fn disable_interrupts() {
+    IRQ_MODE.store(false, Ordering::Release);
+}
+
+// Called from the AHCI vector. Acknowledges the port and the controller and wakes the
+// task waiting for its command.
+pub fn on_irq() {
+    let port = IRQ_PORT.load(Ordering::Relaxed);
+    if port == 0 {
+        return;
+    }
+    unsafe {
+        let status = ((port as usize + PORT_IS) as *const u32).read_volatile();
+        ((port as usize + PORT_IS) as *mut u32).write_volatile(status);
+        PORT_EVENTS.fetch_or(status, Ordering::AcqRel);
+        let hba = IRQ_HBA.load(Ordering::Relaxed) as usize;
+        ((hba + HBA_IS) as *mut u32).write_volatile(IRQ_PORT_BIT.load(Ordering::Relaxed));
+    }
+    COMMAND_WAIT.wake_all();
+}
+
+impl ErrorType for AhciDisk {
+    type Error = ErrorKind;
+}
+
+impl BlockBase for AhciDisk {
+    fn block_size(&self) -> BlockSize {
+        512
+    }
+
+    fn block_count(&self) -> u32 {
+        self.sector_count.min(u32::MAX as u64) as u32
+    }
+}
+
+impl BlockRead for AhciDisk {
+    fn read(&mut self, block: u32, buf: &mut [u8]) -> Result<(), Self::Error> {
+        // Only whole sectors are transferred
+        let whole = buf.len() / 512 * 512;
+        for (i, chunk) in buf[..whole].chunks_mut(DMA_BUFFER_SIZE).enumerate() {
+            let lba = block as u64 + (i * DMA_MAX_SECTORS) as u64;
+            let count = chunk.len() / 512;
+            if let Err(e) = self.issue(ATA_READ_DMA_EXT, lba, count as u16, chunk.len(), false) {
+                crate::serial_println!("AHCI: read failed (LBA: {}): {}", lba, e);
+                return Err(ErrorKind::Other);
+            }
+            let source = unsafe { core::slice::from_raw_parts(self.buffer(), chunk.len()) };
+            chunk.copy_from_slice(source);
+        }
+        Ok(())
+    }
+}
+
+impl BlockWrite for AhciDisk {
+    fn write(&mut self, block: u32, buf: &[u8]) -> Result<(), Self::Error> {
+        let whole = buf.len() / 512 * 512;
+        for (i, chunk) in buf[..whole].chunks(DMA_BUFFER_SIZE).enumerate() {
+            let lba = block as u64 + (i * DMA_MAX_SECTORS) as u64;
+            let count = chunk.len() / 512;
+            let target = unsafe { core::slice::from_raw_parts_mut(self.buffer(), chunk.len()) };
+            target.copy_from_slice(chunk);
+            if let Err(e) = self.issue(ATA_WRITE_DMA_EXT, lba, count as u16, chunk.len(), true) {
+                crate::serial_println!("AHCI: write failed (LBA: {}): {}", lba, e);
+                return Err(ErrorKind::Other);
+            }
+        }
+        Ok(())
+    }
+
+    // Makes the drive write its cache out, so what was written survives a power cut
+    fn flush(&mut self) -> Result<(), Self::Error> {
+        if let Err(e) = self.issue(ATA_FLUSH_CACHE_EXT, 0, 0, 0, false) {
+            crate::serial_println!("AHCI: flush failed: {}", e);
+            return Err(ErrorKind::Other);
+        }
+        Ok(())
+    }
+}
@@ -1,5 +1,157 @@
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86_64::instructions::interrupts;
 use x86_64::instructions::port::Port;
 
+use crate::io::pci::{Bar, PciDevice};
+use crate::sync::IrqMutex;
+use crate::multitasker::wait_queue::WaitQueue;
+
+// LBA48 commands, so neither the LBA nor the sector count is limited to 28/8 bits
+const CMD_READ_PIO_EXT: u8 = 0x24;
+const CMD_READ_DMA_EXT: u8 = 0x25;
+const CMD_WRITE_PIO_EXT: u8 = 0x34;
+const CMD_WRITE_DMA_EXT: u8 = 0x35;
+const CMD_FLUSH_CACHE_EXT: u8 = 0xEA;
+const CMD_IDENTIFY: u8 = 0xEC;
+
+// Status reads before giving up on the drive, several seconds even on fast hardware. The
+// uptime doesn't move while interrupts are off, so polling counts instead.
+const POLL_LIMIT: u32 = 10_000_000;
+// How long a request sleeping on DISK_WAIT gets before the drive counts as wedged
+const COMMAND_TIMEOUT_MS: u64 = 5000;
+
+// Largest request a single PIO command can carry (the count register is 16 bits)
+const PIO_MAX_SECTORS: usize = u16::MAX as usize;
+
+/*************************
+ * INTERRUPT-DRIVEN MODE *
+ *************************/
+// Once interrupts are up, a request issues its command and puts the task to sleep on
+// DISK_WAIT. The drive raises IRQ14 for every sector it has ready (or has taken), and
+// the handler moves that sector and wakes the task when the whole request is done.
+// Requests are serialised by the block cache lock, so only one transfer is ever in flight.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Phase {
+    Reading,
+    Writing,
+    DmaReading,
+    DmaWriting,
+    Flushing,
+    Done,
+    Failed,
+}
+
+struct Transfer {
+    buffer: *mut u8,
+    len: usize,
+    sectors_left: usize,
+    offset: usize,
+    phase: Phase,
+}
+
+// The buffer belongs to the task sleeping in wait_for_transfer, which doesn't touch it
+// again until the transfer is finished.
+unsafe impl Send for Transfer {}
+
+static TRANSFER: IrqMutex<Option<Transfer>> = IrqMutex::new(None);
+static DISK_WAIT: WaitQueue = WaitQueue::new();
+static IRQ_MODE: AtomicBool = AtomicBool::new(false);
+
+/******************
+ * BUS-MASTER DMA *
+ ******************/
+// A PCI IDE controller can move the data itself. We give its bus master a PRD (physical
+// region descriptor) table pointing at a physically contiguous buffer, start it, and the
+// IRQ arrives once the whole command is done. The buffer is a 64 KiB bounce buffer below
+// 4 GiB (PRD addresses are 32 bit), so requests go out in chunks of DMA_MAX_SECTORS.
+const DMA_BUFFER_SIZE: usize = 64 * 1024;
+const DMA_MAX_SECTORS: usize = DMA_BUFFER_SIZE / 512;
+
+const BM_START: u8 = 1 << 0;
+const BM_READ: u8 = 1 << 3; // Direction: device to memory
+const BM_STATUS_ERROR: u8 = 1 << 1;
+const BM_STATUS_IRQ: u8 = 1 << 2;
+
+#[repr(C)]
+struct PrdEntry {
+    address: u32,
+    byte_count: u16, // 0 means 64 KiB
+    flags: u16,      // Bit 15 marks the last entry
+}
+
+#[derive(Clone, Copy)]
+struct BusMaster {
+    base: u16, // I/O ports from BAR4, the primary channel is the first 8
+    prdt_phys: u64,
+    buffer_phys: u64,
+}
+
+static BUS_MASTER: IrqMutex<Option<BusMaster>> = IrqMutex::new(None);
+
+impl BusMaster {
+    // Sets up the PRD table and bounce buffer for a PCI IDE controller
+    fn init(device: &PciDevice) -> Result<Self, &'static str> {
+        let base = match device.bars[4] {
+            Bar::Io { port, .. } if (device.prog_if & 0x80) != 0 => port,
+            _ => return Err("IDE controller can't bus master"),
+        };
+
+        let prdt_phys = crate::memory::allocate_contiguous(1, 0x1000, 1 << 32)
+            .ok_or("no memory for the PRD table")?;
+        // Aligned to its size so the single PRD entry never crosses a 64 KiB boundary
+        let buffer_phys = crate::memory::allocate_contiguous(
+            DMA_BUFFER_SIZE / 0x1000,
+            DMA_BUFFER_SIZE as u64,
+            1 << 32,
+        )
+        .ok_or("no memory for the DMA buffer")?;
+
+        device.address.enable_bus_master();
+        Ok(Self {
+            base,
+            prdt_phys,
+            buffer_phys,
+        })
+    }
+
+    fn buffer(&self) -> *mut u8 {
+        crate::memory::phys_to_virt(self.buffer_phys) as *mut u8
+    }
+
+    // Points the controller at a single PRD entry covering `bytes` of the bounce buffer
+    fn prepare(&self, bytes: usize, writing: bool) {
+        let prd = crate::memory::phys_to_virt(self.prdt_phys) as *mut PrdEntry;
+        unsafe {
+            prd.write_volatile(PrdEntry {
+                address: self.buffer_phys as u32,
+                byte_count: bytes as u16,
+                flags: 0x8000,
+            });
+            Port::<u8>::new(self.base).write(if writing { 0 } else { BM_READ });
+            Port::<u32>::new(self.base + 4).write(self.prdt_phys as u32);
+        }
+        self.clear_status();
+    }
+
+    fn start(&self, writing: bool) {
+        let direction = if writing { 0 } else { BM_READ };
+        unsafe { Port::<u8>::new(self.base).write(direction | BM_START) };
+    }
+
+    fn stop(&self) {
+        unsafe { Port::<u8>::new(self.base).write(0) };
+    }
+
+    fn status(&self) -> u8 {
+        unsafe { Port::<u8>::new(self.base + 2).read() }
+    }
+
+    // The IRQ and error bits are cleared by writing 1 to them
+    fn clear_status(&self) {
+        unsafe { Port::<u8>::new(self.base + 2).write(BM_STATUS_ERROR | BM_STATUS_IRQ) };
+    }
+}
+
 pub struct AtaPio {
     data_port: Port<u16>,
     error_port: Port<u8>,
@@ -13,39 +165,202 @@ pub struct AtaPio {
 }
 
 impl AtaPio {
-    pub fn read_sectors(&mut self, lba: u32, count: u8, buffer: &mut [u8]) {
-        let flags_were_enabled = interrupts_enabled();
-        // Prevent context switches during timing-sensitive disk IO
-        unsafe { core::arch::asm!("cli") };
+    // Reads buffer.len() / 512 sectors starting at `lba`, split into as many commands as needed
+    pub fn read_sectors(&mut self, lba: u64, buffer: &mut [u8]) -> Result<(), &'static str> {
+        let (bus_master, max_sectors) = self.request_mode();
+        for (i, chunk) in buffer.chunks_mut(max_sectors * 512).enumerate() {
+            let lba = lba + (i * max_sectors) as u64;
+            let result = match bus_master {
+                _ if !IRQ_MODE.load(Ordering::Acquire) => self.read_sectors_polled(lba, chunk),
+                Some(bus_master) => self.read_dma(bus_master, lba, chunk),
+                None => self.read_pio(lba, chunk),
+            };
+            if let Err(e) = result {
+                crate::serial_println!("ATA Error during read_sectors (LBA: {}): {}", lba, e);
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn write_sectors(&mut self, lba: u64, buffer: &[u8]) -> Result<(), &'static str> {
+        let (bus_master, max_sectors) = self.request_mode();
+        for (i, chunk) in buffer.chunks(max_sectors * 512).enumerate() {
+            let lba = lba + (i * max_sectors) as u64;
+            let result = match bus_master {
+                _ if !IRQ_MODE.load(Ordering::Acquire) => self.write_sectors_polled(lba, chunk),
+                Some(bus_master) => self.write_dma(bus_master, lba, chunk),
+                None => self.write_pio(lba, chunk),
+            };
+            if let Err(e) = result {
+                crate::serial_println!("ATA Error during write_sectors (LBA: {}): {}", lba, e);
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    // DMA needs the IRQ to know when it's done, before that everything is polled PIO
+    fn request_mode(&self) -> (Option<BusMaster>, usize) {
+        let bus_master = BUS_MASTER.lock().filter(|_| IRQ_MODE.load(Ordering::Acquire));
+        let max_sectors = if bus_master.is_some() { DMA_MAX_SECTORS } else { PIO_MAX_SECTORS };
+        (bus_master, max_sectors)
+    }
+
+    fn read_dma(
+        &mut self,
+        bus_master: BusMaster,
+        lba: u64,
+        buffer: &mut [u8],
+    ) -> Result<(), &'static str> {
+        let count = sector_count(buffer.len());
+        bus_master.prepare(count * 512, false);
+        let transfer = Transfer::dma(Phase::DmaReading);
+        self.start_transfer(lba, count, CMD_READ_DMA_EXT, transfer, Some(bus_master));
+        wait_for_transfer()?;
+
+        let source = unsafe { core::slice::from_raw_parts(bus_master.buffer(), buffer.len()) };
+        buffer.copy_from_slice(source);
+        Ok(())
+    }
+
+    fn write_dma(
+        &mut self,
+        bus_master: BusMaster,
+        lba: u64,
+        buffer: &[u8],
+    ) -> Result<(), &'static str> {
+        let count = sector_count(buffer.len());
+        let target = unsafe { core::slice::from_raw_parts_mut(bus_master.buffer(), count * 512) };
+        target[..buffer.len()].copy_from_slice(buffer);
+        target[buffer.len()..].fill(0);
 
+        bus_master.prepare(count * 512, true);
+        let transfer = Transfer::dma(Phase::DmaWriting);
+        self.start_transfer(lba, count, CMD_WRITE_DMA_EXT, transfer, Some(bus_master));
+        wait_for_transfer()
+    }
+
+    fn read_pio(&mut self, lba: u64, buffer: &mut [u8]) -> Result<(), &'static str> {
+        let count = sector_count(buffer.len());
+        let transfer = Transfer {
+            buffer: buffer.as_mut_ptr(),
+            len: buffer.len(),
+            sectors_left: count,
+            offset: 0,
+            phase: Phase::Reading,
+        };
+        self.start_transfer(lba, count, CMD_READ_PIO_EXT, transfer, None);
+        wait_for_transfer()
+    }
+
+    fn write_pio(&mut self, lba: u64, buffer: &[u8]) -> Result<(), &'static str> {
+        let count = sector_count(buffer.len());
+        // The pointer is only ever read from while writing
+        let transfer = Transfer {
+            buffer: buffer.as_ptr() as *mut u8,
+            len: buffer.len(),
+            sectors_left: count,
+            offset: 0,
+            phase: Phase::Writing,
+        };
+        self.start_transfer(lba, count, CMD_WRITE_PIO_EXT, transfer, None);
+        wait_for_transfer()
+    }
+
+    // Issues the command and hands the transfer over to the IRQ handler
+    fn start_transfer(
+        &mut self,
+        lba: u64,
+        count: usize,
+        command: u8,
+        mut transfer: Transfer,
+        bus_master: Option<BusMaster>,
+    ) {
+        interrupts::without_interrupts(|| {
+            self.select_lba48(lba, count as u16);
+            unsafe { self.command_port.write(command) };
+
+            if let Some(bus_master) = bus_master {
+                bus_master.start(transfer.phase == Phase::DmaWriting);
+            } else if transfer.phase == Phase::Writing {
+                // No IRQ for the first sector of a PIO write, the drive just waits for the data
+                match self.wait_data_request() {
+                    Ok(()) => self.send_sector(&mut transfer),
+                    Err(_) => transfer.phase = Phase::Failed,
+                }
+            }
+
+            *TRANSFER.lock() = Some(transfer);
+        });
+    }
+
+    // LBA48 registers are two deep: the high bytes go in first, then the low bytes
+    fn select_lba48(&mut self, lba: u64, count: u16) {
         unsafe {
-            // 1. Prepare the drive for a multi-sector read
-            self.device_port.write(0xE0 | ((lba >> 24) & 0x0F) as u8);
-            self.sector_count_port.write(count);
+            self.device_port.write(0x40); // Master drive, LBA addressing
+            self.sector_count_port.write((count >> 8) as u8);
+            self.lba_low_port.write((lba >> 24) as u8);
+            self.lba_mid_port.write((lba >> 32) as u8);
+            self.lba_high_port.write((lba >> 40) as u8);
+            self.sector_count_port.write(count as u8);
             self.lba_low_port.write(lba as u8);
             self.lba_mid_port.write((lba >> 8) as u8);
             self.lba_high_port.write((lba >> 16) as u8);
-            self.command_port.write(0x20); // 0x20 = Read Sectors
         }
+    }
+
+    // Moves one sector from the drive into the transfer buffer
+    fn receive_sector(&mut self, transfer: &mut Transfer) {
+        for _ in 0..256 {
+            let data = unsafe { self.data_port.read() };
+            // Safety check: ensure we don't overflow the provided buffer
+            if transfer.offset + 1 < transfer.len {
+                unsafe {
+                    *transfer.buffer.add(transfer.offset) = data as u8;
+                    *transfer.buffer.add(transfer.offset + 1) = (data >> 8) as u8;
+                }
+            }
+            transfer.offset += 2;
+        }
+        transfer.sectors_left -= 1;
+    }
+
+    // Moves one sector from the transfer buffer to the drive
+    fn send_sector(&mut self, transfer: &mut Transfer) {
+        for _ in 0..256 {
+            let data = if transfer.offset + 1 < transfer.len {
+                let bytes = unsafe { transfer.buffer.add(transfer.offset) };
+                unsafe { (*bytes as u16) | ((*bytes.add(1) as u16) << 8) }
+            } else {
+                0
+            };
+            unsafe { self.data_port.write(data) };
+            transfer.offset += 2;
+        }
+        transfer.sectors_left -= 1;
+    }
+
+    // Polled PIO, used before interrupts are enabled (e.g. while mounting the filesystem)
+    fn read_sectors_polled(&mut self, lba: u64, buffer: &mut [u8]) -> Result<(), &'static str> {
+        let count = sector_count(buffer.len());
+        let flags_were_enabled = interrupts_enabled();
+        // Prevent context switches during timing-sensitive disk IO
+        unsafe { core::arch::asm!("cli") };
+
+        // 1. Prepare the drive for a multi-sector read
+        self.select_lba48(lba, count as u16);
+        unsafe { self.command_port.write(CMD_READ_PIO_EXT) };
 
         // 2. We MUST loop 'count' times
-        for sector in 0..count as usize {
-            // Wait for the drive to finish seeking and fill its internal buffer
-            while self.is_busy() {}
-            if self.has_error() {
-                crate::serial_println!("ATA Error during read_sectors (LBA: {})", lba);
+        for sector in 0..count {
+            // Wait for the drive to finish seeking and fill its internal buffer, DRQ must be
+            // set for each sector
+            if let Err(e) = self.wait_data_request() {
                 if flags_were_enabled { unsafe { core::arch::asm!("sti") }; }
-                return;
+                return Err(e);
             }
 
-            while !self.is_ready() {
-                if self.has_error() {
-                    crate::serial_println!("ATA Error while waiting for ready (LBA: {})", lba);
-                    if flags_were_enabled { unsafe { core::arch::asm!("sti") }; }
-                    return;
-                }
-            } // DRQ must be set for each sector
-
             // 3. Transfer 256 words (512 bytes) for THIS sector
             for i in 0..256 {
                 let data = unsafe { self.data_port.read() };
@@ -60,56 +375,143 @@ impl AtaPio {
         }
 
         if flags_were_enabled { unsafe { core::arch::asm!("sti") }; }
+        Ok(())
     }
 
-    pub fn write_sectors(&mut self, lba: u32, count: u8, buffer: &[u8]) {
+    fn write_sectors_polled(&mut self, lba: u64, buffer: &[u8]) -> Result<(), &'static str> {
+        let count = sector_count(buffer.len());
         let flags_were_enabled = interrupts_enabled();
         unsafe { core::arch::asm!("cli") };
 
-        unsafe {
-            self.device_port.write(0xE0 | ((lba >> 24) & 0x0F) as u8);
-            self.sector_count_port.write(count);
-            self.lba_low_port.write(lba as u8);
-            self.lba_mid_port.write((lba >> 8) as u8);
-            self.lba_high_port.write((lba >> 16) as u8);
-            self.command_port.write(0x30); // Write Sectors command
-        }
+        self.select_lba48(lba, count as u16);
+        unsafe { self.command_port.write(CMD_WRITE_PIO_EXT) };
 
-        for sector in 0..count as usize {
-            while self.is_busy() {}
-            if self.has_error() {
-                crate::serial_println!("ATA Error during write_sectors (LBA: {})", lba);
+        for sector in 0..count {
+            // Drive says "Okay, give me the next 512 bytes"
+            if let Err(e) = self.wait_data_request() {
                 if flags_were_enabled { unsafe { core::arch::asm!("sti") }; }
-                return;
+                return Err(e);
             }
 
-            while !self.is_ready() {
-                if self.has_error() {
-                    crate::serial_println!("ATA Error while waiting for ready in write (LBA: {})", lba);
-                    if flags_were_enabled { unsafe { core::arch::asm!("sti") }; }
-                    return;
-                }
-            } // Drive says "Okay, give me the next 512 bytes"
-
             for i in 0..256 {
                 let offset = (sector * 512) + (i * 2);
-                let data = (buffer[offset] as u16) | ((buffer[offset + 1] as u16) << 8);
+                let data = if offset + 1 < buffer.len() {
+                    (buffer[offset] as u16) | ((buffer[offset + 1] as u16) << 8)
+                } else {
+                    0
+                };
                 unsafe { self.data_port.write(data) };
             }
         }
 
         // Always flush after a write operation
         unsafe {
-            self.command_port.write(0xE7);
+            self.command_port.write(CMD_FLUSH_CACHE_EXT);
         }
-        while self.is_busy() {}
+        let flushed = self.wait_flushed();
         if flags_were_enabled { unsafe { core::arch::asm!("sti") }; }
+        flushed
     }
 }
 
 impl AtaPio {
     pub fn init() -> Self {
-        let mut bus = Self {
+        let mut bus = Self::primary();
+
+        // Select the master drive
+        unsafe {
+            bus.device_port.write(0xA0);
+        }
+
+        bus
+    }
+
+    // Asks the master drive how many sectors it has. Polled, so it works before interrupts
+    // are up. The LBA48 count (words 100-103) is used when the drive supports it.
+    pub fn identify(&mut self) -> Result<u64, &'static str> {
+        interrupts::without_interrupts(|| {
+            unsafe {
+                self.device_port.write(0xA0);
+                self.sector_count_port.write(0);
+                self.lba_low_port.write(0);
+                self.lba_mid_port.write(0);
+                self.lba_high_port.write(0);
+                self.command_port.write(CMD_IDENTIFY);
+            }
+            // A status of 0 means nothing is attached, 0xFF a floating bus with no controller
+            let status = unsafe { self.status_port.read() };
+            if status == 0 || status == 0xFF {
+                return Err("no drive on the primary ATA bus");
+            }
+            if self.poll_status(|status| (status & 0x80) == 0).is_none() {
+                return Err("primary ATA drive stays busy");
+            }
+            // ATAPI and SATA drives put their signature here instead of answering
+            if unsafe { self.lba_mid_port.read() != 0 || self.lba_high_port.read() != 0 } {
+                return Err("primary ATA drive is not an ATA disk");
+            }
+            match self.poll_status(|status| (status & 0x09) != 0) {
+                None => return Err("primary ATA drive never answered IDENTIFY"),
+                Some(status) if (status & 0x08) == 0 => return Err("drive rejected IDENTIFY"),
+                Some(_) => {}
+            }
+
+            let mut words = [0u16; 256];
+            for word in words.iter_mut() {
+                *word = unsafe { self.data_port.read() };
+            }
+            let word = |i: usize| words[i] as u64;
+            if (word(83) & (1 << 10)) != 0 {
+                Ok(word(100) | (word(101) << 16) | (word(102) << 32) | (word(103) << 48))
+            } else {
+                Ok(word(60) | (word(61) << 16))
+            }
+        })
+    }
+
+    // Reads the status until `done` accepts it and returns it, or None after POLL_LIMIT reads
+    fn poll_status(&mut self, done: impl Fn(u8) -> bool) -> Option<u8> {
+        (0..POLL_LIMIT)
+            .map(|_| unsafe { self.status_port.read() })
+            .find(|&status| done(status))
+    }
+
+    // Makes the drive write its cache out. Every write already ends with this, it's for
+    // callers that have to be sure, like shutdown.
+    pub fn flush_cache(&mut self) -> Result<(), &'static str> {
+        if IRQ_MODE.load(Ordering::Acquire) {
+            // No data either way, the IRQ after the command finishes it
+            let transfer = Transfer::dma(Phase::Flushing);
+            self.start_transfer(0, 0, CMD_FLUSH_CACHE_EXT, transfer, None);
+            return wait_for_transfer();
+        }
+        interrupts::without_interrupts(|| {
+            unsafe { self.command_port.write(CMD_FLUSH_CACHE_EXT) };
+            self.wait_flushed()
+        })
+    }
+
+    // Polls until a flush command has finished
+    fn wait_flushed(&mut self) -> Result<(), &'static str> {
+        match self.poll_status(|status| (status & 0x80) == 0) {
+            None => Err("drive never finished flushing its cache"),
+            Some(status) if (status & 0x01) != 0 => Err("drive failed to flush its cache"),
+            Some(_) => Ok(()),
+        }
+    }
+
+    // Polls until the drive is ready to move the next sector (DRQ) or reports an error
+    fn wait_data_request(&mut self) -> Result<(), &'static str> {
+        match self.poll_status(|status| (status & 0x80) == 0 && (status & 0x09) != 0) {
+            None => Err("drive timed out"),
+            Some(status) if (status & 0x01) != 0 => Err("drive reported an error"),
+            Some(_) => Ok(()),
+        }
+    }
+
+    // The primary bus registers, without touching the drive
+    fn primary() -> Self {
+        Self {
             data_port: Port::new(0x1F0),
             error_port: Port::new(0x1F1),
             sector_count_port: Port::new(0x1F2),
@@ -119,14 +521,7 @@ impl AtaPio {
             device_port: Port::new(0x1F6),
             command_port: Port::new(0x1F7),
             status_port: Port::new(0x1F7),
-        };
-
-        // Select the master drive
-        unsafe {
-            bus.device_port.write(0xA0);
         }
-
-        bus
     }
 
     pub fn is_busy(&mut self) -> bool {
@@ -142,8 +537,134 @@ impl AtaPio {
     }
 }
 
+impl Transfer {
+    // DMA transfers never touch a buffer from the IRQ handler
+    fn dma(phase: Phase) -> Self {
+        Self {
+            buffer: core::ptr::null_mut(),
+            len: 0,
+            sectors_left: 0,
+            offset: 0,
+            phase,
+        }
+    }
+}
+
+// Sleeps until the IRQ handler has finished the transfer in flight, or gives up on it after
+// COMMAND_TIMEOUT_MS. Either way the transfer is gone afterwards, so a late IRQ can't touch
+// the buffer.
+fn wait_for_transfer() -> Result<(), &'static str> {
+    let deadline = crate::timer::get_uptime_ms() + COMMAND_TIMEOUT_MS;
+    DISK_WAIT.wait_until_deadline(
+        || matches!(TRANSFER.lock().as_ref().map(|t| t.phase), Some(Phase::Done | Phase::Failed)),
+        Some(deadline),
+    );
+
+    let finished = TRANSFER.lock().take();
+    match finished.map(|transfer| transfer.phase) {
+        Some(Phase::Done) => Ok(()),
+        Some(Phase::Failed) => Err("drive reported an error"),
+        _ => {
+            if let Some(bus_master) = *BUS_MASTER.lock() {
+                bus_master.stop();
+            }
+            Err("drive timed out")
+        }
+    }
+}
+
+// Partial sectors still need a whole sector on the wire
+fn sector_count(len: usize) -> usize {
+    len.div_ceil(512)
+}
+
 fn interrupts_enabled() -> bool {
     let rflags: u64;
     unsafe { core::arch::asm!("pushfq; pop {}", out(reg) rflags, options(nomem, preserves_flags)) };
     (rflags & (1 << 9)) != 0
 }
+
+// Turns on bus-master DMA through a PCI IDE controller, transfers use PIO until this succeeds
+pub fn attach_bus_master(device: &PciDevice) -> Result<(), &'static str> {
+    let bus_master = BusMaster::init(device)?;
+    crate::println!("ATA: bus-master DMA at I/O {:#x}", bus_master.base);
+    *BUS_MASTER.lock() = Some(bus_master);
+    Ok(())
+}
+
+// Switches from polled PIO to IRQ14 completions. Call once interrupts are enabled.
+pub fn enable_interrupts() {
+    unsafe {
+        Port::<u8>::new(0x3F6).write(0x00); // Clear nIEN in the device control register
+    }
+    IRQ_MODE.store(true, Ordering::Release);
+}
+
+// Back to polling, for when the CPU that takes IRQ14 may be gone
+pub fn disable_interrupts() {
+    IRQ_MODE.store(false, Ordering::Release);
+}
+
+// Called from the IRQ14/IRQ15 handler. Reading the status register also
+// acknowledges the interrupt on the drive side.
+pub fn on_irq(secondary: bool) {
+    if secondary {
+        // Nothing of ours sits on the secondary bus, just acknowledge it
+        unsafe { Port::<u8>::new(0x177).read() };
+        return;
+    }
+
+    let mut guard = TRANSFER.lock();
+
+    // A bus master raises the IRQ once the whole command is done, stop it before anything else
+    let mut dma_failed = false;
+    if let Some(Phase::DmaReading | Phase::DmaWriting) = guard.as_ref().map(|t| t.phase) {
+        let Some(bus_master) = *BUS_MASTER.lock() else {
+            return;
+        };
+        let status = bus_master.status();
+        if (status & BM_STATUS_IRQ) == 0 {
+            return; // Not from our controller
+        }
+        bus_master.stop();
+        bus_master.clear_status();
+        dma_failed = (status & BM_STATUS_ERROR) != 0;
+    }
+
+    let mut ata = AtaPio::primary();
+    let status = unsafe { ata.status_port.read() };
+    if (status & 0x80) != 0 {
+        return; // Still busy, not for us
+    }
+
+    let Some(transfer) = guard.as_mut() else {
+        return;
+    };
+
+    if (status & 0x01) != 0 || dma_failed {
+        transfer.phase = Phase::Failed;
+    } else {
+        match transfer.phase {
+            Phase::Reading => {
+                ata.receive_sector(transfer);
+                if transfer.sectors_left == 0 {
+                    transfer.phase = Phase::Done;
+                }
+            }
+            Phase::Writing if transfer.sectors_left > 0 => ata.send_sector(transfer),
+            Phase::Writing | Phase::DmaWriting => {
+                // Always flush after a write operation, its IRQ completes the request
+                unsafe { ata.command_port.write(CMD_FLUSH_CACHE_EXT) };
+                transfer.phase = Phase::Flushing;
+            }
+            Phase::DmaReading | Phase::Flushing => transfer.phase = Phase::Done,
+            Phase::Done | Phase::Failed => {}
+        }
+    }
+
+    let finished = matches!(transfer.phase, Phase::Done | Phase::Failed);
+    drop(guard);
+    if finished {
+        DISK_WAIT.wake_all();
+    }
+}
@@ -0,0 +1,60 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+
+// The HPET's main counter. It runs at a fixed rate the hardware reports, which makes it a
+// better ruler than the PIT for measuring the TSC. Its comparators are left alone, the
+// interrupts come from the local APIC timers.
+
+const REG_CAPABILITIES: u64 = 0x00;
+const REG_CONFIG: u64 = 0x10;
+const REG_MAIN_COUNTER: u64 = 0xF0;
+
+const CONFIG_ENABLE: u64 = 1;
+// The spec caps the tick period at 100ns, anything longer is a broken table
+const MAX_PERIOD_FS: u64 = 100_000_000;
+const FEMTOSECONDS_PER_MS: u64 = 1_000_000_000_000;
+
+// Virtual address of the registers, 0 if there is no HPET
+static BASE: AtomicU64 = AtomicU64::new(0);
+static PERIOD_FS: AtomicU64 = AtomicU64::new(0);
+
+fn read(reg: u64) -> u64 {
+    unsafe { ((BASE.load(Ordering::Relaxed) + reg) as *const u64).read_volatile() }
+}
+
+fn write(reg: u64, value: u64) {
+    unsafe { ((BASE.load(Ordering::Relaxed) + reg) as *mut u64).write_volatile(value) }
+}
+
+// Finds the HPET through ACPI and starts its main counter. Returns false if there is none.
+pub fn init() -> bool {
+    let Some(address) = crate::acpi::hpet_address() else {
+        return false;
+    };
+    BASE.store(crate::memory::map_mmio(address, 0x400), Ordering::Relaxed);
+
+    let period = read(REG_CAPABILITIES) >> 32;
+    if period == 0 || period > MAX_PERIOD_FS {
+        BASE.store(0, Ordering::Relaxed);
+        return false;
+    }
+    PERIOD_FS.store(period, Ordering::Relaxed);
+    write(REG_CONFIG, read(REG_CONFIG) | CONFIG_ENABLE);
+    true
+}
+
+pub fn available() -> bool {
+    BASE.load(Ordering::Relaxed) != 0
+}
+
+pub fn counter() -> u64 {
+    read(REG_MAIN_COUNTER)
+}
+
+// Spins for `ms` milliseconds
+pub fn wait_ms(ms: u64) {
+    let ticks = ms * FEMTOSECONDS_PER_MS / PERIOD_FS.load(Ordering::Relaxed);
+    let start = counter();
+    while counter().wrapping_sub(start) < ticks {
+        core::hint::spin_loop();
+    }
+}
@@ -0,0 +1,118 @@
+use super::keyboard::KeyEvent;
+use crate::multitasker::wait_queue::WaitQueue;
+use crate::sync::IrqMutex;
+use alloc::collections::VecDeque;
+
+// Everything done with the keyboard and mouse in one queue, in the order it happened and
+// stamped with the uptime, so a program that wants both doesn't have to merge two streams
+// and guess which came first. Like the keyboard's queues it belongs to the foreground group
+// and is emptied when that changes.
+
+pub const INPUT_KEY: u16 = 1;
+pub const INPUT_MOTION: u16 = 2;
+pub const INPUT_BUTTON: u16 = 3;
+pub const INPUT_WHEEL: u16 = 4;
+
+// Button codes, the same bits as the mouse's button mask
+pub const BUTTON_LEFT: u16 = 1 << 0;
+pub const BUTTON_RIGHT: u16 = 1 << 1;
+pub const BUTTON_MIDDLE: u16 = 1 << 2;
+pub const BUTTON_4: u16 = 1 << 3;
+pub const BUTTON_5: u16 = 1 << 4;
+
+// A program that stops reading loses the oldest events past this
+const MAX_EVENTS: usize = 256;
+
+// Same layout as InputEvent in rustos_user
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct InputEvent {
+    pub time_ns: u64,   // Uptime when it happened
+    pub kind: u16,      // INPUT_*
+    pub code: u16,      // The keycode of a key, BUTTON_* of a button
+    pub modifiers: u16, // Keyboard MOD_* at the time, also for mouse events
+    pub pressed: u8,    // 1 down, 0 up, for keys and buttons
+    _reserved: u8,
+    pub dx: i32,      // Motion, positive is right
+    pub dy: i32,      // Motion, positive is up, or wheel clicks, positive is away from the user
+    pub unicode: u32, // The character a key types, 0 if none
+    _reserved2: u32,
+}
+
+static EVENTS: IrqMutex<VecDeque<InputEvent>> = IrqMutex::new(VecDeque::new());
+static INPUT_WAIT: WaitQueue = WaitQueue::new();
+
+fn push(kind: u16, fill: impl FnOnce(&mut InputEvent)) {
+    let mut event = InputEvent {
+        time_ns: crate::timer::uptime_ns(),
+        kind,
+        modifiers: super::keyboard::modifiers(),
+        ..Default::default()
+    };
+    fill(&mut event);
+
+    {
+        let mut events = EVENTS.lock();
+        // Motion nobody has read yet is added up, a moving mouse can't crowd out the keys
+        match events.back_mut() {
+            Some(last) if kind == INPUT_MOTION && last.kind == INPUT_MOTION => {
+                last.dx = last.dx.saturating_add(event.dx);
+                last.dy = last.dy.saturating_add(event.dy);
+                last.time_ns = event.time_ns;
+                last.modifiers = event.modifiers;
+            }
+            _ => {
+                if events.len() == MAX_EVENTS {
+                    events.pop_front();
+                }
+                events.push_back(event);
+            }
+        }
+    }
+    INPUT_WAIT.wake_all();
+}
+
+pub fn push_key(key: &KeyEvent) {
+    push(INPUT_KEY, |event| {
+        event.code = key.keycode;
+        event.modifiers = key.modifiers;
+        event.pressed = key.pressed;
+        event.unicode = key.unicode;
+    });
+}
+
+pub fn push_motion(dx: i32, dy: i32) {
+    push(INPUT_MOTION, |event| {
+        event.dx = dx;
+        event.dy = dy;
+    });
+}
+
+pub fn push_button(button: u16, pressed: bool) {
+    push(INPUT_BUTTON, |event| {
+        event.code = button;
+        event.pressed = pressed as u8;
+    });
+}
+
+// `clicks` positive is away from the user
+pub fn push_wheel(clicks: i32) {
+    push(INPUT_WHEEL, |event| event.dy = clicks);
+}
+
+pub fn pop() -> Option<InputEvent> {
+    EVENTS.lock().pop_front()
+}
+
+pub fn clear() {
+    EVENTS.lock().clear();
+}
+
+// Blocks until the current task is in the foreground with events to read, or the uptime
+// reaches `deadline_ms`
+pub fn wait(deadline_ms: Option<u64>) {
+    INPUT_WAIT.wait_until_deadline(
+        || super::keyboard::current_task_has_input() && !EVENTS.lock().is_empty(),
+        deadline_ms,
+    );
+}
@@ -1,197 +1,599 @@
-use core::sync::atomic::{AtomicU64, Ordering};
+use crate::multitasker::wait_queue::WaitQueue;
+use crate::sync::IrqMutex;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use crossbeam_queue::ArrayQueue;
 use lazy_static::lazy_static;
+use x86_64::instructions::port::Port;
 
 lazy_static! {
+    // Raw set 1 bytes, prefixes included, for programs that decode keys themselves
     pub static ref SCANCODE_QUEUE: ArrayQueue<u8> = ArrayQueue::new(100);
+    // The same keys decoded through the keymap
+    static ref KEY_EVENTS: ArrayQueue<KeyEvent> = ArrayQueue::new(100);
 }
 
-pub const SHELL_TASK_ID: u64 = 6;
-static KEYBOARD_FOCUS: AtomicU64 = AtomicU64::new(SHELL_TASK_ID);
+// The process group that owns the keyboard. Only tasks in this group get scancodes.
+// The kernel's group holds it until init is launched, which hands it to init's group.
+static FOREGROUND_GROUP: AtomicU64 = AtomicU64::new(0);
 
-static mut LSHIFT: bool = false;
-static mut RSHIFT: bool = false;
-static mut LCTRL: bool = false;
-static mut RCTRL: bool = false;
-static mut KEY_HELD: [bool; 128] = [false; 128];
+// Set by Ctrl+Z, the scheduler stops the foreground group on its next tick.
+static SUSPEND_REQUESTED: AtomicBool = AtomicBool::new(false);
 
-fn is_shift() -> bool {
-    unsafe { LSHIFT || RSHIFT }
+// Tasks sleeping in wait_key_byte
+static KEY_WAIT: WaitQueue = WaitQueue::new();
+
+static DECODER: IrqMutex<Decoder> = IrqMutex::new(Decoder::new());
+
+/************
+ * KEYCODES *
+ ************/
+// A keycode is the key's set 1 make code, plus 0x80 if it comes with an E0 prefix
+pub const KEYCODE_EXTENDED: u8 = 0x80;
+
+pub const KEYCODE_ESCAPE: u8 = 0x01;
+pub const KEYCODE_BACKSPACE: u8 = 0x0E;
+pub const KEYCODE_TAB: u8 = 0x0F;
+pub const KEYCODE_ENTER: u8 = 0x1C;
+pub const KEYCODE_LEFT_CTRL: u8 = 0x1D;
+pub const KEYCODE_LEFT_SHIFT: u8 = 0x2A;
+pub const KEYCODE_RIGHT_SHIFT: u8 = 0x36;
+pub const KEYCODE_LEFT_ALT: u8 = 0x38;
+pub const KEYCODE_SPACE: u8 = 0x39;
+pub const KEYCODE_CAPS_LOCK: u8 = 0x3A;
+pub const KEYCODE_NUM_LOCK: u8 = 0x45;
+pub const KEYCODE_SCROLL_LOCK: u8 = 0x46;
+pub const KEYCODE_KEYPAD_7: u8 = 0x47;
+pub const KEYCODE_KEYPAD_MINUS: u8 = 0x4A;
+pub const KEYCODE_KEYPAD_PLUS: u8 = 0x4E;
+pub const KEYCODE_KEYPAD_DOT: u8 = 0x53;
+pub const KEYCODE_KEYPAD_ENTER: u8 = 0x9C;
+pub const KEYCODE_RIGHT_CTRL: u8 = 0x9D;
+pub const KEYCODE_ALTGR: u8 = 0xB8;
+pub const KEYCODE_PAUSE: u8 = 0xC5; // Sent as E1 1D 45, no key has E0 45
+pub const KEYCODE_HOME: u8 = 0xC7;
+pub const KEYCODE_UP: u8 = 0xC8;
+pub const KEYCODE_LEFT: u8 = 0xCB;
+pub const KEYCODE_RIGHT: u8 = 0xCD;
+pub const KEYCODE_END: u8 = 0xCF;
+pub const KEYCODE_DOWN: u8 = 0xD0;
+pub const KEYCODE_DELETE: u8 = 0xD3;
+pub const KEYCODE_LEFT_META: u8 = 0xDB;
+pub const KEYCODE_RIGHT_META: u8 = 0xDC;
+
+// Modifier bits of a KeyEvent
+pub const MOD_SHIFT: u16 = 1 << 0;
+pub const MOD_CTRL: u16 = 1 << 1;
+pub const MOD_ALT: u16 = 1 << 2;
+pub const MOD_ALTGR: u16 = 1 << 3;
+pub const MOD_META: u16 = 1 << 4;
+pub const MOD_CAPS_LOCK: u16 = 1 << 5;
+pub const MOD_NUM_LOCK: u16 = 1 << 6;
+pub const MOD_SCROLL_LOCK: u16 = 1 << 7;
+
+// What SYS_GET_KEY returns for the cursor keys
+pub const KEY_UP: u8 = 0x80;
+pub const KEY_DOWN: u8 = 0x81;
+pub const KEY_LEFT: u8 = 0x82;
+pub const KEY_RIGHT: u8 = 0x83;
+pub const KEY_HOME: u8 = 0x84;
+pub const KEY_END: u8 = 0x85;
+pub const KEY_DELETE: u8 = 0x86;
+
+// A key going down or up. Same layout as KeyEvent in rustos_user.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct KeyEvent {
+    pub keycode: u16,
+    pub modifiers: u16, // Held and locked when the key changed, MOD_*
+    pub pressed: u8,    // 1 down, 0 up
+    _reserved: [u8; 3],
+    pub unicode: u32, // The character it types, 0 if none
+}
+
+impl KeyEvent {
+    // The byte SYS_GET_KEY hands out: ASCII, Ctrl+<letter> as its control code (Ctrl+A =
+    // 0x01, ...) and the cursor keys as KEY_*. Releases and everything else have none.
+    pub fn legacy_byte(&self) -> Option<u8> {
+        if self.pressed == 0 {
+            return None;
+        }
+        let cursor = match self.keycode as u8 {
+            KEYCODE_UP => Some(KEY_UP),
+            KEYCODE_DOWN => Some(KEY_DOWN),
+            KEYCODE_LEFT => Some(KEY_LEFT),
+            KEYCODE_RIGHT => Some(KEY_RIGHT),
+            KEYCODE_HOME => Some(KEY_HOME),
+            KEYCODE_END => Some(KEY_END),
+            KEYCODE_DELETE => Some(KEY_DELETE),
+            _ => None,
+        };
+        if cursor.is_some() {
+            return cursor;
+        }
+        let byte = u8::try_from(self.unicode)
+            .ok()
+            .filter(|b| b.is_ascii() && *b != 0)?;
+        if self.modifiers & MOD_CTRL != 0 && byte.is_ascii_alphabetic() {
+            return Some(byte & 0x1F);
+        }
+        Some(byte)
+    }
+}
+
+/***********
+ * DECODER *
+ ***********/
+// Turns the byte stream into key events and keeps the modifier and lock state
+struct Decoder {
+    extended: bool,    // The last byte was E0
+    pause_bytes: u8,   // Bytes of an E1 sequence still to come
+    held: [bool; 256], // By keycode, before Num Lock turns keypad digits into cursor keys
+    locks: u16,        // MOD_CAPS_LOCK | MOD_NUM_LOCK | MOD_SCROLL_LOCK
+    leds: Leds,
+}
+
+enum Decoded {
+    Nothing, // A keyboard reply, or a repeat of a held key
+    Prefix,  // Part of a multi-byte sequence
+    Key(KeyEvent),
+}
+
+impl Decoder {
+    const fn new() -> Self {
+        Self {
+            extended: false,
+            pause_bytes: 0,
+            held: [false; 256],
+            locks: 0,
+            leds: Leds::new(),
+        }
+    }
+
+    fn decode(&mut self, byte: u8) -> Decoded {
+        match byte {
+            KEYBOARD_ACK | KEYBOARD_RESEND => {
+                self.leds.on_reply(byte, self.locks);
+                return Decoded::Nothing;
+            }
+            0xE0 => {
+                self.extended = true;
+                return Decoded::Prefix;
+            }
+            // Pause: E1 1D 45 when pressed, E1 9D C5 right after, it never repeats
+            0xE1 => {
+                self.pause_bytes = 2;
+                return Decoded::Prefix;
+            }
+            _ if self.pause_bytes > 0 => {
+                self.pause_bytes -= 1;
+                if self.pause_bytes > 0 {
+                    return Decoded::Prefix;
+                }
+                let pressed = byte & 0x80 == 0;
+                return Decoded::Key(self.event(KEYCODE_PAUSE, pressed));
+            }
+            _ => {}
+        }
+
+        let extended = core::mem::take(&mut self.extended);
+        let pressed = byte & 0x80 == 0;
+        let keycode = (byte & 0x7F) | if extended { KEYCODE_EXTENDED } else { 0 };
+        // The fake shifts some keyboards wrap around the cursor keys
+        if keycode == KEYCODE_LEFT_SHIFT | KEYCODE_EXTENDED
+            || keycode == KEYCODE_RIGHT_SHIFT | KEYCODE_EXTENDED
+        {
+            return Decoded::Nothing;
+        }
+
+        // Typematic repeats of a held key are dropped
+        if pressed && self.held[keycode as usize] {
+            return Decoded::Nothing;
+        }
+        self.held[keycode as usize] = pressed;
+
+        if pressed {
+            let lock = match keycode {
+                KEYCODE_CAPS_LOCK => MOD_CAPS_LOCK,
+                KEYCODE_NUM_LOCK => MOD_NUM_LOCK,
+                KEYCODE_SCROLL_LOCK => MOD_SCROLL_LOCK,
+                _ => 0,
+            };
+            if lock != 0 {
+                self.locks ^= lock;
+                self.leds.update(self.locks);
+            }
+        }
+
+        // Without Num Lock the keypad is a second set of cursor keys
+        let keycode = match keycode {
+            KEYCODE_KEYPAD_MINUS | KEYCODE_KEYPAD_PLUS => keycode,
+            KEYCODE_KEYPAD_7..=KEYCODE_KEYPAD_DOT if self.locks & MOD_NUM_LOCK == 0 => {
+                keycode | KEYCODE_EXTENDED
+            }
+            _ => keycode,
+        };
+        Decoded::Key(self.event(keycode, pressed))
+    }
+
+    fn modifiers(&self) -> u16 {
+        let held = |keys: &[u8], modifier: u16| {
+            if keys.iter().any(|&k| self.held[k as usize]) {
+                modifier
+            } else {
+                0
+            }
+        };
+        self.locks
+            | held(&[KEYCODE_LEFT_SHIFT, KEYCODE_RIGHT_SHIFT], MOD_SHIFT)
+            | held(&[KEYCODE_LEFT_CTRL, KEYCODE_RIGHT_CTRL], MOD_CTRL)
+            | held(&[KEYCODE_LEFT_ALT], MOD_ALT)
+            | held(&[KEYCODE_ALTGR], MOD_ALTGR)
+            | held(&[KEYCODE_LEFT_META, KEYCODE_RIGHT_META], MOD_META)
+    }
+
+    fn event(&self, keycode: u8, pressed: bool) -> KeyEvent {
+        let modifiers = self.modifiers();
+        let unicode = match keycode {
+            KEYCODE_ESCAPE => Some('\x1B'),
+            KEYCODE_BACKSPACE => Some('\x08'),
+            KEYCODE_TAB => Some('\t'),
+            KEYCODE_ENTER | KEYCODE_KEYPAD_ENTER => Some('\n'),
+            KEYCODE_SPACE => Some(' '),
+            _ => super::keymap::lookup(
+                keycode,
+                modifiers & MOD_SHIFT != 0,
+                modifiers & MOD_ALTGR != 0,
+                modifiers & MOD_CAPS_LOCK != 0,
+            ),
+        };
+        KeyEvent {
+            keycode: keycode as u16,
+            modifiers,
+            pressed: pressed as u8,
+            _reserved: [0; 3],
+            unicode: unicode.map_or(0, |c| c as u32),
+        }
+    }
+
+    // Forgets held keys, the locks stay as they are
+    fn release_all(&mut self) {
+        self.extended = false;
+        self.pause_bytes = 0;
+        self.held = [false; 256];
+    }
+}
+
+/********
+ * LEDS *
+ ********/
+// Setting the lock LEDs takes two bytes, each acknowledged by the keyboard. The ACKs come in
+// through the IRQ like scancodes, so each byte is sent when the previous one is answered.
+const KEYBOARD_DATA: u16 = 0x60;
+const KEYBOARD_STATUS: u16 = 0x64;
+const STATUS_INPUT_FULL: u8 = 0x02;
+const KEYBOARD_SET_LEDS: u8 = 0xED;
+const KEYBOARD_ACK: u8 = 0xFA;
+const KEYBOARD_RESEND: u8 = 0xFE;
+
+const LED_SCROLL_LOCK: u8 = 1 << 0;
+const LED_NUM_LOCK: u8 = 1 << 1;
+const LED_CAPS_LOCK: u8 = 1 << 2;
+
+#[derive(Clone, Copy, PartialEq)]
+enum LedState {
+    Idle,
+    SentCommand,
+    SentMask(u8),
+}
+
+struct Leds {
+    state: LedState,
+    shown: Option<u8>, // What the keyboard was last told, None before the first time
+}
+
+impl Leds {
+    const fn new() -> Self {
+        Self {
+            state: LedState::Idle,
+            shown: None,
+        }
+    }
+
+    fn update(&mut self, locks: u16) {
+        if self.state == LedState::Idle && self.shown != Some(led_mask(locks)) {
+            send_to_keyboard(KEYBOARD_SET_LEDS);
+            self.state = LedState::SentCommand;
+        }
+    }
+
+    fn on_reply(&mut self, reply: u8, locks: u16) {
+        match (self.state, reply) {
+            (LedState::SentCommand, KEYBOARD_ACK) => {
+                let mask = led_mask(locks);
+                send_to_keyboard(mask);
+                self.state = LedState::SentMask(mask);
+            }
+            (LedState::SentMask(mask), KEYBOARD_ACK) => {
+                self.shown = Some(mask);
+                self.state = LedState::Idle;
+                // A lock key may have been pressed while we were busy
+                self.update(locks);
+            }
+            (LedState::SentCommand, KEYBOARD_RESEND) => send_to_keyboard(KEYBOARD_SET_LEDS),
+            (LedState::SentMask(mask), KEYBOARD_RESEND) => send_to_keyboard(mask),
+            _ => {}
+        }
+    }
+}
+
+fn led_mask(locks: u16) -> u8 {
+    let mut mask = 0;
+    if locks & MOD_SCROLL_LOCK != 0 {
+        mask |= LED_SCROLL_LOCK;
+    }
+    if locks & MOD_NUM_LOCK != 0 {
+        mask |= LED_NUM_LOCK;
+    }
+    if locks & MOD_CAPS_LOCK != 0 {
+        mask |= LED_CAPS_LOCK;
+    }
+    mask
+}
+
+fn send_to_keyboard(byte: u8) {
+    unsafe {
+        let mut status = Port::<u8>::new(KEYBOARD_STATUS);
+        for _ in 0..100_000 {
+            if status.read() & STATUS_INPUT_FULL == 0 {
+                break;
+            }
+            core::hint::spin_loop();
+        }
+        Port::<u8>::new(KEYBOARD_DATA).write(byte);
+    }
 }
 
-fn is_ctrl() -> bool {
-    unsafe { LCTRL || RCTRL }
+/**************
+ * FOREGROUND *
+ **************/
+pub fn foreground_group() -> u64 {
+    FOREGROUND_GROUP.load(Ordering::Acquire)
 }
 
-pub fn focused_task() -> u64 {
-    KEYBOARD_FOCUS.load(Ordering::Acquire)
+pub fn is_foreground(pgid: u64) -> bool {
+    foreground_group() == pgid
 }
 
-pub fn task_has_focus(task_id: u64) -> bool {
-    focused_task() == task_id
+// Whether the running task may take keyboard input, i.e. it is in the foreground group.
+// If the scheduler is busy and the task can't be looked at, input is allowed rather
+// than dropped on the floor.
+pub fn current_task_has_input() -> bool {
+    let foreground = foreground_group();
+    crate::multitasker::scheduler::SCHEDULER
+        .try_lock_queue()
+        .and_then(|queue| queue.current.as_ref().map(|task| task.pgid))
+        .map(|pgid| pgid == foreground)
+        .unwrap_or(true)
 }
 
-pub fn set_focus(task_id: u64) {
-    KEYBOARD_FOCUS.store(task_id, Ordering::Release);
+pub fn set_foreground_group(pgid: u64) {
+    FOREGROUND_GROUP.store(pgid, Ordering::Release);
+}
+
+pub fn request_suspend() {
+    SUSPEND_REQUESTED.store(true, Ordering::Release);
+}
+
+pub fn take_suspend_request() -> bool {
+    SUSPEND_REQUESTED.swap(false, Ordering::AcqRel)
 }
 
 pub fn clear_scancodes() {
     while SCANCODE_QUEUE.pop().is_some() {}
+    while KEY_EVENTS.pop().is_some() {}
+    super::input::clear();
 }
 
 pub fn reset_state() {
     clear_scancodes();
-    unsafe {
-        LSHIFT = false;
-        RSHIFT = false;
-        LCTRL = false;
-        RCTRL = false;
-        KEY_HELD = [false; 128];
-    }
+    DECODER.lock().release_all();
 }
 
-pub fn set_focus_and_clear(task_id: u64) {
+pub fn set_foreground_and_clear(pgid: u64) {
     reset_state();
-    set_focus(task_id);
+    set_foreground_group(pgid);
+}
+
+/*********
+ * INPUT *
+ *********/
+// Loads the built-in keymap and turns the lock LEDs off to match
+pub fn init() {
+    super::keymap::init();
+    let mut decoder = DECODER.lock();
+    let locks = decoder.locks;
+    decoder.leds.update(locks);
 }
 
+// Called from the keyboard IRQ with each byte the keyboard sends
 pub fn push_scancode(scancode: u8) {
-    if scancode == 0xE0 {
-        let _ = SCANCODE_QUEUE.push(scancode);
+    let event = match DECODER.lock().decode(scancode) {
+        Decoded::Nothing => return,
+        Decoded::Prefix => {
+            let _ = SCANCODE_QUEUE.push(scancode);
+            return;
+        }
+        Decoded::Key(event) => event,
+    };
+
+    // Ctrl+Z never reaches the program, it suspends the whole foreground group.
+    if event.pressed != 0
+        && event.modifiers & MOD_CTRL != 0
+        && (event.unicode == 'z' as u32 || event.unicode == 'Z' as u32)
+    {
+        request_suspend();
         return;
     }
+    let _ = SCANCODE_QUEUE.push(scancode);
+    let _ = KEY_EVENTS.push(event);
+    super::input::push_key(&event);
+    KEY_WAIT.wake_all();
+}
 
-    let key = (scancode & 0x7F) as usize;
-    let released = (scancode & 0x80) != 0;
+// The modifiers held and locked right now, MOD_*
+pub fn modifiers() -> u16 {
+    DECODER.lock().modifiers()
+}
 
-    unsafe {
-        if released {
-            KEY_HELD[key] = false;
-            let _ = SCANCODE_QUEUE.push(scancode);
-        } else if !KEY_HELD[key] {
-            KEY_HELD[key] = true;
-            let _ = SCANCODE_QUEUE.push(scancode);
-        }
+pub fn read_event() -> Option<KeyEvent> {
+    KEY_EVENTS.pop()
+}
+
+// The next key that has a SYS_GET_KEY byte, skipping releases and keys without one
+pub fn read_byte() -> Option<u8> {
+    core::iter::from_fn(read_event).find_map(|event| event.legacy_byte())
+}
+
+// What SYS_GET_KEY hands the calling task: nothing unless it is in the foreground, then a
+// key typed on the keyboard or else on the serial console
+pub fn read_foreground_byte() -> Option<u8> {
+    if current_task_has_input() {
+        read_byte().or_else(super::tty::read_key)
+    } else {
+        None
     }
 }
 
-pub fn scancode_to_char(scancode: u8) -> Option<char> {
-    scancode_to_byte(scancode).and_then(|byte| {
-        if byte.is_ascii() {
-            Some(byte as char)
-        } else {
-            None
-        }
-    })
+// Sleeps until `read` comes up with a byte, trying again each time a key arrives on the
+// keyboard or the serial console
+pub fn wait_key_byte(mut read: impl FnMut() -> Option<u8>) -> u8 {
+    let mut byte = None;
+    KEY_WAIT.wait_until(|| {
+        byte = read();
+        byte.is_some()
+    });
+    byte.unwrap_or(0)
 }
 
-pub fn scancode_to_byte(scancode: u8) -> Option<u8> {
-    match scancode {
-        // --- Modifier Key Pressed (Make) ---
-        0x2A => {
-            unsafe { LSHIFT = true };
-            None
-        }
-        0x36 => {
-            unsafe { RSHIFT = true };
-            None
-        }
-        0x1D => {
-            unsafe { LCTRL = true };
-            None
-        }
+// For other sources of key bytes, like the serial console
+pub fn wake_key_readers() {
+    KEY_WAIT.wake_all();
+}
 
-        // --- Modifier Key Released (Break) ---
-        0xAA => {
-            unsafe { LSHIFT = false };
-            None
-        }
-        0xB6 => {
-            unsafe { RSHIFT = false };
-            None
-        }
-        0x9D => {
-            unsafe { LCTRL = false };
-            None
-        }
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decoder() -> Decoder {
+        super::super::keymap::init();
+        Decoder::new()
+    }
+
+    // The key events `bytes` decode to, as (keycode, pressed, character)
+    fn keys(decoder: &mut Decoder, bytes: &[u8]) -> Vec<(u8, bool, char)> {
+        bytes
+            .iter()
+            .filter_map(|&byte| match decoder.decode(byte) {
+                Decoded::Key(key) => Some((
+                    key.keycode as u8,
+                    key.pressed != 0,
+                    char::from_u32(key.unicode).unwrap(),
+                )),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn make_and_break_codes() {
+        let mut decoder = decoder();
+        assert_eq!(
+            keys(&mut decoder, &[0x1E, 0x9E, 0x1C]),
+            [
+                (0x1E, true, 'a'),
+                (0x1E, false, 'a'),
+                (KEYCODE_ENTER, true, '\n')
+            ]
+        );
+    }
+
+    #[test]
+    fn shift_is_a_modifier() {
+        let mut decoder = decoder();
+        let typed = keys(&mut decoder, &[0x2A, 0x1E, 0x9E, 0xAA, 0x1E]);
+        assert_eq!(typed[1], (0x1E, true, 'A'));
+        assert_eq!(typed[4], (0x1E, true, 'a'));
+        assert_eq!(decoder.modifiers() & MOD_SHIFT, 0);
+    }
+
+    #[test]
+    fn caps_lock_only_shifts_letters() {
+        let mut decoder = decoder();
+        decoder.locks = MOD_CAPS_LOCK;
+        let typed = keys(&mut decoder, &[0x1E, 0x9E, 0x02, 0x2A, 0x1E]);
+        assert_eq!(typed[0].2, 'A');
+        assert_eq!(typed[2].2, '1');
+        assert_eq!(typed[4].2, 'a');
+    }
+
+    #[test]
+    fn extended_keys_and_fake_shifts() {
+        let mut decoder = decoder();
+        // Some keyboards wrap the cursor keys in E0 2A ... E0 AA
+        assert_eq!(
+            keys(
+                &mut decoder,
+                &[0xE0, 0x2A, 0xE0, 0x48, 0xE0, 0xC8, 0xE0, 0xAA]
+            ),
+            [(KEYCODE_UP, true, '\0'), (KEYCODE_UP, false, '\0')]
+        );
+        assert_eq!(decoder.modifiers(), 0);
+    }
+
+    #[test]
+    fn repeats_are_dropped() {
+        let mut decoder = decoder();
+        let typed = keys(&mut decoder, &[0x1E, 0x1E, 0x1E, 0x9E, 0x1E]);
+        assert_eq!(typed.len(), 3);
+    }
+
+    #[test]
+    fn pause_is_one_key() {
+        let mut decoder = decoder();
+        let typed = keys(&mut decoder, &[0xE1, 0x1D, 0x45, 0xE1, 0x9D, 0xC5]);
+        assert_eq!(
+            typed,
+            [(KEYCODE_PAUSE, true, '\0'), (KEYCODE_PAUSE, false, '\0')]
+        );
+        assert_eq!(decoder.locks & MOD_NUM_LOCK, 0);
+    }
+
+    #[test]
+    fn keypad_follows_num_lock() {
+        let mut decoder = decoder();
+        assert_eq!(
+            keys(&mut decoder, &[0x47, 0xC7]),
+            [(KEYCODE_HOME, true, '\0'), (KEYCODE_HOME, false, '\0')]
+        );
+        decoder.locks = MOD_NUM_LOCK;
+        assert_eq!(keys(&mut decoder, &[0x47]), [(KEYCODE_KEYPAD_7, true, '7')]);
+        // The keypad's minus is never a cursor key
+        decoder.locks = 0;
+        assert_eq!(keys(&mut decoder, &[0x4A])[0].0, KEYCODE_KEYPAD_MINUS);
+    }
+
+    #[test]
+    fn replies_are_not_keys() {
+        let mut decoder = decoder();
+        assert!(keys(&mut decoder, &[KEYBOARD_ACK, KEYBOARD_RESEND]).is_empty());
+    }
 
-        0x02 => Some(if is_shift() { b'!' } else { b'1' }),
-        0x03 => Some(if is_shift() { b'@' } else { b'2' }),
-        0x04 => Some(if is_shift() { b'#' } else { b'3' }),
-        0x05 => Some(if is_shift() { b'$' } else { b'4' }),
-        0x06 => Some(if is_shift() { b'%' } else { b'5' }),
-        0x07 => Some(if is_shift() { b'^' } else { b'6' }),
-        0x08 => Some(if is_shift() { b'&' } else { b'7' }),
-        0x09 => Some(if is_shift() { b'*' } else { b'8' }),
-        0x0A => Some(if is_shift() { b'(' } else { b'9' }),
-        0x0B => Some(if is_shift() { b')' } else { b'0' }),
-        0x0C => Some(if is_shift() { b'_' } else { b'-' }),
-        0x0D => Some(if is_shift() { b'+' } else { b'=' }),
-
-        0x10 => Some(if is_shift() { b'Q' } else { b'q' }),
-        0x11 => Some(if is_shift() { b'W' } else { b'w' }),
-        0x12 => Some(if is_shift() { b'E' } else { b'e' }),
-        0x13 => Some(if is_shift() { b'R' } else { b'r' }),
-        0x14 => Some(if is_shift() { b'T' } else { b't' }),
-        0x15 => Some(if is_shift() { b'Y' } else { b'y' }),
-        0x16 => Some(if is_shift() { b'U' } else { b'u' }),
-        0x17 => Some(if is_shift() { b'I' } else { b'i' }),
-        0x18 => Some(if is_shift() { b'O' } else { b'o' }),
-        0x19 => Some(if is_shift() { b'P' } else { b'p' }),
-        0x1A => Some(if is_shift() { b'{' } else { b'[' }),
-        0x1B => Some(if is_shift() { b'}' } else { b']' }),
-        0x1C => Some(b'\n'),
-
-        0x1E => Some(if is_shift() { b'A' } else { b'a' }),
-        0x1F => Some(if is_ctrl() {
-            0x13
-        } else if is_shift() {
-            b'S'
-        } else {
-            b's'
-        }),
-        0x20 => Some(if is_shift() { b'D' } else { b'd' }),
-        0x21 => Some(if is_shift() { b'F' } else { b'f' }),
-        0x22 => Some(if is_shift() { b'G' } else { b'g' }),
-        0x23 => Some(if is_shift() { b'H' } else { b'h' }),
-        0x24 => Some(if is_shift() { b'J' } else { b'j' }),
-        0x25 => Some(if is_shift() { b'K' } else { b'k' }),
-        0x26 => Some(if is_shift() { b'L' } else { b'l' }),
-        0x27 => Some(if is_shift() { b':' } else { b';' }),
-        0x28 => Some(if is_shift() { b'\"' } else { b'\'' }),
-
-        0x2C => Some(if is_shift() { b'Z' } else { b'z' }),
-        0x2D => Some(if is_ctrl() {
-            0x18
-        } else if is_shift() {
-            b'X'
-        } else {
-            b'x'
-        }),
-        0x2E => Some(if is_shift() { b'C' } else { b'c' }),
-        0x2F => Some(if is_shift() { b'V' } else { b'v' }),
-        0x30 => Some(if is_shift() { b'B' } else { b'b' }),
-        0x31 => Some(if is_shift() { b'N' } else { b'n' }),
-        0x32 => Some(if is_shift() { b'M' } else { b'm' }),
-        0x33 => Some(if is_shift() { b'<' } else { b',' }),
-        0x34 => Some(if is_shift() { b'>' } else { b'.' }),
-        0x35 => Some(if is_shift() { b'?' } else { b'/' }),
-        0x29 => Some(if is_shift() { b'~' } else { b'`' }),
-        0x2B => Some(if is_shift() { b'|' } else { b'\\' }),
-
-        0x39 => Some(b' '),
-        0x0F => Some(b'\t'),
-        0x0E => Some(b'\x08'),
-
-        // --- Arrow Keys and Navigation (Extended scancodes often preceded by 0xE0) ---
-        0x48 => Some(0x80), // Up
-        0x50 => Some(0x81), // Down
-        0x4B => Some(0x82), // Left
-        0x4D => Some(0x83), // Right
-        0x47 => Some(0x84), // Home
-        0x4F => Some(0x85), // End
-        0x53 => Some(0x86), // Delete
-
-        _ => None,
+    #[test]
+    fn release_all_forgets_held_keys() {
+        let mut decoder = decoder();
+        keys(&mut decoder, &[0x2A, 0x1D]);
+        assert_eq!(decoder.modifiers(), MOD_SHIFT | MOD_CTRL);
+        decoder.release_all();
+        assert_eq!(decoder.modifiers(), 0);
     }
 }
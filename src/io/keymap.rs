@@ -0,0 +1,182 @@
+use crate::sync::IrqMutex;
+use alloc::boxed::Box;
+use alloc::string::String;
+
+// Which character each key types, loaded from a text file like the ones in assets/keymaps
+// (see us.kmap for the format). The US map is built in and used until another is loaded.
+
+const BUILTIN_US: &str = include_str!("../../assets/keymaps/us.kmap");
+
+const NORMAL: usize = 0;
+const SHIFT: usize = 1;
+const ALTGR: usize = 2;
+
+pub struct Keymap {
+    pub name: String,
+    // Unicode scalar values by keycode, 0 where the key types nothing
+    chars: [[u32; 3]; 256],
+}
+
+static KEYMAP: IrqMutex<Option<Box<Keymap>>> = IrqMutex::new(None);
+
+impl Keymap {
+    pub fn parse(text: &str) -> Result<Box<Self>, &'static str> {
+        let mut keymap = Box::new(Self {
+            name: String::from("unnamed"),
+            chars: [[0; 3]; 256],
+        });
+        for line in text.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if let Some(name) = line.strip_prefix("name ") {
+                keymap.name = String::from(name.trim());
+                continue;
+            }
+
+            let mut fields = line.split_whitespace();
+            let keycode = fields
+                .next()
+                .and_then(|k| k.strip_prefix("0x"))
+                .and_then(|k| u8::from_str_radix(k, 16).ok())
+                .ok_or("keymap: bad keycode")?;
+            for (slot, field) in fields.enumerate() {
+                if slot > ALTGR {
+                    return Err("keymap: more than three characters for a key");
+                }
+                keymap.chars[keycode as usize][slot] = parse_char(field)?;
+            }
+        }
+        Ok(keymap)
+    }
+
+    // What `keycode` types with these modifiers. Caps Lock works like Shift, but only on
+    // keys that type a letter.
+    fn lookup(&self, keycode: u8, shift: bool, altgr: bool, caps_lock: bool) -> Option<char> {
+        let chars = &self.chars[keycode as usize];
+        let letter = char::from_u32(chars[NORMAL]).is_some_and(char::is_alphabetic);
+        let slot = if altgr {
+            ALTGR
+        } else if shift != (caps_lock && letter) {
+            SHIFT
+        } else {
+            NORMAL
+        };
+        Some(chars[slot])
+            .filter(|&c| c != 0)
+            .and_then(char::from_u32)
+    }
+}
+
+// A character as itself, as U+XXXX, or "none"
+fn parse_char(field: &str) -> Result<u32, &'static str> {
+    if field == "none" {
+        return Ok(0);
+    }
+    if let Some(hex) = field.strip_prefix("U+") {
+        return u32::from_str_radix(hex, 16)
+            .ok()
+            .filter(|&c| char::from_u32(c).is_some())
+            .ok_or("keymap: bad U+ character");
+    }
+    let mut chars = field.chars();
+    match (chars.next(), chars.next()) {
+        (Some(c), None) => Ok(c as u32),
+        _ => Err("keymap: expected a single character"),
+    }
+}
+
+pub fn init() {
+    match Keymap::parse(BUILTIN_US) {
+        Ok(keymap) => *KEYMAP.lock() = Some(keymap),
+        Err(e) => crate::println!("Keyboard: built-in keymap is broken: {}", e),
+    }
+}
+
+// Replaces the keymap with the one in the file at `path`
+pub fn load(path: &str) -> Result<(), &'static str> {
+    let data = crate::fs::vfs::read_file(path)?;
+    let text = core::str::from_utf8(&data).map_err(|_| "keymap: not UTF-8")?;
+    let keymap = Keymap::parse(text)?;
+    crate::println!("Keyboard: using the {} keymap", keymap.name);
+    *KEYMAP.lock() = Some(keymap);
+    Ok(())
+}
+
+pub fn lookup(keycode: u8, shift: bool, altgr: bool, caps_lock: bool) -> Option<char> {
+    KEYMAP
+        .lock()
+        .as_ref()
+        .and_then(|keymap| keymap.lookup(keycode, shift, altgr, caps_lock))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SHIPPED: [(&str, &str); 4] = [
+        ("US", BUILTIN_US),
+        ("UK", include_str!("../../assets/keymaps/uk.kmap")),
+        ("DE", include_str!("../../assets/keymaps/de.kmap")),
+        ("FR", include_str!("../../assets/keymaps/fr.kmap")),
+    ];
+
+    fn shipped(name: &str) -> Box<Keymap> {
+        let (_, text) = SHIPPED.iter().find(|(n, _)| *n == name).unwrap();
+        Keymap::parse(text).unwrap()
+    }
+
+    #[test]
+    fn shipped_keymaps_parse() {
+        for (name, text) in SHIPPED {
+            let keymap = Keymap::parse(text).unwrap_or_else(|e| panic!("{}: {}", name, e));
+            assert_eq!(keymap.name, name);
+        }
+    }
+
+    #[test]
+    fn shift_and_caps_lock() {
+        let us = shipped("US");
+        assert_eq!(us.lookup(0x1E, false, false, false), Some('a'));
+        assert_eq!(us.lookup(0x1E, true, false, false), Some('A'));
+        assert_eq!(us.lookup(0x1E, false, false, true), Some('A'));
+        assert_eq!(us.lookup(0x1E, true, false, true), Some('a'));
+        // Caps Lock leaves keys that don't type a letter alone
+        assert_eq!(us.lookup(0x02, false, false, true), Some('1'));
+        assert_eq!(us.lookup(0x02, true, false, true), Some('!'));
+    }
+
+    #[test]
+    fn altgr_and_missing_slots() {
+        let de = shipped("DE");
+        assert_eq!(de.lookup(0x10, false, true, false), Some('@'));
+        assert_eq!(de.lookup(0x12, false, true, false), Some('€'));
+        assert_eq!(de.lookup(0x1E, false, true, false), None);
+        assert_eq!(de.lookup(0x3B, false, false, false), None); // F1
+    }
+
+    #[test]
+    fn layouts_differ() {
+        assert_eq!(shipped("FR").lookup(0x10, false, false, false), Some('a'));
+        assert_eq!(shipped("UK").lookup(0x03, true, false, false), Some('"'));
+    }
+
+    #[test]
+    fn characters_by_code_point() {
+        let keymap = Keymap::parse("# comment\n\nname Test\n0x10 U+0071 none U+20AC\n").unwrap();
+        assert_eq!(keymap.name, "Test");
+        assert_eq!(keymap.lookup(0x10, false, false, false), Some('q'));
+        assert_eq!(keymap.lookup(0x10, true, false, false), None);
+        assert_eq!(keymap.lookup(0x10, false, true, false), Some('€'));
+    }
+
+    #[test]
+    fn bad_lines_are_rejected() {
+        assert!(Keymap::parse("10 a").is_err());
+        assert!(Keymap::parse("0x100 a").is_err());
+        assert!(Keymap::parse("0x10 a b c d").is_err());
+        assert!(Keymap::parse("0x10 ab").is_err());
+        assert!(Keymap::parse("0x10 U+D800").is_err());
+        assert!(Keymap::parse("0x10 U+zz").is_err());
+    }
+}
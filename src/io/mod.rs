@@ -1,10 +1,18 @@
 use alloc::fmt;
 
+pub mod ahci;
 pub mod ata_driver;
+pub mod hpet;
+pub mod input;
 pub mod keyboard;
+pub mod keymap;
 pub mod log_buffer;
 pub mod mouse;
+pub mod pci;
+pub mod rtc;
 pub mod serial;
+pub mod tty;
+pub mod virtio_blk;
 
 /******************************
  * SET UP SERIAL PRINT MACROS *
@@ -1,12 +1,165 @@
+use crate::sync::IrqMutex;
+use alloc::collections::VecDeque;
 use core::arch::asm;
 use core::sync::atomic::{AtomicI32, AtomicU8, Ordering};
 
-static mut PACKET: [u8; 3] = [0; 3];
-static mut PACKET_IDX: usize = 0;
+// The PS/2 mouse. A plain mouse sends 3-byte packets; init tries the IntelliMouse magic
+// sample rate sequences, after which a mouse that has them sends a 4th byte with the wheel
+// (ID 3) and also buttons 4 and 5 (ID 4).
+
+const MOUSE_ACK: u8 = 0xFA;
+const MOUSE_RESEND: u8 = 0xFE;
+const MOUSE_SET_RESOLUTION: u8 = 0xE8;
+const MOUSE_GET_ID: u8 = 0xF2;
+const MOUSE_SET_SAMPLE_RATE: u8 = 0xF3;
+const MOUSE_ENABLE_REPORTING: u8 = 0xF4;
+const MOUSE_DISABLE_REPORTING: u8 = 0xF5;
+const MOUSE_SET_DEFAULTS: u8 = 0xF6;
+
+const ID_STANDARD: u8 = 0;
+const ID_WHEEL: u8 = 3;
+const ID_FIVE_BUTTONS: u8 = 4;
+
+// Packets per second the mouse may send, and counts per mm it can be set to
+pub const SAMPLE_RATES: [u8; 7] = [10, 20, 40, 60, 80, 100, 200];
+pub const RESOLUTIONS: [u8; 4] = [1, 2, 4, 8];
+const DEFAULT_SAMPLE_RATE: u8 = 100;
+const DEFAULT_RESOLUTION: u8 = 4;
+
+// Bits of the button mask past left (bit 0), right and middle
+pub const BUTTON_4: u8 = 1 << 3;
+pub const BUTTON_5: u8 = 1 << 4;
+const BUTTON_COUNT: u32 = 5;
+
 static MOUSE_DX: AtomicI32 = AtomicI32::new(0);
 static MOUSE_DY: AtomicI32 = AtomicI32::new(0);
+static MOUSE_WHEEL: AtomicI32 = AtomicI32::new(0);
 static MOUSE_BUTTONS: AtomicU8 = AtomicU8::new(0);
 
+static MOUSE: IrqMutex<Mouse> = IrqMutex::new(Mouse::new());
+
+struct Mouse {
+    id: u8,
+    packet: [u8; 4],
+    packet_idx: usize,
+    sample_rate: u8,
+    resolution: u8, // Counts per mm
+    // Command bytes still to send once the mouse is running, the front one is in flight
+    commands: VecDeque<u8>,
+    reporting: bool, // Sending packets, as far as the ACKs we have seen say
+}
+
+struct Packet {
+    dx: i32,
+    dy: i32,    // Positive is up
+    wheel: i32, // Clicks, positive is away from the user
+    buttons: u8,
+}
+
+// What the mouse turned out to be and how it is set up
+#[derive(Clone, Copy)]
+pub struct MouseInfo {
+    pub buttons: u8,
+    pub has_wheel: bool,
+    pub sample_rate: u8,
+    pub resolution: u8,
+}
+
+impl Mouse {
+    const fn new() -> Self {
+        Self {
+            id: ID_STANDARD,
+            packet: [0; 4],
+            packet_idx: 0,
+            sample_rate: DEFAULT_SAMPLE_RATE,
+            resolution: DEFAULT_RESOLUTION,
+            commands: VecDeque::new(),
+            reporting: false,
+        }
+    }
+
+    fn packet_len(&self) -> usize {
+        match self.id {
+            ID_WHEEL | ID_FIVE_BUTTONS => 4,
+            _ => 3,
+        }
+    }
+
+    fn receive(&mut self, byte: u8) -> Option<Packet> {
+        // First packet byte bit3 is always 1. Re-sync if stream got misaligned.
+        if self.packet_idx == 0 && (byte & 0x08) == 0 {
+            return None;
+        }
+
+        self.packet[self.packet_idx] = byte;
+        self.packet_idx += 1;
+        if self.packet_idx < self.packet_len() {
+            return None;
+        }
+        self.packet_idx = 0;
+
+        let flags = self.packet[0];
+        // Drop overflowed packets to avoid bad deltas and keep parser in sync.
+        if (flags & 0xC0) != 0 {
+            return None;
+        }
+
+        // The mouse counts the wheel towards the user, everything else here counts it away
+        let extra = self.packet[3];
+        let (z, extra_buttons) = match self.id {
+            ID_WHEEL => (extra as i8, 0),
+            // The wheel in the low 4 bits, sign extended, and buttons 4 and 5 in bits 4 and 5
+            ID_FIVE_BUTTONS => (
+                ((extra << 4) as i8) >> 4,
+                (extra >> 1) & (BUTTON_4 | BUTTON_5),
+            ),
+            _ => (0, 0),
+        };
+        Some(Packet {
+            dx: self.packet[1] as i8 as i32,
+            dy: self.packet[2] as i8 as i32,
+            wheel: -(z as i32),
+            buttons: (flags & 0x07) | extra_buttons,
+        })
+    }
+
+    fn info(&self) -> MouseInfo {
+        MouseInfo {
+            buttons: if self.id == ID_FIVE_BUTTONS { 5 } else { 3 },
+            has_wheel: self.id != ID_STANDARD,
+            sample_rate: self.sample_rate,
+            resolution: self.resolution,
+        }
+    }
+
+    // Whether `byte` answers the command in flight rather than being part of a packet.
+    // Until MOUSE_DISABLE_REPORTING is acknowledged packets may still arrive, and an ACK is
+    // only told apart from packet data at a packet boundary: a first byte of FA or FE has
+    // both overflow bits set, so it is no packet we would keep anyway.
+    fn is_reply(&self, byte: u8) -> bool {
+        if self.commands.is_empty() || (byte != MOUSE_ACK && byte != MOUSE_RESEND) {
+            return false;
+        }
+        !self.reporting || self.packet_idx == 0
+    }
+
+    // Replies to queued commands. Once reporting is off there are no packet bytes in
+    // between to mistake for an ACK.
+    fn on_reply(&mut self, reply: u8) {
+        if reply == MOUSE_ACK {
+            match self.commands.pop_front() {
+                Some(MOUSE_DISABLE_REPORTING) => self.reporting = false,
+                Some(MOUSE_ENABLE_REPORTING) => self.reporting = true,
+                _ => {}
+            }
+            self.packet_idx = 0;
+        }
+        if let Some(&next) = self.commands.front() {
+            write_mouse_cmd(next);
+        }
+    }
+}
+
 #[inline]
 fn clamp_i32_to_i16(v: i32) -> i16 {
     if v > i16::MAX as i32 {
@@ -18,17 +171,58 @@ fn clamp_i32_to_i16(v: i32) -> i16 {
     }
 }
 
-pub fn take_deltas_packed() -> u32 {
+// The motion and wheel clicks since the last call: dx in bits 0-15, dy (positive up) in
+// bits 16-31 and the wheel (positive away from the user) in bits 32-47
+pub fn take_deltas_packed() -> u64 {
     let dx = clamp_i32_to_i16(MOUSE_DX.swap(0, Ordering::AcqRel));
     let dy = clamp_i32_to_i16(MOUSE_DY.swap(0, Ordering::AcqRel));
+    let wheel = clamp_i32_to_i16(MOUSE_WHEEL.swap(0, Ordering::AcqRel));
 
-    (dx as u16 as u32) | ((dy as u16 as u32) << 16)
+    (dx as u16 as u64) | ((dy as u16 as u64) << 16) | ((wheel as u16 as u64) << 32)
 }
 
 pub fn get_buttons_mask() -> u8 {
     MOUSE_BUTTONS.load(Ordering::Acquire)
 }
 
+pub fn info() -> MouseInfo {
+    MOUSE.lock().info()
+}
+
+// What MOUSE_SET_RESOLUTION takes for `counts_per_mm`
+fn resolution_code(counts_per_mm: u8) -> Option<u8> {
+    RESOLUTIONS
+        .iter()
+        .position(|&r| r == counts_per_mm)
+        .map(|code| code as u8)
+}
+
+// Changes how often the mouse reports and how far a count goes. The commands go out one
+// per ACK from the interrupt handler, like the keyboard LEDs.
+pub fn configure(sample_rate: u8, resolution: u8) -> Result<(), &'static str> {
+    if !SAMPLE_RATES.contains(&sample_rate) {
+        return Err("mouse: unsupported sample rate");
+    }
+    let resolution_code = resolution_code(resolution).ok_or("mouse: unsupported resolution")?;
+
+    let mut mouse = MOUSE.lock();
+    mouse.sample_rate = sample_rate;
+    mouse.resolution = resolution;
+    let idle = mouse.commands.is_empty();
+    mouse.commands.extend([
+        MOUSE_DISABLE_REPORTING,
+        MOUSE_SET_SAMPLE_RATE,
+        sample_rate,
+        MOUSE_SET_RESOLUTION,
+        resolution_code,
+        MOUSE_ENABLE_REPORTING,
+    ]);
+    if idle {
+        write_mouse_cmd(MOUSE_DISABLE_REPORTING);
+    }
+    Ok(())
+}
+
 #[inline]
 fn inb(port: u16) -> u8 {
     let v: u8;
@@ -85,6 +279,29 @@ fn write_mouse_cmd(cmd: u8) {
     write_data(cmd);
 }
 
+// Sends `bytes` to the mouse, each one waiting for its ACK. Only for init, before the
+// mouse interrupt can take the replies.
+fn command(bytes: &[u8]) -> bool {
+    bytes.iter().all(|&byte| {
+        write_mouse_cmd(byte);
+        read_data_timeout() == Some(MOUSE_ACK)
+    })
+}
+
+fn identify() -> Option<u8> {
+    if command(&[MOUSE_GET_ID]) {
+        read_data_timeout()
+    } else {
+        None
+    }
+}
+
+fn set_sample_rates(rates: &[u8]) -> bool {
+    rates
+        .iter()
+        .all(|&rate| command(&[MOUSE_SET_SAMPLE_RATE, rate]))
+}
+
 pub fn init_ps2_mouse() {
     // Enable auxiliary (mouse) device on PS/2 controller.
     write_cmd(0xA8);
@@ -103,42 +320,161 @@ pub fn init_ps2_mouse() {
     write_cmd(0x60);
     write_data(cfg);
 
-    // Set defaults and enable streaming on the mouse device.
-    write_mouse_cmd(0xF6);
+    // Set defaults, this also takes the mouse back to 3-byte packets.
+    write_mouse_cmd(MOUSE_SET_DEFAULTS);
     let _ = read_data_timeout(); // Expect ACK 0xFA.
 
-    write_mouse_cmd(0xF4);
+    // The IntelliMouse handshake: rates 200, 100, 80 turn on the wheel, then 200, 200, 80
+    // turn on buttons 4 and 5. Each step only counts if the ID changes to say so.
+    let mut id = ID_STANDARD;
+    if set_sample_rates(&[200, 100, 80]) && identify() == Some(ID_WHEEL) {
+        id = ID_WHEEL;
+        if set_sample_rates(&[200, 200, 80]) && identify() == Some(ID_FIVE_BUTTONS) {
+            id = ID_FIVE_BUTTONS;
+        }
+    }
+
+    let _ = command(&[MOUSE_SET_SAMPLE_RATE, DEFAULT_SAMPLE_RATE]);
+    if let Some(code) = resolution_code(DEFAULT_RESOLUTION) {
+        let _ = command(&[MOUSE_SET_RESOLUTION, code]);
+    }
+
+    let mut mouse = MOUSE.lock();
+    mouse.id = id;
+    mouse.reporting = true;
+    crate::println!(
+        "Mouse: {} buttons{}",
+        mouse.info().buttons,
+        if id == ID_STANDARD {
+            ""
+        } else {
+            " and a wheel"
+        }
+    );
+
+    write_mouse_cmd(MOUSE_ENABLE_REPORTING);
     let _ = read_data_timeout();
 }
 
 pub fn on_irq_byte(byte: u8) {
-    unsafe {
-        // First packet byte bit3 is always 1. Re-sync if stream got misaligned.
-        if PACKET_IDX == 0 && (byte & 0x08) == 0 {
+    let packet = {
+        let mut mouse = MOUSE.lock();
+        if mouse.is_reply(byte) {
+            mouse.on_reply(byte);
             return;
         }
+        match mouse.receive(byte) {
+            Some(packet) => packet,
+            None => return,
+        }
+    };
 
-        PACKET[PACKET_IDX] = byte;
-        PACKET_IDX += 1;
+    MOUSE_DX.fetch_add(packet.dx, Ordering::AcqRel);
+    MOUSE_DY.fetch_add(packet.dy, Ordering::AcqRel);
+    MOUSE_WHEEL.fetch_add(packet.wheel, Ordering::AcqRel);
+    let old_buttons = MOUSE_BUTTONS.swap(packet.buttons, Ordering::AcqRel);
 
-        if PACKET_IDX < 3 {
-            return;
+    // The same packet for the input queue: movement first, then each button that changed,
+    // then the wheel
+    if packet.dx != 0 || packet.dy != 0 {
+        super::input::push_motion(packet.dx, packet.dy);
+    }
+    for bit in 0..BUTTON_COUNT {
+        let button = 1 << bit;
+        if (packet.buttons ^ old_buttons) & button != 0 {
+            super::input::push_button(button as u16, packet.buttons & button != 0);
         }
+    }
+    if packet.wheel != 0 {
+        super::input::push_wheel(packet.wheel);
+    }
+}
 
-        PACKET_IDX = 0;
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        let flags = PACKET[0];
-        // Drop overflowed packets to avoid bad deltas and keep parser in sync.
-        if (flags & 0xC0) != 0 {
-            return;
-        }
+    fn mouse(id: u8) -> Mouse {
+        let mut mouse = Mouse::new();
+        mouse.id = id;
+        mouse.reporting = true;
+        mouse
+    }
+
+    // The packets `bytes` decode to, as (dx, dy, wheel, buttons)
+    fn packets(mouse: &mut Mouse, bytes: &[u8]) -> Vec<(i32, i32, i32, u8)> {
+        bytes
+            .iter()
+            .filter_map(|&byte| mouse.receive(byte))
+            .map(|packet| (packet.dx, packet.dy, packet.wheel, packet.buttons))
+            .collect()
+    }
+
+    #[test]
+    fn standard_packets() {
+        let mut mouse = mouse(ID_STANDARD);
+        assert_eq!(
+            packets(&mut mouse, &[0x09, 0x05, 0x03, 0x3A, 0xFB, 0xFE]),
+            [(5, 3, 0, 0x01), (-5, -2, 0, 0x02)]
+        );
+    }
+
+    #[test]
+    fn resyncs_on_a_bad_first_byte() {
+        let mut mouse = mouse(ID_STANDARD);
+        // Two stray bytes without bit 3, then a whole packet
+        assert_eq!(
+            packets(&mut mouse, &[0x05, 0x03, 0x0C, 0x01, 0x02]),
+            [(1, 2, 0, 0x04)]
+        );
+    }
+
+    #[test]
+    fn overflowed_packets_are_dropped() {
+        let mut mouse = mouse(ID_STANDARD);
+        assert_eq!(
+            packets(&mut mouse, &[0x48, 0x7F, 0x00, 0x08, 0x01, 0x00]),
+            [(1, 0, 0, 0)]
+        );
+    }
+
+    #[test]
+    fn wheel_counts_away_from_the_user() {
+        let mut mouse = mouse(ID_WHEEL);
+        assert_eq!(
+            packets(&mut mouse, &[0x08, 0, 0, 0xFF, 0x08, 0, 0, 0x02]),
+            [(0, 0, 1, 0), (0, 0, -2, 0)]
+        );
+    }
+
+    #[test]
+    fn five_button_wheel_and_buttons() {
+        let mut mouse = mouse(ID_FIVE_BUTTONS);
+        assert_eq!(
+            packets(&mut mouse, &[0x08, 0, 0, 0x1F, 0x09, 0, 0, 0x21]),
+            [(0, 0, 1, BUTTON_4), (0, 0, -1, 0x01 | BUTTON_5)]
+        );
+    }
 
-        // Intentionally keep IRQ work minimal. Packet consumers can be added later.
-        let _dx = PACKET[1] as i8 as i16;
-        let _dy = PACKET[2] as i8 as i16;
+    #[test]
+    fn replies_only_between_packets_until_reporting_is_off() {
+        let mut mouse = mouse(ID_STANDARD);
+        mouse.commands.push_back(MOUSE_DISABLE_REPORTING);
+        // FA in the middle of a packet is a delta of -6
+        assert!(mouse.receive(0x18).is_none());
+        assert!(!mouse.is_reply(MOUSE_ACK));
+        assert!(mouse.receive(MOUSE_ACK).is_none());
+        assert!(!mouse.is_reply(MOUSE_ACK));
+        assert_eq!(mouse.receive(MOUSE_ACK).map(|packet| packet.dy), Some(-6));
+        assert!(mouse.is_reply(MOUSE_ACK));
+        assert!(!mouse.is_reply(0x08));
 
-        MOUSE_DX.fetch_add(_dx as i32, Ordering::AcqRel);
-        MOUSE_DY.fetch_add(_dy as i32, Ordering::AcqRel);
-        MOUSE_BUTTONS.store(flags & 0x07, Ordering::Release);
+        // With reporting off every FA answers a command
+        mouse.reporting = false;
+        mouse.packet_idx = 1;
+        assert!(mouse.is_reply(MOUSE_ACK));
+        assert!(mouse.is_reply(MOUSE_RESEND));
+        mouse.commands.clear();
+        assert!(!mouse.is_reply(MOUSE_ACK));
     }
 }
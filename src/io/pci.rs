@@ -0,0 +1,597 @@
+use alloc::vec::Vec;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::instructions::port::Port;
+
+use crate::acpi::EcamRegion;
+
+// Legacy configuration mechanism #1: write the address of a config register to
+// CONFIG_ADDRESS, then read or write it through CONFIG_DATA. It only reaches the first
+// 256 bytes of each function, so ECAM (memory mapped, found through the ACPI MCFG
+// table) is used instead whenever the firmware provides it.
+const CONFIG_ADDRESS: u16 = 0xCF8;
+const CONFIG_DATA: u16 = 0xCFC;
+
+const COMMAND_IO_SPACE: u16 = 1 << 0;
+const COMMAND_MEMORY_SPACE: u16 = 1 << 1;
+const COMMAND_BUS_MASTER: u16 = 1 << 2;
+const COMMAND_INTX_DISABLE: u16 = 1 << 10;
+
+const CAP_MSI: u8 = 0x05;
+const CAP_MSIX: u8 = 0x11;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PciAddress {
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+}
+
+/*****************
+ * CONFIG ACCESS *
+ *****************/
+// ECAM gives every bus 1 MiB of config space, a bus is mapped the first time it's touched
+struct Ecam {
+    region: EcamRegion,
+    buses: [u64; 256], // Virtual base of each bus, 0 while unmapped
+}
+
+static ECAM: Mutex<Option<Ecam>> = Mutex::new(None);
+
+impl Ecam {
+    fn function_base(&mut self, address: PciAddress) -> Option<u64> {
+        if address.bus < self.region.start_bus || address.bus > self.region.end_bus {
+            return None;
+        }
+        let bus = address.bus as usize;
+        if self.buses[bus] == 0 {
+            let offset = ((bus - self.region.start_bus as usize) as u64) << 20;
+            self.buses[bus] = crate::memory::map_mmio(self.region.base + offset, 1 << 20);
+        }
+        Some(self.buses[bus] + ((address.device as u64) << 15) + ((address.function as u64) << 12))
+    }
+}
+
+impl PciAddress {
+    pub fn read_u32(&self, offset: u8) -> u32 {
+        // Address and data must go out back to back, an interrupt in between could move the address
+        interrupts::without_interrupts(|| {
+            if let Some(base) = ECAM.lock().as_mut().and_then(|e| e.function_base(*self)) {
+                return unsafe { ((base + (offset & 0xFC) as u64) as *const u32).read_volatile() };
+            }
+            unsafe {
+                Port::<u32>::new(CONFIG_ADDRESS).write(self.config_address(offset));
+                Port::<u32>::new(CONFIG_DATA).read()
+            }
+        })
+    }
+
+    pub fn write_u32(&self, offset: u8, value: u32) {
+        interrupts::without_interrupts(|| {
+            if let Some(base) = ECAM.lock().as_mut().and_then(|e| e.function_base(*self)) {
+                unsafe { ((base + (offset & 0xFC) as u64) as *mut u32).write_volatile(value) };
+                return;
+            }
+            unsafe {
+                Port::<u32>::new(CONFIG_ADDRESS).write(self.config_address(offset));
+                Port::<u32>::new(CONFIG_DATA).write(value);
+            }
+        })
+    }
+
+    pub fn read_u16(&self, offset: u8) -> u16 {
+        (self.read_u32(offset & !3) >> ((offset & 2) * 8)) as u16
+    }
+
+    pub fn write_u16(&self, offset: u8, value: u16) {
+        let shift = (offset & 2) * 8;
+        let old = self.read_u32(offset & !3) & !(0xFFFF << shift);
+        self.write_u32(offset & !3, old | ((value as u32) << shift));
+    }
+
+    pub fn read_u8(&self, offset: u8) -> u8 {
+        (self.read_u32(offset & !3) >> ((offset & 3) * 8)) as u8
+    }
+
+    pub fn vendor_id(&self) -> u16 {
+        self.read_u16(0x00)
+    }
+
+    pub fn device_id(&self) -> u16 {
+        self.read_u16(0x02)
+    }
+
+    // (class, subclass, programming interface)
+    pub fn class(&self) -> (u8, u8, u8) {
+        let value = self.read_u32(0x08);
+        ((value >> 24) as u8, (value >> 16) as u8, (value >> 8) as u8)
+    }
+
+    // Bit 7 set means the device has more than one function
+    pub fn header_type(&self) -> u8 {
+        self.read_u8(0x0E)
+    }
+
+    // Config space offsets of every capability in the device's list, with their IDs
+    pub fn capabilities(&self) -> impl Iterator<Item = (u8, u8)> + '_ {
+        let has_list = (self.read_u16(0x06) & (1 << 4)) != 0;
+        let mut next = if has_list {
+            self.read_u8(0x34) & 0xFC
+        } else {
+            0
+        };
+        core::iter::from_fn(move || {
+            if next == 0 {
+                return None;
+            }
+            let offset = next;
+            next = self.read_u8(offset + 1) & 0xFC;
+            Some((offset, self.read_u8(offset)))
+        })
+    }
+
+    // Lets the device decode its BARs and master the bus for DMA
+    pub fn enable_bus_master(&self) {
+        let command = self.read_u16(0x04);
+        self.write_u16(
+            0x04,
+            command | COMMAND_IO_SPACE | COMMAND_MEMORY_SPACE | COMMAND_BUS_MASTER,
+        );
+    }
+
+    fn config_address(&self, offset: u8) -> u32 {
+        (1 << 31)
+            | ((self.bus as u32) << 16)
+            | ((self.device as u32) << 11)
+            | ((self.function as u32) << 8)
+            | (offset as u32 & 0xFC)
+    }
+}
+
+/********
+ * BARS *
+ ********/
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Bar {
+    None,
+    Io {
+        port: u16,
+        size: u32,
+    },
+    Memory {
+        address: u64,
+        size: u64,
+        prefetchable: bool,
+    },
+}
+
+impl Bar {
+    pub fn memory_address(&self) -> Option<u64> {
+        match *self {
+            Bar::Memory { address, .. } => Some(address),
+            _ => None,
+        }
+    }
+
+    pub fn io_port(&self) -> Option<u16> {
+        match *self {
+            Bar::Io { port, .. } => Some(port),
+            _ => None,
+        }
+    }
+}
+
+// Sizes each BAR the usual way: write all ones, see which address bits stick, then put
+// the original value back. Decoding is off meanwhile so nothing answers at the
+// temporary address.
+fn decode_bars(address: PciAddress) -> [Bar; 6] {
+    let mut bars = [Bar::None; 6];
+    let count = match address.header_type() & 0x7F {
+        0x00 => 6,
+        0x01 => 2, // PCI-to-PCI bridge
+        _ => 0,
+    };
+
+    let command = address.read_u16(0x04);
+    address.write_u16(0x04, command & !(COMMAND_IO_SPACE | COMMAND_MEMORY_SPACE));
+
+    let mut index = 0;
+    while index < count {
+        let offset = 0x10 + index as u8 * 4;
+        let original = address.read_u32(offset);
+        let mask = probe_register(address, offset);
+
+        if (original & 1) != 0 {
+            let mask = mask & 0xFFFC;
+            if mask != 0 {
+                bars[index] = Bar::Io {
+                    port: (original & 0xFFFC) as u16,
+                    size: (!mask & 0xFFFF) + 1,
+                };
+            }
+        } else {
+            let is_64bit = ((original >> 1) & 0b11) == 0b10 && index + 1 < count;
+            let (high, high_mask) = if is_64bit {
+                (
+                    address.read_u32(offset + 4),
+                    probe_register(address, offset + 4),
+                )
+            } else {
+                (0, u32::MAX)
+            };
+
+            let full_mask = ((high_mask as u64) << 32) | (mask & 0xFFFF_FFF0) as u64;
+            if (mask & 0xFFFF_FFF0) != 0 {
+                bars[index] = Bar::Memory {
+                    address: ((high as u64) << 32) | (original & 0xFFFF_FFF0) as u64,
+                    size: (!full_mask).wrapping_add(1),
+                    prefetchable: (original & (1 << 3)) != 0,
+                };
+            }
+            if is_64bit {
+                index += 1; // The upper half took the next slot
+            }
+        }
+        index += 1;
+    }
+
+    address.write_u16(0x04, command);
+    bars
+}
+
+// Which bits of a BAR are writable, the original value is restored afterwards
+fn probe_register(address: PciAddress, offset: u8) -> u32 {
+    let original = address.read_u32(offset);
+    address.write_u32(offset, u32::MAX);
+    let mask = address.read_u32(offset);
+    address.write_u32(offset, original);
+    mask
+}
+
+/*******
+ * MSI *
+ *******/
+#[derive(Clone, Copy, Debug)]
+pub struct MsiCapability {
+    pub offset: u8,
+    pub is_64bit: bool,
+    pub per_vector_masking: bool,
+    pub max_vectors: u8,
+}
+
+fn parse_msi(address: PciAddress) -> Option<MsiCapability> {
+    let (offset, _) = address.capabilities().find(|&(_, id)| id == CAP_MSI)?;
+    let control = address.read_u16(offset + 2);
+    Some(MsiCapability {
+        offset,
+        is_64bit: (control & (1 << 7)) != 0,
+        per_vector_masking: (control & (1 << 8)) != 0,
+        max_vectors: 1 << ((control >> 1) & 0b111),
+    })
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct MsixCapability {
+    pub offset: u8,
+    pub table_bar: u8,
+    pub table_offset: u32,
+    pub table_size: u16,
+}
+
+fn parse_msix(address: PciAddress) -> Option<MsixCapability> {
+    let (offset, _) = address.capabilities().find(|&(_, id)| id == CAP_MSIX)?;
+    let control = address.read_u16(offset + 2);
+    let table = address.read_u32(offset + 4);
+    Some(MsixCapability {
+        offset,
+        table_bar: (table & 0b111) as u8,
+        table_offset: table & !0b111,
+        table_size: (control & 0x7FF) + 1,
+    })
+}
+
+/*******************
+ * DEVICE REGISTRY *
+ *******************/
+#[derive(Clone, Debug)]
+pub struct PciDevice {
+    pub address: PciAddress,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub class: u8,
+    pub subclass: u8,
+    pub prog_if: u8,
+    pub revision: u8,
+    pub interrupt_line: u8,
+    pub bars: [Bar; 6],
+    pub msi: Option<MsiCapability>,
+    pub msix: Option<MsixCapability>,
+    pub driver: Option<&'static str>, // Name of the driver that claimed it
+}
+
+static DEVICES: Mutex<Vec<PciDevice>> = Mutex::new(Vec::new());
+
+impl PciDevice {
+    fn read(address: PciAddress) -> Self {
+        let (class, subclass, prog_if) = address.class();
+        Self {
+            address,
+            vendor_id: address.vendor_id(),
+            device_id: address.device_id(),
+            class,
+            subclass,
+            prog_if,
+            revision: address.read_u8(0x08),
+            interrupt_line: address.read_u8(0x3C),
+            bars: decode_bars(address),
+            msi: parse_msi(address),
+            msix: parse_msix(address),
+            driver: None,
+        }
+    }
+
+    // Points the device's MSI at local APIC `apic_id` with `vector` and switches the
+    // legacy INTx pin off. Only a single message is enabled even if more are offered.
+    pub fn enable_msi(&self, apic_id: u8, vector: u8) -> Result<(), &'static str> {
+        let msi = self.msi.ok_or("device has no MSI capability")?;
+        let address = self.address;
+        address.write_u32(msi.offset + 4, 0xFEE0_0000 | ((apic_id as u32) << 12));
+        let data_offset = if msi.is_64bit {
+            address.write_u32(msi.offset + 8, 0);
+            msi.offset + 12
+        } else {
+            msi.offset + 8
+        };
+        address.write_u16(data_offset, vector as u16);
+
+        let control = address.read_u16(msi.offset + 2) & !(0b111 << 4);
+        address.write_u16(msi.offset + 2, control | 1);
+        let command = address.read_u16(0x04);
+        address.write_u16(0x04, command | COMMAND_INTX_DISABLE);
+        Ok(())
+    }
+
+    // Same as enable_msi for MSI-X: points entry 0 of the table at local APIC `apic_id`
+    // with `vector`. The other entries stay masked.
+    pub fn enable_msix(&self, apic_id: u8, vector: u8) -> Result<(), &'static str> {
+        let msix = self.msix.ok_or("device has no MSI-X capability")?;
+        let base = self
+            .bars
+            .get(msix.table_bar as usize)
+            .and_then(Bar::memory_address)
+            .ok_or("MSI-X table in an I/O BAR")?;
+        let entry = crate::memory::map_mmio(base + msix.table_offset as u64, 16) as *mut u32;
+        unsafe {
+            entry.write_volatile(0xFEE0_0000 | ((apic_id as u32) << 12));
+            entry.add(1).write_volatile(0);
+            entry.add(2).write_volatile(vector as u32);
+            entry.add(3).write_volatile(0); // Unmasked
+        }
+
+        // Enable, and clear the function mask
+        let address = self.address;
+        let control = address.read_u16(msix.offset + 2) & !(1 << 14);
+        address.write_u16(msix.offset + 2, control | (1 << 15));
+        let command = address.read_u16(0x04);
+        address.write_u16(0x04, command | COMMAND_INTX_DISABLE);
+        Ok(())
+    }
+}
+
+// Builds the device registry. Needs memory up for ECAM, and has to run before any
+// driver goes looking for its device.
+pub fn init() {
+    if let Some(region) = crate::acpi::ecam_regions()
+        .into_iter()
+        .find(|region| region.segment == 0)
+    {
+        *ECAM.lock() = Some(Ecam {
+            region,
+            buses: [0; 256],
+        });
+        crate::println!("PCI: ECAM at {:#x}", region.base);
+    }
+
+    let mut found = Vec::new();
+    scan_bus(0, &mut found);
+    crate::println!("PCI: {} devices found", found.len());
+    *DEVICES.lock() = found;
+}
+
+// Walks every device on a bus, following PCI-to-PCI bridges to the buses behind them
+fn scan_bus(bus: u8, found: &mut Vec<PciDevice>) {
+    for device in 0..32u8 {
+        let first = PciAddress {
+            bus,
+            device,
+            function: 0,
+        };
+        if first.vendor_id() == 0xFFFF {
+            continue;
+        }
+        let functions = if (first.header_type() & 0x80) != 0 {
+            8
+        } else {
+            1
+        };
+        for function in 0..functions {
+            let address = PciAddress {
+                bus,
+                device,
+                function,
+            };
+            if address.vendor_id() == 0xFFFF {
+                continue;
+            }
+            let pci_device = PciDevice::read(address);
+            let is_bridge = pci_device.class == 0x06 && pci_device.subclass == 0x04;
+            found.push(pci_device);
+            if is_bridge {
+                let secondary = address.read_u8(0x19);
+                if secondary > bus {
+                    scan_bus(secondary, found);
+                }
+            }
+        }
+    }
+}
+
+pub fn devices() -> Vec<PciDevice> {
+    DEVICES.lock().clone()
+}
+
+pub fn find_by_class(class: u8, subclass: u8) -> Option<PciDevice> {
+    DEVICES
+        .lock()
+        .iter()
+        .find(|d| d.class == class && d.subclass == subclass)
+        .cloned()
+}
+
+pub fn find_by_id(vendor_id: u16, device_ids: &[u16]) -> Option<PciDevice> {
+    DEVICES
+        .lock()
+        .iter()
+        .find(|d| d.vendor_id == vendor_id && device_ids.contains(&d.device_id))
+        .cloned()
+}
+
+/*******************
+ * DRIVER MATCHING *
+ *******************/
+// A driver names the class (and optionally the vendor) it handles. Registering it
+// offers every unclaimed matching device to `probe`, returning true claims the device.
+pub struct PciDriver {
+    pub name: &'static str,
+    pub class: u8,
+    pub subclass: u8,
+    pub vendor_id: Option<u16>,
+    pub probe: fn(&PciDevice) -> bool,
+}
+
+impl PciDriver {
+    fn matches(&self, device: &PciDevice) -> bool {
+        device.driver.is_none()
+            && device.class == self.class
+            && device.subclass == self.subclass
+            && self
+                .vendor_id
+                .is_none_or(|vendor| vendor == device.vendor_id)
+    }
+}
+
+pub fn register_driver(driver: &'static PciDriver) {
+    // Probe copies so the registry isn't locked while a driver initializes
+    let candidates: Vec<PciDevice> = devices()
+        .into_iter()
+        .filter(|device| driver.matches(device))
+        .collect();
+
+    for device in candidates {
+        if !(driver.probe)(&device) {
+            continue;
+        }
+        if let Some(entry) = DEVICES
+            .lock()
+            .iter_mut()
+            .find(|d| d.address == device.address)
+        {
+            entry.driver = Some(driver.name);
+        }
+    }
+}
+
+/*********
+ * NAMES *
+ *********/
+pub fn vendor_name(vendor_id: u16) -> &'static str {
+    match vendor_id {
+        0x8086 => "Intel",
+        0x1022 => "AMD",
+        0x1002 => "ATI",
+        0x10DE => "NVIDIA",
+        0x10EC => "Realtek",
+        0x14E4 => "Broadcom",
+        0x1234 => "QEMU",
+        0x1AF4 | 0x1B36 => "Red Hat",
+        0x15AD => "VMware",
+        0x80EE => "VirtualBox",
+        _ => "Unknown",
+    }
+}
+
+pub fn class_name(class: u8, subclass: u8) -> &'static str {
+    match (class, subclass) {
+        (0x01, 0x00) => "SCSI controller",
+        (0x01, 0x01) => "IDE controller",
+        (0x01, 0x05) => "ATA controller",
+        (0x01, 0x06) => "SATA controller",
+        (0x01, 0x08) => "NVMe controller",
+        (0x01, _) => "Storage controller",
+        (0x02, 0x00) => "Ethernet controller",
+        (0x02, _) => "Network controller",
+        (0x03, 0x00) => "VGA controller",
+        (0x03, _) => "Display controller",
+        (0x04, 0x01) => "Audio device",
+        (0x04, 0x03) => "HD audio",
+        (0x04, _) => "Multimedia device",
+        (0x05, _) => "Memory controller",
+        (0x06, 0x00) => "Host bridge",
+        (0x06, 0x01) => "ISA bridge",
+        (0x06, 0x04) => "PCI bridge",
+        (0x06, _) => "Bridge",
+        (0x07, _) => "Communication",
+        (0x08, _) => "System peripheral",
+        (0x09, _) => "Input controller",
+        (0x0C, 0x03) => "USB controller",
+        (0x0C, 0x05) => "SMBus controller",
+        (0x0C, _) => "Serial bus",
+        _ => "Unknown device",
+    }
+}
+
+// One record of the pci_list syscall, same layout as rustos_user::PciInfo
+#[repr(C)]
+pub struct UserPciInfo {
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+    pub class: u8,
+    pub subclass: u8,
+    pub prog_if: u8,
+    pub revision: u8,
+    pub interrupt_line: u8,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub vendor_name: [u8; 16],
+    pub class_name: [u8; 24],
+    pub driver: [u8; 16],
+}
+
+impl UserPciInfo {
+    pub fn new(device: &PciDevice) -> Self {
+        Self {
+            bus: device.address.bus,
+            device: device.address.device,
+            function: device.address.function,
+            class: device.class,
+            subclass: device.subclass,
+            prog_if: device.prog_if,
+            revision: device.revision,
+            interrupt_line: device.interrupt_line,
+            vendor_id: device.vendor_id,
+            device_id: device.device_id,
+            vendor_name: padded(vendor_name(device.vendor_id)),
+            class_name: padded(class_name(device.class, device.subclass)),
+            driver: padded(device.driver.unwrap_or("")),
+        }
+    }
+}
+
+// NUL padded copy of a name, cut short if it doesn't fit
+fn padded<const N: usize>(name: &str) -> [u8; N] {
+    let mut out = [0u8; N];
+    let len = name.len().min(N);
+    out[..len].copy_from_slice(&name.as_bytes()[..len]);
+    out
+}
@@ -0,0 +1,133 @@
+use x86_64::instructions::port::Port;
+
+// The CMOS real-time clock, the only thing on a PC that knows the date at boot.
+// Its registers can hold BCD or binary, 12 or 24 hour time depending on status register B,
+// and the year is only two digits unless firmware points at a century register (ACPI FADT).
+
+const CMOS_ADDRESS: u16 = 0x70;
+const CMOS_DATA: u16 = 0x71;
+
+const REG_SECONDS: u8 = 0x00;
+const REG_MINUTES: u8 = 0x02;
+const REG_HOURS: u8 = 0x04;
+const REG_DAY: u8 = 0x07;
+const REG_MONTH: u8 = 0x08;
+const REG_YEAR: u8 = 0x09;
+const REG_STATUS_A: u8 = 0x0A;
+const REG_STATUS_B: u8 = 0x0B;
+
+const STATUS_A_UPDATING: u8 = 0x80;
+const STATUS_B_24_HOUR: u8 = 0x02;
+const STATUS_B_BINARY: u8 = 0x04;
+const HOUR_PM: u8 = 0x80;
+
+const FADT_CENTURY_OFFSET: usize = 108;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RtcTime {
+    pub year: u16,
+    pub month: u8, // 1-12
+    pub day: u8,   // 1-31
+    pub hour: u8,  // 0-23
+    pub minute: u8,
+    pub second: u8,
+}
+
+fn read_register(reg: u8) -> u8 {
+    unsafe {
+        Port::<u8>::new(CMOS_ADDRESS).write(reg);
+        Port::<u8>::new(CMOS_DATA).read()
+    }
+}
+
+fn bcd_to_binary(value: u8) -> u8 {
+    (value & 0x0F) + (value >> 4) * 10
+}
+
+// The FADT says which CMOS register holds the century, 0 if there isn't one
+fn century_register() -> u8 {
+    crate::acpi::find_table(b"FACP")
+        .filter(|fadt| fadt.len > FADT_CENTURY_OFFSET)
+        .map(|fadt| fadt.read_u8(FADT_CENTURY_OFFSET))
+        .unwrap_or(0)
+}
+
+// Raw register values, read outside of an update cycle
+fn read_raw(century_reg: u8) -> [u8; 7] {
+    while read_register(REG_STATUS_A) & STATUS_A_UPDATING != 0 {
+        core::hint::spin_loop();
+    }
+    [
+        read_register(REG_SECONDS),
+        read_register(REG_MINUTES),
+        read_register(REG_HOURS),
+        read_register(REG_DAY),
+        read_register(REG_MONTH),
+        read_register(REG_YEAR),
+        if century_reg != 0 {
+            read_register(century_reg)
+        } else {
+            0
+        },
+    ]
+}
+
+pub fn read() -> RtcTime {
+    let century_reg = century_register();
+
+    // An update can still start halfway through reading, so read until two reads agree
+    let raw = x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut last = read_raw(century_reg);
+        loop {
+            let next = read_raw(century_reg);
+            if next == last {
+                return next;
+            }
+            last = next;
+        }
+    });
+    let [
+        mut second,
+        mut minute,
+        mut hour,
+        mut day,
+        mut month,
+        mut year,
+        mut century,
+    ] = raw;
+
+    let status_b = read_register(REG_STATUS_B);
+    let pm = hour & HOUR_PM != 0;
+    hour &= !HOUR_PM;
+    if status_b & STATUS_B_BINARY == 0 {
+        second = bcd_to_binary(second);
+        minute = bcd_to_binary(minute);
+        hour = bcd_to_binary(hour);
+        day = bcd_to_binary(day);
+        month = bcd_to_binary(month);
+        year = bcd_to_binary(year);
+        century = bcd_to_binary(century);
+    }
+    if status_b & STATUS_B_24_HOUR == 0 {
+        // 12 hour clock: 12 AM is midnight, 12 PM is noon
+        hour %= 12;
+        if pm {
+            hour += 12;
+        }
+    }
+
+    let year = if century != 0 {
+        century as u16 * 100 + year as u16
+    } else {
+        2000 + year as u16 // Without a century register, assume this one
+    };
+
+    RtcTime {
+        year,
+        month,
+        day,
+        hour,
+        minute,
+        second,
+    }
+}
@@ -1,5 +1,22 @@
 const SERIAL_PORT: u16 = 0x3F8; // COM1 port address
 
+// Register offsets from SERIAL_PORT
+const REG_DATA: u16 = 0; // Divisor low byte while DLAB is set
+const REG_INTERRUPT_ENABLE: u16 = 1; // Divisor high byte while DLAB is set
+const REG_FIFO_CONTROL: u16 = 2;
+const REG_LINE_CONTROL: u16 = 3;
+const REG_MODEM_CONTROL: u16 = 4;
+const REG_LINE_STATUS: u16 = 5;
+
+const LINE_DLAB: u8 = 0x80;
+const LINE_8N1: u8 = 0x03;
+const FIFO_ENABLE_CLEAR_TRIGGER_1: u8 = 0x07; // Interrupt as soon as one byte is in
+const MODEM_DTR_RTS_OUT2: u8 = 0x0B; // OUT2 gates the UART's interrupt line on PCs
+const INTERRUPT_DATA_AVAILABLE: u8 = 0x01;
+const STATUS_DATA_READY: u8 = 0x01;
+
+const BAUD_DIVISOR: u16 = 1; // 115200 baud
+
 pub fn is_transmit_empty() -> bool {
     let status: u8;
     unsafe {
@@ -35,3 +52,51 @@ pub fn serial_write_str(s: &str) {
         serial_write_byte(byte);
     }
 }
+
+// Sets COM1 to 115200 8N1 and turns on the receive interrupt (IRQ4)
+pub fn init() {
+    write_register(REG_INTERRUPT_ENABLE, 0);
+    write_register(REG_LINE_CONTROL, LINE_DLAB);
+    write_register(REG_DATA, BAUD_DIVISOR as u8);
+    write_register(REG_INTERRUPT_ENABLE, (BAUD_DIVISOR >> 8) as u8);
+    write_register(REG_LINE_CONTROL, LINE_8N1);
+    write_register(REG_FIFO_CONTROL, FIFO_ENABLE_CLEAR_TRIGGER_1);
+    write_register(REG_MODEM_CONTROL, MODEM_DTR_RTS_OUT2);
+
+    // Whatever arrived before now is noise from the firmware's console
+    while read_register(REG_LINE_STATUS) & STATUS_DATA_READY != 0 {
+        read_register(REG_DATA);
+    }
+    write_register(REG_INTERRUPT_ENABLE, INTERRUPT_DATA_AVAILABLE);
+}
+
+// IRQ4: hands every received byte to the serial TTY
+pub fn on_irq() {
+    while read_register(REG_LINE_STATUS) & STATUS_DATA_READY != 0 {
+        super::tty::receive(read_register(REG_DATA));
+    }
+}
+
+fn read_register(reg: u16) -> u8 {
+    let value: u8;
+    unsafe {
+        core::arch::asm!(
+            "in al, dx",
+            out("al") value,
+            in("dx") SERIAL_PORT + reg,
+            options(nomem, nostack, preserves_flags)
+        );
+    }
+    value
+}
+
+fn write_register(reg: u16, value: u8) {
+    unsafe {
+        core::arch::asm!(
+            "out dx, al",
+            in("dx") SERIAL_PORT + reg,
+            in("al") value,
+            options(nomem, nostack, preserves_flags)
+        );
+    }
+}
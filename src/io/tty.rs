@@ -0,0 +1,190 @@
+use super::keyboard::{KEY_DELETE, KEY_DOWN, KEY_END, KEY_HOME, KEY_LEFT, KEY_RIGHT, KEY_UP};
+use crate::sync::IrqMutex;
+use crossbeam_queue::ArrayQueue;
+use lazy_static::lazy_static;
+
+// The serial console. Bytes from COM1 go through a line discipline that turns what a
+// terminal sends (CR for Enter, DEL for Backspace, ESC [ A for Up, ...) into the key codes
+// the keyboard produces, so programs read both the same way. Lines aren't collected here:
+// the shell edits its own line and echoes what it accepts, which reaches the serial port
+// like the rest of its output.
+
+lazy_static! {
+    // Decoded keys waiting for the foreground task
+    static ref SERIAL_INPUT: ArrayQueue<u8> = ArrayQueue::new(256);
+}
+
+static LINE_DISCIPLINE: IrqMutex<LineDiscipline> = IrqMutex::new(LineDiscipline::new());
+
+const KEY_BACKSPACE: u8 = 0x08;
+const ESC: u8 = 0x1B;
+const DEL: u8 = 0x7F;
+const CTRL_Z: u8 = 0x1A;
+
+#[derive(Clone, Copy)]
+enum Escape {
+    None,
+    Esc,      // ESC seen
+    Csi(u16), // ESC [ and the number so far
+    Ss3,      // ESC O, what some terminals send for the arrows and Home/End
+}
+
+struct LineDiscipline {
+    escape: Escape,
+    after_cr: bool, // A LF right after a CR is the same Enter
+}
+
+impl LineDiscipline {
+    const fn new() -> Self {
+        Self {
+            escape: Escape::None,
+            after_cr: false,
+        }
+    }
+
+    // The key `byte` completes, if any
+    fn receive(&mut self, byte: u8) -> Option<u8> {
+        let after_cr = core::mem::replace(&mut self.after_cr, byte == b'\r');
+        match self.escape {
+            Escape::None => match byte {
+                ESC => {
+                    self.escape = Escape::Esc;
+                    None
+                }
+                b'\r' => Some(b'\n'),
+                b'\n' if after_cr => None,
+                DEL => Some(KEY_BACKSPACE),
+                _ => Some(byte),
+            },
+            Escape::Esc => {
+                self.escape = match byte {
+                    b'[' => Escape::Csi(0),
+                    b'O' => Escape::Ss3,
+                    _ => Escape::None,
+                };
+                None
+            }
+            Escape::Csi(n) => match byte {
+                b'0'..=b'9' => {
+                    self.escape = Escape::Csi(n.saturating_mul(10) + (byte - b'0') as u16);
+                    None
+                }
+                // Modifiers after the ';' are dropped, Ctrl+Right is still Right
+                b';' => None,
+                b'~' => {
+                    self.escape = Escape::None;
+                    match n {
+                        1 | 7 => Some(KEY_HOME),
+                        3 => Some(KEY_DELETE),
+                        4 | 8 => Some(KEY_END),
+                        _ => None,
+                    }
+                }
+                0x40..=0x7E => {
+                    self.escape = Escape::None;
+                    cursor_key(byte)
+                }
+                _ => None,
+            },
+            Escape::Ss3 => {
+                self.escape = Escape::None;
+                cursor_key(byte)
+            }
+        }
+    }
+}
+
+fn cursor_key(last: u8) -> Option<u8> {
+    match last {
+        b'A' => Some(KEY_UP),
+        b'B' => Some(KEY_DOWN),
+        b'C' => Some(KEY_RIGHT),
+        b'D' => Some(KEY_LEFT),
+        b'H' => Some(KEY_HOME),
+        b'F' => Some(KEY_END),
+        _ => None,
+    }
+}
+
+// Called from the COM1 interrupt with each byte received
+pub fn receive(byte: u8) {
+    let Some(key) = LINE_DISCIPLINE.lock().receive(byte) else {
+        return;
+    };
+    // Like on the keyboard, Ctrl+Z suspends the foreground group instead of being read
+    if key == CTRL_Z {
+        super::keyboard::request_suspend();
+        return;
+    }
+    let _ = SERIAL_INPUT.push(key);
+    super::keyboard::wake_key_readers();
+}
+
+// The next key typed on the serial console. Unlike the keyboard's, this input isn't dropped
+// when the foreground changes, so a script can type ahead of the program that will read it.
+pub fn read_key() -> Option<u8> {
+    SERIAL_INPUT.pop()
+}
+
+// Sends a byte of output to the terminal. Terminals need CR LF to start a new line.
+pub fn write_byte(byte: u8) {
+    if byte == b'\n' {
+        super::serial::serial_write_byte(b'\r');
+    }
+    super::serial::serial_write_byte(byte);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+
+    fn keys(bytes: &[u8]) -> Vec<u8> {
+        let mut discipline = LineDiscipline::new();
+        bytes
+            .iter()
+            .filter_map(|&b| discipline.receive(b))
+            .collect()
+    }
+
+    #[test]
+    fn plain_bytes_pass_through() {
+        assert_eq!(keys(b"ls -l"), b"ls -l");
+    }
+
+    #[test]
+    fn enter_is_one_newline() {
+        assert_eq!(keys(b"a\rb\nc\r\nd"), b"a\nb\nc\nd");
+        assert_eq!(keys(b"\r\r\n\n"), b"\n\n\n");
+    }
+
+    #[test]
+    fn delete_is_backspace() {
+        assert_eq!(keys(&[b'x', DEL]), [b'x', KEY_BACKSPACE]);
+    }
+
+    #[test]
+    fn csi_and_ss3_cursor_keys() {
+        assert_eq!(
+            keys(b"\x1b[A\x1b[B\x1b[C\x1b[D"),
+            [KEY_UP, KEY_DOWN, KEY_RIGHT, KEY_LEFT]
+        );
+        assert_eq!(keys(b"\x1bOH\x1bOF"), [KEY_HOME, KEY_END]);
+        assert_eq!(keys(b"\x1b[1;5C"), [KEY_RIGHT]);
+    }
+
+    #[test]
+    fn tilde_sequences() {
+        assert_eq!(keys(b"\x1b[1~\x1b[7~"), [KEY_HOME, KEY_HOME]);
+        assert_eq!(keys(b"\x1b[4~\x1b[8~"), [KEY_END, KEY_END]);
+        assert_eq!(keys(b"\x1b[3~"), [KEY_DELETE]);
+        assert_eq!(keys(b"\x1b[2~"), []); // Insert
+    }
+
+    #[test]
+    fn unknown_sequences_are_swallowed() {
+        assert_eq!(keys(b"\x1b[Zq"), b"q");
+        assert_eq!(keys(b"\x1bxq"), b"q");
+        assert_eq!(keys(b"\x1bOzq"), b"q");
+    }
+}
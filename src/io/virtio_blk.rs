@@ -0,0 +1,413 @@
+use core::sync::atomic::{AtomicBool, Ordering};
+use embedded_io::{ErrorKind, ErrorType};
+use simple_fatfs::block_io::{BlockBase, BlockRead, BlockSize, BlockWrite};
+
+use crate::interrupts::VIRTIO_BLK_VECTOR;
+use crate::io::pci::PciDevice;
+use crate::multitasker::wait_queue::WaitQueue;
+
+// virtio-blk over the modern (virtio 1.0) PCI transport. The device describes where its
+// register blocks live with vendor specific PCI capabilities. We drive a single split
+// virtqueue: every request is a chain of three descriptors (header, data, status byte),
+// and the device answers through the used ring. Once interrupts are on it also raises an
+// MSI-X message and the task sleeps on REQUEST_WAIT, before that (or without MSI-X) the
+// used ring is polled. Data goes through a 64 KiB bounce buffer.
+
+pub const VIRTIO_VENDOR: u16 = 0x1AF4;
+const VIRTIO_BLK_DEVICES: [u16; 2] = [0x1042, 0x1001]; // Modern, transitional
+
+const PCI_CAP_VENDOR: u8 = 0x09;
+const CAP_COMMON_CFG: u8 = 1;
+const CAP_NOTIFY_CFG: u8 = 2;
+const CAP_DEVICE_CFG: u8 = 4;
+
+// Common configuration registers
+const COMMON_DEVICE_FEATURE_SELECT: usize = 0x00;
+const COMMON_DEVICE_FEATURE: usize = 0x04;
+const COMMON_DRIVER_FEATURE_SELECT: usize = 0x08;
+const COMMON_DRIVER_FEATURE: usize = 0x0C;
+const COMMON_DEVICE_STATUS: usize = 0x14;
+const COMMON_QUEUE_SELECT: usize = 0x16;
+const COMMON_QUEUE_SIZE: usize = 0x18;
+const COMMON_QUEUE_MSIX_VECTOR: usize = 0x1A;
+const COMMON_QUEUE_ENABLE: usize = 0x1C;
+const COMMON_QUEUE_NOTIFY_OFF: usize = 0x1E;
+const COMMON_QUEUE_DESC: usize = 0x20;
+const COMMON_QUEUE_DRIVER: usize = 0x28;
+const COMMON_QUEUE_DEVICE: usize = 0x30;
+
+const STATUS_ACKNOWLEDGE: u8 = 1;
+const STATUS_DRIVER: u8 = 2;
+const STATUS_DRIVER_OK: u8 = 4;
+const STATUS_FEATURES_OK: u8 = 8;
+const FEATURE_BLK_FLUSH: u32 = 1 << 9;
+const FEATURE_VERSION_1: u32 = 1 << 0; // Bit 32, in the second feature word
+
+const DESC_NEXT: u16 = 1;
+const DESC_WRITE: u16 = 2; // Device writes into this buffer
+
+const REQUEST_IN: u32 = 0;
+const REQUEST_OUT: u32 = 1;
+const REQUEST_FLUSH: u32 = 4;
+
+// Descriptor table, available ring, used ring and the request header/status all
+// share one frame
+const QUEUE_SIZE: u16 = 8;
+const AVAIL_OFFSET: u64 = 0x100;
+const USED_OFFSET: u64 = 0x200;
+const HEADER_OFFSET: u64 = 0x400;
+const STATUS_OFFSET: u64 = 0x410;
+
+const DMA_BUFFER_SIZE: usize = 64 * 1024;
+const DMA_MAX_SECTORS: usize = DMA_BUFFER_SIZE / 512;
+
+const REQUEST_TIMEOUT_MS: u64 = 5000;
+const POLL_LIMIT: u32 = 10_000_000; // Register reads, the uptime doesn't move during the probe
+const NO_VECTOR: u16 = 0xFFFF;
+
+static REQUEST_WAIT: WaitQueue = WaitQueue::new();
+static MSIX_ENABLED: AtomicBool = AtomicBool::new(false);
+static IRQ_MODE: AtomicBool = AtomicBool::new(false);
+
+#[repr(C)]
+struct Descriptor {
+    address: u64,
+    len: u32,
+    flags: u16,
+    next: u16,
+}
+
+#[repr(C)]
+struct RequestHeader {
+    kind: u32,
+    reserved: u32,
+    sector: u64,
+}
+
+pub struct VirtioBlk {
+    common: u64, // Virtual addresses of the mapped register blocks
+    notify: u64,
+    device: u64,
+    queue_phys: u64,
+    buffer_phys: u64,
+    queue_size: u16,
+    next_avail: u16,
+    last_used: u16,
+    sector_count: u64,
+    can_flush: bool,
+}
+
+impl VirtioBlk {
+    pub fn init(device: &PciDevice) -> Result<Self, &'static str> {
+        // virtio-scsi shares the vendor and class, only the device ID tells them apart
+        if device.vendor_id != VIRTIO_VENDOR || !VIRTIO_BLK_DEVICES.contains(&device.device_id) {
+            return Err("not a virtio-blk device");
+        }
+        let (pci, bars) = (device.address, device.bars);
+        pci.enable_bus_master();
+
+        // Each virtio capability names a BAR, an offset into it and a length
+        let (mut common, mut notify, mut device, mut notify_multiplier) = (0, 0, 0, 0);
+        for (cap, id) in pci.capabilities() {
+            if id != PCI_CAP_VENDOR {
+                continue;
+            }
+            let Some(bar) = bars.get(pci.read_u8(cap + 4) as usize) else {
+                continue;
+            };
+            let base = bar
+                .memory_address()
+                .ok_or("virtio capability in an I/O BAR")?;
+            let offset = pci.read_u32(cap + 8) as u64;
+            let length = pci.read_u32(cap + 12) as usize;
+            let mapped = || crate::memory::map_mmio(base + offset, length);
+            match pci.read_u8(cap + 3) {
+                CAP_COMMON_CFG => common = mapped(),
+                CAP_NOTIFY_CFG => {
+                    notify = mapped();
+                    notify_multiplier = pci.read_u32(cap + 16);
+                }
+                CAP_DEVICE_CFG => device = mapped(),
+                _ => {}
+            }
+        }
+        if common == 0 || notify == 0 || device == 0 {
+            return Err("virtio-blk device has no modern interface");
+        }
+
+        let queue_phys = crate::memory::allocate_contiguous(1, 0x1000, u64::MAX)
+            .ok_or("no memory for the virtqueue")?;
+        let buffer_phys =
+            crate::memory::allocate_contiguous(DMA_BUFFER_SIZE / 0x1000, 0x1000, u64::MAX)
+                .ok_or("no memory for the virtio DMA buffer")?;
+        unsafe {
+            core::ptr::write_bytes(
+                crate::memory::phys_to_virt(queue_phys) as *mut u8,
+                0,
+                0x1000,
+            );
+        }
+
+        let mut disk = Self {
+            common,
+            notify,
+            device,
+            queue_phys,
+            buffer_phys,
+            queue_size: 0,
+            next_avail: 0,
+            last_used: 0,
+            sector_count: 0,
+            can_flush: false,
+        };
+
+        // Reset, then walk the status bits up as the spec describes
+        disk.write_common_u8(COMMON_DEVICE_STATUS, 0);
+        if !poll(|| disk.read_common_u8(COMMON_DEVICE_STATUS) == 0) {
+            return Err("virtio-blk device won't reset");
+        }
+        disk.write_common_u8(COMMON_DEVICE_STATUS, STATUS_ACKNOWLEDGE | STATUS_DRIVER);
+
+        // All we want is the modern interface itself, plus cache flushes if there are any
+        disk.write_common_u32(COMMON_DEVICE_FEATURE_SELECT, 0);
+        let offered = disk.read_common_u32(COMMON_DEVICE_FEATURE);
+        disk.can_flush = (offered & FEATURE_BLK_FLUSH) != 0;
+        disk.write_common_u32(COMMON_DRIVER_FEATURE_SELECT, 0);
+        disk.write_common_u32(COMMON_DRIVER_FEATURE, offered & FEATURE_BLK_FLUSH);
+        disk.write_common_u32(COMMON_DRIVER_FEATURE_SELECT, 1);
+        disk.write_common_u32(COMMON_DRIVER_FEATURE, FEATURE_VERSION_1);
+        let status = STATUS_ACKNOWLEDGE | STATUS_DRIVER | STATUS_FEATURES_OK;
+        disk.write_common_u8(COMMON_DEVICE_STATUS, status);
+        if (disk.read_common_u8(COMMON_DEVICE_STATUS) & STATUS_FEATURES_OK) == 0 {
+            return Err("virtio-blk device rejected our features");
+        }
+
+        disk.write_common_u16(COMMON_QUEUE_SELECT, 0);
+        disk.queue_size = disk.read_common_u16(COMMON_QUEUE_SIZE).min(QUEUE_SIZE);
+        if disk.queue_size < 3 {
+            return Err("virtio-blk queue too small");
+        }
+        disk.write_common_u16(COMMON_QUEUE_SIZE, disk.queue_size);
+        disk.write_common_u64(COMMON_QUEUE_DESC, queue_phys);
+        disk.write_common_u64(COMMON_QUEUE_DRIVER, queue_phys + AVAIL_OFFSET);
+        disk.write_common_u64(COMMON_QUEUE_DEVICE, queue_phys + USED_OFFSET);
+        let notify_off = disk.read_common_u16(COMMON_QUEUE_NOTIFY_OFF) as u64;
+        disk.notify += notify_off * notify_multiplier as u64;
+        disk.write_common_u16(COMMON_QUEUE_ENABLE, 1);
+
+        disk.write_common_u8(COMMON_DEVICE_STATUS, status | STATUS_DRIVER_OK);
+
+        // Capacity in 512 byte sectors is the first field of the device config
+        disk.sector_count = unsafe { (disk.device as *const u64).read_volatile() };
+        Ok(disk)
+    }
+
+    pub fn sector_count(&self) -> u64 {
+        self.sector_count
+    }
+
+    // Routes the queue's used buffer notifications to VIRTIO_BLK_VECTOR over MSI-X. Only
+    // the disk the filesystem ends up on calls this, requests are still polled until
+    // enable_interrupts().
+    pub fn setup_interrupts(&self, device: &PciDevice) {
+        if !crate::interrupts::apic_enabled() {
+            return;
+        }
+        if let Err(e) = device.enable_msix(crate::interrupts::lapic_id(), VIRTIO_BLK_VECTOR) {
+            crate::println!("virtio-blk: {}, polling for completions", e);
+            return;
+        }
+        // Table entry 0 for the queue, the device answers NO_VECTOR if it can't
+        self.write_common_u16(COMMON_QUEUE_SELECT, 0);
+        self.write_common_u16(COMMON_QUEUE_MSIX_VECTOR, 0);
+        if self.read_common_u16(COMMON_QUEUE_MSIX_VECTOR) == NO_VECTOR {
+            crate::println!("virtio-blk: queue has no MSI-X vector, polling for completions");
+            return;
+        }
+        MSIX_ENABLED.store(true, Ordering::Release);
+    }
+
+    // Sends one request down the queue and waits for the device to hand it back
+    fn request(&mut self, kind: u32, sector: u64, bytes: usize) -> Result<(), &'static str> {
+        let queue = crate::memory::phys_to_virt(self.queue_phys);
+        unsafe {
+            ((queue + HEADER_OFFSET) as *mut RequestHeader).write_volatile(RequestHeader {
+                kind,
+                reserved: 0,
+                sector,
+            });
+            ((queue + STATUS_OFFSET) as *mut u8).write_volatile(0xFF);
+
+            let descriptors = queue as *mut Descriptor;
+            let data_flags = if kind == REQUEST_IN { DESC_WRITE } else { 0 };
+            let chain = [
+                (self.queue_phys + HEADER_OFFSET, 16, DESC_NEXT),
+                (self.buffer_phys, bytes as u32, data_flags | DESC_NEXT),
+                (self.queue_phys + STATUS_OFFSET, 1, DESC_WRITE),
+            ];
+            // A flush has no data, the header links straight to the status byte
+            let chain: &[(u64, u32, u16)] = if bytes == 0 {
+                &[chain[0], chain[2]]
+            } else {
+                &chain
+            };
+            for (i, &(address, len, flags)) in chain.iter().enumerate() {
+                descriptors.add(i).write_volatile(Descriptor {
+                    address,
+                    len,
+                    flags,
+                    next: i as u16 + 1,
+                });
+            }
+
+            // Publish descriptor 0 as the head of the chain
+            let avail = (queue + AVAIL_OFFSET) as *mut u16;
+            let slot = self.next_avail % self.queue_size;
+            avail.add(2 + slot as usize).write_volatile(0);
+            core::sync::atomic::fence(core::sync::atomic::Ordering::SeqCst);
+            self.next_avail = self.next_avail.wrapping_add(1);
+            avail.add(1).write_volatile(self.next_avail);
+            core::sync::atomic::fence(core::sync::atomic::Ordering::SeqCst);
+            (self.notify as *mut u16).write_volatile(0);
+
+            let used_idx = (queue + USED_OFFSET + 2) as *const u16;
+            if !self.wait_for_used(used_idx) {
+                return Err("virtio-blk request timed out");
+            }
+            self.last_used = self.last_used.wrapping_add(1);
+
+            match ((queue + STATUS_OFFSET) as *const u8).read_volatile() {
+                0 => Ok(()),
+                _ => Err("virtio-blk request failed"),
+            }
+        }
+    }
+
+    // Waits for the device to move the used index past our last request
+    fn wait_for_used(&self, used_idx: *const u16) -> bool {
+        let last_used = self.last_used;
+        let answered = || unsafe { used_idx.read_volatile() } != last_used;
+        if IRQ_MODE.load(Ordering::Acquire) {
+            let deadline = crate::timer::get_uptime_ms() + REQUEST_TIMEOUT_MS;
+            REQUEST_WAIT.wait_until_deadline(answered, Some(deadline))
+        } else {
+            poll(answered)
+        }
+    }
+
+    fn buffer(&self) -> *mut u8 {
+        crate::memory::phys_to_virt(self.buffer_phys) as *mut u8
+    }
+
+    fn read_common_u8(&self, offset: usize) -> u8 {
+        unsafe { ((self.common as usize + offset) as *const u8).read_volatile() }
+    }
+
+    fn write_common_u8(&self, offset: usize, value: u8) {
+        unsafe { ((self.common as usize + offset) as *mut u8).write_volatile(value) }
+    }
+
+    fn read_common_u16(&self, offset: usize) -> u16 {
+        unsafe { ((self.common as usize + offset) as *const u16).read_volatile() }
+    }
+
+    fn write_common_u16(&self, offset: usize, value: u16) {
+        unsafe { ((self.common as usize + offset) as *mut u16).write_volatile(value) }
+    }
+
+    fn read_common_u32(&self, offset: usize) -> u32 {
+        unsafe { ((self.common as usize + offset) as *const u32).read_volatile() }
+    }
+
+    fn write_common_u32(&self, offset: usize, value: u32) {
+        unsafe { ((self.common as usize + offset) as *mut u32).write_volatile(value) }
+    }
+
+    // 64 bit fields may be written as two halves, low first
+    fn write_common_u64(&self, offset: usize, value: u64) {
+        self.write_common_u32(offset, value as u32);
+        self.write_common_u32(offset + 4, (value >> 32) as u32);
+    }
+}
+
+// Spins until `done()`, giving up after POLL_LIMIT tries
+fn poll(mut done: impl FnMut() -> bool) -> bool {
+    for _ in 0..POLL_LIMIT {
+        if done() {
+            return true;
+        }
+        core::hint::spin_loop();
+    }
+    false
+}
+
+// Called once interrupts are on, from then on a request sleeps until its MSI-X arrives
+pub fn enable_interrupts() {
+    IRQ_MODE.store(MSIX_ENABLED.load(Ordering::Acquire), Ordering::Release);
+}
+
+// Back to polling, for when the CPU the MSI-X goes to may be gone
+pub fn disable_interrupts() {
+    IRQ_MODE.store(false, Ordering::Release);
+}
+
+// Called from the virtio-blk vector. With MSI-X there is no ISR status to read, the
+// message itself says the used ring moved.
+pub fn on_irq() {
+    REQUEST_WAIT.wake_all();
+}
+
+impl ErrorType for VirtioBlk {
+    type Error = ErrorKind;
+}
+
+impl BlockBase for VirtioBlk {
+    fn block_size(&self) -> BlockSize {
+        512
+    }
+
+    fn block_count(&self) -> u32 {
+        self.sector_count.min(u32::MAX as u64) as u32
+    }
+}
+
+impl BlockRead for VirtioBlk {
+    fn read(&mut self, block: u32, buf: &mut [u8]) -> Result<(), Self::Error> {
+        // Only whole sectors are transferred
+        let whole = buf.len() / 512 * 512;
+        for (i, chunk) in buf[..whole].chunks_mut(DMA_BUFFER_SIZE).enumerate() {
+            let sector = block as u64 + (i * DMA_MAX_SECTORS) as u64;
+            if let Err(e) = self.request(REQUEST_IN, sector, chunk.len()) {
+                crate::serial_println!("virtio-blk: read failed (sector: {}): {}", sector, e);
+                return Err(ErrorKind::Other);
+            }
+            let source = unsafe { core::slice::from_raw_parts(self.buffer(), chunk.len()) };
+            chunk.copy_from_slice(source);
+        }
+        Ok(())
+    }
+}
+
+impl BlockWrite for VirtioBlk {
+    fn write(&mut self, block: u32, buf: &[u8]) -> Result<(), Self::Error> {
+        let whole = buf.len() / 512 * 512;
+        for (i, chunk) in buf[..whole].chunks(DMA_BUFFER_SIZE).enumerate() {
+            let sector = block as u64 + (i * DMA_MAX_SECTORS) as u64;
+            let target = unsafe { core::slice::from_raw_parts_mut(self.buffer(), chunk.len()) };
+            target.copy_from_slice(chunk);
+            if let Err(e) = self.request(REQUEST_OUT, sector, chunk.len()) {
+                crate::serial_println!("virtio-blk: write failed (sector: {}): {}", sector, e);
+                return Err(ErrorKind::Other);
+            }
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), Self::Error> {
+        if !self.can_flush {
+            return Ok(()); // Without the feature the device has no write cache to flush
+        }
+        self.request(REQUEST_FLUSH, 0, 0)
+            .map_err(|_| ErrorKind::Other)
+    }
+}
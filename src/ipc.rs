@@ -0,0 +1,189 @@
+/**************************************************************************************************************************************************************************************
+ *                                                                                   DOCUMENTATION                                                                                    *
+ *                                        THIS MODULE IMPLEMENTS MESSAGE CHANNELS, THE WAY TASKS TALK TO EACH OTHER APART FROM THE FILESYSTEM.                                        *
+ *                                            A CHANNEL IS A BOUNDED CROSSBEAM ARRAYQUEUE OF FIXED SIZE MESSAGES, LIKE THE SCANCODE QUEUE.                                            *
+ * TASKS NEVER SEE CHANNELS DIRECTLY, THEY HOLD HANDLES IN A PER PROCESS HANDLE TABLE (SHARED BY ITS THREADS), SO A HANDLE IS A CAPABILITY: YOU CAN ONLY USE CHANNELS YOU WERE GIVEN. *
+ *            A MESSAGE CAN CARRY ONE HANDLE, THE RECEIVER GETS ITS OWN HANDLE TO THE SAME CHANNEL. CHANNELS CAN ALSO BE PUBLISHED UNDER A NAME SO SERVICES CAN BE FOUND.             *
+ *                                        RECEIVE BLOCKS THE TASK (WAITING WITH A DEADLINE) UNTIL A SEND WAKES IT UP OR THE TIMEOUT RUNS OUT.                                         *
+ *************************************************************************************************************************************************************************************/
+
+use crate::sync::IrqMutex;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::{Arc, Weak};
+use crossbeam_queue::ArrayQueue;
+
+pub const MESSAGE_MAX: usize = 256;
+pub const NO_HANDLE: u64 = u64::MAX;
+const CHANNEL_CAPACITY: usize = 32;
+
+// What user space hands us for send and gets back from receive.
+// Must match rustos_user::Message.
+#[repr(C)]
+pub struct UserMessage {
+    pub len: u64,
+    pub handle: u64, // NO_HANDLE if the message doesn't carry one
+    pub sender: u64, // Filled in by the kernel, ignored on send
+    pub data: [u8; MESSAGE_MAX],
+}
+
+struct Message {
+    sender: u64,
+    len: usize,
+    data: [u8; MESSAGE_MAX],
+    channel: Option<Arc<Channel>>, // A handle in flight, installed in the receiver's table
+}
+
+pub struct Channel {
+    id: u64,
+    queue: ArrayQueue<Message>,
+}
+
+struct Ipc {
+    handles: BTreeMap<(u64, u64), Arc<Channel>>, // (process id, handle) -> channel
+    names: BTreeMap<String, Weak<Channel>>,
+    next_handle: u64,
+    next_channel: u64,
+}
+
+static IPC: IrqMutex<Ipc> = IrqMutex::new(Ipc {
+    handles: BTreeMap::new(),
+    names: BTreeMap::new(),
+    next_handle: 1,
+    next_channel: 1,
+});
+
+fn with_ipc<R>(f: impl FnOnce(&mut Ipc) -> R) -> R {
+    f(&mut IPC.lock())
+}
+
+impl Ipc {
+    fn install(&mut self, owner: u64, channel: Arc<Channel>) -> u64 {
+        let handle = self.next_handle;
+        self.next_handle += 1;
+        self.handles.insert((owner, handle), channel);
+        handle
+    }
+
+    fn lookup(&self, owner: u64, handle: u64) -> Result<&Arc<Channel>, &'static str> {
+        self.handles
+            .get(&(owner, handle))
+            .ok_or("Invalid channel handle")
+    }
+}
+
+/*********************************
+ * CREATING AND OPENING CHANNELS *
+ *********************************/
+// Creates a channel and returns the owner's handle to it. Named channels can be
+// opened by anyone for as long as at least one handle to them is alive.
+pub fn create(owner: u64, name: Option<&str>) -> Result<u64, &'static str> {
+    with_ipc(|ipc| {
+        if let Some(name) = name {
+            if ipc.names.get(name).is_some_and(|c| c.strong_count() > 0) {
+                return Err("Channel name already in use");
+            }
+        }
+
+        let channel = Arc::new(Channel {
+            id: ipc.next_channel,
+            queue: ArrayQueue::new(CHANNEL_CAPACITY),
+        });
+        ipc.next_channel += 1;
+
+        if let Some(name) = name {
+            ipc.names
+                .insert(String::from(name), Arc::downgrade(&channel));
+        }
+        Ok(ipc.install(owner, channel))
+    })
+}
+
+pub fn open(owner: u64, name: &str) -> Result<u64, &'static str> {
+    with_ipc(|ipc| {
+        let channel = ipc
+            .names
+            .get(name)
+            .and_then(Weak::upgrade)
+            .ok_or("No channel with that name")?;
+        Ok(ipc.install(owner, channel))
+    })
+}
+
+pub fn close(owner: u64, handle: u64) -> Result<(), &'static str> {
+    with_ipc(|ipc| {
+        ipc.handles
+            .remove(&(owner, handle))
+            .map(|_| ())
+            .ok_or("Invalid channel handle")
+    })
+}
+
+// Drops every handle a process still holds, called when its last thread is reaped.
+pub fn release_process(process_id: u64) {
+    with_ipc(|ipc| ipc.handles.retain(|&(owner, _), _| owner != process_id));
+}
+
+// The channel behind a handle, used to block a receiver on it.
+pub fn channel_id(owner: u64, handle: u64) -> Option<u64> {
+    with_ipc(|ipc| ipc.lookup(owner, handle).ok().map(|c| c.id))
+}
+
+// Whether a receive on `handle` would find a message
+pub fn has_messages(owner: u64, handle: u64) -> bool {
+    with_ipc(|ipc| ipc.lookup(owner, handle).is_ok_and(|c| !c.queue.is_empty()))
+}
+
+/*************************
+ * SENDING AND RECEIVING *
+ *************************/
+// Queues a message, optionally passing along one of the sender's handles.
+// Never blocks: a full channel is an error. Returns the channel ID so the
+// caller can wake anyone blocked on it.
+pub fn send(
+    sender: u64,
+    handle: u64,
+    data: &[u8],
+    pass_handle: Option<u64>,
+) -> Result<u64, &'static str> {
+    if data.len() > MESSAGE_MAX {
+        return Err("Message too large");
+    }
+
+    with_ipc(|ipc| {
+        let channel = ipc.lookup(sender, handle)?;
+        let passed = match pass_handle {
+            Some(h) => Some(ipc.lookup(sender, h)?.clone()),
+            None => None,
+        };
+
+        let mut message = Message {
+            sender,
+            len: data.len(),
+            data: [0; MESSAGE_MAX],
+            channel: passed,
+        };
+        message.data[..data.len()].copy_from_slice(data);
+
+        channel.queue.push(message).map_err(|_| "Channel is full")?;
+        Ok(channel.id)
+    })
+}
+
+// Pops the next message into `out`. Ok(false) means the channel is empty.
+pub fn try_receive(owner: u64, handle: u64, out: &mut UserMessage) -> Result<bool, &'static str> {
+    with_ipc(|ipc| {
+        let Some(message) = ipc.lookup(owner, handle)?.queue.pop() else {
+            return Ok(false);
+        };
+
+        out.len = message.len as u64;
+        out.sender = message.sender;
+        out.data[..message.len].copy_from_slice(&message.data[..message.len]);
+        out.handle = match message.channel {
+            Some(channel) => ipc.install(owner, channel),
+            None => NO_HANDLE,
+        };
+        Ok(true)
+    })
+}
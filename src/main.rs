@@ -1,5 +1,5 @@
-#![no_std] // Indicate that we are not using the standard library
-#![no_main] // Indicate that we are not using the standard main function
+#![cfg_attr(not(test), no_std)] // Indicate that we are not using the standard library
+#![cfg_attr(not(test), no_main)] // Indicate that we are not using the standard main function
 #![feature(alloc_error_handler)]
 #![feature(c_variadic)]
 #![allow(warnings)]
@@ -16,22 +16,26 @@ use limine::{BaseRevision, request::FramebufferRequest};
 
 // Import modules
 
+mod acpi; // ACPI tables (MCFG, MADT, FADT, ...)
+mod clock; // Wall clock (CMOS RTC + PIT)
 mod fs; // Filesystem handling (FAT32)
 mod globals; // Global variables and constants
 mod helpers; // Helper function
 mod interrupts; // GDT and IDT setup
 mod io; // Input/Output handling (keyboard, mouse, etc.)
+mod ipc; // Message channels between tasks
 mod memory; // Memory management (paging, heap, etc.)
 mod multitasker; // Multitasking and scheduler
+mod power; // ACPI shutdown and reboot
 pub mod program_loader; // Program loading functionality
 mod screen; // Screen rendering and framebuffer management
-pub mod shell; // Simple Shell
+mod smp; // Other CPUs, per-CPU data and IPIs
+mod sync; // Interrupt-safe locks
 mod timer; // Timer and sleep functions
 
 // Use functions and structs from modules
 use crate::helpers::{enable_sse, hcf};
 use crate::interrupts::{init_idt, init_pic};
-use crate::io::keyboard::{SCANCODE_QUEUE, scancode_to_char};
 
 #[used]
 #[unsafe(link_section = ".limine_requests")]
@@ -52,6 +56,7 @@ pub static FRAMEBUFFER_REQUEST: FramebufferRequest = FramebufferRequest::new();
 #[unsafe(link_section = ".limine_requests")]
 pub static HHDM_REQUEST: HhdmRequest = HhdmRequest::new();
 
+#[cfg(not(test))]
 #[panic_handler]
 fn panic(_info: &PanicInfo) -> ! {
     // 1. Force a newline and print a clear marker to serial
@@ -82,6 +87,7 @@ fn panic(_info: &PanicInfo) -> ! {
     hcf();
 }
 
+#[cfg(not(test))]
 #[alloc_error_handler]
 fn alloc_error_handler(layout: Layout) -> ! {
     panic!(
@@ -102,6 +108,9 @@ pub extern "C" fn kmain() -> ! {
         hcf();
     }
 
+    // GS has to point at this CPU's data before any interrupt or lock needs it
+    smp::init_bsp();
+
     println!("Kernel started!");
     println!("Loading IDT and PIC...");
     unsafe {
@@ -110,6 +119,9 @@ pub extern "C" fn kmain() -> ! {
     }
     println!("IDT and PIC loaded.");
 
+    // COM1 input, for driving the shell over the serial port
+    crate::io::serial::init();
+
     println!("Initializing PS/2 mouse...");
     crate::io::mouse::init_ps2_mouse();
     println!("PS/2 mouse initialized.");
@@ -118,6 +130,14 @@ pub extern "C" fn kmain() -> ! {
     memory::init();
     println!("Memory initialized.");
 
+    // The keymap lives on the heap
+    crate::io::keyboard::init();
+
+    println!("Setting up APIC...");
+    if !interrupts::init_apic() {
+        println!("No APIC found, staying on the 8259 PIC.");
+    }
+
     println!("Setting up Framebuffer...");
     let writer: screen::renderer::FramebufferWriter; // Declare framebuffer writer
 
@@ -170,6 +190,12 @@ pub extern "C" fn kmain() -> ! {
     }
     println!("Framebuffer setup complete.");
 
+    println!("Scanning PCI...");
+    io::pci::init();
+
+    println!("Reading the RTC...");
+    clock::init();
+
     println!("Initializing Filesystem...");
     fs::init_fs();
     println!("Filesystem initialized.");
@@ -178,55 +204,55 @@ pub extern "C" fn kmain() -> ! {
     multitasker::init_multitasking();
     println!("Multitasking setup complete.");
 
-    let idle_task = crate::multitasker::task::Task::new(
-        0,
-        crate::multitasker::idle_task as *const () as u64,
-        0,
-        None,
-    );
     let _compositor_task = crate::multitasker::task::Task::new(
         3,
         crate::screen::compositor_task as *const () as u64,
         0,
         None,
-    );
+    )
+    .with_name("compositor");
     let task_a: multitasker::task::Task =
-        crate::multitasker::task::Task::new(5, task_a as *const () as u64, 0, None);
-    let task_shell = crate::multitasker::task::Task::new(
-        6,
-        crate::shell::task_shell as *const () as u64,
-        0,
-        None,
-    );
+        crate::multitasker::task::Task::new(5, task_a as *const () as u64, 0, None)
+            .with_name("task_a");
     let task_serial = crate::multitasker::task::Task::new(
         7,
         crate::screen::serial_task as *const () as u64,
         0,
         None,
-    );
-
-    let mut sched = crate::multitasker::scheduler::SCHEDULER.lock();
-    if let Some(ref mut scheduler) = *sched {
-        scheduler.add_task(idle_task);
-        scheduler.add_task(_compositor_task);
-        scheduler.add_task(task_serial);
-        // scheduler.add_task(task_a);
-        scheduler.add_task(task_shell);
+    )
+    .with_name("serial");
+
+    let scheduler = &crate::multitasker::scheduler::SCHEDULER;
+    scheduler.add_task(_compositor_task);
+    scheduler.add_task(task_serial);
+    // scheduler.add_task(task_a);
+    // Flushes the disk cache
+    multitasker::spawn_kernel_thread("writeback", fs::block_cache::writeback_task, 0);
+
+    // The shell is a user program now, init starts it for us.
+    println!("Launching {}...", crate::program_loader::INIT_PATH);
+    match crate::program_loader::launch_init() {
+        Ok(task_id) => println!("init running as task {}.", task_id),
+        Err(e) => println!("Failed to launch {}: {}", crate::program_loader::INIT_PATH, e),
     }
-    drop(sched);
 
     println!("Setting up Timer...");
     timer::init_timer();
     println!("Timer setup complete.");
 
+    println!("Starting the other CPUs...");
+    smp::start_aps();
+
     // Now safe to enable interrupts
     println!("Enabling interrupts...");
     unsafe {
         asm!("sti"); // Enable interrupts
     }
-    loop {
-        crate::multitasker::yield_now();
-    }
+    crate::fs::block_device::enable_interrupts();
+
+    // Boot is done, from here on this context is the BSP's idle task
+    smp::current().set_idle_task(0);
+    crate::multitasker::idle_task()
 }
 
 fn task_a() -> ! {
@@ -254,31 +280,17 @@ fn task_ls() -> ! {
     loop {
         crate::println!("--- File System Root Directory ---");
 
-        {
-            let fs_lock = fs::FILESYSTEM.lock();
-
-            if let Some(fs) = fs_lock.as_ref() {
-                // 1. Unwrap the Result from read_dir to get the iterator
-                if let Ok(dir_iter) = fs.read_dir("/") {
-                    for entry_result in dir_iter {
-                        // 2. Unwrap the Result from the iterator to get the DirEntry
-                        if let Ok(entry) = entry_result {
-                            let path = entry.path();
-                            if entry.is_dir() {
-                                crate::println!("[DIR]  {}", path);
-                            } else if entry.is_file() {
-                                // Note: use .file_size() based on the example
-                                let size = entry.file_size();
-                                crate::println!("[FILE] {} ({} bytes)", path, size);
-                            }
-                        }
+        match fs::vfs::read_dir("/") {
+            Ok(entries) => {
+                for entry in entries {
+                    if entry.kind == fs::vfs::NodeKind::Directory {
+                        crate::println!("[DIR]  /{}", entry.name);
+                    } else {
+                        crate::println!("[FILE] /{} ({} bytes)", entry.name, entry.size);
                     }
-                } else {
-                    crate::println!("LS Task: Could not open root directory.");
                 }
-            } else {
-                crate::println!("LS Task: Filesystem not initialized!");
             }
+            Err(_) => crate::println!("LS Task: Could not open root directory."),
         }
 
         crate::println!("--- End of Directory ---");
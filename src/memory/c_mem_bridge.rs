@@ -14,7 +14,7 @@ unsafe fn layout_for_size(size: usize) -> Option<Layout> {
     Layout::from_size_align(size.checked_add(HEADER_SIZE)?, ALIGNMENT).ok()
 }
 
-#[unsafe(no_mangle)]
+#[cfg_attr(not(test), unsafe(no_mangle))]
 pub extern "C" fn malloc(size: usize) -> *mut u8 {
     if size == 0 {
         return ALIGNMENT as *mut u8; // Return aligned non-null for zero-size
@@ -40,7 +40,7 @@ pub extern "C" fn malloc(size: usize) -> *mut u8 {
     }
 }
 
-#[unsafe(no_mangle)]
+#[cfg_attr(not(test), unsafe(no_mangle))]
 pub extern "C" fn free(ptr: *mut u8) {
     if ptr.is_null() || ptr as usize == ALIGNMENT {
         return; // Handle zero-size sentinel
@@ -56,7 +56,7 @@ pub extern "C" fn free(ptr: *mut u8) {
     }
 }
 
-#[unsafe(no_mangle)]
+#[cfg_attr(not(test), unsafe(no_mangle))]
 pub extern "C" fn calloc(nmemb: usize, size: usize) -> *mut u8 {
     // Check for overflow
     let Some(total) = nmemb.checked_mul(size) else {
@@ -67,7 +67,7 @@ pub extern "C" fn calloc(nmemb: usize, size: usize) -> *mut u8 {
     malloc(total)
 }
 
-#[unsafe(no_mangle)]
+#[cfg_attr(not(test), unsafe(no_mangle))]
 pub extern "C" fn realloc(ptr: *mut u8, new_size: usize) -> *mut u8 {
     if ptr.is_null() || ptr as usize == ALIGNMENT {
         return malloc(new_size);
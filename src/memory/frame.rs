@@ -1,8 +1,8 @@
+use crate::sync::IrqMutex;
 use crate::{helpers::hcf, screen_println, serial_println};
 use limine::request::MemoryMapRequest;
-use spin::Mutex;
 
-pub static FRAME_ALLOCATOR: Mutex<Option<BitmapAllocator>> = Mutex::new(None);
+pub static FRAME_ALLOCATOR: IrqMutex<Option<BitmapAllocator>> = IrqMutex::new(None);
 
 pub struct BitmapAllocator {
     pub bitmap: &'static mut [u8],
@@ -63,6 +63,53 @@ impl BitmapAllocator {
         screen_println!("Out of memory!");
         None
     }
+
+    // Frames below the highest usable address that are handed out or reserved
+    fn used_frames(&self) -> usize {
+        let frames = self
+            .total_pages
+            .min((self.highest_address / 0x1000) as usize);
+        let full_bytes = frames / 8;
+        let mut used: usize = self.bitmap[..full_bytes]
+            .iter()
+            .map(|byte| byte.count_ones() as usize)
+            .sum();
+        for frame_idx in full_bytes * 8..frames {
+            if !self.is_free(frame_idx) {
+                used += 1;
+            }
+        }
+        used
+    }
+
+    fn is_free(&self, frame_idx: usize) -> bool {
+        (self.bitmap[frame_idx / 8] & (1 << (frame_idx % 8))) == 0
+    }
+
+    // Finds `count` free frames in a row, starting on an `align` byte boundary and ending
+    // below `limit`. Devices doing DMA need buffers like that.
+    fn alloc_contiguous(&mut self, count: usize, align: u64, limit: u64) -> Option<u64> {
+        let step = (align / 0x1000).max(1) as usize;
+        let end = self
+            .total_pages
+            .min((limit.min(self.highest_address) / 0x1000) as usize);
+
+        let mut frame = 256usize.next_multiple_of(step); // Skip first 1MB, like alloc_frame
+        while frame + count <= end {
+            match (frame..frame + count).find(|&f| !self.is_free(f)) {
+                Some(used) => frame = (used + 1).next_multiple_of(step),
+                None => {
+                    for f in frame..frame + count {
+                        self.mark_used(f as u64 * 0x1000);
+                    }
+                    return Some(frame as u64 * 0x1000);
+                }
+            }
+        }
+
+        serial_println!("No {} contiguous frames available!", count);
+        None
+    }
 }
 
 #[used]
@@ -206,6 +253,27 @@ pub fn allocate_frame() -> Option<u64> {
     }
 }
 
+pub fn allocate_contiguous(count: usize, align: u64, limit: u64) -> Option<u64> {
+    if let Some(ref mut allocator) = *FRAME_ALLOCATOR.lock() {
+        allocator.alloc_contiguous(count, align, limit)
+    } else {
+        None
+    }
+}
+
+// (total, used) physical frames, for /proc/meminfo
+pub fn frame_stats() -> (usize, usize) {
+    match FRAME_ALLOCATOR.lock().as_ref() {
+        Some(allocator) => (
+            allocator
+                .total_pages
+                .min((allocator.highest_address / 0x1000) as usize),
+            allocator.used_frames(),
+        ),
+        None => (0, 0),
+    }
+}
+
 pub fn deallocate_frame(addr: u64) {
     let mut lock = FRAME_ALLOCATOR.lock();
     if let Some(ref mut allocator) = *lock {
@@ -1,11 +1,15 @@
 use core::alloc::GlobalAlloc;
 
-use crate::memory::sys_sbrk;
+use crate::memory::grow_heap;
 
 use super::paging::{OffsetPageTable, PageTableFlags};
-use linked_list_allocator::LockedHeap;
+use crate::sync::IrqMutex;
+use core::ptr::NonNull;
+use linked_list_allocator::Heap;
 
-pub struct DynamicLockedHeap(pub LockedHeap);
+// Interrupt handlers allocate too (the scheduler queues and drops tasks), so the heap lock
+// keeps interrupts off while held
+pub struct DynamicLockedHeap(pub IrqMutex<Heap>);
 
 unsafe impl GlobalAlloc for DynamicLockedHeap {
     unsafe fn alloc(&self, layout: core::alloc::Layout) -> *mut u8 {
@@ -15,14 +19,11 @@ unsafe impl GlobalAlloc for DynamicLockedHeap {
         match heap.allocate_first_fit(layout) {
             Ok(ptr) => ptr.as_ptr(),
             Err(_) => {
-                drop(heap); // release the lock before growing the heap
                 let grow_size = layout.size().max(1024 * 1024); // grow by at least 1 MiB this stops frequent small allocations from growing the heap
-                sys_sbrk(grow_size as isize);
+                grow_heap(&mut heap, grow_size as isize);
 
                 // try to allocate again
-                self.0
-                    .lock()
-                    .allocate_first_fit(layout)
+                heap.allocate_first_fit(layout)
                     .map(|ptr| ptr.as_ptr())
                     .unwrap_or(core::ptr::null_mut()) // return null if allocation fails again
             }
@@ -30,12 +31,14 @@ unsafe impl GlobalAlloc for DynamicLockedHeap {
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: core::alloc::Layout) {
-        unsafe { self.0.dealloc(ptr, layout) }
+        if let Some(ptr) = NonNull::new(ptr) {
+            unsafe { self.0.lock().deallocate(ptr, layout) }
+        }
     }
 }
 
-#[global_allocator]
-pub static ALLOCATOR: DynamicLockedHeap = DynamicLockedHeap(LockedHeap::empty());
+#[cfg_attr(not(test), global_allocator)]
+pub static ALLOCATOR: DynamicLockedHeap = DynamicLockedHeap(IrqMutex::new(Heap::empty()));
 
 pub const HEAP_START: u64 = 0xFFFF_A000_0000_0000;
 pub const HEAP_SIZE: u64 = 1024 * 1024 * 25; // allocate 25 MiB for the heap
@@ -3,10 +3,16 @@ pub mod frame;
 mod heap;
 pub mod paging;
 
-pub use frame::allocate_frame;
+pub use frame::{allocate_contiguous, allocate_frame, frame_stats};
 pub use heap::{get_heap_size, get_heap_usage};
 
+use core::sync::atomic::{AtomicU64, Ordering};
 use heap::{ALLOCATOR, HEAP_START};
+use linked_list_allocator::Heap;
+
+// Device registers get their own uncached window, away from the HHDM (which may use huge pages)
+const MMIO_START: u64 = 0xFFFF_C000_0000_0000;
+static MMIO_NEXT: AtomicU64 = AtomicU64::new(MMIO_START);
 
 pub fn init() {
     frame::mem_map_init();
@@ -14,8 +20,37 @@ pub fn init() {
     heap::init_heap(&mut mapper);
 }
 
+// Physical memory is mapped at the HHDM offset, so this is how the kernel reaches
+// frames it handed to a device.
+pub fn phys_to_virt(phys: u64) -> u64 {
+    phys + crate::HHDM_REQUEST.get_response().unwrap().offset()
+}
+
+// Maps `size` bytes of device registers at `phys` and returns where they ended up
+pub fn map_mmio(phys: u64, size: usize) -> u64 {
+    let offset = phys & 0xFFF;
+    let pages = (offset + size as u64).div_ceil(0x1000);
+    let virt = MMIO_NEXT.fetch_add(pages * 0x1000, Ordering::Relaxed);
+
+    let mut mapper = paging::get_active_mapper();
+    let flags = paging::PageTableFlags::PRESENT
+        | paging::PageTableFlags::WRITABLE
+        | paging::PageTableFlags::WRITE_THROUGH
+        | paging::PageTableFlags::NO_CACHE;
+    for page in 0..pages {
+        mapper.map(virt + page * 0x1000, (phys & !0xFFF) + page * 0x1000, flags);
+    }
+
+    virt + offset
+}
+
 pub fn sys_sbrk(increment: isize) -> *mut u8 {
-    let heap_size = ALLOCATOR.0.lock().size();
+    grow_heap(&mut ALLOCATOR.0.lock(), increment)
+}
+
+// Takes the locked heap, so two CPUs can't both map the same pages onto its end
+pub fn grow_heap(heap: &mut Heap, increment: isize) -> *mut u8 {
+    let heap_size = heap.size();
     let old_end_of_heap = HEAP_START + heap_size as u64;
 
     if increment == 0 {
@@ -38,7 +73,7 @@ pub fn sys_sbrk(increment: isize) -> *mut u8 {
         }
 
         unsafe {
-            ALLOCATOR.0.lock().extend(increment as usize);
+            heap.extend(increment as usize);
         }
     }
 
@@ -1,8 +1,12 @@
 use bitflags::bitflags;
 use core::arch::asm;
 
+use crate::sync::IrqMutex;
 use crate::{memory::allocate_frame, serial_println};
 
+// The page tables are shared by every CPU, one of them edits them at a time
+static MAP_LOCK: IrqMutex<()> = IrqMutex::new(());
+
 bitflags! {
     #[derive(Clone, Copy, Debug, PartialEq, Eq)]
     pub struct PageTableFlags: u64 {
@@ -88,16 +92,24 @@ impl OffsetPageTable {
         let p2_idx = ((virt >> 21) & 0x1ff) as usize;
         let p1_idx = ((virt >> 12) & 0x1ff) as usize;
 
+        let guard = MAP_LOCK.lock();
         let l3 =
             Self::next_table_or_create(&mut self.level_4_table.entries[p4_idx], self.hhdm_offset);
         let l2 = Self::next_table_or_create(&mut l3.entries[p3_idx], self.hhdm_offset);
         let l1 = Self::next_table_or_create(&mut l2.entries[p2_idx], self.hhdm_offset);
 
+        let was_present = l1.entries[p1_idx].flags().contains(PageTableFlags::PRESENT);
         l1.entries[p1_idx].set_address(phys, flags | PageTableFlags::PRESENT);
 
         unsafe {
             asm!("invlpg [{}]", in(reg) virt, options(nostack, preserves_flags));
         }
+        drop(guard);
+
+        // Other CPUs may still have the old translation cached
+        if was_present {
+            crate::smp::tlb_shootdown(virt);
+        }
     }
 
     // navigates one level down, creates it if it doesn't exist
@@ -7,31 +7,89 @@
 
 pub mod scheduler;
 pub mod task;
+pub mod wait_queue;
+
+use crate::smp::percpu;
+use core::arch::global_asm;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+// IDs below 100 are reserved for the tasks the kernel sets up at boot.
+static NEXT_TASK_ID: AtomicU64 = AtomicU64::new(100);
+
+pub fn allocate_task_id() -> u64 {
+    NEXT_TASK_ID.fetch_add(1, Ordering::Relaxed)
+}
 
 pub fn init_multitasking() {
-    let mut new_scheduler = scheduler::Scheduler::new();
-
-    // We save the Main Task, aka the kernel task
-    let main_task = task::Task {
-        id: 0,
-        stack_pointer: 0, // Will be set during the first context switch
-        wake_at: 0,
-        status: task::TaskStatus::Ready,
-        fpu_state: task::FpuState::default(),
-        stack_base: 0,
-        stack_size: 0,
-        owned_program_image: None,
-        owned_arg_bytes: None,
-    };
-
-    // We set the current task to the main task before enabling the scheduler
-    new_scheduler.current_task = Some(main_task);
-    *scheduler::SCHEDULER.lock() = Some(new_scheduler); // Publish the scheduler for use by the timer interrupt handler
+    // We save the Main Task, aka the kernel task. Its stack pointer is set during the first
+    // context switch.
+    let main_task = task::Task::boot_context(0, "kernel");
+    percpu::current().set_current_task(main_task.id);
+
+    // The main task is what the BSP runs until the timer interrupt handler switches away
+    scheduler::SCHEDULER.cpu_online(0, main_task);
+}
+
+// Called when a task exits or is killed. If it led the foreground group, the keyboard
+// goes back to whoever launched it and any graphics it owned are released.
+pub fn release_foreground(task: &task::Task) {
+    if task.id == task.pgid && crate::io::keyboard::is_foreground(task.pgid) {
+        crate::io::keyboard::set_foreground_and_clear(task.parent_id);
+        crate::screen::exit_exclusive_mode();
+        crate::screen::vfb::release_owner(task.id);
+    }
+}
+
+/***********
+ * THREADS *
+ ***********/
+// A thread's entry function returns here with its exit code in RAX,
+// which we pass straight on to SYS_THREAD_EXIT (37).
+global_asm!(
+    r#"
+    .global thread_return_trampoline
+    thread_return_trampoline:
+        mov rdi, rax
+        mov rax, 37
+        int 0x80
+        ud2
+"#
+);
+
+unsafe extern "C" {
+    fn thread_return_trampoline();
+}
+
+pub fn thread_return_address() -> u64 {
+    thread_return_trampoline as *const () as u64
+}
+
+// Starts `entry(arg)` as a kernel thread, it ends when `entry` returns.
+pub fn spawn_kernel_thread(name: &str, entry: extern "C" fn(u64) -> u64, arg: u64) -> u64 {
+    let id = allocate_task_id();
+    let thread = task::Task::new(id, entry as *const () as u64, arg, None)
+        .with_name(name)
+        .with_return_address(thread_return_address());
+    scheduler::SCHEDULER.add_task(thread);
+    id
 }
 
 pub fn yield_now() {
+    use x86_64::instructions::interrupts::without_interrupts;
+
+    // Yielding from inside a syscall (e.g. while waiting on the disk) re-enters the
+    // interrupt stubs, which overwrite the FPU snapshot taken when the syscall started.
+    // Keep a copy so the task still gets its own FPU state back when the syscall returns,
+    // on whichever CPU it wakes up on.
+    let saved = without_interrupts(percpu::copy_fpu_snapshot);
+
     unsafe {
-        core::arch::asm!("int 0x20"); // Manually trigger the Timer/Scheduler IRQ
+        // SYS_YIELD rather than int 0x20, so a voluntary yield doesn't count as a timer tick
+        core::arch::asm!("int 0x80", inlateout("rax") 8u64 => _);
+    }
+
+    if let Some(snapshot) = saved {
+        without_interrupts(|| percpu::restore_fpu_snapshot(snapshot));
     }
 }
 
@@ -40,10 +98,15 @@ pub fn idle_task() -> ! {
         // Increment a global counter of "idle time"
         unsafe { crate::globals::IDLE_TICKS.fetch_add(1, core::sync::atomic::Ordering::Relaxed) };
 
-        // Use 'hlt' to stop the CPU until the next interrupt
-        // This is crucial: 'hlt' makes the loop pulse at the same rate as the timer
+        // Tickless: set the timer for the next deadline on this CPU rather than waking up
+        // every timeslice. Interrupts stay off until 'hlt', so a wake up can't slip in between.
+        x86_64::instructions::interrupts::disable();
+        scheduler::SCHEDULER.arm_timer();
+
+        // Use 'hlt' to stop the CPU until the next interrupt.
+        // 'sti' only takes effect after the next instruction, so 'hlt' always gets to run.
         unsafe {
-            core::arch::asm!("hlt");
+            core::arch::asm!("sti; hlt");
         }
     }
 }
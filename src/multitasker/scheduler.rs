@@ -1,25 +1,20 @@
 /******************************************************************************************************************************************************************************************************************************************************
  *                                                                                                                   DOCUMENTATION                                                                                                                    *
  *                                                                              THIS IS THE SCHEDULER, IT MANAGES THE QUEUE OF TASKS AND DECIDES WHICH ONE TO RUN NEXT,                                                                               *
- *                                     WE STORE THE SCHEDULER IN A GLOBAL MUTEX<OPTION<SCHEDULER>> SO THAT IT CAN BE SAFELY ACCESSED FROM THE TIMER INTERRUPT HANDLER, WHICH IS WHERE THE CONTEXT SWITCH HAPPENS.                                     *
+ *                             WE KEEP ONE SCHEDULER FOR THE WHOLE MACHINE, BUT EVERY RUN QUEUE IN IT HAS ITS OWN IRQMUTEX, SO THE TIMER INTERRUPT HANDLERS OF DIFFERENT CPUS DON'T WAIT FOR EACH OTHER TO SWITCH TASKS.                              *
+ *                                                               EVERY CPU HAS ITS OWN RUN QUEUE AND IDLE TASK. A CPU WITH NOTHING RUNNABLE STEALS A TASK FROM THE BUSIEST OTHER QUEUE.                                                               *
  * THE SCHEDULER ALSO SAVES AND RESTORES THE FPU/SSE STATE OF TASKS DURING CONTEXT SWITCHES, USING THE FXSAVE/FXRSTOR INSTRUCTIONS. THIS IS CRUCIAL FOR SUPPORTING FLOATING-POINT OPERATIONS IN USER TASKS WITHOUT CORRUPTING THE KERNEL'S FPU STATE. *
  *                                   WE DEALLOCATE TASKS WHEN THEY ARE KILLED OR EXITED, THIS IS DONE USING THE DROP IMPLEMENTATION OF THE TASK STRUCT, WHICH DEALLOCATES THE STACK MEMORY AND ANY OWNED RESOURCES.                                   *
  *                                                                                                       THIS CAN BE FOUND IN THE TASK.RS FILE.                                                                                                       *
- ******************************************************************************************************************************************************************************************************************************************************/
+ *****************************************************************************************************************************************************************************************************************************************************/
 
-use super::task::Task;
-use crate::alloc::collections::VecDeque;
-use spin::Mutex;
-use x86_64::instructions::interrupts;
-
-#[repr(C, align(16))]
-pub struct InterruptFpuSnapshot(pub [u8; 512]);
-
-#[unsafe(no_mangle)]
-pub static mut INTERRUPT_FPU_SNAPSHOT: InterruptFpuSnapshot = InterruptFpuSnapshot([0; 512]);
-
-#[unsafe(no_mangle)]
-pub static mut INTERRUPT_FPU_SNAPSHOT_VALID: u8 = 0;
+use super::task::{Task, TaskStatus};
+use crate::alloc::collections::{BTreeMap, VecDeque};
+use crate::alloc::vec::Vec;
+use crate::smp::percpu::{self, MAX_CPUS};
+use crate::sync::{IrqMutex, IrqMutexGuard};
+use x86_64::VirtAddr;
+use x86_64::registers::model_specific::FsBase;
 
 pub enum SchedulerMode {
     RoundRobin,
@@ -31,117 +26,664 @@ pub struct SchedulerStats {
     pub priority: u8,
 }
 
+// What one CPU runs. The idle task sits in its own slot and only runs when nothing else can.
+pub struct RunQueue {
+    pub current: Option<Task>,
+    pub idle: Option<Task>,
+    pub ready: VecDeque<Task>,
+    // A task that ended while running here. We are still on its stack until the switch
+    // away from it is done, so it is only dropped at this CPU's next schedule.
+    zombie: Option<Task>,
+    online: bool,
+}
+
+// Task state that has to look the same from every CPU at once
+struct Registry {
+    alive: BTreeMap<u64, u64>, // task id -> process id, from add_task until reaped
+    exited_threads: BTreeMap<u64, (u64, u64)>, // thread id -> (process id, exit code), until joined
+}
+
+// What reap needs of a task that ended, copied out so it runs with no queue locked
+#[derive(Clone, Copy)]
+struct Ended {
+    id: u64,
+    process_id: u64,
+    exit_code: u64,
+    status: TaskStatus,
+}
+
+// What became of a request to wait for another task
+pub enum Wait {
+    Exited(u64),  // A thread that had already ended, with its exit code
+    Blocked(u64), // Asleep until the task ends, switch to this stack pointer
+    Refused,      // No such task, or not one the caller may wait for
+}
+
+// Every run queue has its own lock. A CPU holding its own queue only ever try_locks another
+// one (to steal from it). Whatever looks at every queue locks them one at a time with no
+// other queue held, the registry may be held around that. Nothing takes the registry while
+// holding a queue.
 pub struct Scheduler {
-    pub tasks: VecDeque<Task>,
-    pub current_task: Option<Task>,
+    cpus: [IrqMutex<RunQueue>; MAX_CPUS], // Indexed by CPU index, the BSP is 0
     pub mode: SchedulerMode,
+    registry: IrqMutex<Registry>,
 }
-impl Scheduler {
-    pub fn new() -> Self {
-        Scheduler {
-            tasks: VecDeque::new(),
-            current_task: None,
-            mode: SchedulerMode::RoundRobin,
+
+impl RunQueue {
+    const fn new() -> Self {
+        Self {
+            current: None,
+            idle: None,
+            ready: VecDeque::new(),
+            zombie: None,
+            online: false,
         }
     }
 
-    pub fn add_task(&mut self, task: Task) {
-        x86_64::instructions::interrupts::without_interrupts(|| {
-            self.tasks.push_back(task);
-        });
+    // Its idle task is out of the idle slot while it runs
+    fn is_idling(&self) -> bool {
+        self.online && self.idle.is_none()
     }
 
-    pub fn get_next_task(&mut self) -> Option<Task> {
-        let now = crate::timer::get_uptime_ms();
+    fn has_runnable(&self, now: u64) -> bool {
+        self.ready.iter().any(|task| is_runnable(task, now))
+    }
 
-        for _ in 0..self.tasks.len() {
-            let task = self.tasks.pop_front()?;
+    // Every task here, running, idle or queued
+    fn tasks(&self) -> impl Iterator<Item = &Task> {
+        self.current
+            .iter()
+            .chain(self.idle.iter())
+            .chain(self.ready.iter())
+    }
 
-            // If it's the Idle Task (ID 0) OR it's time to wake up
-            if task.id == 0 || now >= task.wake_at {
+    fn tasks_mut(&mut self) -> impl Iterator<Item = &mut Task> {
+        self.current
+            .iter_mut()
+            .chain(self.idle.iter_mut())
+            .chain(self.ready.iter_mut())
+    }
+
+    fn next_runnable(&mut self, now: u64, ended: &mut Vec<Ended>) -> Option<Task> {
+        for _ in 0..self.ready.len() {
+            let task = self.ready.pop_front()?;
+            if percpu::is_switching_out(task.id) {
+                self.ready.push_back(task);
+            } else if is_terminated(&task) {
+                // A thread whose process exited under it
+                ended.push(Ended::from(&task));
+            } else if is_runnable(&task, now) {
                 return Some(task);
             } else {
-                // Not ready yet, put it back in the queue and try the next one
-                self.tasks.push_back(task);
+                // Still sleeping, put it back at the end of the line
+                self.ready.push_back(task);
             }
         }
         None
     }
 
+    fn switch_to(&mut self, mut task: Task) -> u64 {
+        let next_sp = task.stack_pointer;
+        task.status = TaskStatus::Running;
+        // If it was a blocked receive or futex wait that timed out, it is no longer waiting
+        task.wait_channel = None;
+        task.wait_futex = None;
+        task.wait_deadline = None;
+
+        // RESTORE SSE/FPU STATE AND THREAD-LOCAL STORAGE
+        unsafe {
+            core::arch::asm!("fxrstor [{}]", in(reg) &task.fpu_state.data);
+        }
+        FsBase::write(VirtAddr::new_truncate(task.fs_base));
+
+        percpu::current().set_current_task(task.id);
+        self.current = Some(task);
+        next_sp
+    }
+
+    // Tickless: sets this CPU's timer for when it has to look at its queue again. That is
+    // the end of the timeslice if others are waiting for the CPU, otherwise the first
+    // deadline of a sleeping task, but never later than the slice for a lone task (or the
+    // idle wake up if nothing is running).
+    fn arm_timer(&self) {
+        if !crate::timer::is_tickless() {
+            return;
+        }
+        let now_ns = crate::timer::uptime_ns();
+        let now = now_ns / 1_000_000;
+
+        let contended = self
+            .ready
+            .iter()
+            .any(|task| !is_terminated(task) && is_runnable(task, now));
+        let limit = if contended {
+            crate::timer::TIMESLICE_NS
+        } else if self.idle.is_none() {
+            crate::timer::MAX_IDLE_NS
+        } else {
+            crate::timer::LONE_TASK_SLICE_NS
+        };
+        let next_wake = self
+            .ready
+            .iter()
+            .filter_map(|task| match task.status {
+                TaskStatus::Stopped => None,
+                TaskStatus::Waiting => task.wait_deadline,
+                _ => Some(task.wake_at),
+            })
+            .min()
+            .map(|ms| (ms * 1_000_000).saturating_sub(now_ns))
+            .unwrap_or(u64::MAX);
+
+        crate::interrupts::arm_apic_timer(limit.min(next_wake));
+    }
+}
+
+impl Registry {
+    // Collects the exit code of a finished thread of `process_id`, if it hasn't been joined yet.
+    fn take_thread_exit_code(&mut self, process_id: u64, thread_id: u64) -> Option<u64> {
+        match self.exited_threads.get(&thread_id) {
+            Some(&(owner, code)) if owner == process_id => {
+                self.exited_threads.remove(&thread_id);
+                Some(code)
+            }
+            _ => None,
+        }
+    }
+}
+
+impl From<&Task> for Ended {
+    fn from(task: &Task) -> Self {
+        Self {
+            id: task.id,
+            process_id: task.process_id,
+            exit_code: task.exit_code,
+            status: task.status,
+        }
+    }
+}
+
+impl Scheduler {
+    const fn new() -> Self {
+        Scheduler {
+            cpus: [const { IrqMutex::new(RunQueue::new()) }; MAX_CPUS],
+            mode: SchedulerMode::RoundRobin,
+            registry: IrqMutex::new(Registry {
+                alive: BTreeMap::new(),
+                exited_threads: BTreeMap::new(),
+            }),
+        }
+    }
+
+    // Called on every CPU once it is set up, `idle` is the context it is running in now
+    pub fn cpu_online(&self, cpu: usize, idle: Task) {
+        self.registry.lock().alive.insert(idle.id, idle.process_id);
+        let mut queue = self.cpus[cpu].lock();
+        queue.current = Some(idle);
+        queue.online = true;
+    }
+
+    // The queues of the CPUs that are set up
+    fn queues(&self) -> impl Iterator<Item = (usize, &IrqMutex<RunQueue>)> {
+        percpu::cpus().map(move |cpu| (cpu.index, &self.cpus[cpu.index]))
+    }
+
+    // Runs `f` on every run queue in turn, each locked only while `f` has it
+    fn for_each_queue(&self, mut f: impl FnMut(usize, &mut RunQueue)) {
+        for (cpu, queue) in self.queues() {
+            f(cpu, &mut queue.lock());
+        }
+    }
+
+    fn find_task<R>(&self, mut f: impl FnMut(&Task) -> Option<R>) -> Option<R> {
+        self.queues()
+            .find_map(|(_, queue)| queue.lock().tasks().find_map(&mut f))
+    }
+
+    // Queues a task on the least busy CPU, waking that CPU up if it is idling
+    pub fn add_task(&self, task: Task) {
+        let Some(cpu) = self
+            .queues()
+            .filter_map(|(cpu, queue)| {
+                let queue = queue.lock();
+                queue.online.then_some((cpu, queue.ready.len()))
+            })
+            .min_by_key(|&(_, len)| len)
+            .map(|(cpu, _)| cpu)
+        else {
+            return;
+        };
+        self.registry.lock().alive.insert(task.id, task.process_id);
+        let mut queue = self.cpus[cpu].lock();
+        queue.ready.push_back(task);
+        let idling = queue.is_idling();
+        drop(queue);
+        if idling && cpu != this_cpu() {
+            crate::smp::reschedule(cpu);
+        }
+    }
+
+    // After tasks became runnable: an idling CPU may be asleep until its next deadline,
+    // so the ones with work in their queue are woken to look at it
+    fn kick_idle_cpus(&self) {
+        let now = crate::timer::get_uptime_ms();
+        let this = this_cpu();
+        for (cpu, queue) in self.queues() {
+            let kick = {
+                let queue = queue.lock();
+                queue.is_idling() && queue.has_runnable(now)
+            };
+            if cpu != this && kick {
+                crate::smp::reschedule(cpu);
+            }
+        }
+    }
+
+    // The timer for the idle task on this CPU, it arms it before it halts
+    pub fn arm_timer(&self) {
+        self.cpus[this_cpu()].lock().arm_timer();
+    }
+
+    // This CPU's queue, unless someone holds it. For looking at the running task from
+    // places that must not wait, like an exception handler.
+    pub fn try_lock_queue(&self) -> Option<IrqMutexGuard<'_, RunQueue>> {
+        self.cpus[this_cpu()].try_lock()
+    }
+
+    // Runs `f` on the task running on this CPU, None before the scheduler runs here
+    pub fn with_current<R>(&self, f: impl FnOnce(&mut Task) -> R) -> Option<R> {
+        self.cpus[this_cpu()].lock().current.as_mut().map(f)
+    }
+
+    // Calls `f` on every task, with whether it is running on a CPU right now
+    pub fn for_each_task(&self, mut f: impl FnMut(&Task, bool)) {
+        self.for_each_queue(|_, queue| {
+            let running = queue.current.as_ref().map(|task| task.id);
+            for task in queue.tasks() {
+                f(task, Some(task.id) == running);
+            }
+        });
+    }
+
     pub fn get_current_task_id(&self) -> u64 {
-        self.current_task.as_ref().map(|t| t.id).unwrap_or(0)
+        self.with_current(|t| t.id).unwrap_or(0)
+    }
+
+    pub fn get_current_process_id(&self) -> u64 {
+        self.with_current(|t| t.process_id).unwrap_or(0)
+    }
+
+    pub fn task_status(&self, id: u64) -> Option<TaskStatus> {
+        self.find_task(|t| (t.id == id).then_some(t.status))
+    }
+
+    pub fn group_exists(&self, pgid: u64) -> bool {
+        self.find_task(|t| (t.pgid == pgid).then_some(())).is_some()
+    }
+
+    // Ctrl+Z: suspend every task in the foreground group and give the keyboard back
+    // to the parent of the group leader. Only done if that parent is blocked in
+    // SYS_WAIT on the leader, otherwise nobody would be around to resume the group.
+    fn stop_foreground_group(&self) {
+        let pgid = crate::io::keyboard::foreground_group();
+        if self
+            .find_task(|t| (t.waiting_on == Some(pgid)).then_some(()))
+            .is_none()
+        {
+            return;
+        }
+        let Some(parent_id) = self.find_task(|t| (t.id == pgid).then_some(t.parent_id)) else {
+            return;
+        };
+
+        self.for_each_queue(|_, queue| {
+            for task in queue.tasks_mut() {
+                if task.pgid == pgid
+                    && task.status != TaskStatus::Killed
+                    && task.status != TaskStatus::Exited
+                {
+                    task.status = TaskStatus::Stopped;
+                }
+            }
+        });
+        crate::serial_println!("Scheduler: Stopped process group {}", pgid);
+
+        crate::io::keyboard::set_foreground_and_clear(parent_id);
+        crate::screen::exit_exclusive_mode();
+        self.wake_waiters(pgid, 2);
+    }
+
+    // Resumes a stopped group, returns false if there is no such group.
+    pub fn continue_group(&self, pgid: u64) -> bool {
+        let mut found = false;
+        self.for_each_queue(|_, queue| {
+            for task in queue.tasks_mut() {
+                if task.pgid == pgid {
+                    found = true;
+                    if task.status == TaskStatus::Stopped {
+                        task.status = TaskStatus::Ready;
+                    }
+                }
+            }
+        });
+        self.kick_idle_cpus();
+        found
+    }
+
+    // Marks every other thread of a process as terminated, they get reaped the next
+    // time the scheduler comes across them. Threads running on other CPUs are
+    // interrupted so they stop right away.
+    pub fn terminate_process(&self, process_id: u64, status: TaskStatus) {
+        let this = this_cpu();
+        self.for_each_queue(|cpu, queue| {
+            for task in queue.tasks_mut() {
+                if task.process_id == process_id
+                    && task.status != TaskStatus::Killed
+                    && task.status != TaskStatus::Exited
+                {
+                    super::release_foreground(task);
+                    task.status = status;
+                }
+            }
+            let running_it = queue
+                .current
+                .as_ref()
+                .is_some_and(|t| t.process_id == process_id);
+            if cpu != this && running_it {
+                crate::smp::reschedule(cpu);
+            }
+        });
     }
 
-    pub fn schedule(&mut self, stack_pointer: u64) -> u64 {
+    // SYS_WAIT on any task, or thread_join on a thread of the caller's own process. The
+    // registry stays locked from the check until the caller is marked as waiting, and its
+    // queue until it is switched out, so the task can't be reaped in between unnoticed.
+    pub fn wait_for(&self, stack_pointer: u64, id: u64, joining: bool) -> Wait {
+        let mut registry = self.registry.lock();
+        let mut queue = self.cpus[this_cpu()].lock();
+        let Some(waiter) = queue.current.as_mut() else {
+            return Wait::Refused;
+        };
+        if joining {
+            if let Some(code) = registry.take_thread_exit_code(waiter.process_id, id) {
+                return Wait::Exited(code);
+            }
+        }
+        let Some(&process_id) = registry.alive.get(&id) else {
+            return Wait::Refused;
+        };
+        // thread_join takes another thread of our own process, never its main thread
+        let foreign = joining && (process_id != waiter.process_id || id == process_id);
+        if id == waiter.id || foreign {
+            return Wait::Refused;
+        }
+
+        waiter.status = TaskStatus::Waiting;
+        waiter.waiting_on = Some(id);
+        drop(registry);
+        Wait::Blocked(self.schedule_locked(queue, stack_pointer))
+    }
+
+    // Wakes up to `count` tasks blocked in SYS_FUTEX_WAIT on `addr`, returns how many woke.
+    pub fn wake_futex(&self, addr: u64, count: u64) -> u64 {
+        let mut woken = 0;
+        self.for_each_queue(|_, queue| {
+            for task in queue.ready.iter_mut() {
+                if woken == count {
+                    break;
+                }
+                if task.wait_futex == Some(addr) {
+                    task.wait_futex = None;
+                    task.wait_deadline = None;
+                    task.status = TaskStatus::Ready;
+                    unsafe {
+                        (task.stack_pointer as *mut u64).write(0);
+                    }
+                    woken += 1;
+                }
+            }
+        });
+        self.kick_idle_cpus();
+        woken
+    }
+
+    // Wakes a task blocked on a WaitQueue. Tasks blocked on something more specific
+    // (a child, a channel or a futex) are left alone. The task may not have switched out
+    // yet if it went to sleep on another CPU, then it just carries on.
+    pub fn wake_task(&self, id: u64) {
+        self.for_each_queue(|_, queue| {
+            for task in queue.tasks_mut() {
+                if task.id == id
+                    && task.status == TaskStatus::Waiting
+                    && task.waiting_on.is_none()
+                    && task.wait_channel.is_none()
+                    && task.wait_futex.is_none()
+                {
+                    task.status = TaskStatus::Ready;
+                }
+            }
+        });
+        self.kick_idle_cpus();
+    }
+
+    // Unblocks every task waiting to receive on an IPC channel, they retry the receive.
+    pub fn wake_channel_waiters(&self, channel_id: u64) {
+        self.for_each_queue(|_, queue| {
+            for task in queue.ready.iter_mut() {
+                if task.wait_channel == Some(channel_id) {
+                    task.wait_channel = None;
+                    task.wait_deadline = None;
+                    task.status = TaskStatus::Ready;
+                }
+            }
+        });
+        self.kick_idle_cpus();
+    }
+
+    // Unblocks every task sitting in SYS_WAIT on `id`. The wait status is written
+    // straight into the saved RAX of the waiter so the syscall returns it.
+    fn wake_waiters(&self, id: u64, wait_status: u64) {
+        self.wake_waiters_with(id, |_| wait_status);
+    }
+
+    fn wake_waiters_with(&self, id: u64, status_for: impl Fn(&Task) -> u64) {
+        self.for_each_queue(|_, queue| {
+            for task in queue.ready.iter_mut() {
+                if task.waiting_on == Some(id) {
+                    let wait_status = status_for(task);
+                    task.waiting_on = None;
+                    task.status = TaskStatus::Ready;
+                    unsafe {
+                        (task.stack_pointer as *mut u64).write(wait_status);
+                    }
+                }
+            }
+        });
+        self.kick_idle_cpus();
+    }
+
+    pub fn schedule(&self, stack_pointer: u64) -> u64 {
+        if crate::io::keyboard::take_suspend_request() {
+            self.stop_foreground_group();
+        }
+        self.schedule_locked(self.cpus[this_cpu()].lock(), stack_pointer)
+    }
+
+    // Blocks the task running on this CPU if `block` decides to (setting what it waits
+    // for) and schedules, returning the stack pointer to switch to. The queue stays locked
+    // from the decision until the task is switched out, so a wake up from another CPU
+    // can't come in between and miss it.
+    pub fn block_current(
+        &self,
+        stack_pointer: u64,
+        block: impl FnOnce(&mut Task) -> bool,
+    ) -> Option<u64> {
+        let mut queue = self.cpus[this_cpu()].lock();
+        if !block(queue.current.as_mut()?) {
+            return None;
+        }
+        Some(self.schedule_locked(queue, stack_pointer))
+    }
+
+    fn schedule_locked(&self, mut queue: IrqMutexGuard<'_, RunQueue>, stack_pointer: u64) -> u64 {
         let now = crate::timer::get_uptime_ms();
+        let cpu = this_cpu();
+        let mut ended = Vec::new();
+
+        // Whatever ended here last time, we are off its stack by now
+        queue.zombie = None;
 
         // 1. Save the state of the task that just finished
-        if let Some(mut task) = self.current_task.take() {
-            if task.status == super::task::TaskStatus::Killed
-                || task.status == super::task::TaskStatus::Exited
-            {
-                crate::serial_println!(
-                    "Scheduler: Reaping task {} (status: {:?})",
-                    task.id,
-                    task.status
-                );
+        if let Some(mut task) = queue.current.take() {
+            // No other CPU may pick it up until the stub has left its stack
+            percpu::set_switching_from(task.id);
+            if is_terminated(&task) {
+                ended.push(Ended::from(&task));
+                queue.zombie = Some(task);
             } else {
                 task.stack_pointer = stack_pointer;
-                task.status = super::task::TaskStatus::Ready;
+                // A task that just blocked itself stays blocked until someone wakes it.
+                if task.status == TaskStatus::Running {
+                    task.status = TaskStatus::Ready;
+                }
 
                 // SAVE SSE/FPU STATE.
                 // If we entered from an interrupt/syscall, take the snapshot captured
                 // at entry so kernel SIMD usage does not bleed into the task state.
-                unsafe {
-                    if INTERRUPT_FPU_SNAPSHOT_VALID != 0 {
-                        core::ptr::copy_nonoverlapping(
-                            core::ptr::addr_of!(INTERRUPT_FPU_SNAPSHOT.0) as *const u8,
-                            task.fpu_state.data.as_mut_ptr(),
-                            512,
-                        );
-                        INTERRUPT_FPU_SNAPSHOT_VALID = 0;
-                    } else {
+                if !percpu::take_fpu_snapshot(&mut task.fpu_state.data) {
+                    unsafe {
                         core::arch::asm!("fxsave [{}]", in(reg) &mut task.fpu_state.data);
                     }
                 }
 
-                self.tasks.push_back(task);
+                if percpu::current().idle_task() == Some(task.id) {
+                    queue.idle = Some(task);
+                } else {
+                    queue.ready.push_back(task);
+                }
             }
         }
 
-        // 2. Look for the next READY task
-        // We loop through the queue to find someone who is awake.
-        for _ in 0..self.tasks.len() {
-            if let Some(mut task) = self.tasks.pop_front() {
-                if task.id == 0 || now >= task.wake_at {
-                    // We found a task!
-                    let next_sp = task.stack_pointer;
-                    task.status = super::task::TaskStatus::Running;
+        // 2. Look for the next READY task, in our own queue first, then in someone else's,
+        // and if there is nothing to do anywhere, idle
+        let next = queue
+            .next_runnable(now, &mut ended)
+            .or_else(|| self.steal(cpu, now))
+            .or_else(|| queue.idle.take());
 
-                    // RESTORE SSE/FPU STATE
-                    unsafe {
-                        core::arch::asm!("fxrstor [{}]", in(reg) &task.fpu_state.data);
-                    }
+        let next_sp = match next {
+            Some(task) => queue.switch_to(task),
+            // 3. Fallback: no idle task yet, keep running on the same stack
+            None => stack_pointer,
+        };
+        // The idle task arms its own timer before it halts
+        if queue.idle.is_some() {
+            queue.arm_timer();
+        }
+        let spare_work = queue.has_runnable(now);
+        drop(queue);
 
-                    self.current_task = Some(task);
-                    return next_sp;
-                } else {
-                    // Still sleeping, put it back at the end of the line
-                    self.tasks.push_back(task);
-                }
+        for task in &ended {
+            self.reap(task);
+        }
+        // More runnable tasks here than we can run, an idling CPU can steal one
+        if spare_work {
+            let idler = self
+                .queues()
+                .find(|&(other, queue)| other != cpu && queue.lock().is_idling());
+            if let Some((idler, _)) = idler {
+                crate::smp::reschedule(idler);
             }
         }
+        next_sp
+    }
+
+    // Takes a runnable task from the busiest other CPU that has one. The caller holds its
+    // own queue and the other CPU may be trying to steal from us, so queues that are
+    // locked are skipped rather than waited for.
+    fn steal(&self, cpu: usize, now: u64) -> Option<Task> {
+        let mut victims: Vec<(usize, usize)> = self
+            .queues()
+            .filter(|&(other, _)| other != cpu)
+            .filter_map(|(other, queue)| Some((other, queue.try_lock()?.ready.len())))
+            .collect();
+        victims.sort_by_key(|&(_, len)| core::cmp::Reverse(len));
 
-        // 3. Fallback: If NO ONE is ready (which shouldn't happen if Idle is in the queue)
-        stack_pointer
+        for (victim, _) in victims {
+            let Some(mut queue) = self.cpus[victim].try_lock() else {
+                continue;
+            };
+            let position = queue.ready.iter().position(|task| {
+                !is_terminated(task) && is_runnable(task, now) && !percpu::is_switching_out(task.id)
+            });
+            if let Some(task) = position.and_then(|position| queue.ready.remove(position)) {
+                return Some(task);
+            }
+        }
+        None
+    }
+
+    // Wakes whoever waits on a finished task and, once the last thread of a process is
+    // gone, frees what the process held. The caller drops the task itself.
+    fn reap(&self, task: &Ended) {
+        crate::serial_println!(
+            "Scheduler: Reaping task {} (status: {:?})",
+            task.id,
+            task.status
+        );
+        let wait_status = if task.status == TaskStatus::Killed {
+            1
+        } else {
+            0
+        };
+
+        let mut registry = self.registry.lock();
+        registry.alive.remove(&task.id);
+        // Threads joining a thread of their own process get its exit code instead
+        self.wake_waiters_with(task.id, |waiter| {
+            if waiter.process_id == task.process_id {
+                task.exit_code
+            } else {
+                wait_status
+            }
+        });
+
+        let process_alive = registry
+            .alive
+            .values()
+            .any(|&process_id| process_id == task.process_id);
+        if process_alive {
+            if task.id != task.process_id {
+                registry
+                    .exited_threads
+                    .insert(task.id, (task.process_id, task.exit_code));
+            }
+        } else {
+            registry
+                .exited_threads
+                .retain(|_, &mut (owner, _)| owner != task.process_id);
+            drop(registry);
+            crate::ipc::release_process(task.process_id);
+        }
     }
 }
 
-pub static SCHEDULER: Mutex<Option<Scheduler>> = Mutex::new(None);
+fn this_cpu() -> usize {
+    crate::smp::cpu_index()
+}
 
-pub fn with_scheduler<R>(f: impl FnOnce(&mut Option<Scheduler>) -> R) -> R {
-    interrupts::without_interrupts(|| {
-        let mut scheduler_lock = SCHEDULER.lock();
-        f(&mut scheduler_lock)
-    })
+fn is_terminated(task: &Task) -> bool {
+    task.status == TaskStatus::Killed || task.status == TaskStatus::Exited
 }
+
+// Tasks must not be stopped and must be past their wake time. Blocked tasks only run
+// again early if their wait has a deadline.
+fn is_runnable(task: &Task, now: u64) -> bool {
+    match task.status {
+        TaskStatus::Stopped => false,
+        TaskStatus::Waiting => task.wait_deadline.is_some_and(|deadline| now >= deadline),
+        _ => now >= task.wake_at,
+    }
+}
+
+pub static SCHEDULER: Scheduler = Scheduler::new();
@@ -9,6 +9,8 @@
 use crate::{
     alloc::alloc::{Layout, alloc, dealloc},
     alloc::boxed::Box,
+    alloc::string::String,
+    alloc::sync::Arc,
     alloc::vec::Vec,
 };
 
@@ -19,6 +21,7 @@ pub enum TaskStatus {
     Killed, // The "Crashing" state
     Exited, // Normal termination
     Waiting,
+    Stopped, // Suspended by Ctrl+Z until its group is continued
 }
 
 #[repr(align(16))]
@@ -42,12 +45,22 @@ impl Default for FpuState {
 pub struct Task {
     pub fpu_state: FpuState,
     pub id: u64,
+    pub name: String, // What the task runs, shown in /proc. Threads share their process's name.
+    pub parent_id: u64, // The task that launched us, it gets keyboard focus back when we terminate.
+    pub pgid: u64,    // Process group, the keyboard belongs to one group at a time.
+    pub process_id: u64, // Threads share the process ID of the task that created them.
+    pub waiting_on: Option<u64>, // Set while blocked in SYS_WAIT on another task.
+    pub wait_channel: Option<u64>, // Set while blocked receiving on an IPC channel.
+    pub wait_futex: Option<u64>, // Set while blocked in SYS_FUTEX_WAIT on this address.
+    pub wait_deadline: Option<u64>, // Uptime (ms) at which a blocked receive, futex or input wait gives up.
+    pub exit_code: u64,             // Handed to whoever joins this thread.
+    pub fs_base: u64, // Thread-local storage pointer, loaded into FS base when we run.
     pub stack_pointer: u64, // This is the pointer to the TaskContext on the task's stack.
     pub wake_at: u64,
     pub status: TaskStatus,
     pub stack_base: u64, // The base of the allocated stack, used for deallocation.
     pub stack_size: usize, // The size of the allocated stack, used for deallocation.
-    pub owned_program_image: Option<Vec<u8>>, // Keep original ELF allocation to preserve alignment.
+    pub owned_program_image: Option<Arc<Vec<u8>>>, // Keep original ELF allocation to preserve alignment, shared by all threads.
     pub owned_arg_bytes: Option<Box<[u8]>>, // NUL-terminated argument bytes kept alive for task lifetime.
 }
 
@@ -115,6 +128,16 @@ impl Task {
 
         Self {
             id,
+            name: String::new(),
+            parent_id: 0,
+            pgid: id,
+            process_id: id,
+            waiting_on: None,
+            wait_channel: None,
+            wait_futex: None,
+            wait_deadline: None,
+            exit_code: 0,
+            fs_base: 0,
             stack_pointer: context_ptr as u64,
             wake_at: 0,
             status: TaskStatus::Ready,
@@ -126,10 +149,73 @@ impl Task {
         }
     }
 
+    // The context a CPU is already running in, like the kernel's main thread or an AP's
+    // boot stack. It has no stack of its own to free, its stack pointer gets saved the first
+    // time the scheduler switches away from it.
+    pub fn boot_context(id: u64, name: &str) -> Self {
+        Self {
+            id,
+            name: String::from(name),
+            parent_id: 0,
+            pgid: id,
+            process_id: id,
+            waiting_on: None,
+            wait_channel: None,
+            wait_futex: None,
+            wait_deadline: None,
+            exit_code: 0,
+            fs_base: 0,
+            stack_pointer: 0,
+            wake_at: 0,
+            status: TaskStatus::Running,
+            fpu_state: FpuState::default(),
+            stack_base: 0,
+            stack_size: 0,
+            owned_program_image: None,
+            owned_arg_bytes: None,
+        }
+    }
+
+    pub fn with_name(mut self, name: &str) -> Self {
+        self.name = String::from(name);
+        self
+    }
+
+    pub fn with_parent(mut self, parent_id: u64) -> Self {
+        self.parent_id = parent_id;
+        self
+    }
+
+    // Makes this task a thread of `parent`: same process, same process group and the
+    // same program image, which stays alive until the last thread is gone.
+    pub fn as_thread_of(mut self, parent: &Task) -> Self {
+        self.parent_id = parent.id;
+        self.pgid = parent.pgid;
+        self.process_id = parent.process_id;
+        self.name = parent.name.clone();
+        self.owned_program_image = parent.owned_program_image.clone();
+        self
+    }
+
+    pub fn with_fs_base(mut self, fs_base: u64) -> Self {
+        self.fs_base = fs_base;
+        self
+    }
+
+    // Puts a return address just above the initial stack pointer, so when the entry
+    // function returns it "returns" into `trampoline` (which ends the thread).
+    pub fn with_return_address(self, trampoline: u64) -> Self {
+        let aligned_top = (self.stack_base + self.stack_size as u64) & !0xF;
+        unsafe {
+            ((aligned_top - 8) as *mut u64).write(trampoline);
+        }
+        self
+    }
+
     // This function allows us to attach owned memory (like the program image and argument bytes) to the task, ensuring that they will be kept alive for the lifetime of the task and automatically deallocated when the task is dropped. This is crucial for preventing memory leaks when tasks exit or are killed.
     pub fn with_owned_memory(
         mut self,
-        owned_program_image: Option<Vec<u8>>,
+        owned_program_image: Option<Arc<Vec<u8>>>,
         owned_arg_bytes: Option<Box<[u8]>>,
     ) -> Self {
         self.owned_program_image = owned_program_image;
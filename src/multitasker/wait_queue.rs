@@ -0,0 +1,77 @@
+// A list of tasks sleeping until some event happens, for example a disk IRQ.
+// Whoever handles the event calls wake_all, which makes the sleepers runnable again.
+
+use crate::alloc::collections::VecDeque;
+use crate::sync::IrqMutex;
+
+use super::scheduler::SCHEDULER;
+use super::task::TaskStatus;
+
+pub struct WaitQueue {
+    waiters: IrqMutex<VecDeque<u64>>,
+}
+
+impl WaitQueue {
+    pub const fn new() -> Self {
+        Self {
+            waiters: IrqMutex::new(VecDeque::new()),
+        }
+    }
+
+    // Blocks the current task until `done()` returns true. `done` is checked with the
+    // waiter list locked, so a wake up from another CPU can't slip in between the check
+    // and going to sleep.
+    pub fn wait_until(&self, done: impl FnMut() -> bool) {
+        self.wait_until_deadline(done, None);
+    }
+
+    // Like wait_until, but also gives up once the uptime reaches `deadline` (ms). Returns
+    // whether `done()` came true.
+    pub fn wait_until_deadline(
+        &self,
+        mut done: impl FnMut() -> bool,
+        deadline: Option<u64>,
+    ) -> bool {
+        let mut listed = None;
+        loop {
+            let sleeping = {
+                let mut waiters = self.waiters.lock();
+                // Woken by the deadline rather than wake_all we are still listed, and a later
+                // wake_all would wake us in the middle of something else
+                if let Some(id) = listed.take() {
+                    waiters.retain(|&waiter| waiter != id);
+                }
+                if done() {
+                    return true;
+                }
+                if deadline.is_some_and(|deadline| crate::timer::get_uptime_ms() >= deadline) {
+                    return false;
+                }
+                let id = SCHEDULER.with_current(|task| {
+                    task.status = TaskStatus::Waiting;
+                    task.wait_deadline = deadline;
+                    task.id
+                });
+                // No scheduler yet, just poll
+                if let Some(id) = id {
+                    waiters.push_back(id);
+                    listed = Some(id);
+                }
+                id.is_some()
+            };
+            if sleeping {
+                super::yield_now();
+            }
+        }
+    }
+
+    pub fn wake_all(&self) {
+        let waiters = core::mem::take(&mut *self.waiters.lock());
+        if waiters.is_empty() {
+            return;
+        }
+        for id in waiters {
+            SCHEDULER.wake_task(id);
+        }
+    }
+}
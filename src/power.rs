@@ -0,0 +1,152 @@
+/*****************************************************************************************
+ *                                     DOCUMENTATION                                     *
+ *                   THIS MODULE TURNS THE MACHINE OFF OR RESTARTS IT.                   *
+ *         BOTH HALT THE OTHER CPUS, THEN FLUSH THE FILESYSTEMS, THE DISK CACHE          *
+ *     AND THE DRIVE'S OWN WRITE CACHE, SO WHAT WAS WRITTEN BEFORE REACHES THE DISK.     *
+ * SHUTDOWN ENTERS ACPI SLEEP STATE S5 THROUGH THE PM1 CONTROL REGISTERS THE FADT NAMES. *
+ *  REBOOT WRITES THE FADT'S RESET REGISTER AND FALLS BACK TO THE KEYBOARD CONTROLLER.   *
+ ****************************************************************************************/
+
+use crate::acpi::{ADDRESS_SPACE_IO, ADDRESS_SPACE_MEMORY, ADDRESS_SPACE_PCI, Fadt};
+use x86_64::instructions::port::Port;
+
+const PM1_SCI_ENABLED: u16 = 1 << 0;
+const PM1_SLP_TYP_SHIFT: u16 = 10;
+const PM1_SLP_EN: u16 = 1 << 13;
+
+// QEMU's PIIX4 power management block, when the DSDT can't be read
+const QEMU_PM1A_CONTROL: u16 = 0x604;
+const QEMU_S5_SLEEP_TYPE: u8 = 0;
+
+const KEYBOARD_CONTROLLER_COMMAND: u16 = 0x64;
+const KEYBOARD_CONTROLLER_BUSY: u8 = 0x02;
+const KEYBOARD_CONTROLLER_PULSE_RESET: u8 = 0xFE;
+
+pub fn shutdown() -> ! {
+    flush();
+    x86_64::instructions::interrupts::disable();
+    crate::serial_println!("Power: shutting down");
+
+    let fadt = crate::acpi::fadt();
+    if let Some(fadt) = fadt.filter(|fadt| fadt.pm1a_control != 0) {
+        let (slp_typ_a, slp_typ_b) =
+            crate::acpi::s5_sleep_types(&fadt).unwrap_or((QEMU_S5_SLEEP_TYPE, QEMU_S5_SLEEP_TYPE));
+        enable_acpi(&fadt);
+        enter_sleep_state(fadt.pm1a_control, slp_typ_a);
+        if fadt.pm1b_control != 0 {
+            enter_sleep_state(fadt.pm1b_control, slp_typ_b);
+        }
+    }
+    enter_sleep_state(QEMU_PM1A_CONTROL, QEMU_S5_SLEEP_TYPE);
+
+    crate::println!("It is now safe to turn off your computer.");
+    crate::helpers::hcf()
+}
+
+pub fn reboot() -> ! {
+    flush();
+    x86_64::instructions::interrupts::disable();
+    crate::serial_println!("Power: rebooting");
+
+    if let Some(fadt) = crate::acpi::fadt() {
+        write_reset_register(&fadt);
+    }
+
+    // The 8042's output port drives the CPU reset line
+    unsafe {
+        let mut command = Port::<u8>::new(KEYBOARD_CONTROLLER_COMMAND);
+        for _ in 0..100_000 {
+            if command.read() & KEYBOARD_CONTROLLER_BUSY == 0 {
+                break;
+            }
+            core::hint::spin_loop();
+        }
+        command.write(KEYBOARD_CONTROLLER_PULSE_RESET);
+    }
+    settle();
+
+    // Last resort: an empty IDT turns the next interrupt into a triple fault
+    unsafe {
+        let empty = x86_64::structures::DescriptorTablePointer {
+            limit: 0,
+            base: x86_64::VirtAddr::new(0),
+        };
+        x86_64::instructions::tables::lidt(&empty);
+        core::arch::asm!("int3");
+    }
+    crate::helpers::hcf()
+}
+
+// Halts the other CPUs so nothing is written behind our back, then writes back whatever
+// the filesystems and the disk cache still hold. The disk drivers poll from here on, the
+// CPU their interrupts go to may be one of the halted ones.
+fn flush() {
+    crate::smp::halt_others();
+    crate::fs::block_device::disable_interrupts();
+    if let Err(e) = crate::fs::vfs::sync_all() {
+        crate::println!("Power: sync failed: {}", e);
+    }
+    if crate::fs::block_cache::sync().is_err() {
+        crate::println!("Power: disk cache sync failed");
+    }
+}
+
+// Hands power management from the firmware (SMM) to us, unless that already happened
+fn enable_acpi(fadt: &Fadt) {
+    let mut pm1a = Port::<u16>::new(fadt.pm1a_control);
+    if unsafe { pm1a.read() } & PM1_SCI_ENABLED != 0
+        || fadt.smi_command == 0
+        || fadt.acpi_enable == 0
+    {
+        return;
+    }
+    unsafe { Port::<u8>::new(fadt.smi_command).write(fadt.acpi_enable) };
+    for _ in 0..1_000_000 {
+        if unsafe { pm1a.read() } & PM1_SCI_ENABLED != 0 {
+            return;
+        }
+        core::hint::spin_loop();
+    }
+}
+
+fn enter_sleep_state(pm1_control: u16, sleep_type: u8) {
+    let mut port = Port::<u16>::new(pm1_control);
+    unsafe {
+        let value = port.read() & !(0b111 << PM1_SLP_TYP_SHIFT);
+        port.write(value | (sleep_type as u16) << PM1_SLP_TYP_SHIFT | PM1_SLP_EN);
+    }
+}
+
+fn write_reset_register(fadt: &Fadt) {
+    let Some(reset) = fadt.reset_register else {
+        return;
+    };
+    match reset.space {
+        ADDRESS_SPACE_IO => unsafe {
+            Port::<u8>::new(reset.address as u16).write(fadt.reset_value)
+        },
+        ADDRESS_SPACE_MEMORY => unsafe {
+            (crate::memory::map_mmio(reset.address, 1) as *mut u8).write_volatile(fadt.reset_value)
+        },
+        // Device in bits 32-47, function in 16-31 and the register in 0-15, on bus 0
+        ADDRESS_SPACE_PCI => {
+            let device = crate::io::pci::PciAddress {
+                bus: 0,
+                device: (reset.address >> 32) as u8,
+                function: (reset.address >> 16) as u8,
+            };
+            let offset = reset.address as u8;
+            let shift = (offset & 3) * 8;
+            let old = device.read_u32(offset & !3) & !(0xFF << shift);
+            device.write_u32(offset & !3, old | (fadt.reset_value as u32) << shift);
+        }
+        _ => return,
+    }
+    settle();
+}
+
+// Gives a reset a moment to take effect before trying the next way. The timer interrupts
+// are off, so this busy waits one calibration period on the HPET or PIT.
+fn settle() {
+    crate::timer::measure(|| 0);
+}
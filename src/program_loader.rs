@@ -1,9 +1,9 @@
+use crate::alloc::sync::Arc;
 use crate::alloc::vec::Vec;
 use crate::fs;
 use crate::multitasker::scheduler::SCHEDULER;
 use crate::multitasker::task::Task;
 use crate::println;
-use simple_fatfs::FileSystem;
 
 const ELF_MAGIC: [u8; 4] = [0x7f, b'E', b'L', b'F'];
 const PT_LOAD: u32 = 1;
@@ -16,6 +16,10 @@ const R_X86_64_RELATIVE: u32 = 8;
 const USER_STACK_TOP: u64 = 0x0000_0000_8000_0000;
 const USER_STACK_PAGES: usize = 8;
 
+// The first user program, started by the kernel at boot. It is responsible for
+// launching the shell and gets the keyboard back whenever its children exit.
+pub const INIT_PATH: &str = "/bin/init";
+
 #[derive(Clone, Copy)]
 struct Elf64Header {
     e_entry: u64,
@@ -216,96 +220,64 @@ fn load_elf_image(bytes: &[u8]) -> Result<(Vec<u8>, u64), &'static str> {
     Ok((image, entry_offset))
 }
 
-pub fn launch_program(filename: &str, arg: Option<&str>) -> Result<u64, &'static str> {
-    crate::serial_println!("launch_program: starting for {}", filename);
-
-    let file_content = fs::with_filesystem(|fs_slot| -> Result<Vec<u8>, &'static str> {
-        crate::serial_println!("launch_program: acquired FS lock");
-        let fs = fs_slot.as_mut().ok_or("Filesystem not initialized")?;
+// init starts out in the foreground, its group is set from whatever id it gets
+pub fn launch_init() -> Result<u64, &'static str> {
+    launch_program(INIT_PATH, None, 0, true)
+}
 
-        let mut actual_path = None;
+pub fn launch_program(
+    filename: &str,
+    arg: Option<&str>,
+    parent_id: u64,
+    foreground: bool,
+) -> Result<u64, &'static str> {
+    crate::serial_println!("launch_program: starting for {}", filename);
 
-        // If a path was provided, try it directly first.
-        if filename.contains('/') {
-            if fs.get_ro_file(filename).is_ok() {
-                actual_path = Some(filename.into());
-            } else {
-                let with_bin = crate::alloc::format!("{}.bin", filename);
-                if fs.get_ro_file(with_bin.as_str()).is_ok() {
-                    actual_path = Some(with_bin);
-                }
-            }
-        }
+    let mut actual_path = None;
 
-        // Backwards-compatible root lookup by bare program name.
-        if actual_path.is_none() {
-            if let Ok(dir_iter) = fs.read_dir("/") {
-                crate::serial_println!("launch_program: successfully read root dir");
-                let target = filename
-                    .to_uppercase()
-                    .replace(".", "")
-                    .replace("\\", "")
-                    .replace("/", "");
-                for entry_result in dir_iter {
-                    if let Ok(entry) = entry_result {
-                        if entry.is_file() {
-                            let p_stripped = crate::alloc::format!("{}", entry.path())
-                                .to_uppercase()
-                                .replace(".", "")
-                                .replace("\\", "")
-                                .replace("/", "");
-                            if p_stripped == target
-                                || p_stripped == crate::alloc::format!("{}BIN", target)
-                            {
-                                actual_path = Some(crate::alloc::format!("{}", entry.path()));
-                                break;
-                            }
-                        }
-                    }
-                }
+    // If a path was provided, try it directly first.
+    if filename.contains('/') {
+        if fs::vfs::resolve(filename).is_ok() {
+            actual_path = Some(filename.into());
+        } else {
+            let with_bin = crate::alloc::format!("{}.bin", filename);
+            if fs::vfs::resolve(with_bin.as_str()).is_ok() {
+                actual_path = Some(with_bin);
             }
         }
+    }
 
-        let actual_path = actual_path.ok_or("File not found on FAT32 filesystem")?;
-        crate::serial_println!("launch_program: found file at path {}", actual_path);
-
-        let mut file = match fs.get_ro_file(actual_path.as_str()) {
-            Ok(f) => f,
-            Err(_) => return Err("Failed to open found file"),
-        };
-        crate::serial_println!("launch_program: successfully opened file");
-
-        let size = file.file_size() as usize;
-        crate::serial_println!("launch_program: file size is {}", size);
-        if size == 0 {
-            return Err("File is empty");
-        }
-
-        let mut file_content: Vec<u8> = alloc::vec::Vec::with_capacity(size);
-        let mut buf = [0u8; 512];
-        let mut total_read = 0;
-
-        crate::serial_println!("launch_program: starting chunked read loop");
-        loop {
-            match embedded_io::Read::read(&mut file, &mut buf) {
-                Ok(0) => break,
-                Ok(n) => {
-                    file_content.extend_from_slice(&buf[..n]);
-                    total_read += n;
-                    if total_read >= size {
+    // Backwards-compatible root lookup by bare program name.
+    if actual_path.is_none() {
+        if let Ok(entries) = fs::vfs::read_dir("/") {
+            crate::serial_println!("launch_program: successfully read root dir");
+            let target = filename
+                .to_uppercase()
+                .replace(".", "")
+                .replace("\\", "")
+                .replace("/", "");
+            for entry in entries {
+                if entry.kind == fs::vfs::NodeKind::File {
+                    let p_stripped = entry.name.to_uppercase().replace(".", "");
+                    if p_stripped == target || p_stripped == crate::alloc::format!("{}BIN", target)
+                    {
+                        actual_path = Some(crate::alloc::format!("/{}", entry.name));
                         break;
                     }
                 }
-                Err(_) => return Err("Error reading file"),
             }
         }
-        crate::serial_println!(
-            "launch_program: finished read loop. Read {} bytes",
-            total_read
-        );
+    }
 
-        Ok(file_content)
-    })?;
+    let actual_path: crate::alloc::string::String =
+        actual_path.ok_or("File not found on FAT32 filesystem")?;
+    crate::serial_println!("launch_program: found file at path {}", actual_path);
+
+    let file_content = fs::vfs::read_file(actual_path.as_str())?;
+    crate::serial_println!("launch_program: read {} bytes", file_content.len());
+    if file_content.is_empty() {
+        return Err("File is empty");
+    }
 
     let (program_image, entry_offset) = load_elf_image(&file_content)?;
     let entry_point = program_image.as_ptr() as u64 + entry_offset;
@@ -321,30 +293,20 @@ pub fn launch_program(filename: &str, arg: Option<&str>) -> Result<u64, &'static
         .map(|boxed| boxed.as_ptr() as u64)
         .unwrap_or(0);
 
-    // Generate a task ID (just a hacky static counter for now)
-    static mut NEXT_TASK_ID: u64 = 100;
-    let task_id = unsafe {
-        let id: u64 = NEXT_TASK_ID;
-        NEXT_TASK_ID += 1;
-        id
-    };
-
+    let task_id = crate::multitasker::allocate_task_id();
     let new_task = Task::new(task_id, entry_point, arg_ptr, None)
-        .with_owned_memory(Some(program_image), arg_box);
+        .with_name(actual_path.as_str())
+        .with_parent(parent_id)
+        .with_owned_memory(Some(Arc::new(program_image)), arg_box);
     crate::serial_println!("launch_program: created task");
 
-    crate::multitasker::scheduler::with_scheduler(|scheduler_slot| {
-        if let Some(ref mut scheduler) = *scheduler_slot {
-            // Hand keyboard focus to the new task before interrupts can run it.
-            // Otherwise a very short-lived program like `hello` can exit before
-            // we switch focus, and the launcher would then point focus at a dead task.
-            crate::io::keyboard::set_focus_and_clear(task_id);
-            scheduler.add_task(new_task);
-        } else {
-            return Err("Scheduler not initialized");
-        }
-        Ok(())
-    })?;
+    // Hand the keyboard to the new task's group before any CPU can run it.
+    // Otherwise a very short-lived program like `hello` can exit before
+    // we switch focus, and the launcher would then point focus at a dead task.
+    if foreground {
+        crate::io::keyboard::set_foreground_and_clear(new_task.pgid);
+    }
+    SCHEDULER.add_task(new_task);
     crate::serial_println!("launch_program: task added to scheduler");
 
     crate::serial_println!("launch_program: complete! Returning task ID.");
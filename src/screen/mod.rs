@@ -110,7 +110,7 @@ pub fn serial_task() -> ! {
         // Drain serial queue
         for _ in 0..1024 {
             if let Some(byte) = crate::io::log_buffer::SERIAL_QUEUE.pop_char() {
-                crate::io::serial::serial_write_byte(byte);
+                crate::io::tty::write_byte(byte);
             } else {
                 break;
             }
@@ -351,11 +351,52 @@ impl<'a> FramebufferWriter<'a> {
                 let char_h = self.font.header.height as u64;
                 self.set_cursor(visual_col * char_w, visual_line * char_h);
             }
+            'K' => {
+                // Erase from the cursor to the end of the current line
+                let char_h = self.font.header.height as u64;
+                let width = self.width.saturating_sub(self.cursor_x);
+                self.clear_rect(self.cursor_x, self.cursor_y, width, char_h);
+            }
+            'C' => {
+                // Move cursor forward: \x1B[<n>C
+                let n = self.esc_param_or(1);
+                let char_w = self.font.header.width as u64;
+                let max_x = self.width.saturating_sub(char_w);
+                self.move_cursor_x((self.cursor_x + n * char_w).min(max_x));
+            }
+            'D' => {
+                // Move cursor back without erasing: \x1B[<n>D
+                let n = self.esc_param_or(1);
+                let char_w = self.font.header.width as u64;
+                self.move_cursor_x(self.cursor_x.saturating_sub(n * char_w));
+            }
             _ => {}
         }
         self.in_esc = false;
     }
 
+    // Parses the first numeric parameter of the pending escape sequence.
+    fn esc_param_or(&self, default: u64) -> u64 {
+        let mut value = 0u64;
+        let mut has_digits = false;
+        for i in 0..(self.esc_idx - 1) {
+            let b = self.esc_buf[i];
+            if !b.is_ascii_digit() {
+                break;
+            }
+            value = value * 10 + (b - b'0') as u64;
+            has_digits = true;
+        }
+        if has_digits { value } else { default }
+    }
+
+    fn move_cursor_x(&mut self, x: u64) {
+        // Redraw the row so the hardware cursor does not leave a ghost behind
+        let char_h = self.font.header.height as u64;
+        self.mark_dirty(self.cursor_y, char_h);
+        self.cursor_x = x;
+    }
+
     pub fn set_cursor(&mut self, x: u64, y: u64) {
         // Mark old line as dirty to erase old cursor from screen
         let char_h = self.font.header.height as u64;
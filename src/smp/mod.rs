@@ -0,0 +1,180 @@
+/*****************************************************************************************************
+ *                                           DOCUMENTATION                                           *
+ *            THIS MODULE BRINGS UP THE OTHER CPUS (APPLICATION PROCESSORS) LIMINE FOUND.            *
+ * EACH ONE GETS ITS OWN PER-CPU DATA BEHIND GS, LOADS THE SHARED IDT AND STARTS ITS OWN APIC TIMER, *
+ *           THEN BECOMES THE IDLE TASK OF ITS RUN QUEUE AND LETS THE SCHEDULER TAKE OVER.           *
+ *         CPUS POKE EACH OTHER WITH IPIS: ONE TO RESCHEDULE, ONE TO DROP A STALE TLB ENTRY.         *
+ ****************************************************************************************************/
+
+pub mod percpu;
+
+pub use percpu::{cpu_index, current};
+
+use crate::multitasker::task::Task;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use limine::mp::Cpu;
+use limine::request::MpRequest;
+
+#[used]
+#[unsafe(link_section = ".limine_requests")]
+pub static MP_REQUEST: MpRequest = MpRequest::new();
+
+// CPUs running the scheduler, the BSP included
+static ONLINE: AtomicUsize = AtomicUsize::new(1);
+// Page tables the APs switch to, the BSP's
+static KERNEL_CR3: AtomicU64 = AtomicU64::new(0);
+
+pub fn init_bsp() {
+    percpu::init_bsp();
+}
+
+pub fn online_cpus() -> usize {
+    ONLINE.load(Ordering::Acquire)
+}
+
+// Starts every AP and waits for them to come online. Needs the APICs, the scheduler and
+// a calibrated APIC timer, without those the kernel stays on one CPU.
+pub fn start_aps() {
+    let Some(response) = MP_REQUEST.get_response() else {
+        crate::println!("SMP: no MP response, running on one CPU");
+        return;
+    };
+    if !crate::interrupts::apic_enabled() || !crate::interrupts::apic_timer_calibrated() {
+        crate::println!("SMP: no APIC timer, running on one CPU");
+        return;
+    }
+
+    let bsp = response.bsp_lapic_id();
+    let aps: alloc::vec::Vec<&&Cpu> = response
+        .cpus()
+        .iter()
+        .filter(|cpu| cpu.lapic_id != bsp)
+        .take(percpu::MAX_CPUS - 1)
+        .collect();
+
+    KERNEL_CR3.store(
+        x86_64::registers::control::Cr3::read_raw()
+            .0
+            .start_address()
+            .as_u64(),
+        Ordering::Release,
+    );
+    for (i, cpu) in aps.iter().enumerate() {
+        let index = i + 1;
+        let per_cpu = percpu::create_ap(index, cpu.lapic_id);
+        cpu.extra.store(per_cpu as u64, Ordering::Release);
+        cpu.goto_address.write(ap_entry);
+
+        // One at a time, so boot messages don't interleave
+        while online_cpus() <= index {
+            core::hint::spin_loop();
+        }
+    }
+    crate::println!("SMP: {} CPU(s) online", online_cpus());
+}
+
+unsafe extern "C" fn ap_entry(cpu: &Cpu) -> ! {
+    unsafe {
+        core::arch::asm!("mov cr3, {}", in(reg) KERNEL_CR3.load(Ordering::Acquire));
+        crate::helpers::enable_sse();
+        percpu::install_ap(cpu.extra.load(Ordering::Acquire) as *mut percpu::PerCpu);
+        crate::interrupts::load_idt();
+    }
+    crate::interrupts::init_ap_apic();
+
+    // What runs on this boot stack from here on is the CPU's idle task
+    let index = cpu_index();
+    let idle = Task::boot_context(crate::multitasker::allocate_task_id(), "idle");
+    current().set_idle_task(idle.id);
+    crate::multitasker::scheduler::SCHEDULER.cpu_online(index, idle);
+    crate::println!("SMP: CPU {} (APIC {}) online", index, current().lapic_id);
+    ONLINE.fetch_add(1, Ordering::AcqRel);
+
+    x86_64::instructions::interrupts::enable();
+    crate::multitasker::idle_task()
+}
+
+/********
+ * IPIS *
+ ********/
+// Makes `cpu` run the scheduler now instead of at its next tick
+pub fn reschedule(cpu: usize) {
+    if let Some(target) = percpu::cpus().find(|c| c.index == cpu) {
+        crate::interrupts::send_ipi(target.lapic_id, crate::interrupts::RESCHEDULE_VECTOR);
+    }
+}
+
+// The page being shot down and the CPUs that still have to drop it, one bit per CPU index
+static SHOOTDOWN_LOCK: spin::Mutex<()> = spin::Mutex::new(());
+static SHOOTDOWN_ADDRESS: AtomicU64 = AtomicU64::new(0);
+static SHOOTDOWN_TARGETS: AtomicU64 = AtomicU64::new(0);
+
+// Makes every other CPU forget the translation of `virt`, after its mapping changed.
+// Returns once all of them have. Callers may have interrupts off, so while waiting we
+// answer shootdowns aimed at us ourselves, otherwise two CPUs could wait on each other.
+pub fn tlb_shootdown(virt: u64) {
+    let online = online_cpus();
+    if online < 2 {
+        return;
+    }
+    let guard = loop {
+        if let Some(guard) = SHOOTDOWN_LOCK.try_lock() {
+            break guard;
+        }
+        on_tlb_shootdown();
+        core::hint::spin_loop();
+    };
+
+    let everyone = if online >= 64 {
+        u64::MAX
+    } else {
+        (1 << online) - 1
+    };
+    SHOOTDOWN_ADDRESS.store(virt, Ordering::Release);
+    SHOOTDOWN_TARGETS.store(everyone & !(1 << cpu_index()), Ordering::Release);
+    crate::interrupts::broadcast_ipi(crate::interrupts::TLB_SHOOTDOWN_VECTOR);
+    while SHOOTDOWN_TARGETS.load(Ordering::Acquire) != 0 {
+        core::hint::spin_loop();
+    }
+    drop(guard);
+}
+
+// How many CPUs answered halt_others
+static HALTED: AtomicUsize = AtomicUsize::new(0);
+
+// Stops every other CPU for good, before shutdown or reboot. Waits a little for them to
+// answer, then this is the only CPU online.
+pub fn halt_others() {
+    let others = online_cpus() - 1;
+    if others == 0 {
+        return;
+    }
+    crate::interrupts::broadcast_ipi(crate::interrupts::HALT_VECTOR);
+    for _ in 0..10_000_000 {
+        if HALTED.load(Ordering::Acquire) >= others {
+            break;
+        }
+        core::hint::spin_loop();
+    }
+    // Nobody is left to answer a TLB shootdown
+    ONLINE.store(1, Ordering::Release);
+}
+
+// Called from the halt IPI, interrupts stay off so this CPU never runs anything again
+pub fn on_halt() -> ! {
+    HALTED.fetch_add(1, Ordering::AcqRel);
+    crate::helpers::hcf()
+}
+
+// Drops the page being shot down if this CPU still has to
+pub fn on_tlb_shootdown() {
+    let bit = 1 << cpu_index();
+    if SHOOTDOWN_TARGETS.load(Ordering::Acquire) & bit == 0 {
+        return;
+    }
+    let virt = SHOOTDOWN_ADDRESS.load(Ordering::Acquire);
+    unsafe {
+        core::arch::asm!("invlpg [{}]", in(reg) virt, options(nostack, preserves_flags));
+    }
+    SHOOTDOWN_TARGETS.fetch_and(!bit, Ordering::AcqRel);
+}
@@ -0,0 +1,192 @@
+use alloc::boxed::Box;
+use alloc::vec;
+use core::arch::asm;
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicPtr, AtomicU8, AtomicU64, Ordering};
+use x86_64::VirtAddr;
+use x86_64::instructions::segmentation::{CS, DS, ES, SS, Segment};
+use x86_64::instructions::tables::load_tss;
+use x86_64::registers::model_specific::GsBase;
+use x86_64::structures::gdt::{Descriptor, GlobalDescriptorTable};
+use x86_64::structures::tss::TaskStateSegment;
+
+// Everything a CPU keeps to itself, found through the GS base. The interrupt stubs reach the
+// FPU snapshot and the switch marker with gs: relative addressing, the rest goes through
+// current().
+
+pub const MAX_CPUS: usize = 64;
+pub const DOUBLE_FAULT_IST: usize = 0;
+const DOUBLE_FAULT_STACK_SIZE: usize = 16 * 1024;
+const NO_TASK: u64 = u64::MAX;
+
+// The FPU state of whatever an interrupt or syscall interrupted, saved by the stubs on entry
+#[repr(C, align(16))]
+pub struct InterruptFpuSnapshot(pub [u8; 512]);
+
+#[repr(C, align(64))]
+pub struct PerCpu {
+    self_ptr: u64, // Must stay first, current() reads it from gs:[0]
+    pub(crate) fpu_snapshot: UnsafeCell<InterruptFpuSnapshot>,
+    pub(crate) fpu_snapshot_valid: AtomicU8,
+    // The task whose stack this CPU is still on after the scheduler switched away from it,
+    // until the stub loads the next stack pointer. No other CPU may run it before then.
+    pub(crate) switching_from: AtomicU64,
+    pub index: usize,
+    pub lapic_id: u32,
+    current_task: AtomicU64,
+    idle_task: AtomicU64,
+    gdt: GlobalDescriptorTable<9>,
+    tss: TaskStateSegment,
+}
+
+// Every CPU that has been set up, by index
+static CPUS: [AtomicPtr<PerCpu>; MAX_CPUS] =
+    [const { AtomicPtr::new(core::ptr::null_mut()) }; MAX_CPUS];
+
+static mut BSP_CPU: PerCpu = PerCpu::new(0, 0);
+static mut BSP_DOUBLE_FAULT_STACK: [u8; DOUBLE_FAULT_STACK_SIZE] = [0; DOUBLE_FAULT_STACK_SIZE];
+
+impl PerCpu {
+    const fn new(index: usize, lapic_id: u32) -> Self {
+        Self {
+            self_ptr: 0,
+            fpu_snapshot: UnsafeCell::new(InterruptFpuSnapshot([0; 512])),
+            fpu_snapshot_valid: AtomicU8::new(0),
+            switching_from: AtomicU64::new(NO_TASK),
+            index,
+            lapic_id,
+            current_task: AtomicU64::new(0),
+            idle_task: AtomicU64::new(NO_TASK),
+            gdt: GlobalDescriptorTable::empty(),
+            tss: TaskStateSegment::new(),
+        }
+    }
+
+    pub fn current_task(&self) -> u64 {
+        self.current_task.load(Ordering::Relaxed)
+    }
+
+    pub fn set_current_task(&self, id: u64) {
+        self.current_task.store(id, Ordering::Relaxed);
+    }
+
+    pub fn idle_task(&self) -> Option<u64> {
+        Some(self.idle_task.load(Ordering::Relaxed)).filter(|&id| id != NO_TASK)
+    }
+
+    pub fn set_idle_task(&self, id: u64) {
+        self.idle_task.store(id, Ordering::Relaxed);
+    }
+
+    pub fn is_bsp(&self) -> bool {
+        self.index == 0
+    }
+}
+
+// Sets up the boot CPU. Runs first thing at boot, before there is a heap or an IDT.
+pub fn init_bsp() {
+    unsafe {
+        let stack = core::ptr::addr_of_mut!(BSP_DOUBLE_FAULT_STACK) as u64;
+        install(
+            core::ptr::addr_of_mut!(BSP_CPU),
+            stack + DOUBLE_FAULT_STACK_SIZE as u64,
+        );
+    }
+}
+
+// Creates the per-CPU data of an application processor, it installs it itself once running
+pub fn create_ap(index: usize, lapic_id: u32) -> *mut PerCpu {
+    Box::into_raw(Box::new(PerCpu::new(index, lapic_id)))
+}
+
+// Called on the CPU that owns `cpu`: points GS at it and loads its GDT and TSS
+pub unsafe fn install_ap(cpu: *mut PerCpu) {
+    let stack = Box::leak(vec![0u8; DOUBLE_FAULT_STACK_SIZE].into_boxed_slice());
+    unsafe { install(cpu, stack.as_ptr() as u64 + DOUBLE_FAULT_STACK_SIZE as u64) };
+}
+
+unsafe fn install(cpu: *mut PerCpu, double_fault_stack_top: u64) {
+    unsafe {
+        (*cpu).self_ptr = cpu as u64;
+        (*cpu).tss.interrupt_stack_table[DOUBLE_FAULT_IST] =
+            VirtAddr::new(double_fault_stack_top & !0xF);
+
+        // Same layout as the GDT Limine leaves us, so the selectors tasks are built with
+        // (0x28 code, 0x30 data) stay valid. The 16 and 32 bit segments aren't needed.
+        let gdt = &mut *core::ptr::addr_of_mut!((*cpu).gdt);
+        for _ in 0..4 {
+            gdt.append(Descriptor::UserSegment(0));
+        }
+        let code = gdt.append(Descriptor::kernel_code_segment());
+        let data = gdt.append(Descriptor::kernel_data_segment());
+        let tss = gdt.append(Descriptor::tss_segment(&*core::ptr::addr_of!((*cpu).tss)));
+
+        (*core::ptr::addr_of!((*cpu).gdt)).load();
+        CS::set_reg(code);
+        SS::set_reg(data);
+        DS::set_reg(data);
+        ES::set_reg(data);
+        load_tss(tss);
+
+        GsBase::write(VirtAddr::new(cpu as u64));
+        CPUS[(*cpu).index].store(cpu, Ordering::Release);
+    }
+}
+
+pub fn current() -> &'static PerCpu {
+    let cpu: u64;
+    unsafe {
+        asm!("mov {}, gs:[0]", out(reg) cpu, options(nostack, readonly, preserves_flags));
+        &*(cpu as *const PerCpu)
+    }
+}
+
+pub fn cpu_index() -> usize {
+    current().index
+}
+
+// Every CPU that is set up
+pub fn cpus() -> impl Iterator<Item = &'static PerCpu> {
+    CPUS.iter()
+        .map(|cpu| cpu.load(Ordering::Acquire))
+        .take_while(|cpu| !cpu.is_null())
+        .map(|cpu| unsafe { &*cpu })
+}
+
+// Whether another CPU is still running on `task`'s stack
+pub fn is_switching_out(task: u64) -> bool {
+    let this = cpu_index();
+    cpus().any(|cpu| cpu.index != this && cpu.switching_from.load(Ordering::Acquire) == task)
+}
+
+pub fn set_switching_from(task: u64) {
+    current().switching_from.store(task, Ordering::Release);
+}
+
+/****************
+ * FPU SNAPSHOT *
+ ****************/
+// These run with interrupts off, so the task can't move to another CPU halfway through
+
+// Takes the snapshot the stubs saved on entry, if there is one
+pub fn take_fpu_snapshot(dest: &mut [u8; 512]) -> bool {
+    let cpu = current();
+    if cpu.fpu_snapshot_valid.load(Ordering::Relaxed) == 0 {
+        return false;
+    }
+    dest.copy_from_slice(unsafe { &(*cpu.fpu_snapshot.get()).0 });
+    cpu.fpu_snapshot_valid.store(0, Ordering::Relaxed);
+    true
+}
+
+pub fn copy_fpu_snapshot() -> Option<InterruptFpuSnapshot> {
+    let cpu = current();
+    (cpu.fpu_snapshot_valid.load(Ordering::Relaxed) != 0)
+        .then(|| InterruptFpuSnapshot(unsafe { (*cpu.fpu_snapshot.get()).0 }))
+}
+
+pub fn restore_fpu_snapshot(snapshot: InterruptFpuSnapshot) {
+    let cpu = current();
+    unsafe { *cpu.fpu_snapshot.get() = snapshot };
+    cpu.fpu_snapshot_valid.store(1, Ordering::Relaxed);
+}
@@ -0,0 +1,86 @@
+use core::mem::ManuallyDrop;
+use core::ops::{Deref, DerefMut};
+#[cfg(not(test))]
+use x86_64::instructions::interrupts;
+
+// Host unit tests run as an ordinary process, which can't touch the interrupt flag
+#[cfg(test)]
+mod interrupts {
+    pub fn are_enabled() -> bool {
+        false
+    }
+
+    pub fn disable() {}
+
+    pub fn enable() {}
+}
+
+// A spin lock that keeps interrupts off on its CPU while it is held. Anything an interrupt
+// handler locks needs one: if the handler fired on a CPU already holding the lock, it would
+// spin on itself forever. Interrupts only come back on if they were on when locking.
+pub struct IrqMutex<T> {
+    inner: spin::Mutex<T>,
+}
+
+pub struct IrqMutexGuard<'a, T> {
+    guard: ManuallyDrop<spin::MutexGuard<'a, T>>,
+    interrupts_were_enabled: bool,
+}
+
+impl<T> IrqMutex<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            inner: spin::Mutex::new(value),
+        }
+    }
+
+    pub fn lock(&self) -> IrqMutexGuard<'_, T> {
+        let interrupts_were_enabled = interrupts::are_enabled();
+        interrupts::disable();
+        IrqMutexGuard {
+            guard: ManuallyDrop::new(self.inner.lock()),
+            interrupts_were_enabled,
+        }
+    }
+
+    pub fn try_lock(&self) -> Option<IrqMutexGuard<'_, T>> {
+        let interrupts_were_enabled = interrupts::are_enabled();
+        interrupts::disable();
+        match self.inner.try_lock() {
+            Some(guard) => Some(IrqMutexGuard {
+                guard: ManuallyDrop::new(guard),
+                interrupts_were_enabled,
+            }),
+            None => {
+                if interrupts_were_enabled {
+                    interrupts::enable();
+                }
+                None
+            }
+        }
+    }
+}
+
+impl<T> Deref for IrqMutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T> DerefMut for IrqMutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<T> Drop for IrqMutexGuard<'_, T> {
+    fn drop(&mut self) {
+        // Unlock first, an interrupt must not find the lock still taken
+        unsafe { ManuallyDrop::drop(&mut self.guard) };
+        if self.interrupts_were_enabled {
+            interrupts::enable();
+        }
+    }
+}
@@ -1,38 +1,76 @@
-use core::sync::atomic::{AtomicU64, Ordering};
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use x86_64::instructions::port::Port;
 
 use crate::{multitasker::yield_now, serial_println};
 static TICKS: AtomicU64 = AtomicU64::new(0);
 
 pub const TICKS_PER_SECOND: u64 = 1000;
-const PIT_BASE_FREQUENCY: u64 = 1193180;
+pub const PIT_BASE_FREQUENCY: u64 = 1193180;
+
+// Once the TSC is measured it is the clock: uptime is how far it counted since TSC_START.
+// Before that, or if measuring failed, uptime is the number of timer interrupts.
+static TSC_HZ: AtomicU64 = AtomicU64::new(0);
+static TSC_START: AtomicU64 = AtomicU64::new(0);
+// The latest uptime handed out, so the clock never goes back if the CPUs' TSCs differ a bit
+static LAST_UPTIME_NS: AtomicU64 = AtomicU64::new(0);
+// Whether the local APIC timers are one-shot, armed for the next deadline instead of ticking
+static TICKLESS: AtomicBool = AtomicBool::new(false);
+
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+pub const CALIBRATION_MS: u64 = 10;
+
+// A task sharing its CPU runs this long before the next one gets a turn
+pub const TIMESLICE_NS: u64 = NANOS_PER_SECOND / TICKS_PER_SECOND;
+// A task alone on its CPU is still interrupted this often, for Ctrl+Z and load balancing
+pub const LONE_TASK_SLICE_NS: u64 = 10_000_000;
+// An idle CPU with nothing to wait for still wakes up this often
+pub const MAX_IDLE_NS: u64 = NANOS_PER_SECOND;
 
 pub fn tick() {
     // Relaxed ordering is fine here because we're just incrementing a counter
     TICKS.fetch_add(1, Ordering::Relaxed);
 }
 
+pub fn uptime_ns() -> u64 {
+    let hz = TSC_HZ.load(Ordering::Relaxed);
+    if hz == 0 {
+        return TICKS.load(Ordering::Relaxed) * (NANOS_PER_SECOND / TICKS_PER_SECOND);
+    }
+    let elapsed = read_tsc().saturating_sub(TSC_START.load(Ordering::Relaxed));
+    let ns = (elapsed as u128 * NANOS_PER_SECOND as u128 / hz as u128) as u64;
+    LAST_UPTIME_NS.fetch_max(ns, Ordering::Relaxed).max(ns)
+}
+
 pub fn get_uptime_ms() -> u64 {
-    let current_ticks = TICKS.load(Ordering::Relaxed);
-    // (ticks * 1000) / TICKS_PER_SECOND gives us milliseconds
-    (current_ticks * 1000) / TICKS_PER_SECOND
+    uptime_ns() / 1_000_000
+}
+
+pub fn is_tickless() -> bool {
+    TICKLESS.load(Ordering::Relaxed)
 }
 
 pub fn sleep_ms(ms: u64) {
     let wake_at = get_uptime_ms() + ms;
 
     // Set the wake_at time for the current task
-    x86_64::instructions::interrupts::without_interrupts(|| {
-        if let Some(ref mut sched) = *crate::multitasker::scheduler::SCHEDULER.lock() {
-            if let Some(ref mut task) = sched.current_task {
-                task.wake_at = wake_at;
-            }
-        }
-    });
+    crate::multitasker::scheduler::SCHEDULER.with_current(|task| task.wake_at = wake_at);
 
     // Yield immediately so the Idle task can take over
     crate::multitasker::yield_now();
 }
 pub fn init_timer() {
+    calibrate_tsc();
+
+    // The local APIC timer replaces the PIT when the APICs are in use. With a TSC to keep
+    // time it doesn't have to tick at all, it is armed for whatever comes next instead.
+    if crate::interrupts::start_apic_timer(TICKS_PER_SECOND) {
+        if TSC_HZ.load(Ordering::Relaxed) != 0 {
+            TICKLESS.store(true, Ordering::Relaxed);
+            crate::interrupts::arm_apic_timer(TIMESLICE_NS);
+            crate::println!("Timer: tickless, the APIC timer is one-shot");
+        }
+        return;
+    }
     let divisor: u16 = (PIT_BASE_FREQUENCY / TICKS_PER_SECOND) as u16;
 
     unsafe {
@@ -44,3 +82,75 @@ pub fn init_timer() {
         core::arch::asm!("out 0x40, al", in("al") ((divisor >> 8) & 0xFF) as u8, options(nomem, nostack));
     }
 }
+
+/*******
+ * TSC *
+ *******/
+pub fn read_tsc() -> u64 {
+    unsafe { core::arch::x86_64::_rdtsc() }
+}
+
+fn calibrate_tsc() {
+    let source = if crate::io::hpet::init() {
+        "HPET"
+    } else {
+        "PIT"
+    };
+    let hz = measure(read_tsc) * 1000 / CALIBRATION_MS;
+    if hz == 0 {
+        crate::println!("Timer: TSC calibration failed, counting ticks");
+        return;
+    }
+
+    // Without an invariant TSC the rate follows the CPU clock, good enough under QEMU
+    let invariant = unsafe { core::arch::x86_64::__cpuid(0x8000_0007) }.edx & (1 << 8) != 0;
+    TSC_START.store(read_tsc(), Ordering::Relaxed);
+    TSC_HZ.store(hz, Ordering::Relaxed);
+    crate::println!(
+        "Timer: TSC at {} MHz, measured with the {}{}",
+        hz / 1_000_000,
+        source,
+        if invariant { "" } else { " (not invariant)" }
+    );
+}
+
+// How far `read` advances in CALIBRATION_MS, timed by the HPET if there is one and by
+// PIT channel 2 otherwise
+pub fn measure(read: impl Fn() -> u64) -> u64 {
+    if crate::io::hpet::available() {
+        let start = read();
+        crate::io::hpet::wait_ms(CALIBRATION_MS);
+        return read().wrapping_sub(start);
+    }
+
+    const PIT_CHANNEL_2: u16 = 0x42;
+    const PIT_COMMAND: u16 = 0x43;
+    const PIT_GATE_PORT: u16 = 0x61;
+    const GATE: u8 = 0x01;
+    const SPEAKER: u8 = 0x02;
+    const OUTPUT: u8 = 0x20;
+
+    let count = (PIT_BASE_FREQUENCY * CALIBRATION_MS / 1000) as u16;
+    let mut gate = Port::<u8>::new(PIT_GATE_PORT);
+    let mut command = Port::<u8>::new(PIT_COMMAND);
+    let mut channel_2 = Port::<u8>::new(PIT_CHANNEL_2);
+    unsafe {
+        let old_gate = gate.read();
+
+        // Channel 2 in mode 0 counts down once and raises its output, speaker stays off
+        gate.write(old_gate & !(GATE | SPEAKER));
+        command.write(0xB0);
+        channel_2.write(count as u8);
+        channel_2.write((count >> 8) as u8);
+
+        let start = read();
+        gate.write((old_gate & !SPEAKER) | GATE);
+        while gate.read() & OUTPUT == 0 {
+            core::hint::spin_loop();
+        }
+        let elapsed = read().wrapping_sub(start);
+
+        gate.write(old_gate);
+        elapsed
+    }
+}